@@ -0,0 +1,96 @@
+//! Benchmarks of the pull parser.
+//!
+//! Every document is parsed with bulk text scanning enabled and disabled, so the effect
+//! of the scanning can be seen by comparing `*_bulk` and `*_per_char` results.
+
+extern crate test;
+extern crate xml;
+
+use std::io::BufReader;
+
+use test::Bencher;
+
+use xml::reader::{EventReader, ParserConfig};
+use xml::reader::events;
+
+static LOREM: &'static str =
+    "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor \
+     incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud \
+     exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.";
+
+/// Elements with long text content.
+fn text_document() -> String {
+    let mut s = String::from_str("<root>\n");
+    for i in range(0u, 1000) {
+        s.push_str(format!("  <item id=\"{}\">{}</item>\n", i, LOREM).as_slice());
+    }
+    s.push_str("</root>");
+    s
+}
+
+/// Empty elements with long attribute values.
+fn attributes_document() -> String {
+    let mut s = String::from_str("<root>\n");
+    for i in range(0u, 1000) {
+        s.push_str(format!("  <item id=\"{}\" title='{}' description=\"{}\"/>\n", i, LOREM, LOREM).as_slice());
+    }
+    s.push_str("</root>");
+    s
+}
+
+/// Long comments and CDATA sections.
+fn comments_document() -> String {
+    let mut s = String::from_str("<root>\n");
+    for _ in range(0u, 1000) {
+        s.push_str(format!("  <!-- {} -->\n  <![CDATA[{}]]>\n", LOREM, LOREM).as_slice());
+    }
+    s.push_str("</root>");
+    s
+}
+
+fn parse(data: &str, config: ParserConfig) -> uint {
+    let mut reader = EventReader::new_with_config(BufReader::new(data.as_bytes()), config);
+    let mut n = 0u;
+    for e in reader.events() {
+        match e {
+            events::Error(e) => panic!("Unexpected error: {}", e),
+            _ => n += 1
+        }
+    }
+    n
+}
+
+fn bench_document(b: &mut Bencher, data: String, bulk: bool) {
+    b.bytes = data.len() as u64;
+    b.iter(|| parse(data.as_slice(), ParserConfig::new().ignore_comments(false).bulk_text_scanning(bulk)));
+}
+
+#[bench]
+fn text_bulk(b: &mut Bencher) {
+    bench_document(b, text_document(), true);
+}
+
+#[bench]
+fn text_per_char(b: &mut Bencher) {
+    bench_document(b, text_document(), false);
+}
+
+#[bench]
+fn attributes_bulk(b: &mut Bencher) {
+    bench_document(b, attributes_document(), true);
+}
+
+#[bench]
+fn attributes_per_char(b: &mut Bencher) {
+    bench_document(b, attributes_document(), false);
+}
+
+#[bench]
+fn comments_bulk(b: &mut Bencher) {
+    bench_document(b, comments_document(), true);
+}
+
+#[bench]
+fn comments_per_char(b: &mut Bencher) {
+    bench_document(b, comments_document(), false);
+}
@@ -1,15 +1,204 @@
 //! Contains several types used throughout the library.
 
 use std::fmt;
+use std::io;
 
 /// XML parsing error.
 ///
-/// Consists of a row and column reference and a message.
+/// Consists of a row and column reference, a kind and a message. Errors caused by a specific
+/// construct, like a duplicate attribute, also carry its source span.
 #[deriving(Clone, PartialEq, Eq)]
 pub struct Error {
     row: uint,
     col: uint,
-    msg: String
+    kind: ErrorKind,
+    msg: String,
+    span: Option<Span>
+}
+
+/// Kind of an XML parsing error.
+///
+/// Every kind carries the data specific to it, like the offending name or token. Kinds are
+/// grouped into several categories, see `ErrorKind::category()` method.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ErrorKind {
+    /// A token which is not allowed in the current context.
+    UnexpectedToken {
+        /// The token which was found.
+        pub found: String,
+        /// Description of the token or construct which was expected instead, e.g. `"="`.
+        pub expected: &'static str,
+        /// Description of the construct which was being parsed, e.g. `"opening tag"`.
+        pub context: &'static str
+    },
+
+    /// The document ended before the current construct was complete.
+    UnexpectedEof,
+
+    /// A string which does not match `Name` or `QName` production.
+    InvalidName(String),
+
+    /// Invalid value of a pseudo-attribute of the XML declaration.
+    InvalidDeclarationValue {
+        /// Name of the pseudo-attribute, e.g. `"version"`.
+        pub name: &'static str,
+        /// The value which was found.
+        pub value: String
+    },
+
+    /// A character which does not match `Char` production of the document XML version.
+    InvalidCharacter(char),
+
+    /// A processing instruction with an empty or reserved target name.
+    InvalidProcessingInstruction(String),
+
+    /// The document does not contain a root element.
+    MissingRootElement,
+
+    /// The document ended while the given element was still open.
+    UnclosedElement(Name),
+
+    /// A closing tag which does not match the currently open element.
+    MismatchedClosingTag {
+        /// Name from the closing tag.
+        pub found: Name,
+        /// Name of the currently open element.
+        pub expected: Name
+    },
+
+    /// An attribute which is specified more than once in the same start tag.
+    DuplicateAttribute(Name),
+
+    /// Character data outside of the root element.
+    CharactersOutsideRoot(String),
+
+    /// A name with a prefix which is not bound to any namespace.
+    UnboundPrefix(Name),
+
+    /// A reserved prefix (`xml` or `xmlns`) which is used in a way it cannot be used.
+    ReservedPrefix(String),
+
+    /// A namespace declaration which is not allowed, like undeclaring a prefix
+    /// or binding a reserved namespace as the default one.
+    InvalidNamespaceBinding {
+        /// The prefix being declared, `None` for the default namespace.
+        pub prefix: Option<String>,
+        /// The namespace URI.
+        pub uri: String
+    },
+
+    /// A reference to an entity which is not declared.
+    UndefinedEntity(String),
+
+    /// A reference to an entity from its own replacement text.
+    RecursiveEntity(String),
+
+    /// A reference to an unparsed entity in the content.
+    UnparsedEntityReference(String),
+
+    /// A reference to an external parsed entity, which are not supported.
+    ExternalEntityReference(String),
+
+    /// A character reference to an invalid character; contains the text of the reference
+    /// between `&` and `;`.
+    InvalidCharacterReference(String),
+
+    /// An entity declaration with invalid replacement text.
+    InvalidEntityValue(String),
+
+    /// An encoding which is not supported by the decoder factory.
+    UnsupportedEncoding(String),
+
+    /// The declared encoding is incompatible with the encoding detected from the input.
+    EncodingConflict {
+        /// Encoding label from the XML declaration.
+        pub declared: String,
+        /// Name of the detected encoding.
+        pub detected: String
+    },
+
+    /// Bytes of the input which do not form a valid character in the document encoding.
+    DecodingError {
+        /// Name of the encoding used to decode the input.
+        pub encoding: &'static str,
+        /// Offset of the first byte of the invalid sequence from the beginning of the input.
+        pub offset: uint
+    },
+
+    /// An error reported by the underlying stream.
+    InputError(io::IoError),
+
+    /// Expansion of the given entity exceeds one of the configured limits.
+    EntityExpansionLimit {
+        /// Name of the entity being expanded.
+        pub entity: String,
+        /// The exceeded limit.
+        pub limit: ExpansionLimit
+    }
+}
+
+/// Entity expansion limit, see `ParserConfig` for their meaning.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ExpansionLimit {
+    /// Maximum nesting depth of entity references.
+    DepthLimit(uint),
+
+    /// Maximum total size of replacement text, in bytes.
+    SizeLimit(uint),
+
+    /// Maximum ratio of total replacement text size to the document size.
+    RatioLimit(uint)
+}
+
+/// Category of an XML parsing error.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ErrorCategory {
+    /// The document does not match XML grammar.
+    SyntaxError,
+
+    /// The document is syntactically correct but violates a well-formedness constraint.
+    WellFormednessError,
+
+    /// The document violates a namespace constraint.
+    NamespaceError,
+
+    /// An entity or a character reference cannot be expanded.
+    EntityError,
+
+    /// The document encoding is not supported or is declared incorrectly.
+    EncodingError,
+
+    /// The underlying stream has failed.
+    IoError,
+
+    /// One of the configured limits was exceeded.
+    LimitError
+}
+
+impl ErrorKind {
+    /// Returns a category this kind belongs to.
+    pub fn category(&self) -> ErrorCategory {
+        match *self {
+            UnexpectedToken { .. } | UnexpectedEof | InvalidName(_) |
+            InvalidDeclarationValue { .. } => SyntaxError,
+
+            InvalidCharacter(_) | InvalidProcessingInstruction(_) | MissingRootElement |
+            UnclosedElement(_) | MismatchedClosingTag { .. } | DuplicateAttribute(_) |
+            CharactersOutsideRoot(_) => WellFormednessError,
+
+            UnboundPrefix(_) | ReservedPrefix(_) | InvalidNamespaceBinding { .. } => NamespaceError,
+
+            UndefinedEntity(_) | RecursiveEntity(_) | UnparsedEntityReference(_) |
+            ExternalEntityReference(_) | InvalidCharacterReference(_) |
+            InvalidEntityValue(_) => EntityError,
+
+            UnsupportedEncoding(_) | EncodingConflict { .. } | DecodingError { .. } => EncodingError,
+
+            InputError(_) => IoError,
+
+            EntityExpansionLimit { .. } => LimitError
+        }
+    }
 }
 
 /// Represents a thing which has a position inside some textual document.
@@ -24,6 +213,71 @@ pub trait HasPosition {
     fn col(&self) -> uint;
 }
 
+/// A position inside a textual document.
+///
+/// Rows and columns are zero-based, columns are counted in characters. The offset is
+/// counted in bytes of the input, from its very beginning, including a byte order mark.
+#[deriving(Clone, PartialEq, Eq)]
+pub struct TextPosition {
+    /// Row (line) number.
+    pub row: uint,
+
+    /// Column number.
+    pub col: uint,
+
+    /// Byte offset.
+    pub offset: uint
+}
+
+impl TextPosition {
+    /// Returns the position of the beginning of a document.
+    #[inline]
+    pub fn new() -> TextPosition {
+        TextPosition { row: 0, col: 0, offset: 0 }
+    }
+}
+
+impl fmt::Show for TextPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.row + 1, self.col + 1)
+    }
+}
+
+impl HasPosition for TextPosition {
+    #[inline]
+    fn row(&self) -> uint { self.row }
+
+    #[inline]
+    fn col(&self) -> uint { self.col }
+}
+
+/// A fragment of a textual document.
+///
+/// The start position points to the first character of the fragment, and the end position
+/// points right after its last character.
+#[deriving(Clone, PartialEq, Eq)]
+pub struct Span {
+    /// Position of the beginning of the fragment.
+    pub start: TextPosition,
+
+    /// Position of the end of the fragment.
+    pub end: TextPosition
+}
+
+impl Span {
+    /// Returns an empty span at the given position.
+    #[inline]
+    pub fn empty_at(position: TextPosition) -> Span {
+        Span { start: position.clone(), end: position }
+    }
+}
+
+impl fmt::Show for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
 impl fmt::Show for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}:{}: {}", self.row + 1, self.col + 1, self.msg)
@@ -40,21 +294,41 @@ impl HasPosition for Error {
 
 impl Error {
     /// Creates a new error using position information from the provided
-    /// `HasPosition` object and a message.
+    /// `HasPosition` object, a kind and a message.
     #[inline]
-    pub fn new<O: HasPosition>(o: &O, msg: String) -> Error {
-        Error { row: o.row(), col: o.col(), msg: msg }
+    pub fn new<O: HasPosition>(o: &O, kind: ErrorKind, msg: String) -> Error {
+        Error { row: o.row(), col: o.col(), kind: kind, msg: msg, span: None }
     }
 
-    /// Creates a new error using provided position information and a message.
+    /// Creates a new error using provided position information, a kind and a message.
     #[inline]
-    pub fn new_full(row: uint, col: uint, msg: String) -> Error {
-        Error { row: row, col: col, msg: msg }
+    pub fn new_full(row: uint, col: uint, kind: ErrorKind, msg: String) -> Error {
+        Error { row: row, col: col, kind: kind, msg: msg, span: None }
+    }
+
+    /// Creates a new error caused by the construct at the given span, a kind and a message.
+    ///
+    /// The position of the error is the beginning of the span.
+    #[inline]
+    pub fn new_with_span(span: Span, kind: ErrorKind, msg: String) -> Error {
+        Error { row: span.start.row, col: span.start.col, kind: kind, msg: msg, span: Some(span) }
     }
 
     /// Returns a reference to a message which is contained inside this error.
     #[inline]
     pub fn msg<'a>(&'a self) -> &'a str { self.msg.as_slice() }
+
+    /// Returns a reference to the kind of this error.
+    #[inline]
+    pub fn kind<'a>(&'a self) -> &'a ErrorKind { &self.kind }
+
+    /// Returns a category of this error.
+    #[inline]
+    pub fn category(&self) -> ErrorCategory { self.kind.category() }
+
+    /// Returns the source span of the construct which caused this error, if it is known.
+    #[inline]
+    pub fn span<'a>(&'a self) -> Option<&'a Span> { self.span.as_ref() }
 }
 
 /// XML qualified name.
@@ -212,6 +486,61 @@ pub fn is_whitespace_char(c: char) -> bool {
     }
 }
 
+/// Checks whether the given character is allowed in XML 1.0 documents (`Char`)
+/// as is defined by XML 1.0 specification, [section 2.2][1].
+///
+/// [1]: http://www.w3.org/TR/2008/REC-xml-20081126/#charsets
+pub fn is_xml10_char(c: char) -> bool {
+    match c {
+        '\x09' | '\x0A' | '\x0D' |
+        '\x20'...'\uD7FF' | '\uE000'...'\uFFFD' | '\U00010000'...'\U0010FFFF' => true,
+        _ => false
+    }
+}
+
+/// Checks whether the given character is allowed in XML 1.1 documents (`Char`)
+/// as is defined by XML 1.1 specification, [section 2.2][1].
+///
+/// Restricted characters (see `is_xml11_restricted_char`) are valid `Char`s, but they
+/// can only appear in a document as character references.
+///
+/// [1]: http://www.w3.org/TR/2006/REC-xml11-20060816/#charsets
+pub fn is_xml11_char(c: char) -> bool {
+    match c {
+        '\x01'...'\uD7FF' | '\uE000'...'\uFFFD' | '\U00010000'...'\U0010FFFF' => true,
+        _ => false
+    }
+}
+
+/// Checks whether the given character is a restricted character (`RestrictedChar`)
+/// as is defined by XML 1.1 specification, [section 2.2][1].
+///
+/// [1]: http://www.w3.org/TR/2006/REC-xml11-20060816/#charsets
+pub fn is_xml11_restricted_char(c: char) -> bool {
+    match c {
+        '\x01'...'\x08' | '\x0B'...'\x0C' | '\x0E'...'\x1F' |
+        '\x7F'...'\x84' | '\x86'...'\x9F' => true,
+        _ => false
+    }
+}
+
+/// Checks whether the given character can appear literally in a document of the given version.
+pub fn is_valid_literal_char(c: char, version: XmlVersion) -> bool {
+    match version {
+        Version10 => is_xml10_char(c),
+        Version11 => is_xml11_char(c) && !is_xml11_restricted_char(c)
+    }
+}
+
+/// Checks whether the given character can be referenced with a character reference
+/// in a document of the given version.
+pub fn is_valid_referenced_char(c: char, version: XmlVersion) -> bool {
+    match version {
+        Version10 => is_xml10_char(c),
+        Version11 => is_xml11_char(c)
+    }
+}
+
 /// Checks whether the given character is a name start character (`NameStartChar`)
 /// as is defined by XML 1.1 specification, [section 2.3][1].
 ///
@@ -307,7 +636,23 @@ impl<T> OptionOps<T> for Option<T> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Name, Attribute};
+    use super::{Name, Attribute, Version10, Version11, is_valid_literal_char, is_valid_referenced_char};
+
+    #[test]
+    fn char_validity() {
+        assert!(is_valid_literal_char('\t', Version10));
+        assert!(is_valid_literal_char('\x85', Version10));
+        assert!(!is_valid_literal_char('\x01', Version10));
+        assert!(!is_valid_literal_char('\uFFFE', Version10));
+        assert!(!is_valid_referenced_char('\x01', Version10));
+
+        assert!(!is_valid_literal_char('\x01', Version11));
+        assert!(is_valid_literal_char('\x85', Version11));
+        assert!(!is_valid_literal_char('\x86', Version11));
+        assert!(is_valid_referenced_char('\x01', Version11));
+        assert!(is_valid_referenced_char('\x85', Version11));
+        assert!(!is_valid_referenced_char('\x00', Version11));
+    }
 
     #[test]
     fn attribute_show() {
@@ -0,0 +1,269 @@
+//! Contains `DocumentBuilder` which constructs documents from reader events.
+
+use common;
+use common::Error;
+use namespace::{Namespace, NamespaceStack};
+use reader::events;
+use reader::events::XmlEvent;
+
+use dom::{Document, Doctype, Element, Node, ElementNode, TextNode, CommentNode, CDataNode,
+          ProcessingInstructionNode, Text, Comment, CData, ProcessingInstruction};
+
+/// Constructs a `Document` from events of `reader::EventReader`.
+///
+/// Events are passed to the builder with `push_event()` method in the order they are emitted by
+/// the reader. After `EndDocument` event the document can be taken with `into_document()` method.
+///
+/// Adjacent `Characters` and `Whitespace` events are stored as a single text node. Namespace
+/// mappings of `StartElement` events are compared with the mappings of the parent element, and
+/// only the new and changed ones are stored in the element.
+pub struct DocumentBuilder {
+    document: Document,
+    stack: Vec<Element>,
+    scopes: Vec<Namespace>,
+    finished: bool
+}
+
+impl DocumentBuilder {
+    /// Creates a new builder.
+    pub fn new() -> DocumentBuilder {
+        DocumentBuilder {
+            document: Document {
+                version: common::Version10,
+                encoding: "UTF-8".to_string(),
+                standalone: None,
+                doctype: None,
+                children: Vec::new()
+            },
+            stack: Vec::new(),
+            scopes: vec!(NamespaceStack::default().squash()),
+            finished: false
+        }
+    }
+
+    /// Adds the next event to the document.
+    ///
+    /// `Error` events are returned as errors; the document cannot be completed after them.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the event cannot follow the previous ones, for example, if `EndElement` event
+    /// is pushed when there is no open element, or if an event is pushed after `EndDocument`.
+    pub fn push_event(&mut self, event: XmlEvent) -> Result<(), Error> {
+        if self.finished {
+            panic!("The document is already finished");
+        }
+        match event {
+            events::StartDocument { version, encoding, standalone } => {
+                self.document.version = version;
+                self.document.encoding = encoding;
+                self.document.standalone = standalone;
+            }
+
+            events::Doctype { name, public_id, system_id, declarations } =>
+                self.document.doctype = Some(Doctype {
+                    name: name,
+                    public_id: public_id,
+                    system_id: system_id,
+                    declarations: declarations
+                }),
+
+            events::StartElement { name, attributes, namespace } => {
+                let mut declared = Namespace::empty();
+                {
+                    let parent = self.scopes.last().unwrap();
+                    for (prefix, uri) in namespace.0.iter() {
+                        if parent.get(prefix) != Some(uri.as_slice()) {
+                            declared.put(prefix.clone(), uri.clone());
+                        }
+                    }
+                }
+                self.scopes.push(namespace);
+                self.stack.push(Element {
+                    name: name,
+                    attributes: attributes,
+                    namespace: declared,
+                    children: Vec::new()
+                });
+            }
+
+            events::EndElement { .. } => {
+                let element = match self.stack.pop() {
+                    Some(element) => element,
+                    None => panic!("Unexpected EndElement event: no element is open")
+                };
+                self.scopes.pop();
+                self.append_node(ElementNode(element));
+            }
+
+            events::Characters(data) | events::Whitespace(data) => {
+                // text split by ignored comments or converted CDATA is merged
+                let merged = match self.current_children().last_mut() {
+                    Some(node) => match *node {
+                        TextNode(Text(ref mut text)) => { text.push_str(data.as_slice()); true }
+                        _ => false
+                    },
+                    None => false
+                };
+                if !merged {
+                    self.append_node(TextNode(Text(data)));
+                }
+            }
+
+            events::CData(data) => self.append_node(CDataNode(CData(data))),
+            events::Comment(data) => self.append_node(CommentNode(Comment(data))),
+            events::ProcessingInstruction { name, data } =>
+                self.append_node(ProcessingInstructionNode(ProcessingInstruction { name: name, data: data })),
+
+            events::EndDocument => {
+                if !self.stack.is_empty() {
+                    panic!("Unexpected EndDocument event: some elements are not closed");
+                }
+                self.finished = true;
+            }
+
+            events::Error(e) => return Err(e)
+        }
+        Ok(())
+    }
+
+    /// Checks whether `EndDocument` event has been pushed to the builder.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the constructed document, or `None` if `EndDocument` event has not been
+    /// pushed yet.
+    pub fn into_document(self) -> Option<Document> {
+        if self.finished { Some(self.document) } else { None }
+    }
+
+    fn current_children<'a>(&'a mut self) -> &'a mut Vec<Node> {
+        match self.stack.last_mut() {
+            Some(element) => &mut element.children,
+            None => &mut self.document.children
+        }
+    }
+
+    #[inline]
+    fn append_node(&mut self, node: Node) {
+        self.current_children().push(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use common::{Name, Attribute};
+    use reader::{EventReader, ParserConfig};
+    use reader::events;
+    use namespace::Namespace;
+
+    use dom::{Document, ElementNode, TextNode, CommentNode, CDataNode, ProcessingInstructionNode,
+              Text, Comment, CData, ProcessingInstruction};
+    use super::DocumentBuilder;
+
+    fn parse(data: &str) -> Document {
+        let mut reader = EventReader::new_with_config(
+            BufReader::new(data.as_bytes()),
+            ParserConfig::new().ignore_comments(false)
+        );
+        Document::from_reader(&mut reader).unwrap()
+    }
+
+    fn declarations(ns: &Namespace) -> Vec<(Option<String>, String)> {
+        let mut result: Vec<(Option<String>, String)> = ns.0.iter().map(|(p, u)| (p.clone(), u.clone())).collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn document_structure() {
+        let doc = parse("<?xml version='1.0' standalone='yes'?>\n<!DOCTYPE a>\n<?pi data?>\
+                         <a x='1'>text &amp; more<!--c--><b/><![CDATA[<cdata>]]></a><!--after-->");
+        assert_eq!(doc.standalone, Some(true));
+        assert_eq!(doc.doctype.as_ref().unwrap().name.as_slice(), "a");
+        assert_eq!(doc.children.len(), 3);
+        assert!(doc.children[0] == ProcessingInstructionNode(ProcessingInstruction {
+            name: "pi".to_string(),
+            data: Some("data".to_string())
+        }));
+        assert!(doc.children[2] == CommentNode(Comment("after".to_string())));
+
+        let a = doc.root();
+        assert_eq!(a.name, Name::new_local("a"));
+        assert_eq!(a.attributes, vec!(Attribute::new_local("x", "1")));
+        assert_eq!(a.children.len(), 4);
+        assert!(a.children[0] == TextNode(Text("text & more".to_string())));
+        assert!(a.children[1] == CommentNode(Comment("c".to_string())));
+        assert!(a.children[2].as_element().unwrap().children.is_empty());
+        assert!(a.children[3] == CDataNode(CData("<cdata>".to_string())));
+        assert_eq!(a.text().as_slice(), "text & more<cdata>");
+    }
+
+    #[test]
+    fn namespace_scopes() {
+        let doc = parse("<a xmlns='urn:d' xmlns:p='urn:p'><p:b xmlns:q='urn:q'><c xmlns:p='urn:p'/></p:b>\
+                         <d xmlns='urn:other' xmlns:p='urn:p2'/></a>");
+        let a = doc.root();
+        assert_eq!(declarations(&a.namespace), vec!(
+            (None, "urn:d".to_string()),
+            (Some("p".to_string()), "urn:p".to_string())
+        ));
+
+        let b = a.child("b", Some("urn:p")).unwrap();
+        assert_eq!(declarations(&b.namespace), vec!((Some("q".to_string()), "urn:q".to_string())));
+
+        // redundant declaration of the same mapping is not stored
+        let c = b.child("c", Some("urn:d")).unwrap();
+        assert!(c.namespace.is_empty());
+
+        let d = a.child("d", Some("urn:other")).unwrap();
+        assert_eq!(declarations(&d.namespace), vec!(
+            (None, "urn:other".to_string()),
+            (Some("p".to_string()), "urn:p2".to_string())
+        ));
+    }
+
+    #[test]
+    fn unprefixed_attributes_have_no_namespace() {
+        let doc = parse("<a xmlns='urn:d' x='1' xmlns:p='urn:p' p:x='2'/>");
+        let a = doc.root();
+        assert_eq!(a.attribute("x", None), Some("1"));
+        assert_eq!(a.attribute("x", Some("urn:d")), None);
+        assert_eq!(a.attribute("x", Some("urn:p")), Some("2"));
+    }
+
+    #[test]
+    fn adjacent_text_is_merged() {
+        let mut builder = DocumentBuilder::new();
+        let events = vec!(
+            events::StartElement {
+                name: Name::new_local("a"),
+                attributes: Vec::new(),
+                namespace: Namespace::empty()
+            },
+            events::Characters("a".to_string()),
+            events::Whitespace(" ".to_string()),
+            events::Characters("b".to_string()),
+            events::EndElement { name: Name::new_local("a") }
+        );
+        for ev in events.into_iter() {
+            builder.push_event(ev).unwrap();
+            assert!(!builder.is_finished());
+        }
+        builder.push_event(events::EndDocument).unwrap();
+
+        let doc = builder.into_document().unwrap();
+        assert!(doc.children[0] == ElementNode(doc.root().clone()));
+        assert!(doc.root().children == vec!(TextNode(Text("a b".to_string()))));
+    }
+
+    #[test]
+    fn errors() {
+        let mut reader = EventReader::new_from_str_slice("<a><b></a>");
+        assert!(Document::from_reader(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,778 @@
+//! Contains a simple DOM tree model of XML documents.
+//!
+//! A `Document` owns its nodes, and every `Element` owns its children. Nodes are addressed by
+//! their indices in the children lists; `Document` methods also accept paths, that is, sequences
+//! of such indices starting from the top level of the document.
+//!
+//! Every element stores only the namespace mappings declared on it, so the mappings in scope of
+//! an element are the combination of the mappings of the element and all of its ancestors. Names
+//! of elements and attributes always carry resolved namespace URIs.
+//!
+//! Documents are usually obtained from `EventReader` with `Document::from_reader()` method,
+//! see `builder` module for details. They are written back to XML with `Document::write_to()`
+//! or `Document::to_string_with_config()` methods.
+
+use std::io::{Buffer, MemWriter};
+
+use common;
+use common::{Error, Name, Attribute, XmlVersion};
+use namespace::Namespace;
+use reader::EventReader;
+use reader::dtd;
+use reader::dtd::MarkupDeclaration;
+use writer::{EventWriter, EventWriterResult, EmitterConfig};
+use writer::events;
+
+pub use self::builder::DocumentBuilder;
+
+pub mod builder;
+
+/// A node of a document tree.
+#[deriving(Clone, PartialEq)]
+pub enum Node {
+    /// An element.
+    ElementNode(Element),
+    /// Character data.
+    TextNode(Text),
+    /// A comment.
+    CommentNode(Comment),
+    /// A CDATA section.
+    CDataNode(CData),
+    /// A processing instruction.
+    ProcessingInstructionNode(ProcessingInstruction)
+}
+
+impl Node {
+    /// Returns the element in this node, if it is an element node.
+    #[inline]
+    pub fn as_element<'a>(&'a self) -> Option<&'a Element> {
+        match *self {
+            ElementNode(ref e) => Some(e),
+            _ => None
+        }
+    }
+
+    /// Returns the element in this node for modification, if it is an element node.
+    #[inline]
+    pub fn as_element_mut<'a>(&'a mut self) -> Option<&'a mut Element> {
+        match *self {
+            ElementNode(ref mut e) => Some(e),
+            _ => None
+        }
+    }
+
+    /// Checks whether this node is an element node.
+    #[inline]
+    pub fn is_element(&self) -> bool {
+        self.as_element().is_some()
+    }
+
+    /// Returns the character data of this node, that is, the text of text and CDATA nodes
+    /// and the text of all descendants of element nodes. Other nodes have no character data.
+    pub fn text(&self) -> String {
+        match *self {
+            ElementNode(ref e) => e.text(),
+            TextNode(Text(ref data)) | CDataNode(CData(ref data)) => data.clone(),
+            _ => String::new()
+        }
+    }
+}
+
+/// Character data.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Text(pub String);
+
+/// A comment.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Comment(pub String);
+
+/// A CDATA section.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct CData(pub String);
+
+/// A processing instruction.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ProcessingInstruction {
+    /// Processing instruction target.
+    pub name: String,
+
+    /// Processing instruction content.
+    pub data: Option<String>
+}
+
+/// A document type declaration.
+#[deriving(Clone, PartialEq)]
+pub struct Doctype {
+    /// Name of the document root element.
+    pub name: String,
+
+    /// Public identifier of the external subset.
+    pub public_id: Option<String>,
+
+    /// System identifier of the external subset.
+    pub system_id: Option<String>,
+
+    /// Markup declarations from the internal subset, in document order.
+    pub declarations: Vec<MarkupDeclaration>
+}
+
+/// An XML element.
+#[deriving(Clone, PartialEq)]
+pub struct Element {
+    /// Qualified name of the element.
+    pub name: Name,
+
+    /// Attributes of the element, in document order.
+    pub attributes: Vec<Attribute>,
+
+    /// Namespace mappings declared on this element.
+    ///
+    /// Mappings declared on the ancestors are not included here.
+    pub namespace: Namespace,
+
+    /// Child nodes of the element.
+    pub children: Vec<Node>
+}
+
+impl Element {
+    /// Returns a new element with the given name and no attributes, namespace declarations
+    /// or children.
+    #[inline]
+    pub fn new(name: Name) -> Element {
+        Element {
+            name: name,
+            attributes: Vec::new(),
+            namespace: Namespace::empty(),
+            children: Vec::new()
+        }
+    }
+
+    /// Returns a new element with the given local name, see `new()`.
+    #[inline]
+    pub fn new_local(name: &str) -> Element {
+        Element::new(Name::new_local(name))
+    }
+
+    /// Returns the value of the attribute with the given local name and namespace URI.
+    pub fn attribute<'a>(&'a self, local_name: &str, namespace: Option<&str>) -> Option<&'a str> {
+        self.attributes.iter()
+            .find(|a| a.name.local_name.as_slice() == local_name && a.name.namespace_ref() == namespace)
+            .map(|a| a.value.as_slice())
+    }
+
+    /// Sets the value of the attribute with the given name, adding the attribute if the element
+    /// does not have it yet.
+    ///
+    /// The attribute is looked up by the local name and the namespace URI of the given name;
+    /// the prefix of an existing attribute is replaced.
+    pub fn set_attribute(&mut self, name: Name, value: &str) {
+        match self.attributes.iter_mut()
+                  .find(|a| a.name.local_name == name.local_name && a.name.namespace == name.namespace) {
+            Some(a) => {
+                a.name = name;
+                a.value = value.to_string();
+                return;
+            }
+            None => {}
+        }
+        self.attributes.push(Attribute::new(name, value));
+    }
+
+    /// Removes the attribute with the given local name and namespace URI and returns its value.
+    pub fn remove_attribute(&mut self, local_name: &str, namespace: Option<&str>) -> Option<String> {
+        let position = self.attributes.iter()
+            .position(|a| a.name.local_name.as_slice() == local_name && a.name.namespace_ref() == namespace);
+        position.map(|i| self.attributes.remove(i).unwrap().value)
+    }
+
+    /// Declares a namespace mapping on this element, replacing an existing declaration
+    /// of the same prefix. `None` prefix means the default namespace.
+    ///
+    /// Names of the element and its descendants are not changed.
+    pub fn declare_namespace(&mut self, prefix: Option<&str>, uri: &str) {
+        self.namespace.0.insert(prefix.map(|p| p.to_string()), uri.to_string());
+    }
+
+    /// Returns an iterator over the child elements.
+    #[inline]
+    pub fn child_elements<'a>(&'a self) -> ChildElements<'a> {
+        ChildElements { nodes: self.children.iter() }
+    }
+
+    /// Returns the first child element with the given local name and namespace URI.
+    pub fn child<'a>(&'a self, local_name: &str, namespace: Option<&str>) -> Option<&'a Element> {
+        self.child_elements()
+            .find(|e| e.name.local_name.as_slice() == local_name && e.name.namespace_ref() == namespace)
+    }
+
+    /// Returns the first child element with the given local name and namespace URI
+    /// for modification.
+    pub fn child_mut<'a>(&'a mut self, local_name: &str, namespace: Option<&str>) -> Option<&'a mut Element> {
+        self.children.iter_mut()
+            .filter_map(|n| n.as_element_mut())
+            .find(|e| e.name.local_name.as_slice() == local_name && e.name.namespace_ref() == namespace)
+    }
+
+    /// Returns the concatenated text of all text and CDATA descendants of the element.
+    pub fn text(&self) -> String {
+        let mut result = String::new();
+        for node in self.children.iter() {
+            match *node {
+                ElementNode(ref e) => result.push_str(e.text().as_slice()),
+                TextNode(Text(ref data)) | CDataNode(CData(ref data)) => result.push_str(data.as_slice()),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Appends a node to the end of the children list.
+    #[inline]
+    pub fn append_child(&mut self, node: Node) {
+        self.children.push(node);
+    }
+
+    /// Inserts a node into the children list at the given index, shifting the following
+    /// nodes to the right.
+    ///
+    /// # Failure
+    ///
+    /// Fails if `index` is greater than the number of children.
+    #[inline]
+    pub fn insert_child(&mut self, index: uint, node: Node) {
+        self.children.insert(index, node);
+    }
+
+    /// Removes the child node at the given index and returns it.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there is no child with this index.
+    pub fn remove_child(&mut self, index: uint) -> Node {
+        match self.children.remove(index) {
+            Some(node) => node,
+            None => panic!("No child node with index {}", index)
+        }
+    }
+
+    /// Replaces the child node at the given index and returns the old node.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there is no child with this index.
+    pub fn replace_child(&mut self, index: uint, node: Node) -> Node {
+        let old = self.remove_child(index);
+        self.children.insert(index, node);
+        old
+    }
+
+    /// Moves the child node at index `from` so that it ends up at index `to`.
+    ///
+    /// # Failure
+    ///
+    /// Fails if any of the indices is out of bounds.
+    pub fn move_child(&mut self, from: uint, to: uint) {
+        let node = self.remove_child(from);
+        self.children.insert(to, node);
+    }
+}
+
+/// An iterator over child elements of an element, created by `Element::child_elements()`.
+pub struct ChildElements<'a> {
+    nodes: ::std::slice::Items<'a, Node>
+}
+
+impl<'a> Iterator<&'a Element> for ChildElements<'a> {
+    fn next(&mut self) -> Option<&'a Element> {
+        loop {
+            match self.nodes.next() {
+                Some(&ElementNode(ref e)) => return Some(e),
+                Some(_) => {}
+                None => return None
+            }
+        }
+    }
+}
+
+/// An XML document.
+///
+/// Top-level nodes of a document are the root element and comments and processing instructions
+/// before and after it.
+#[deriving(Clone, PartialEq)]
+pub struct Document {
+    /// XML version from the XML declaration.
+    pub version: XmlVersion,
+
+    /// Document encoding from the XML declaration.
+    pub encoding: String,
+
+    /// Standalone flag from the XML declaration.
+    pub standalone: Option<bool>,
+
+    /// The document type declaration.
+    pub doctype: Option<Doctype>,
+
+    /// Top-level nodes of the document.
+    pub children: Vec<Node>
+}
+
+impl Document {
+    /// Returns a new document with the given root element and the default XML declaration.
+    pub fn new(root: Element) -> Document {
+        Document {
+            version: common::Version10,
+            encoding: "UTF-8".to_string(),
+            standalone: None,
+            doctype: None,
+            children: vec!(ElementNode(root))
+        }
+    }
+
+    /// Reads a document from the given reader.
+    ///
+    /// The reader is read until the end of the document. Parsing errors are returned as is.
+    pub fn from_reader<B: Buffer>(reader: &mut EventReader<B>) -> Result<Document, Error> {
+        let mut builder = DocumentBuilder::new();
+        loop {
+            try!(builder.push_event(reader.next()));
+            if builder.is_finished() {
+                return Ok(builder.into_document().unwrap());
+            }
+        }
+    }
+
+    /// Writes the document to the given writer.
+    ///
+    /// Every element is written with the namespace mappings declared on it, so `xmlns`
+    /// attributes appear only on the elements which introduce the mappings.
+    ///
+    /// The writer always produces UTF-8, so the document is declared as UTF-8 regardless
+    /// of its `encoding` field. The internal subset of the document type declaration is
+    /// written back from its markup declarations.
+    pub fn write_to<W: Writer>(&self, writer: &mut EventWriter<W>) -> EventWriterResult<()> {
+        try!(writer.write(events::StartDocument {
+            version: self.version,
+            encoding: Some("UTF-8"),
+            standalone: self.standalone
+        }));
+        match self.doctype {
+            Some(ref doctype) => {
+                let subset = if doctype.declarations.is_empty() {
+                    None
+                } else {
+                    Some(internal_subset(doctype.declarations.as_slice()))
+                };
+                try!(writer.write(events::Doctype {
+                    name: doctype.name.as_slice(),
+                    public_id: doctype.public_id.as_ref().map(|s| s.as_slice()),
+                    system_id: doctype.system_id.as_ref().map(|s| s.as_slice()),
+                    internal_subset: subset.as_ref().map(|s| s.as_slice())
+                }));
+            }
+            None => {}
+        }
+        for node in self.children.iter() {
+            try!(write_node(node, writer));
+        }
+        Ok(())
+    }
+
+    /// Writes the document to a string using the given emitter configuration.
+    pub fn to_string_with_config(&self, config: EmitterConfig) -> EventWriterResult<String> {
+        let mut buf = MemWriter::new();
+        {
+            let mut writer = EventWriter::new_with_config(buf.by_ref(), config);
+            try!(self.write_to(&mut writer));
+        }
+        // the emitter only writes valid UTF-8
+        Ok(String::from_utf8(buf.unwrap()).unwrap())
+    }
+
+    /// Returns the root element of the document.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the root element has been removed.
+    pub fn root<'a>(&'a self) -> &'a Element {
+        match self.children.iter().filter_map(|n| n.as_element()).next() {
+            Some(e) => e,
+            None => panic!("Document has no root element")
+        }
+    }
+
+    /// Returns the root element of the document for modification.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the root element has been removed.
+    pub fn root_mut<'a>(&'a mut self) -> &'a mut Element {
+        match self.children.iter_mut().filter_map(|n| n.as_element_mut()).next() {
+            Some(e) => e,
+            None => panic!("Document has no root element")
+        }
+    }
+
+    /// Returns the node at the given path.
+    ///
+    /// The first index of the path selects a top-level node, the following ones select
+    /// children of elements. Returns `None` if there is no such node.
+    pub fn node<'a>(&'a self, path: &[uint]) -> Option<&'a Node> {
+        let mut nodes = self.children.as_slice();
+        let mut result = None;
+        for &i in path.iter() {
+            if i >= nodes.len() {
+                return None;
+            }
+            let node = &nodes[i];
+            nodes = match *node {
+                ElementNode(ref e) => e.children.as_slice(),
+                _ => nodes.slice_to(0)
+            };
+            result = Some(node);
+        }
+        result
+    }
+
+    /// Returns the node at the given path for modification, see `node()`.
+    pub fn node_mut<'a>(&'a mut self, path: &[uint]) -> Option<&'a mut Node> {
+        if path.is_empty() {
+            return None;
+        }
+        let last = *path.last().unwrap();
+        self.children_at_mut(path.init()).and_then(|nodes| nodes.iter_mut().nth(last))
+    }
+
+    /// Inserts a node so that it ends up at the given path, shifting the following siblings.
+    ///
+    /// # Failure
+    ///
+    /// Fails if the path is empty or if the parent of the path is not an element.
+    pub fn insert_node(&mut self, path: &[uint], node: Node) {
+        if path.is_empty() {
+            panic!("Empty node path");
+        }
+        let (last, parent) = (*path.last().unwrap(), path.init());
+        match self.children_at_mut(parent) {
+            Some(nodes) => nodes.insert(last, node),
+            None => panic!("No element at path {}", parent)
+        }
+    }
+
+    /// Removes the node at the given path and returns it.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there is no node at the path.
+    pub fn remove_node(&mut self, path: &[uint]) -> Node {
+        if path.is_empty() {
+            panic!("Empty node path");
+        }
+        let (last, parent) = (*path.last().unwrap(), path.init());
+        match self.children_at_mut(parent).and_then(|nodes| nodes.remove(last)) {
+            Some(node) => node,
+            None => panic!("No node at path {}", path)
+        }
+    }
+
+    /// Moves the node at path `from` to path `to`.
+    ///
+    /// The node is removed first, so `to` is interpreted in the tree without the node. For
+    /// example, moving the first child of an element to its end is done with paths `[.., 0]`
+    /// and `[.., n - 1]`, where `n` is the number of children.
+    ///
+    /// # Failure
+    ///
+    /// Fails if there is no node at `from` or no place at `to`.
+    pub fn move_node(&mut self, from: &[uint], to: &[uint]) {
+        let node = self.remove_node(from);
+        self.insert_node(to, node);
+    }
+
+    /// Returns the children list of the node at the given path, or the top-level nodes
+    /// if the path is empty.
+    fn children_at_mut<'a>(&'a mut self, path: &[uint]) -> Option<&'a mut Vec<Node>> {
+        let mut nodes = &mut self.children;
+        for &i in path.iter() {
+            let node = match nodes.iter_mut().nth(i) {
+                Some(node) => node,
+                None => return None
+            };
+            nodes = match *node {
+                ElementNode(ref mut e) => &mut e.children,
+                _ => return None
+            };
+        }
+        Some(nodes)
+    }
+}
+
+fn write_node<W: Writer>(node: &Node, writer: &mut EventWriter<W>) -> EventWriterResult<()> {
+    match *node {
+        ElementNode(ref e) => {
+            try!(writer.write(events::StartElement {
+                name: &e.name,
+                attributes: e.attributes.as_slice(),
+                namespace: &e.namespace
+            }));
+            for child in e.children.iter() {
+                try!(write_node(child, writer));
+            }
+            writer.write(events::EndElement { name: Some(&e.name) })
+        }
+        TextNode(Text(ref data)) => writer.write(events::Characters(data.as_slice())),
+        CommentNode(Comment(ref data)) => writer.write(events::Comment(data.as_slice())),
+        CDataNode(CData(ref data)) => writer.write(events::CData(data.as_slice())),
+        ProcessingInstructionNode(ProcessingInstruction { ref name, ref data }) =>
+            writer.write(events::ProcessingInstruction {
+                name: name.as_slice(),
+                data: data.as_ref().map(|d| d.as_slice())
+            })
+    }
+}
+
+/// Reconstructs the text of an internal subset from its markup declarations.
+fn internal_subset(declarations: &[MarkupDeclaration]) -> String {
+    let declarations: Vec<String> = declarations.iter().map(|d| match *d {
+        dtd::ElementDecl { ref name, ref content } =>
+            format!("<!ELEMENT {} {}>", name, content_spec(content)),
+        dtd::AttlistDecl { ref element, ref attributes } => {
+            let mut result = format!("<!ATTLIST {}", element);
+            for attr in attributes.iter() {
+                result.push_str(format!(" {} {} {}", attr.name, attribute_type(&attr.attribute_type),
+                                        default_decl(&attr.default)).as_slice());
+            }
+            result.push_str(">");
+            result
+        }
+        dtd::EntityDecl { ref name, parameter, ref value } => {
+            let value = match *value {
+                dtd::InternalEntity(ref value) => quote(value.as_slice()),
+                dtd::ExternalEntity { ref public_id, ref system_id, ref notation } => {
+                    let mut result = external_id(public_id, &Some(system_id.clone()));
+                    match *notation {
+                        Some(ref notation) => result.push_str(format!(" NDATA {}", notation).as_slice()),
+                        None => {}
+                    }
+                    result
+                }
+            };
+            format!("<!ENTITY {}{} {}>", if parameter { "% " } else { "" }, name, value)
+        }
+        dtd::NotationDecl { ref name, ref public_id, ref system_id } =>
+            format!("<!NOTATION {} {}>", name, external_id(public_id, system_id)),
+        dtd::CommentDecl(ref data) => format!("<!--{}-->", data),
+        dtd::ProcessingInstructionDecl { ref name, ref data } => match *data {
+            Some(ref data) => format!("<?{} {}?>", name, data),
+            None => format!("<?{}?>", name)
+        },
+        dtd::ParameterEntityRef(ref name) => format!("%{};", name)
+    }).collect();
+    declarations.connect("\n")
+}
+
+fn content_spec(spec: &dtd::ContentSpec) -> String {
+    match *spec {
+        dtd::EmptyContent => "EMPTY".to_string(),
+        dtd::AnyContent => "ANY".to_string(),
+        dtd::MixedContent(ref names) if names.is_empty() => "(#PCDATA)".to_string(),
+        dtd::MixedContent(ref names) => format!("(#PCDATA|{})*", names.connect("|")),
+        dtd::ChildrenContent(ref particle) => content_particle(particle)
+    }
+}
+
+fn content_particle(particle: &dtd::ContentParticle) -> String {
+    let (mut result, repetition) = match *particle {
+        dtd::NameParticle(ref name, repetition) => (name.clone(), repetition),
+        dtd::ChoiceParticle(ref particles, repetition) => {
+            let particles: Vec<String> = particles.iter().map(|p| content_particle(p)).collect();
+            (format!("({})", particles.connect("|")), repetition)
+        }
+        dtd::SeqParticle(ref particles, repetition) => {
+            let particles: Vec<String> = particles.iter().map(|p| content_particle(p)).collect();
+            (format!("({})", particles.connect(",")), repetition)
+        }
+    };
+    result.push_str(match repetition {
+        dtd::Once => "",
+        dtd::Optional => "?",
+        dtd::ZeroOrMore => "*",
+        dtd::OneOrMore => "+"
+    });
+    result
+}
+
+fn attribute_type(attribute_type: &dtd::AttributeType) -> String {
+    match *attribute_type {
+        dtd::CDataType => "CDATA".to_string(),
+        dtd::IdType => "ID".to_string(),
+        dtd::IdRefType => "IDREF".to_string(),
+        dtd::IdRefsType => "IDREFS".to_string(),
+        dtd::EntityType => "ENTITY".to_string(),
+        dtd::EntitiesType => "ENTITIES".to_string(),
+        dtd::NmTokenType => "NMTOKEN".to_string(),
+        dtd::NmTokensType => "NMTOKENS".to_string(),
+        dtd::NotationType(ref names) => format!("NOTATION ({})", names.connect("|")),
+        dtd::EnumerationType(ref names) => format!("({})", names.connect("|"))
+    }
+}
+
+fn default_decl(default: &dtd::DefaultDecl) -> String {
+    match *default {
+        dtd::RequiredDefault => "#REQUIRED".to_string(),
+        dtd::ImpliedDefault => "#IMPLIED".to_string(),
+        dtd::FixedDefault(ref value) => format!("#FIXED {}", quote(value.as_slice())),
+        dtd::ValueDefault(ref value) => quote(value.as_slice())
+    }
+}
+
+fn external_id(public_id: &Option<String>, system_id: &Option<String>) -> String {
+    match (public_id, system_id) {
+        (&Some(ref public_id), &Some(ref system_id)) =>
+            format!("PUBLIC {} {}", quote(public_id.as_slice()), quote(system_id.as_slice())),
+        (&Some(ref public_id), &None) => format!("PUBLIC {}", quote(public_id.as_slice())),
+        (&None, &Some(ref system_id)) => format!("SYSTEM {}", quote(system_id.as_slice())),
+        (&None, &None) => String::new()
+    }
+}
+
+/// Quotes a literal which has been read from a declaration; such literal never contains
+/// both kinds of quotes.
+fn quote(literal: &str) -> String {
+    if literal.contains_char('"') {
+        format!("'{}'", literal)
+    } else {
+        format!("\"{}\"", literal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, BufferedReader, File};
+
+    use common::Name;
+    use reader::{EventReader, ParserConfig};
+    use writer::EmitterConfig;
+
+    use super::{Document, Element, ElementNode, TextNode, CommentNode, Text, Comment};
+
+    fn parse(data: &str) -> Document {
+        let mut reader = EventReader::new_with_config(
+            BufReader::new(data.as_bytes()),
+            ParserConfig::new().ignore_comments(false)
+        );
+        Document::from_reader(&mut reader).unwrap()
+    }
+
+    #[test]
+    fn attributes() {
+        let mut e = Element::new_local("a");
+        e.set_attribute(Name::new_local("x"), "1");
+        e.set_attribute(Name::new("y", "p", "urn:p"), "2");
+        assert_eq!(e.attribute("x", None), Some("1"));
+        assert_eq!(e.attribute("y", Some("urn:p")), Some("2"));
+        assert_eq!(e.attribute("y", None), None);
+
+        e.set_attribute(Name::new("y", "q", "urn:p"), "3");
+        assert_eq!(e.attributes.len(), 2);
+        assert_eq!(e.attributes[1].name.prefix_ref(), Some("q"));
+        assert_eq!(e.attribute("y", Some("urn:p")), Some("3"));
+
+        assert_eq!(e.remove_attribute("x", None), Some("1".to_string()));
+        assert_eq!(e.remove_attribute("x", None), None);
+        assert_eq!(e.attributes.len(), 1);
+    }
+
+    #[test]
+    fn element_children() {
+        let mut e = Element::new_local("a");
+        e.append_child(TextNode(Text("1".to_string())));
+        e.append_child(ElementNode(Element::new_local("b")));
+        e.insert_child(0, CommentNode(Comment("c".to_string())));
+        e.append_child(ElementNode(Element::new_local("c")));
+        assert_eq!(e.child_elements().map(|e| e.name.local_name.clone()).collect::<Vec<String>>(),
+                   vec!("b".to_string(), "c".to_string()));
+
+        e.move_child(3, 0);
+        assert_eq!(e.children[0].as_element().unwrap().name.local_name.as_slice(), "c");
+        assert!(e.child("b", None).is_some());
+
+        let removed = e.remove_child(0);
+        assert!(removed.is_element());
+        assert!(e.child("c", None).is_none());
+
+        let old = e.replace_child(0, TextNode(Text("2".to_string())));
+        assert!(old == CommentNode(Comment("c".to_string())));
+        assert_eq!(e.text().as_slice(), "21");
+    }
+
+    #[test]
+    fn document_paths() {
+        let mut doc = parse("<!--c--><a><b><c/></b><d/></a>");
+        assert!(doc.node([0, 0].as_slice()).is_none());
+        assert_eq!(doc.node([1, 0, 0].as_slice()).and_then(|n| n.as_element()).map(|e| e.name.local_name.clone()),
+                   Some("c".to_string()));
+
+        // move <c/> to the end of <a>
+        doc.move_node([1, 0, 0].as_slice(), [1, 2].as_slice());
+        assert!(doc.root().child("b", None).unwrap().children.is_empty());
+        assert_eq!(doc.root().children[2].as_element().unwrap().name.local_name.as_slice(), "c");
+
+        let d = doc.remove_node([1, 1].as_slice());
+        doc.insert_node([0].as_slice(), d);
+        assert!(doc.node([0].as_slice()).unwrap().is_element());
+        assert_eq!(doc.root().name.local_name.as_slice(), "d");
+
+        doc.root_mut().append_child(TextNode(Text("text".to_string())));
+        assert_eq!(doc.node([0].as_slice()).unwrap().text().as_slice(), "text");
+    }
+
+    #[test]
+    fn namespace_declarations_are_written_once() {
+        let doc = parse("<a xmlns:p='urn:p'><p:b><p:c xmlns:q='urn:q' q:x='1'/></p:b></a>");
+        let result = doc.to_string_with_config(EmitterConfig::new()).unwrap();
+        assert!(result.as_slice().contains("<a xmlns:p=\"urn:p\">"));
+        assert!(result.as_slice().contains("<p:b>"));
+        assert!(result.as_slice().contains("<p:c xmlns:q=\"urn:q\" q:x=\"1\"/>"));
+        assert_eq!(result.as_slice().match_indices("xmlns").count(), 2);
+        assert!(parse(result.as_slice()) == doc);
+    }
+
+    #[test]
+    fn doctype_round_trip() {
+        let doc = parse(
+            "<?xml version='1.0' encoding='ISO-8859-1'?>\
+             <!DOCTYPE a PUBLIC '-//A//EN' 'a.dtd' [\
+             <!ELEMENT a (#PCDATA|b)*><!ELEMENT b ((c|d)+,e?)><!ELEMENT c EMPTY>\
+             <!ATTLIST b x CDATA #IMPLIED y (p|q) 'p' z NOTATION (n) #FIXED \"n\">\
+             <!ENTITY e 'say \"hi\"'><!ENTITY % p SYSTEM 'p.ent'><!NOTATION n PUBLIC '-//N//EN'>\
+             <!--c--><?pi data?>%p;]><a/>"
+        );
+        assert_eq!(doc.doctype.as_ref().unwrap().declarations.len(), 10);
+
+        let result = doc.to_string_with_config(EmitterConfig::new()).unwrap();
+        assert!(result.as_slice().starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+        let written = parse(result.as_slice());
+        assert!(written.doctype == doc.doctype);
+    }
+
+    #[test]
+    fn sample_files_round_trip() {
+        for i in range(1u, 5) {
+            let path = Path::new(format!("data/sample_{}.xml", i));
+            let mut reader = EventReader::new_with_config(
+                BufferedReader::new(File::open(&path).unwrap()),
+                ParserConfig::new().ignore_comments(false)
+            );
+            let doc = Document::from_reader(&mut reader).unwrap();
+
+            let result = doc.to_string_with_config(EmitterConfig::new()).unwrap();
+            let written = parse(result.as_slice());
+
+            assert_eq!(written.version, doc.version);
+            assert_eq!(written.encoding.as_slice(), "UTF-8");
+            assert_eq!(written.standalone, doc.standalone);
+            assert!(written.doctype == doc.doctype, "Doctype of {} is not preserved", path.display());
+            assert!(written.children == doc.children, "{} is not preserved", path.display());
+        }
+    }
+}
@@ -13,4 +13,7 @@ pub mod common;
 pub mod namespace;
 pub mod reader;
 pub mod writer;
+pub mod sax;
+pub mod dom;
+pub mod xpath;
 
@@ -0,0 +1,656 @@
+//! Contains a reader for in-memory documents whose events borrow the input string.
+//!
+//! `BorrowedEventReader` emits `XmlEvent<'a>` events which mirror `reader::events::XmlEvent`,
+//! but their strings are `MaybeOwned<'a>` values. A string is a slice of the input when it
+//! appears in the document verbatim, that is, when no references were expanded and no
+//! normalization was applied to it; otherwise it is an owned string. The parser reports
+//! where each string comes from, so verbatim text is not copied at all.
+
+use std::fmt;
+use std::io::BufReader;
+use std::str::{MaybeOwned, Slice, Owned};
+
+use common;
+use common::{XmlVersion, Span, escape_str};
+use common::Error as CommonError;
+use namespace::{Namespace, NS_XML_URI, NS_XMLNS_URI};
+
+use reader::config::ParserConfig;
+use reader::dtd::MarkupDeclaration;
+use reader::events;
+use reader::parser::{PullParser, Fragment, SourceFragment, EventFragment};
+
+/// Qualified name whose parts may borrow the input document.
+///
+/// This is the counterpart of `common::Name`.
+#[deriving(Clone, PartialEq)]
+pub struct Name<'a> {
+    /// An XML namespace prefix.
+    ///
+    /// This field is always `None` when `namespace` is `None`.
+    pub prefix: Option<MaybeOwned<'a>>,
+
+    /// An XML namespace identifier.
+    pub namespace: Option<MaybeOwned<'a>>,
+
+    /// Local (namespace-less) name.
+    pub local_name: MaybeOwned<'a>
+}
+
+impl<'a> fmt::Show for Name<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.namespace {
+            Some(ref namespace) => try!(write!(f, "{{{}}}", namespace)),
+            None => {}
+        }
+        match self.prefix {
+            Some(ref prefix) => try!(write!(f, "{}:", prefix)),
+            None => {}
+        }
+        write!(f, "{}", self.local_name)
+    }
+}
+
+impl<'a> Name<'a> {
+    /// Converts this name into `common::Name`, copying borrowed parts.
+    pub fn into_owned(self) -> common::Name {
+        common::Name {
+            prefix: self.prefix.map(|p| p.into_string()),
+            namespace: self.namespace.map(|ns| ns.into_string()),
+            local_name: self.local_name.into_string()
+        }
+    }
+}
+
+/// Element attribute whose name and value may borrow the input document.
+///
+/// This is the counterpart of `common::Attribute`.
+#[deriving(Clone, PartialEq)]
+pub struct Attribute<'a> {
+    /// Qualified name of the attribute.
+    pub name: Name<'a>,
+
+    /// Attribute value.
+    pub value: MaybeOwned<'a>
+}
+
+impl<'a> fmt::Show for Attribute<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}=\"{}\"", self.name, escape_str(self.value.as_slice()))
+    }
+}
+
+impl<'a> Attribute<'a> {
+    /// Converts this attribute into `common::Attribute`, copying borrowed parts.
+    #[inline]
+    pub fn into_owned(self) -> common::Attribute {
+        common::Attribute { name: self.name.into_owned(), value: self.value.into_string() }
+    }
+}
+
+/// An element of an XML input stream which may borrow the input document.
+///
+/// Items of this enum are emitted by `BorrowedEventReader`. Each of them corresponds to
+/// the item of `reader::events::XmlEvent` with the same name; see its documentation
+/// for details.
+#[deriving(PartialEq, Clone)]
+pub enum XmlEvent<'a> {
+    /// Corresponds to XML document declaration.
+    StartDocument {
+        /// XML version.
+        pub version: XmlVersion,
+
+        /// XML document encoding.
+        pub encoding: MaybeOwned<'a>,
+
+        /// XML standalone declaration.
+        pub standalone: Option<bool>
+    },
+
+    /// Denotes a document type declaration.
+    Doctype {
+        /// Name of the document root element.
+        pub name: MaybeOwned<'a>,
+
+        /// Public identifier of the external subset.
+        pub public_id: Option<MaybeOwned<'a>>,
+
+        /// System identifier of the external subset.
+        pub system_id: Option<MaybeOwned<'a>>,
+
+        /// Markup declarations from the internal subset, in document order.
+        pub declarations: Vec<MarkupDeclaration>
+    },
+
+    /// Denotes to the end of the document stream.
+    EndDocument,
+
+    /// Denotes an XML processing instruction.
+    ProcessingInstruction {
+        /// Processing instruction target.
+        pub name: MaybeOwned<'a>,
+
+        /// Processing instruction content.
+        pub data: Option<MaybeOwned<'a>>
+    },
+
+    /// Denotes a beginning of an XML element.
+    StartElement {
+        /// Qualified name of the element.
+        pub name: Name<'a>,
+
+        /// A list of attributes associated with the element.
+        pub attributes: Vec<Attribute<'a>>,
+
+        /// Contents of the namespace mapping at this point of the document.
+        pub namespace: Namespace,
+    },
+
+    /// Denotes an end of an XML element.
+    EndElement {
+        /// Qualified name of the element.
+        pub name: Name<'a>
+    },
+
+    /// Denotes CDATA content.
+    CData(MaybeOwned<'a>),
+
+    /// Denotes a comment.
+    Comment(MaybeOwned<'a>),
+
+    /// Denotes character data outside of tags.
+    Characters(MaybeOwned<'a>),
+
+    /// Denotes a chunk of whitespace outside of tags.
+    Whitespace(MaybeOwned<'a>),
+
+    /// Denotes parsing error.
+    Error(CommonError)
+}
+
+impl<'a> fmt::Show for XmlEvent<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StartDocument { ref version, ref encoding, ref standalone } =>
+                write!(f, "StartDocument({}, {}, {})", version, *encoding, *standalone),
+            EndDocument =>
+                write!(f, "EndDocument"),
+            Doctype { ref name, ref public_id, ref system_id, ref declarations } =>
+                write!(f, "Doctype({}, {}, {}, {})", name, public_id, system_id, declarations),
+            ProcessingInstruction { ref name, ref data } =>
+                write!(f, "ProcessingInstruction({}{})", *name, match *data {
+                    Some(ref data) => format!(", {}", data),
+                    None       => String::new()
+                }),
+            StartElement { ref name, ref attributes, namespace: Namespace(ref namespace) } =>
+                write!(f, "StartElement({}, {}{})", name, namespace, if attributes.is_empty() {
+                    String::new()
+                } else {
+                    let attributes: Vec<String> = attributes.iter().map(
+                        |a| format!("{} -> {}", a.name, a.value)
+                    ).collect();
+                    format!(", [{}]", attributes.connect(", "))
+                }),
+            EndElement { ref name } =>
+                write!(f, "EndElement({})", name),
+            Comment(ref data) =>
+                write!(f, "Comment({})", data),
+            CData(ref data) =>
+                write!(f, "CData({})", data),
+            Characters(ref data) =>
+                write!(f, "Characters({})", data),
+            Whitespace(ref data) =>
+                write!(f, "Whitespace({})", data),
+            Error(ref e) =>
+                write!(f, "Error(row: {}, col: {}, message: {})", e.row()+1, e.col()+1, e.msg())
+        }
+    }
+}
+
+impl<'a> XmlEvent<'a> {
+    /// Converts this event into `reader::events::XmlEvent`, copying borrowed strings.
+    pub fn into_owned(self) -> events::XmlEvent {
+        match self {
+            StartDocument { version, encoding, standalone } =>
+                events::StartDocument {
+                    version: version,
+                    encoding: encoding.into_string(),
+                    standalone: standalone
+                },
+            Doctype { name, public_id, system_id, declarations } =>
+                events::Doctype {
+                    name: name.into_string(),
+                    public_id: public_id.map(|id| id.into_string()),
+                    system_id: system_id.map(|id| id.into_string()),
+                    declarations: declarations
+                },
+            EndDocument => events::EndDocument,
+            ProcessingInstruction { name, data } =>
+                events::ProcessingInstruction {
+                    name: name.into_string(),
+                    data: data.map(|d| d.into_string())
+                },
+            StartElement { name, attributes, namespace } =>
+                events::StartElement {
+                    name: name.into_owned(),
+                    attributes: attributes.into_iter().map(|a| a.into_owned()).collect(),
+                    namespace: namespace
+                },
+            EndElement { name } => events::EndElement { name: name.into_owned() },
+            CData(data) => events::CData(data.into_string()),
+            Comment(data) => events::Comment(data.into_string()),
+            Characters(data) => events::Characters(data.into_string()),
+            Whitespace(data) => events::Whitespace(data.into_string()),
+            Error(e) => events::Error(e)
+        }
+    }
+}
+
+/// Pull-based XML parser over a string slice whose events borrow the string.
+///
+/// The reader uses the same parser as `EventReader`, so it accepts the same documents, honors
+/// all options of `ParserConfig` and reports the same errors. The only difference is the type
+/// of events: their strings refer to the input wherever it is possible.
+pub struct BorrowedEventReader<'a> {
+    source: &'a str,
+    input: BufReader<'a>,
+    parser: PullParser,
+
+    // namespace URIs found verbatim in the currently open start tags and the number of
+    // them in each of these tags
+    namespace_uris: Vec<&'a str>,
+    namespace_frames: Vec<uint>
+}
+
+impl<'a> BorrowedEventReader<'a> {
+    /// Creates a new parser over the given string with the default configuration.
+    #[inline]
+    pub fn new(source: &'a str) -> BorrowedEventReader<'a> {
+        BorrowedEventReader::new_with_config(source, ParserConfig::new())
+    }
+
+    /// Creates a new parser over the given string with the provided configuration.
+    ///
+    /// The string is always decoded as UTF-8, regardless of the encoding declared
+    /// in the document, so `ignore_encoding_declaration` option is always set.
+    pub fn new_with_config(source: &'a str, config: ParserConfig) -> BorrowedEventReader<'a> {
+        BorrowedEventReader {
+            source: source,
+            input: BufReader::new(source.as_bytes()),
+            parser: {
+                let mut parser = PullParser::new(config.ignore_encoding_declaration(true));
+                parser.record_fragments();
+                parser
+            },
+            namespace_uris: Vec::new(),
+            namespace_frames: Vec::new()
+        }
+    }
+
+    /// Pulls and returns next XML event from the document.
+    ///
+    /// If returned event is `Error` or `EndDocument`, then further calls to this method
+    /// will return this event again.
+    pub fn next(&mut self) -> XmlEvent<'a> {
+        let ev = self.parser.next(&mut self.input);
+        match ev {
+            events::StartDocument { version, encoding, standalone } =>
+                StartDocument {
+                    version: version,
+                    encoding: self.string(0, encoding),
+                    standalone: standalone
+                },
+            events::Doctype { name, public_id, system_id, declarations } => {
+                // absent identifiers are skipped in the numbering of strings
+                let system_index = if public_id.is_some() { 2 } else { 1 };
+                Doctype {
+                    name: self.string(0, name),
+                    public_id: public_id.map(|id| self.string(1, id)),
+                    system_id: system_id.map(|id| self.string(system_index, id)),
+                    declarations: declarations
+                }
+            }
+            events::EndDocument => EndDocument,
+            events::ProcessingInstruction { name, data } =>
+                ProcessingInstruction {
+                    name: self.string(0, name),
+                    data: data.map(|data| self.string(1, data))
+                },
+            events::StartElement { name, attributes, namespace } => {
+                self.push_namespace_uris(1 + 2 * attributes.len());
+                let name = self.name(0, name);
+                let attributes = attributes.into_iter().enumerate().map(|(i, attr)| Attribute {
+                    name: self.name(1 + 2 * i, attr.name),
+                    value: self.string(2 + 2 * i, attr.value)
+                }).collect();
+                StartElement { name: name, attributes: attributes, namespace: namespace }
+            }
+            events::EndElement { name } => {
+                let name = self.name(0, name);
+                let n = self.namespace_frames.pop().unwrap();
+                let len = self.namespace_uris.len();
+                self.namespace_uris.truncate(len - n);
+                EndElement { name: name }
+            }
+            events::CData(data) => CData(self.string(0, data)),
+            events::Comment(data) => Comment(self.string(0, data)),
+            events::Characters(data) => Characters(self.string(0, data)),
+            events::Whitespace(data) => Whitespace(self.string(0, data)),
+            events::Error(e) => Error(e)
+        }
+    }
+
+    /// Returns the fragment of the document occupied by the last event returned by `next()`.
+    ///
+    /// See `EventReader::last_event_span()` for details.
+    #[inline]
+    pub fn last_event_span<'b>(&'b self) -> &'b Span {
+        self.parser.last_event_span()
+    }
+
+    /// Returns spans of attributes of the last event returned by `next()`.
+    ///
+    /// See `EventReader::last_attribute_spans()` for details.
+    #[inline]
+    pub fn last_attribute_spans<'b>(&'b self) -> &'b [Span] {
+        self.parser.last_attribute_spans()
+    }
+
+    /// Returns an iterator over XML events.
+    ///
+    /// When the next event is `Error` or `EndDocument`, then it will be returned by
+    /// the iterator once, and then it will stop producing events.
+    #[inline]
+    pub fn events<'r>(&'r mut self) -> Events<'r, 'a> {
+        Events { reader: self, finished: false }
+    }
+
+    /// Returns the `i`-th string of the last event, given its value from the parser.
+    ///
+    /// The string is a slice of the source if it is a single fragment of it; otherwise it is
+    /// assembled from its fragments, unless the parser has already put all of it into `value`.
+    fn string(&self, i: uint, value: String) -> MaybeOwned<'a> {
+        let source: &'a str = self.source;
+        let fragments = match self.parser.last_event_fragments(i) {
+            Some(fragments) => fragments,
+            None => return Owned(value)
+        };
+        match single_source(fragments) {
+            Some((start, end)) => return Slice(source.slice(start, end)),
+            None => {}
+        }
+        let len = fragments.iter().fold(0, |len, f| len + f.len());
+        if len == value.len() {
+            return Owned(value);
+        }
+        let mut result = String::with_capacity(len);
+        for f in fragments.iter() {
+            match *f {
+                SourceFragment(start, end) => result.push_str(source.slice(start, end)),
+                EventFragment(start, end) => result.push_str(value.as_slice().slice(start, end))
+            }
+        }
+        Owned(result)
+    }
+
+    /// Returns the `i`-th string of the last event, which is a qualified name, splitting
+    /// the slice of the source into the prefix and the local name.
+    fn name(&self, i: uint, name: common::Name) -> Name<'a> {
+        let source: &'a str = self.source;
+        let common::Name { prefix, namespace, local_name } = name;
+        let namespace = namespace.map(|ns| self.borrow_namespace(ns));
+        let qname = self.parser.last_event_fragments(i).and_then(single_source)
+                                                       .map(|(start, end)| source.slice(start, end));
+        match qname {
+            Some(qname) => match prefix {
+                Some(ref p) if qname.len() == p.len() + 1 + local_name.len() &&
+                               qname.starts_with(p.as_slice()) => {
+                    return Name {
+                        prefix: Some(Slice(qname.slice_to(p.len()))),
+                        namespace: namespace,
+                        local_name: Slice(qname.slice_from(p.len() + 1))
+                    };
+                }
+                None if qname == local_name.as_slice() => {
+                    return Name { prefix: None, namespace: namespace, local_name: Slice(qname) };
+                }
+                _ => {}
+            },
+            None => {}
+        }
+        Name {
+            prefix: prefix.map(|p| Owned(p)),
+            namespace: namespace,
+            local_name: Owned(local_name)
+        }
+    }
+
+    /// Returns a slice of the source equal to the namespace URI, if one was found in
+    /// the open start tags, or the predefined URI.
+    fn borrow_namespace(&self, uri: String) -> MaybeOwned<'a> {
+        if uri.as_slice() == NS_XML_URI {
+            return Slice(NS_XML_URI);
+        }
+        if uri.as_slice() == NS_XMLNS_URI {
+            return Slice(NS_XMLNS_URI);
+        }
+        for &u in self.namespace_uris.iter().rev() {
+            if u == uri.as_slice() {
+                return Slice(u);
+            }
+        }
+        Owned(uri)
+    }
+
+    /// Stores values of namespace declarations of the last start tag which appear in the
+    /// source verbatim; they are strings of the event starting from `first`.
+    fn push_namespace_uris(&mut self, first: uint) {
+        let source: &'a str = self.source;
+        let mut n = 0;
+        for i in range(first, self.parser.last_event_string_count()) {
+            match self.parser.last_event_fragments(i).and_then(single_source) {
+                Some((start, end)) => {
+                    self.namespace_uris.push(source.slice(start, end));
+                    n += 1;
+                }
+                None => {}
+            }
+        }
+        self.namespace_frames.push(n);
+    }
+}
+
+/// Returns the range of the source if the string consists of a single fragment of it.
+fn single_source(fragments: &[Fragment]) -> Option<(uint, uint)> {
+    if fragments.len() != 1 {
+        return None;
+    }
+    match fragments[0] {
+        SourceFragment(start, end) => Some((start, end)),
+        EventFragment(..) => None
+    }
+}
+
+/// XML events iterator, created by `events()` method on `BorrowedEventReader`.
+pub struct Events<'r, 'a: 'r> {
+    reader: &'r mut BorrowedEventReader<'a>,
+    finished: bool
+}
+
+impl<'r, 'a: 'r> Iterator<XmlEvent<'a>> for Events<'r, 'a> {
+    #[inline]
+    fn next(&mut self) -> Option<XmlEvent<'a>> {
+        if self.finished { None }
+        else {
+            let ev = self.reader.next();
+            match ev {
+                EndDocument | Error(_) => self.finished = true,
+                _ => {}
+            }
+            Some(ev)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::str::{MaybeOwned, Slice};
+
+    use reader::EventReader;
+    use reader::config::ParserConfig;
+    use reader::events;
+
+    use super::{BorrowedEventReader, Doctype, StartElement, EndElement, Characters, CData,
+                ProcessingInstruction};
+
+    /// Checks that the string is a slice of `source`.
+    fn is_borrowed_from(source: &str, s: &MaybeOwned) -> bool {
+        match *s {
+            Slice(s) => {
+                let base = source.as_ptr() as uint;
+                let ptr = s.as_ptr() as uint;
+                base <= ptr && ptr + s.len() <= base + source.len()
+            }
+            _ => false
+        }
+    }
+
+    fn config() -> ParserConfig {
+        ParserConfig::new().ignore_comments(false).add_entity("nbsp", " ")
+    }
+
+    #[test]
+    fn same_events_as_event_reader() {
+        static DATA: &'static str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+            <!DOCTYPE root [<!ENTITY e \"entity text\">]>\
+            <?pi some data?><!-- comment -->\
+            <root xmlns=\"urn:default\" xmlns:p=\"urn:&amp;p\" a=\"x\ty\" p:b='&lt;'>\
+              text &e;&nbsp;<p:child p:c=\"1\"/>\r\n<![CDATA[<raw>]]>more&#x41;\
+            </root>";
+        let expected: Vec<events::XmlEvent> =
+            EventReader::new_with_config(BufReader::new(DATA.as_bytes()),
+                                         config().ignore_encoding_declaration(true))
+                .events().collect();
+        assert_eq!(expected.last(), Some(&events::EndDocument));
+
+        let mut r = BorrowedEventReader::new_with_config(DATA, config());
+        let actual: Vec<events::XmlEvent> = r.events().map(|e| e.into_owned()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn borrows_verbatim_strings() {
+        static DATA: &'static str =
+            "<?target data?><p:a xmlns:p=\"urn:p\" b=\"value\" c=\"&amp;\">text\
+             <![CDATA[cdata]]><p:d/>a &lt; b</p:a>";
+        let mut r = BorrowedEventReader::new(DATA);
+        let mut events = r.events();
+        events.next();  // StartDocument
+
+        match events.next() {
+            Some(ProcessingInstruction { ref name, data: Some(ref data) }) => {
+                assert!(is_borrowed_from(DATA, name) && is_borrowed_from(DATA, data));
+                assert_eq!(name.as_slice(), "target");
+                assert_eq!(data.as_slice(), "data");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+        match events.next() {
+            Some(StartElement { ref name, ref attributes, .. }) => {
+                assert!(is_borrowed_from(DATA, name.prefix.as_ref().unwrap()));
+                assert!(is_borrowed_from(DATA, name.namespace.as_ref().unwrap()));
+                assert!(is_borrowed_from(DATA, &name.local_name));
+                assert_eq!(name.to_string().as_slice(), "{urn:p}p:a");
+
+                assert_eq!(attributes.len(), 2);
+                assert!(is_borrowed_from(DATA, &attributes[0].name.local_name));
+                assert!(is_borrowed_from(DATA, &attributes[0].value));
+                assert_eq!(attributes[0].value.as_slice(), "value");
+                // the value is unescaped, so it cannot be borrowed
+                assert!(!attributes[1].value.is_slice());
+                assert_eq!(attributes[1].value.as_slice(), "&");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+        match events.next() {
+            Some(Characters(ref data)) => {
+                assert!(is_borrowed_from(DATA, data));
+                assert_eq!(data.as_slice(), "text");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+        match events.next() {
+            Some(CData(ref data)) => {
+                assert!(is_borrowed_from(DATA, data));
+                assert_eq!(data.as_slice(), "cdata");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+        match events.next() {
+            Some(StartElement { ref name, .. }) =>
+                assert!(is_borrowed_from(DATA, name.namespace.as_ref().unwrap())),
+            e => panic!("Unexpected event: {}", e)
+        }
+        match events.next() {
+            Some(EndElement { ref name }) => {
+                assert!(is_borrowed_from(DATA, &name.local_name));
+                assert_eq!(name.local_name.as_slice(), "d");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+        match events.next() {
+            Some(Characters(ref data)) => {
+                assert!(!data.is_slice());
+                assert_eq!(data.as_slice(), "a < b");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+        match events.next() {
+            Some(EndElement { ref name }) => {
+                assert!(is_borrowed_from(DATA, name.prefix.as_ref().unwrap()));
+                assert!(is_borrowed_from(DATA, &name.local_name));
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+    }
+
+    #[test]
+    fn normalized_strings_are_assembled() {
+        static DATA: &'static str = "<!DOCTYPE a SYSTEM 'a.dtd'><a>x &lt; y\r\nz</a>";
+        let mut r = BorrowedEventReader::new(DATA);
+        let mut events = r.events();
+        events.next();  // StartDocument
+
+        match events.next() {
+            Some(Doctype { ref name, system_id: Some(ref system_id), .. }) => {
+                assert!(is_borrowed_from(DATA, name) && is_borrowed_from(DATA, system_id));
+                assert_eq!(name.as_slice(), "a");
+                assert_eq!(system_id.as_slice(), "a.dtd");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+        events.next();  // StartElement
+        match events.next() {
+            Some(Characters(ref data)) => {
+                assert!(!data.is_slice());
+                assert_eq!(data.as_slice(), "x < y\nz");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+    }
+
+    #[test]
+    fn trimmed_text_is_borrowed() {
+        static DATA: &'static str = "<a>  text\n</a>";
+        let mut r = BorrowedEventReader::new_with_config(DATA, ParserConfig::new().trim_whitespace(true));
+        let ev = r.events().nth(2);
+        match ev {
+            Some(Characters(ref data)) => {
+                assert!(is_borrowed_from(DATA, data));
+                assert_eq!(data.as_slice(), "text");
+            }
+            e => panic!("Unexpected event: {}", e)
+        }
+    }
+}
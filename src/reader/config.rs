@@ -1,5 +1,10 @@
 //! Contains parser configuration object.
 
+use std::collections::HashMap;
+
+use reader::encoding;
+use reader::encoding::DecoderFactory;
+
 /// Parser configuration structure.
 ///
 /// This structure contains various configuration options which affect
@@ -49,7 +54,63 @@ pub struct ParserConfig {
     /// Multiple sequential `Characters` events are only possible if either
     /// `cdata_to_characters` or `ignore_comments` are set. Otherwise character
     /// events will always be separated by other events.
-    pub coalesce_characters: bool
+    pub coalesce_characters: bool,
+
+    /// Whether or not should the `encoding` attribute of XML declaration be ignored.
+    /// Default is false.
+    ///
+    /// If true, the document is decoded with the encoding detected from its byte order
+    /// mark, or as UTF-8 if there is none. `EventReader` constructors which accept
+    /// Rust strings set this option because their input is known to be UTF-8.
+    pub ignore_encoding_declaration: bool,
+
+    /// A function which returns decoders for encoding labels.
+    /// Default is `reader::encoding::default_decoder_factory`.
+    ///
+    /// It is used to obtain a decoder for the encoding declared in XML declaration.
+    /// Custom factories can support additional encodings and delegate to the default
+    /// one for the rest.
+    pub decoder_factory: DecoderFactory,
+
+    /// Additional general entities which can be referenced in the document without being
+    /// declared. Default is empty.
+    ///
+    /// This map is from entity names to their values. Values are inserted verbatim: markup
+    /// and references inside them are not parsed. Entities declared in the document DTD
+    /// take precedence over these ones, and predefined XML entities cannot be overridden.
+    /// This is useful for documents which rely on HTML named entities like `&nbsp;`.
+    pub extra_entities: HashMap<String, String>,
+
+    /// Maximum nesting depth of entity expansion. Default is 16.
+    ///
+    /// An entity referenced directly from the document has depth 1, an entity referenced
+    /// from its replacement text has depth 2, and so on. Exceeding this limit is reported
+    /// as an error.
+    pub max_entity_expansion_depth: uint,
+
+    /// Maximum total size of entity replacement text, in bytes of UTF-8 text, which can be
+    /// inserted into the document. Default is 10 MiB.
+    ///
+    /// Every entity reference adds the size of its replacement text to the total, so this limit
+    /// prevents both exponential ("billion laughs") and quadratic blowup attacks.
+    pub max_entity_expansion_size: uint,
+
+    /// Maximum ratio of the total size of entity replacement text to the size of the document
+    /// read so far. Default is 10.
+    ///
+    /// This limit is checked only after the total size of replacement text exceeds 1 MiB, so
+    /// small documents can use entities freely.
+    /// Zero value disables this limit.
+    pub max_entity_expansion_ratio: uint,
+
+    /// Whether or not should runs of text be read from the input in bulk. Default is true.
+    ///
+    /// If true, the lexer scans character data, attribute values, comments and CDATA
+    /// sections for the next significant character and appends whole runs of text to the
+    /// parser buffer instead of producing a token for every character. UTF-8 input is
+    /// scanned directly in the buffer of the reader. The produced events are the same
+    /// either way, so this option is only useful for measurements and debugging.
+    pub bulk_text_scanning: bool
 }
 
 impl ParserConfig {
@@ -71,9 +132,30 @@ impl ParserConfig {
             whitespace_to_characters: false,
             cdata_to_characters: false,
             ignore_comments: true,
-            coalesce_characters: true
+            coalesce_characters: true,
+            ignore_encoding_declaration: false,
+            decoder_factory: encoding::default_decoder_factory,
+            extra_entities: HashMap::new(),
+            max_entity_expansion_depth: 16,
+            max_entity_expansion_size: 10 * 1024 * 1024,
+            max_entity_expansion_ratio: 10,
+            bulk_text_scanning: true
         }
     }
+
+    /// Adds an entity to `extra_entities` map and returns updated config object.
+    ///
+    /// ```rust
+    /// use xml::reader::ParserConfig;
+    ///
+    /// let config = ParserConfig::new()
+    ///     .add_entity("nbsp", "\u00A0")
+    ///     .add_entity("copy", "\u00A9");
+    /// ```
+    pub fn add_entity(mut self, name: &str, value: &str) -> ParserConfig {
+        self.extra_entities.insert(name.to_string(), value.to_string());
+        self
+    }
 }
 
 gen_setters!(ParserConfig,
@@ -81,5 +163,12 @@ gen_setters!(ParserConfig,
     whitespace_to_characters: bool,
     cdata_to_characters: bool,
     ignore_comments: bool,
-    coalesce_characters: bool
+    coalesce_characters: bool,
+    ignore_encoding_declaration: bool,
+    decoder_factory: DecoderFactory,
+    extra_entities: HashMap<String, String>,
+    max_entity_expansion_depth: uint,
+    max_entity_expansion_size: uint,
+    max_entity_expansion_ratio: uint,
+    bulk_text_scanning: bool
 )
@@ -0,0 +1,917 @@
+//! Contains data types for DOCTYPE declarations and a parser for them.
+//!
+//! The pull parser collects the whole `<!DOCTYPE ...>` declaration, including its internal subset,
+//! and then parses it using `parse_doctype` function from this module. Markup declarations are
+//! exposed to the user through `XmlEvent::Doctype` event. Parameter entity references are
+//! recorded but not expanded, and no validation is performed.
+
+use common::{Error, ErrorKind, UnexpectedToken, UnexpectedEof, InvalidProcessingInstruction};
+use common::{HasPosition, XmlVersion, is_whitespace_char, is_name_start_char, is_name_char,
+             is_valid_referenced_char};
+
+/// A markup declaration from the internal subset of a DOCTYPE declaration.
+#[deriving(Clone, PartialEq, Show)]
+pub enum MarkupDeclaration {
+    /// `<!ELEMENT name content>`
+    ElementDecl {
+        /// Name of the element.
+        pub name: String,
+
+        /// Allowed content of the element.
+        pub content: ContentSpec
+    },
+
+    /// `<!ATTLIST element attributes...>`
+    AttlistDecl {
+        /// Name of the element these attributes belong to.
+        pub element: String,
+
+        /// Definitions of the attributes.
+        pub attributes: Vec<AttributeDef>
+    },
+
+    /// `<!ENTITY name value>` or `<!ENTITY % name value>`
+    EntityDecl {
+        /// Name of the entity.
+        pub name: String,
+
+        /// Whether this is a parameter entity (declared with `%`).
+        pub parameter: bool,
+
+        /// Replacement text or external location of the entity.
+        pub value: EntityValue
+    },
+
+    /// `<!NOTATION name external-id>`
+    NotationDecl {
+        /// Name of the notation.
+        pub name: String,
+
+        /// Public identifier of the notation.
+        pub public_id: Option<String>,
+
+        /// System identifier of the notation.
+        pub system_id: Option<String>
+    },
+
+    /// A comment inside the internal subset.
+    CommentDecl(String),
+
+    /// A processing instruction inside the internal subset.
+    ProcessingInstructionDecl {
+        /// Processing instruction target.
+        pub name: String,
+
+        /// Processing instruction content.
+        pub data: Option<String>
+    },
+
+    /// A parameter entity reference (`%name;`) between declarations.
+    ///
+    /// Parameter entities are not expanded.
+    ParameterEntityRef(String)
+}
+
+/// Content specification of an element declaration.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ContentSpec {
+    /// `EMPTY`
+    EmptyContent,
+
+    /// `ANY`
+    AnyContent,
+
+    /// `(#PCDATA | a | b)*`; contains names of allowed child elements.
+    MixedContent(Vec<String>),
+
+    /// Element content described by a content particle.
+    ChildrenContent(ContentParticle)
+}
+
+/// A content particle of an element content model.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ContentParticle {
+    /// An element name.
+    NameParticle(String, Repetition),
+
+    /// A choice list: `(a | b | c)`.
+    ChoiceParticle(Vec<ContentParticle>, Repetition),
+
+    /// A sequence: `(a, b, c)`.
+    SeqParticle(Vec<ContentParticle>, Repetition)
+}
+
+/// Number of occurrences of a content particle.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Repetition {
+    /// Exactly once (no modifier).
+    Once,
+
+    /// `?`
+    Optional,
+
+    /// `*`
+    ZeroOrMore,
+
+    /// `+`
+    OneOrMore
+}
+
+/// A definition of a single attribute in an attribute-list declaration.
+#[deriving(Clone, PartialEq, Show)]
+pub struct AttributeDef {
+    /// Name of the attribute.
+    pub name: String,
+
+    /// Type of the attribute.
+    pub attribute_type: AttributeType,
+
+    /// Default value declaration.
+    pub default: DefaultDecl
+}
+
+/// Type of an attribute.
+#[deriving(Clone, PartialEq, Show)]
+pub enum AttributeType {
+    /// `CDATA`
+    CDataType,
+    /// `ID`
+    IdType,
+    /// `IDREF`
+    IdRefType,
+    /// `IDREFS`
+    IdRefsType,
+    /// `ENTITY`
+    EntityType,
+    /// `ENTITIES`
+    EntitiesType,
+    /// `NMTOKEN`
+    NmTokenType,
+    /// `NMTOKENS`
+    NmTokensType,
+    /// `NOTATION (a | b)`
+    NotationType(Vec<String>),
+    /// `(a | b)`
+    EnumerationType(Vec<String>)
+}
+
+/// Default value declaration of an attribute.
+#[deriving(Clone, PartialEq, Show)]
+pub enum DefaultDecl {
+    /// `#REQUIRED`
+    RequiredDefault,
+    /// `#IMPLIED`
+    ImpliedDefault,
+    /// `#FIXED "value"`
+    FixedDefault(String),
+    /// `"value"`
+    ValueDefault(String)
+}
+
+/// Value of an entity declaration.
+#[deriving(Clone, PartialEq, Show)]
+pub enum EntityValue {
+    /// Literal entity value, exactly as it is written in the declaration.
+    InternalEntity(String),
+
+    /// An external entity.
+    ExternalEntity {
+        /// Public identifier.
+        pub public_id: Option<String>,
+
+        /// System identifier.
+        pub system_id: String,
+
+        /// Notation name of an unparsed entity (`NDATA`).
+        pub notation: Option<String>
+    }
+}
+
+/// Result of DOCTYPE declaration parsing.
+///
+/// Ranges are byte offsets of the name and the identifiers in the parsed text.
+pub struct ParsedDoctype {
+    /// Name of the document root element.
+    pub name: String,
+
+    /// Range of the name in the parsed text.
+    pub name_range: (uint, uint),
+
+    /// Public identifier of the external subset.
+    pub public_id: Option<String>,
+
+    /// Range of the public identifier in the parsed text, without quotes.
+    pub public_id_range: Option<(uint, uint)>,
+
+    /// System identifier of the external subset.
+    pub system_id: Option<String>,
+
+    /// Range of the system identifier in the parsed text, without quotes.
+    pub system_id_range: Option<(uint, uint)>,
+
+    /// Markup declarations from the internal subset, in document order.
+    pub declarations: Vec<MarkupDeclaration>
+}
+
+/// Parses the contents of a DOCTYPE declaration.
+///
+/// `text` should contain everything between `<!DOCTYPE` and the final `>`; `row` and `col`
+/// are the position of the first character of `text` in the document, used for error reporting.
+pub fn parse_doctype(text: &str, row: uint, col: uint) -> Result<ParsedDoctype, Error> {
+    let mut p = DtdParser { text: text, pos: 0, row: row, col: col };
+
+    try!(p.require_whitespace());
+    let name_start = p.pos;
+    let name = try!(p.read_name());
+    let name_range = (name_start, p.pos);
+
+    let (public_id_range, system_id_range) = if p.skip_whitespace() {
+        match try!(p.read_external_id_ranges(false)) {
+            Some((public_id, system_id)) => {
+                p.skip_whitespace();
+                (public_id, system_id)
+            }
+            None => (None, None)
+        }
+    } else {
+        (None, None)
+    };
+    let public_id = public_id_range.map(|(start, end)| text.slice(start, end).to_string());
+    let system_id = system_id_range.map(|(start, end)| text.slice(start, end).to_string());
+
+    let declarations = if p.eat("[") {
+        let declarations = try!(p.read_internal_subset());
+        p.skip_whitespace();
+        declarations
+    } else {
+        Vec::new()
+    };
+
+    match p.peek() {
+        None => Ok(ParsedDoctype {
+            name: name,
+            name_range: name_range,
+            public_id: public_id,
+            public_id_range: public_id_range,
+            system_id: system_id,
+            system_id_range: system_id_range,
+            declarations: declarations
+        }),
+        Some(c) => p.error(format!("Unexpected character inside DOCTYPE declaration: {}", c))
+    }
+}
+
+/// Computes replacement text of an internal entity from its literal value.
+///
+/// Character references are replaced with corresponding characters, while general entity
+/// references are left intact, as is required by XML specification, [section 4.5][1].
+/// Parameter entity references are not allowed in the internal subset, so they are reported
+/// as errors. Character references are checked against `Char` production of the given
+/// XML version.
+///
+/// [1]: http://www.w3.org/TR/2006/REC-xml11-20060816/#intern-replacement
+pub fn replacement_text(literal: &str, version: XmlVersion) -> Result<String, String> {
+    use std::char;
+    use std::num::from_str_radix;
+
+    let mut result = String::with_capacity(literal.len());
+    let mut rest = literal;
+    loop {
+        match rest.find(|c: char| c == '&' || c == '%') {
+            None => {
+                result.push_str(rest);
+                return Ok(result);
+            }
+            Some(idx) => {
+                result.push_str(rest.slice_to(idx));
+                rest = rest.slice_from(idx);
+            }
+        }
+
+        if rest.starts_with("%") {
+            return Err("Parameter entity references are not allowed in internal subset declarations".to_string());
+        }
+
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => return Err(format!("Unterminated reference in entity value: {}", rest))
+        };
+        let reference = rest.slice(1, end);
+        if reference.starts_with("#") {
+            let c = if reference.starts_with("#x") {
+                from_str_radix(reference.slice_from(2), 16)
+            } else {
+                from_str_radix(reference.slice_from(1), 10)
+            }.and_then(char::from_u32);
+            match c {
+                Some(c) if is_valid_referenced_char(c, version) => result.push(c),
+                _ => return Err(format!("Invalid character reference in entity value: &{};", reference))
+            }
+        } else {
+            result.push_str(rest.slice_to(end + 1));
+        }
+        rest = rest.slice_from(end + 1);
+    }
+}
+
+type DtdResult<T> = Result<T, Error>;
+
+struct DtdParser<'a> {
+    text: &'a str,
+    pos: uint,
+    row: uint,
+    col: uint
+}
+
+impl<'a> HasPosition for DtdParser<'a> {
+    #[inline]
+    fn row(&self) -> uint { self.row }
+
+    #[inline]
+    fn col(&self) -> uint { self.col }
+}
+
+impl<'a> DtdParser<'a> {
+    /// Returns a syntax error at the current position, with the kind deduced from
+    /// the next character.
+    fn error<T>(&self, expected: &'static str, msg: String) -> DtdResult<T> {
+        let kind = match self.peek() {
+            Some(c) => UnexpectedToken { found: c.to_string(), expected: expected, context: "DOCTYPE declaration" },
+            None => UnexpectedEof
+        };
+        self.error_of_kind(kind, msg)
+    }
+
+    #[inline]
+    fn error_of_kind<T>(&self, kind: ErrorKind, msg: String) -> DtdResult<T> {
+        Err(Error::new(self, kind, msg))
+    }
+
+    #[inline]
+    fn rest(&self) -> &'a str {
+        self.text.slice_from(self.pos)
+    }
+
+    #[inline]
+    fn peek(&self) -> Option<char> {
+        if self.pos < self.text.len() { Some(self.text.char_at(self.pos)) } else { None }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        if self.pos >= self.text.len() {
+            return None;
+        }
+        let r = self.text.char_range_at(self.pos);
+        self.pos = r.next;
+        if r.ch == '\n' {
+            self.row += 1;
+            self.col = 0;
+        } else {
+            self.col += 1;
+        }
+        Some(r.ch)
+    }
+
+    /// Consumes the given string if the input starts with it.
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            for _ in s.chars() { self.bump(); }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &'static str, what: &str) -> DtdResult<()> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            self.error(s, format!("Expected {} inside {}", s, what))
+        }
+    }
+
+    /// Skips whitespace and returns `true` if there was any.
+    fn skip_whitespace(&mut self) -> bool {
+        let mut skipped = false;
+        loop {
+            match self.peek() {
+                Some(c) if is_whitespace_char(c) => { self.bump(); skipped = true; }
+                _ => return skipped
+            }
+        }
+    }
+
+    fn require_whitespace(&mut self) -> DtdResult<()> {
+        if self.skip_whitespace() {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(c) => self.error("whitespace", format!("Expected whitespace inside DOCTYPE declaration, found {}", c)),
+                None => self.error("whitespace", "Unexpected end of DOCTYPE declaration".to_string())
+            }
+        }
+    }
+
+    /// Consumes everything up to and including `delim` and returns it without `delim`.
+    fn read_until(&mut self, delim: &'static str, what: &str) -> DtdResult<String> {
+        match self.rest().find_str(delim) {
+            Some(idx) => {
+                let result = self.rest().slice_to(idx).to_string();
+                for _ in result.as_slice().chars() { self.bump(); }
+                self.eat(delim);
+                Ok(result)
+            }
+            None => self.error(delim, format!("Unterminated {} inside DOCTYPE declaration", what))
+        }
+    }
+
+    fn read_name(&mut self) -> DtdResult<String> {
+        let mut name = String::new();
+        match self.peek() {
+            Some(c) if is_name_start_char(c) => { self.bump(); name.push(c); }
+            Some(c) => return self.error("name", format!("Unexpected character inside DOCTYPE declaration: {}", c)),
+            None => return self.error("name", "Unexpected end of DOCTYPE declaration".to_string())
+        }
+        loop {
+            match self.peek() {
+                Some(c) if is_name_char(c) => { self.bump(); name.push(c); }
+                _ => return Ok(name)
+            }
+        }
+    }
+
+    fn read_nmtoken(&mut self) -> DtdResult<String> {
+        let mut token = String::new();
+        loop {
+            match self.peek() {
+                Some(c) if is_name_char(c) => { self.bump(); token.push(c); }
+                _ if token.is_empty() => return self.error("name token", "Expected a name token".to_string()),
+                _ => return Ok(token)
+            }
+        }
+    }
+
+    /// Reads a quoted literal and returns its contents.
+    fn read_literal(&mut self) -> DtdResult<String> {
+        let (start, end) = try!(self.read_literal_range());
+        Ok(self.text.slice(start, end).to_string())
+    }
+
+    /// Reads a quoted literal and returns the range of its contents.
+    fn read_literal_range(&mut self) -> DtdResult<(uint, uint)> {
+        match self.peek() {
+            Some(q) if q == '"' || q == '\'' => {
+                self.bump();
+                let delim = if q == '"' { "\"" } else { "'" };
+                let start = self.pos;
+                let literal = try!(self.read_until(delim, "literal"));
+                Ok((start, start + literal.len()))
+            }
+            Some(c) => self.error("quoted literal", format!("Expected quoted literal, found {}", c)),
+            None => self.error("quoted literal", "Unexpected end of DOCTYPE declaration".to_string())
+        }
+    }
+
+    fn read_pubid_literal_range(&mut self) -> DtdResult<(uint, uint)> {
+        let (start, end) = try!(self.read_literal_range());
+        match self.text.slice(start, end).chars().find(|&c| !is_pubid_char(c)) {
+            Some(c) => self.error_of_kind(
+                UnexpectedToken { found: c.to_string(), expected: "public identifier character", context: "DOCTYPE declaration" },
+                format!("Invalid character in public identifier: {}", c)
+            ),
+            None => Ok((start, end))
+        }
+    }
+
+    /// Reads `SYSTEM "sys"` or `PUBLIC "pub" "sys"` if one of them is present.
+    ///
+    /// When `system_optional` is set, system literal may be omitted after a public
+    /// one, as it is allowed in notation declarations.
+    fn read_external_id(&mut self, system_optional: bool) -> DtdResult<Option<(Option<String>, Option<String>)>> {
+        match try!(self.read_external_id_ranges(system_optional)) {
+            Some((public_id, system_id)) => Ok(Some((self.slice_range(public_id), self.slice_range(system_id)))),
+            None => Ok(None)
+        }
+    }
+
+    fn slice_range(&self, range: Option<(uint, uint)>) -> Option<String> {
+        range.map(|(start, end)| self.text.slice(start, end).to_string())
+    }
+
+    /// Same as `read_external_id()`, but returns ranges of the identifiers.
+    fn read_external_id_ranges(&mut self, system_optional: bool) -> DtdResult<Option<(Option<(uint, uint)>, Option<(uint, uint)>)>> {
+        if self.eat("SYSTEM") {
+            try!(self.require_whitespace());
+            let system_id = try!(self.read_literal_range());
+            Ok(Some((None, Some(system_id))))
+        } else if self.eat("PUBLIC") {
+            try!(self.require_whitespace());
+            let public_id = try!(self.read_pubid_literal_range());
+            let save = (self.pos, self.row, self.col);
+            let had_whitespace = self.skip_whitespace();
+            match self.peek() {
+                Some('"') | Some('\'') if had_whitespace => {
+                    let system_id = try!(self.read_literal_range());
+                    Ok(Some((Some(public_id), Some(system_id))))
+                }
+                _ if system_optional => {
+                    let (pos, row, col) = save;
+                    self.pos = pos; self.row = row; self.col = col;
+                    Ok(Some((Some(public_id), None)))
+                }
+                _ => self.error("system identifier", "Expected system identifier after public identifier".to_string())
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_internal_subset(&mut self) -> DtdResult<Vec<MarkupDeclaration>> {
+        let mut declarations = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let decl = if self.eat("]") {
+                return Ok(declarations);
+            } else if self.eat("<!--") {
+                let comment = try!(self.read_until("-->", "comment"));
+                if comment.as_slice().contains("--") || comment.as_slice().ends_with("-") {
+                    return self.error("-->", "Unexpected token inside a comment: --".to_string());
+                }
+                CommentDecl(comment)
+            } else if self.eat("<?") {
+                try!(self.read_processing_instruction())
+            } else if self.eat("<!ELEMENT") {
+                try!(self.read_element_decl())
+            } else if self.eat("<!ATTLIST") {
+                try!(self.read_attlist_decl())
+            } else if self.eat("<!ENTITY") {
+                try!(self.read_entity_decl())
+            } else if self.eat("<!NOTATION") {
+                try!(self.read_notation_decl())
+            } else if self.eat("%") {
+                let name = try!(self.read_name());
+                try!(self.expect(";", "parameter entity reference"));
+                ParameterEntityRef(name)
+            } else {
+                return match self.peek() {
+                    Some(c) => self.error("markup declaration or ]",
+                                          format!("Unexpected character inside DOCTYPE internal subset: {}", c)),
+                    None => self.error("markup declaration or ]", "Unexpected end of DOCTYPE internal subset".to_string())
+                };
+            };
+            declarations.push(decl);
+        }
+    }
+
+    fn finish_declaration(&mut self, what: &str) -> DtdResult<()> {
+        self.skip_whitespace();
+        self.expect(">", what)
+    }
+
+    fn read_processing_instruction(&mut self) -> DtdResult<MarkupDeclaration> {
+        let name = try!(self.read_name());
+        match name.as_slice() {
+            "xml"|"xmL"|"xMl"|"xML"|"Xml"|"XmL"|"XMl"|"XML" =>
+                return self.error_of_kind(InvalidProcessingInstruction(name.clone()),
+                                          format!("Invalid processing instruction: <?{}", name)),
+            _ => {}
+        }
+        let data = if self.eat("?>") {
+            None
+        } else {
+            try!(self.require_whitespace());
+            Some(try!(self.read_until("?>", "processing instruction")))
+        };
+        Ok(ProcessingInstructionDecl { name: name, data: data })
+    }
+
+    fn read_element_decl(&mut self) -> DtdResult<MarkupDeclaration> {
+        try!(self.require_whitespace());
+        let name = try!(self.read_name());
+        try!(self.require_whitespace());
+        let content = if self.eat("EMPTY") {
+            EmptyContent
+        } else if self.eat("ANY") {
+            AnyContent
+        } else if self.eat("(") {
+            self.skip_whitespace();
+            if self.eat("#PCDATA") {
+                try!(self.read_mixed_content())
+            } else {
+                ChildrenContent(try!(self.read_group()))
+            }
+        } else {
+            return self.error("content specification", format!("Invalid content specification of element {}", name));
+        };
+        try!(self.finish_declaration("element declaration"));
+        Ok(ElementDecl { name: name, content: content })
+    }
+
+    /// Reads mixed content specification after `(#PCDATA`.
+    fn read_mixed_content(&mut self) -> DtdResult<ContentSpec> {
+        let mut names = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(")") {
+                if !self.eat("*") && !names.is_empty() {
+                    return self.error(")*", "Mixed content with element names must end with )*".to_string());
+                }
+                return Ok(MixedContent(names));
+            }
+            try!(self.expect("|", "mixed content specification"));
+            self.skip_whitespace();
+            names.push(try!(self.read_name()));
+        }
+    }
+
+    /// Reads a choice or a sequence after its opening parenthesis.
+    fn read_group(&mut self) -> DtdResult<ContentParticle> {
+        let mut items = vec![try!(self.read_content_particle())];
+        let mut separator = None;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(')') => { self.bump(); break; }
+                Some(c) if c == '|' || c == ',' => {
+                    if separator.is_some() && separator != Some(c) {
+                        return self.error("the same separator", "Mixed separators inside content model group".to_string());
+                    }
+                    separator = Some(c);
+                    self.bump();
+                    items.push(try!(self.read_content_particle()));
+                }
+                Some(c) => return self.error("|, , or )", format!("Unexpected character inside content model: {}", c)),
+                None => return self.error("|, , or )", "Unexpected end of content model".to_string())
+            }
+        }
+        let repetition = self.read_repetition();
+        Ok(if separator == Some('|') {
+            ChoiceParticle(items, repetition)
+        } else {
+            SeqParticle(items, repetition)
+        })
+    }
+
+    fn read_content_particle(&mut self) -> DtdResult<ContentParticle> {
+        self.skip_whitespace();
+        if self.eat("(") {
+            self.read_group()
+        } else {
+            let name = try!(self.read_name());
+            Ok(NameParticle(name, self.read_repetition()))
+        }
+    }
+
+    fn read_repetition(&mut self) -> Repetition {
+        if self.eat("?") { Optional }
+        else if self.eat("*") { ZeroOrMore }
+        else if self.eat("+") { OneOrMore }
+        else { Once }
+    }
+
+    fn read_attlist_decl(&mut self) -> DtdResult<MarkupDeclaration> {
+        try!(self.require_whitespace());
+        let element = try!(self.read_name());
+        let mut attributes = Vec::new();
+        loop {
+            let had_whitespace = self.skip_whitespace();
+            if self.eat(">") {
+                return Ok(AttlistDecl { element: element, attributes: attributes });
+            }
+            if !had_whitespace {
+                try!(self.require_whitespace());
+            }
+            let name = try!(self.read_name());
+            try!(self.require_whitespace());
+            let attribute_type = try!(self.read_attribute_type());
+            try!(self.require_whitespace());
+            let default = try!(self.read_default_decl());
+            attributes.push(AttributeDef { name: name, attribute_type: attribute_type, default: default });
+        }
+    }
+
+    fn read_attribute_type(&mut self) -> DtdResult<AttributeType> {
+        if self.eat("CDATA") { Ok(CDataType) }
+        else if self.eat("IDREFS") { Ok(IdRefsType) }
+        else if self.eat("IDREF") { Ok(IdRefType) }
+        else if self.eat("ID") { Ok(IdType) }
+        else if self.eat("ENTITIES") { Ok(EntitiesType) }
+        else if self.eat("ENTITY") { Ok(EntityType) }
+        else if self.eat("NMTOKENS") { Ok(NmTokensType) }
+        else if self.eat("NMTOKEN") { Ok(NmTokenType) }
+        else if self.eat("NOTATION") {
+            try!(self.require_whitespace());
+            try!(self.expect("(", "notation type"));
+            Ok(NotationType(try!(self.read_enumeration(true))))
+        } else if self.eat("(") {
+            Ok(EnumerationType(try!(self.read_enumeration(false))))
+        } else {
+            self.error("attribute type", "Invalid attribute type".to_string())
+        }
+    }
+
+    /// Reads names or name tokens separated by `|` up to the closing parenthesis.
+    fn read_enumeration(&mut self, names: bool) -> DtdResult<Vec<String>> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            values.push(if names { try!(self.read_name()) } else { try!(self.read_nmtoken()) });
+            self.skip_whitespace();
+            if self.eat(")") {
+                return Ok(values);
+            }
+            try!(self.expect("|", "enumerated attribute type"));
+        }
+    }
+
+    fn read_default_decl(&mut self) -> DtdResult<DefaultDecl> {
+        if self.eat("#REQUIRED") {
+            Ok(RequiredDefault)
+        } else if self.eat("#IMPLIED") {
+            Ok(ImpliedDefault)
+        } else if self.eat("#FIXED") {
+            try!(self.require_whitespace());
+            Ok(FixedDefault(try!(self.read_attribute_literal())))
+        } else {
+            Ok(ValueDefault(try!(self.read_attribute_literal())))
+        }
+    }
+
+    fn read_attribute_literal(&mut self) -> DtdResult<String> {
+        let literal = try!(self.read_literal());
+        if literal.as_slice().contains_char('<') {
+            self.error("attribute value without <", "Unexpected token inside attribute value: <".to_string())
+        } else {
+            Ok(literal)
+        }
+    }
+
+    fn read_entity_decl(&mut self) -> DtdResult<MarkupDeclaration> {
+        try!(self.require_whitespace());
+        let parameter = self.eat("%");
+        if parameter {
+            try!(self.require_whitespace());
+        }
+        let name = try!(self.read_name());
+        try!(self.require_whitespace());
+
+        let value = match self.peek() {
+            Some('"') | Some('\'') => InternalEntity(try!(self.read_literal())),
+            _ => match try!(self.read_external_id(false)) {
+                Some((public_id, Some(system_id))) => {
+                    let save = (self.pos, self.row, self.col);
+                    let notation = if !parameter && self.skip_whitespace() && self.eat("NDATA") {
+                        try!(self.require_whitespace());
+                        Some(try!(self.read_name()))
+                    } else {
+                        let (pos, row, col) = save;
+                        self.pos = pos; self.row = row; self.col = col;
+                        None
+                    };
+                    ExternalEntity { public_id: public_id, system_id: system_id, notation: notation }
+                }
+                _ => return self.error("entity value", format!("Invalid value of entity {}", name))
+            }
+        };
+
+        try!(self.finish_declaration("entity declaration"));
+        Ok(EntityDecl { name: name, parameter: parameter, value: value })
+    }
+
+    fn read_notation_decl(&mut self) -> DtdResult<MarkupDeclaration> {
+        try!(self.require_whitespace());
+        let name = try!(self.read_name());
+        try!(self.require_whitespace());
+        match try!(self.read_external_id(true)) {
+            Some((public_id, system_id)) => {
+                try!(self.finish_declaration("notation declaration"));
+                Ok(NotationDecl { name: name, public_id: public_id, system_id: system_id })
+            }
+            None => self.error("external identifier", format!("Expected external identifier of notation {}", name))
+        }
+    }
+}
+
+/// Checks whether the given character is allowed in public identifiers (`PubidChar`).
+fn is_pubid_char(c: char) -> bool {
+    match c {
+        '\x20' | '\x0d' | '\x0a' | 'a'...'z' | 'A'...'Z' | '0'...'9' |
+        '-' | '\'' | '(' | ')' | '+' | ',' | '.' | '/' | ':' | '=' | '?' |
+        ';' | '!' | '*' | '#' | '@' | '$' | '_' | '%' => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{HasPosition, Version10, Version11, UnexpectedToken};
+
+    use super::{
+        parse_doctype, replacement_text,
+        ElementDecl, AttlistDecl, EntityDecl, NotationDecl, CommentDecl,
+        ProcessingInstructionDecl, ParameterEntityRef,
+        EmptyContent, MixedContent, ChildrenContent,
+        NameParticle, ChoiceParticle, SeqParticle,
+        Once, Optional, ZeroOrMore, OneOrMore,
+        AttributeDef, CDataType, IdType, EnumerationType, NotationType,
+        RequiredDefault, ImpliedDefault, FixedDefault, ValueDefault,
+        InternalEntity, ExternalEntity
+    };
+
+    #[test]
+    fn external_ids() {
+        let d = parse_doctype(r#" html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "xhtml1-strict.dtd""#, 0, 0).unwrap();
+        assert_eq!(d.name.as_slice(), "html");
+        assert_eq!(d.public_id, Some("-//W3C//DTD XHTML 1.0 Strict//EN".to_string()));
+        assert_eq!(d.system_id, Some("xhtml1-strict.dtd".to_string()));
+        assert_eq!((d.name_range, d.public_id_range, d.system_id_range), ((1, 5), Some((14, 46)), Some((49, 66))));
+        assert!(d.declarations.is_empty());
+
+        let d = parse_doctype(" a SYSTEM 'a.dtd' ", 0, 0).unwrap();
+        assert_eq!(d.public_id, None);
+        assert_eq!(d.system_id, Some("a.dtd".to_string()));
+    }
+
+    #[test]
+    fn internal_subset() {
+        let d = parse_doctype(r#" doc [
+            <!ELEMENT doc (head?, (p | list)+, foot*)>
+            <!ELEMENT p (#PCDATA | b)*>
+            <!ELEMENT br EMPTY>
+            <!ATTLIST doc id ID #REQUIRED
+                          kind (a|b) "a"
+                          lang CDATA #IMPLIED
+                          version CDATA #FIXED '1>2'
+                          img NOTATION (gif) #IMPLIED>
+            <!-- a comment with > inside -->
+            <?pi some data?>
+            <!ENTITY copy "&#169;">
+            <!ENTITY % common SYSTEM "common.ent">
+            <!ENTITY logo PUBLIC "-//Logo" "logo.gif" NDATA gif>
+            <!NOTATION gif PUBLIC "image/gif">
+            %common;
+        ]"#, 0, 0).unwrap();
+
+        assert_eq!(d.name.as_slice(), "doc");
+        assert_eq!(d.declarations, vec![
+            ElementDecl { name: "doc".to_string(), content: ChildrenContent(SeqParticle(vec![
+                NameParticle("head".to_string(), Optional),
+                ChoiceParticle(vec![
+                    NameParticle("p".to_string(), Once),
+                    NameParticle("list".to_string(), Once)
+                ], OneOrMore),
+                NameParticle("foot".to_string(), ZeroOrMore)
+            ], Once)) },
+            ElementDecl { name: "p".to_string(), content: MixedContent(vec!["b".to_string()]) },
+            ElementDecl { name: "br".to_string(), content: EmptyContent },
+            AttlistDecl { element: "doc".to_string(), attributes: vec![
+                AttributeDef { name: "id".to_string(), attribute_type: IdType, default: RequiredDefault },
+                AttributeDef {
+                    name: "kind".to_string(),
+                    attribute_type: EnumerationType(vec!["a".to_string(), "b".to_string()]),
+                    default: ValueDefault("a".to_string())
+                },
+                AttributeDef { name: "lang".to_string(), attribute_type: CDataType, default: ImpliedDefault },
+                AttributeDef {
+                    name: "version".to_string(), attribute_type: CDataType,
+                    default: FixedDefault("1>2".to_string())
+                },
+                AttributeDef {
+                    name: "img".to_string(), attribute_type: NotationType(vec!["gif".to_string()]),
+                    default: ImpliedDefault
+                }
+            ] },
+            CommentDecl(" a comment with > inside ".to_string()),
+            ProcessingInstructionDecl { name: "pi".to_string(), data: Some("some data".to_string()) },
+            EntityDecl { name: "copy".to_string(), parameter: false, value: InternalEntity("&#169;".to_string()) },
+            EntityDecl { name: "common".to_string(), parameter: true, value: ExternalEntity {
+                public_id: None, system_id: "common.ent".to_string(), notation: None
+            } },
+            EntityDecl { name: "logo".to_string(), parameter: false, value: ExternalEntity {
+                public_id: Some("-//Logo".to_string()), system_id: "logo.gif".to_string(),
+                notation: Some("gif".to_string())
+            } },
+            NotationDecl { name: "gif".to_string(), public_id: Some("image/gif".to_string()), system_id: None },
+            ParameterEntityRef("common".to_string())
+        ]);
+    }
+
+    #[test]
+    fn entity_replacement_text() {
+        assert_eq!(replacement_text("a&#38;#38;b &lt; &#x41;", Version10).unwrap().as_slice(), "a&#38;b &lt; A");
+        assert!(replacement_text("%pe;", Version10).is_err());
+        assert!(replacement_text("&#0;", Version10).is_err());
+        assert!(replacement_text("&#1;", Version10).is_err());
+        assert!(replacement_text("&#1;", Version11).is_ok());
+        assert!(replacement_text("&amp", Version10).is_err());
+    }
+
+    #[test]
+    fn error_position() {
+        let e = parse_doctype(" a [\n  <!ELEMENT a FOO>\n]", 2, 9).unwrap_err();
+        assert_eq!(e.row(), 3);
+        assert_eq!(e.col(), 14);
+        assert_eq!(e.msg(), "Invalid content specification of element a");
+        assert_eq!(*e.kind(), UnexpectedToken { found: "F".to_string(), expected: "content specification", context: "DOCTYPE declaration" });
+    }
+}
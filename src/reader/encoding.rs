@@ -0,0 +1,451 @@
+//! Contains character decoders used by the parser to read non-UTF-8 documents.
+//!
+//! The parser never works with raw bytes directly; instead it asks a `Decoder` for the next
+//! character. The decoder is chosen in two steps, as described in [appendix F][1] of
+//! XML specification: first the beginning of the stream is inspected for a byte order mark or
+//! for a recognizable `<?xml` pattern, and then, after the XML declaration is parsed, its
+//! `encoding` attribute is used to pick the final decoder.
+//!
+//! [1]: http://www.w3.org/TR/2006/REC-xml11-20060816/#sec-guessing
+
+use std::io;
+use std::io::{Buffer, IoResult, IoError};
+use std::char;
+use std::str;
+use std::ascii::AsciiExt;
+
+use common::{ErrorKind, UnsupportedEncoding, EncodingConflict};
+
+/// A character decoder.
+///
+/// Decoders read bytes from a `Buffer` and convert them to characters. Additional decoders
+/// can be plugged into the parser with `ParserConfig::decoder_factory`.
+pub trait Decoder {
+    /// Returns canonical name of the encoding this decoder handles, e.g. `"UTF-8"`.
+    fn name(&self) -> &'static str;
+
+    /// Returns `true` if ASCII characters are encoded by this encoding as single bytes
+    /// with the same values as in ASCII.
+    ///
+    /// Only ASCII-compatible encodings can be switched to after the XML declaration has
+    /// been read with the default UTF-8 decoder.
+    fn is_ascii_compatible(&self) -> bool;
+
+    /// Reads the next character from the buffer.
+    ///
+    /// Returns `EndOfFile` error when the buffer is exhausted and `InvalidInput` error
+    /// when the bytes do not form a valid character in this encoding. Other errors of
+    /// the buffer are passed through; if they happen in the middle of a character, the
+    /// bytes consumed so far must be kept, so the next call completes the character.
+    fn read_char(&mut self, source: &mut Buffer) -> IoResult<char>;
+
+    /// Returns the number of bytes which the given character, as returned by `read_char`,
+    /// occupied in the input.
+    fn encoded_len(&self, c: char) -> uint;
+}
+
+/// A function which returns a decoder for an encoding label, if it is supported.
+///
+/// Labels are taken verbatim from `encoding` attribute of XML declarations, so
+/// implementations should compare them case-insensitively.
+pub type DecoderFactory = fn(&str) -> Option<Box<Decoder + 'static>>;
+
+/// Decodes UTF-8 input.
+pub struct Utf8Decoder {
+    partial: [u8, ..4],
+    partial_len: uint
+}
+
+impl Utf8Decoder {
+    /// Returns a new UTF-8 decoder.
+    pub fn new() -> Utf8Decoder {
+        Utf8Decoder { partial: [0, ..4], partial_len: 0 }
+    }
+}
+
+impl Decoder for Utf8Decoder {
+    fn name(&self) -> &'static str { "UTF-8" }
+
+    fn is_ascii_compatible(&self) -> bool { true }
+
+    fn read_char(&mut self, source: &mut Buffer) -> IoResult<char> {
+        // Buffer::read_char() reports a truncated sequence as the end of file, so
+        // the bytes are read here one by one
+        if self.partial_len == 0 {
+            let first = try!(source.read_byte());
+            match str::utf8_char_width(first) {
+                1 => return Ok(first as char),
+                0 => return Err(invalid_input("invalid UTF-8 sequence")),
+                _ => {}
+            }
+            self.partial[0] = first;
+            self.partial_len = 1;
+        }
+        let width = str::utf8_char_width(self.partial[0]);
+        try!(read_partial(source, &mut self.partial, &mut self.partial_len, width,
+                          "truncated UTF-8 sequence"));
+        self.partial_len = 0;
+        match str::from_utf8(self.partial.slice_to(width)) {
+            Some(s) => Ok(s.char_at(0)),
+            None => Err(invalid_input("invalid UTF-8 sequence"))
+        }
+    }
+
+    #[inline]
+    fn encoded_len(&self, c: char) -> uint { c.len_utf8_bytes() }
+}
+
+/// Decodes UTF-16 input in either byte order.
+pub struct Utf16Decoder {
+    name: &'static str,
+    big_endian: bool,
+    partial: [u8, ..4],
+    partial_len: uint
+}
+
+impl Utf16Decoder {
+    /// Returns a decoder for UTF-16 with little-endian byte order.
+    pub fn little_endian() -> Utf16Decoder {
+        Utf16Decoder::new("UTF-16LE", false)
+    }
+
+    /// Returns a decoder for UTF-16 with big-endian byte order.
+    pub fn big_endian() -> Utf16Decoder {
+        Utf16Decoder::new("UTF-16BE", true)
+    }
+
+    /// Returns a decoder for UTF-16 with unspecified byte order.
+    ///
+    /// Such decoder assumes big-endian byte order, as is required by the Unicode standard
+    /// for unmarked UTF-16 streams.
+    pub fn unmarked() -> Utf16Decoder {
+        Utf16Decoder::new("UTF-16", true)
+    }
+
+    fn new(name: &'static str, big_endian: bool) -> Utf16Decoder {
+        Utf16Decoder { name: name, big_endian: big_endian, partial: [0, ..4], partial_len: 0 }
+    }
+
+    fn unit_at(&self, i: uint) -> u32 {
+        let (first, second) = (self.partial[i] as u32, self.partial[i + 1] as u32);
+        if self.big_endian { (first << 8) | second } else { (second << 8) | first }
+    }
+}
+
+impl Decoder for Utf16Decoder {
+    fn name(&self) -> &'static str { self.name }
+
+    fn is_ascii_compatible(&self) -> bool { false }
+
+    fn read_char(&mut self, source: &mut Buffer) -> IoResult<char> {
+        if self.partial_len == 0 {
+            self.partial[0] = try!(source.read_byte());
+            self.partial_len = 1;
+        }
+        try!(read_partial(source, &mut self.partial, &mut self.partial_len, 2,
+                          "truncated UTF-16 code unit"));
+        let high = self.unit_at(0);
+        let code = match high {
+            0xD800...0xDBFF => {
+                try!(read_partial(source, &mut self.partial, &mut self.partial_len, 4,
+                                  "unpaired UTF-16 surrogate"));
+                let low = self.unit_at(2);
+                match low {
+                    0xDC00...0xDFFF => 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00),
+                    _ => {
+                        self.partial_len = 0;
+                        return Err(invalid_input("unpaired UTF-16 surrogate"));
+                    }
+                }
+            }
+            0xDC00...0xDFFF => {
+                self.partial_len = 0;
+                return Err(invalid_input("unpaired UTF-16 surrogate"));
+            }
+            _ => high
+        };
+        self.partial_len = 0;
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => Err(invalid_input("invalid UTF-16 character"))
+        }
+    }
+
+    #[inline]
+    fn encoded_len(&self, c: char) -> uint {
+        if c as u32 > 0xFFFF { 4 } else { 2 }
+    }
+}
+
+/// Reads bytes of a multi-byte character until `len` bytes are collected in `partial`.
+///
+/// On the end of file the collected bytes are dropped and `InvalidInput` error with
+/// the given description is returned. Other errors keep the bytes, so reading can be
+/// resumed by the next call.
+fn read_partial(source: &mut Buffer, partial: &mut [u8, ..4], partial_len: &mut uint,
+                len: uint, truncated: &'static str) -> IoResult<()> {
+    while *partial_len < len {
+        match source.read_byte() {
+            Ok(b) => {
+                partial[*partial_len] = b;
+                *partial_len += 1;
+            }
+            Err(ref e) if e.kind == io::EndOfFile => {
+                *partial_len = 0;
+                return Err(invalid_input(truncated));
+            }
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(())
+}
+
+/// Decodes ISO-8859-1 (Latin-1) input.
+pub struct Latin1Decoder;
+
+impl Decoder for Latin1Decoder {
+    fn name(&self) -> &'static str { "ISO-8859-1" }
+
+    fn is_ascii_compatible(&self) -> bool { true }
+
+    #[inline]
+    fn read_char(&mut self, source: &mut Buffer) -> IoResult<char> {
+        source.read_byte().map(|b| b as char)
+    }
+
+    #[inline]
+    fn encoded_len(&self, _: char) -> uint { 1 }
+}
+
+/// Characters of Windows-1252 encoding in `0x80`--`0x9F` range.
+///
+/// Bytes which are not defined by the encoding are mapped to corresponding C1 control
+/// characters, like it is done by web browsers.
+static WINDOWS_1252_HIGH: [char, ..32] = [
+    '€', '\x81',   '‚', 'ƒ', '„', '…', '†', '‡',
+    'ˆ', '‰', 'Š', '‹', 'Œ', '\x8D',   'Ž', '\x8F',
+    '\x90',   '‘', '’', '“', '”', '•', '–', '—',
+    '˜', '™', 'š', '›', 'œ', '\x9D',   'ž', 'Ÿ'
+];
+
+/// Decodes Windows-1252 input.
+pub struct Windows1252Decoder;
+
+impl Decoder for Windows1252Decoder {
+    fn name(&self) -> &'static str { "windows-1252" }
+
+    fn is_ascii_compatible(&self) -> bool { true }
+
+    fn read_char(&mut self, source: &mut Buffer) -> IoResult<char> {
+        source.read_byte().map(|b| match b {
+            0x80...0x9F => WINDOWS_1252_HIGH[(b - 0x80) as uint],
+            _ => b as char
+        })
+    }
+
+    #[inline]
+    fn encoded_len(&self, _: char) -> uint { 1 }
+}
+
+/// Returns a decoder for one of the encodings supported out of the box.
+///
+/// Recognized labels (case-insensitive) are `UTF-8`, `UTF-16`, `UTF-16LE`, `UTF-16BE`,
+/// `ISO-8859-1` with its aliases `latin1` and `l1`, `US-ASCII` (decoded as UTF-8)
+/// and `windows-1252`/`cp1252`. This is the default value of `ParserConfig::decoder_factory`.
+pub fn default_decoder_factory(label: &str) -> Option<Box<Decoder + 'static>> {
+    let label = label.to_ascii_lower();
+    match label.as_slice() {
+        "utf-8" | "utf8" | "us-ascii" | "ascii" =>
+            Some(box Utf8Decoder::new() as Box<Decoder>),
+        "utf-16" =>
+            Some(box Utf16Decoder::unmarked() as Box<Decoder>),
+        "utf-16le" =>
+            Some(box Utf16Decoder::little_endian() as Box<Decoder>),
+        "utf-16be" =>
+            Some(box Utf16Decoder::big_endian() as Box<Decoder>),
+        "iso-8859-1" | "iso_8859-1" | "iso8859-1" | "latin1" | "l1" =>
+            Some(box Latin1Decoder as Box<Decoder>),
+        "windows-1252" | "cp1252" =>
+            Some(box Windows1252Decoder as Box<Decoder>),
+        _ => None
+    }
+}
+
+/// Result of inspecting the beginning of a stream.
+pub struct Detection {
+    /// Decoder which should be used to read the stream.
+    pub decoder: Box<Decoder + 'static>,
+
+    /// Whether the detection was unambiguous, that is, based on a byte order mark or
+    /// on a pattern which could be produced only by the detected encoding.
+    ///
+    /// When this flag is set, the encoding declared in the document must agree with
+    /// the detected one.
+    pub authoritative: bool,
+
+    /// Length of the byte order mark which was consumed from the stream, in bytes.
+    pub bom_length: uint
+}
+
+/// Inspects the first bytes of the buffer and selects a decoder for them.
+///
+/// A byte order mark, if present, is consumed. Otherwise nothing is read from the buffer.
+/// If the encoding cannot be recognized, UTF-8 is assumed.
+pub fn detect(source: &mut Buffer) -> IoResult<Detection> {
+    let (decoder, authoritative, bom_len) = {
+        let bytes = match source.fill_buf() {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind == io::EndOfFile => [].as_slice(),
+            Err(e) => return Err(e)
+        };
+        match bytes {
+            [0xEF, 0xBB, 0xBF, ..] =>
+                (box Utf8Decoder::new() as Box<Decoder>, true, 3),
+            [0xFE, 0xFF, ..] =>
+                (box Utf16Decoder::big_endian() as Box<Decoder>, true, 2),
+            [0xFF, 0xFE, ..] =>
+                (box Utf16Decoder::little_endian() as Box<Decoder>, true, 2),
+            [0x00, 0x3C, 0x00, 0x3F, ..] =>
+                (box Utf16Decoder::big_endian() as Box<Decoder>, true, 0),
+            [0x3C, 0x00, 0x3F, 0x00, ..] =>
+                (box Utf16Decoder::little_endian() as Box<Decoder>, true, 0),
+            _ =>
+                (box Utf8Decoder::new() as Box<Decoder>, false, 0)
+        }
+    };
+    source.consume(bom_len);
+    Ok(Detection { decoder: decoder, authoritative: authoritative, bom_length: bom_len })
+}
+
+/// Checks the encoding declared in the document against the detected one.
+///
+/// Returns `Ok(Some(decoder))` if the parser should switch to a new decoder, `Ok(None)`
+/// if the current decoder should be kept, and an error kind if the declaration
+/// is unsupported or conflicts with the detected encoding.
+pub fn resolve_declared(detected: &Detection, declared: &str,
+                        factory: DecoderFactory) -> Result<Option<Box<Decoder + 'static>>, ErrorKind> {
+    let decoder = match factory(declared) {
+        Some(decoder) => decoder,
+        None => return Err(UnsupportedEncoding(declared.to_string()))
+    };
+    let current = detected.decoder.name();
+
+    if detected.authoritative {
+        if decoder.name() == current ||
+           decoder.name() == "UTF-16" && current.starts_with("UTF-16") {
+            Ok(None)
+        } else {
+            Err(EncodingConflict { declared: declared.to_string(), detected: current.to_string() })
+        }
+    } else if !decoder.is_ascii_compatible() {
+        Err(EncodingConflict { declared: declared.to_string(), detected: current.to_string() })
+    } else if decoder.name() == current {
+        Ok(None)
+    } else {
+        Ok(Some(decoder))
+    }
+}
+
+#[inline]
+fn invalid_input(desc: &'static str) -> IoError {
+    IoError { kind: io::InvalidInput, desc: desc, detail: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, EndOfFile, InvalidInput};
+
+    use common::{UnsupportedEncoding, EncodingConflict};
+
+    use super::{Decoder, Utf8Decoder, Utf16Decoder, Latin1Decoder, Windows1252Decoder,
+                detect, resolve_declared, default_decoder_factory};
+
+    fn decode_all(d: &mut Decoder, bytes: &[u8]) -> String {
+        let mut r = BufReader::new(bytes);
+        let mut result = String::new();
+        loop {
+            match d.read_char(&mut r) {
+                Ok(c) => result.push(c),
+                Err(ref e) if e.kind == EndOfFile => break,
+                Err(e) => panic!("Unexpected error: {}", e)
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn utf16_decoding() {
+        let le = [0x3C, 0x00, 0x61, 0x00, 0x3D, 0xD8, 0x00, 0xDE];
+        assert_eq!(decode_all(&mut Utf16Decoder::little_endian(), le).as_slice(), "<a\U0001F600");
+
+        let be = [0x00, 0x3C, 0x00, 0x61, 0xD8, 0x3D, 0xDE, 0x00];
+        assert_eq!(decode_all(&mut Utf16Decoder::big_endian(), be).as_slice(), "<a\U0001F600");
+    }
+
+    #[test]
+    fn utf8_decoding_errors() {
+        assert_eq!(decode_all(&mut Utf8Decoder::new(), [0x61, 0xC3, 0xA9]).as_slice(), "a\xE9");
+
+        let mut d = Utf8Decoder::new();
+        let mut r = BufReader::new([0x61, 0xC3]);
+        assert_eq!(d.read_char(&mut r), Ok('a'));
+        assert_eq!(d.read_char(&mut r).unwrap_err().kind, InvalidInput);
+
+        let mut r = BufReader::new([0xFF, 0x61]);
+        assert_eq!(d.read_char(&mut r).unwrap_err().kind, InvalidInput);
+        assert_eq!(d.read_char(&mut r), Ok('a'));
+    }
+
+    #[test]
+    fn single_byte_decoding() {
+        let bytes = [0x61, 0xE9, 0x80, 0x93];
+        assert_eq!(decode_all(&mut Latin1Decoder, bytes).as_slice(), "a\xE9\x80\x93");
+        assert_eq!(decode_all(&mut Windows1252Decoder, bytes).as_slice(), "a\xE9€“");
+    }
+
+    #[test]
+    fn bom_detection() {
+        macro_rules! check(
+            ($bytes:expr -> $name:expr, $auth:expr, $rest:expr) => ({
+                let bytes: &[u8] = $bytes;
+                let mut r = BufReader::new(bytes);
+                let d = detect(&mut r).unwrap();
+                assert_eq!(d.decoder.name(), $name);
+                assert_eq!(d.authoritative, $auth);
+                assert_eq!(d.bom_length, bytes.len() - $rest);
+                assert_eq!(r.read_to_end().unwrap().len(), $rest);
+            })
+        )
+        check!([0xEF, 0xBB, 0xBF, 0x3C]       -> "UTF-8", true, 1);
+        check!([0xFF, 0xFE, 0x3C, 0x00]       -> "UTF-16LE", true, 2);
+        check!([0xFE, 0xFF, 0x00, 0x3C]       -> "UTF-16BE", true, 2);
+        check!([0x3C, 0x00, 0x3F, 0x00]       -> "UTF-16LE", true, 4);
+        check!([0x3C, 0x3F, 0x78, 0x6D]       -> "UTF-8", false, 4);
+        check!([]                             -> "UTF-8", false, 0);
+    }
+
+    #[test]
+    fn declared_encoding_resolution() {
+        let mut r = BufReader::new([0x3C, 0x3F]);
+        let d = detect(&mut r).unwrap();
+        assert!(resolve_declared(&d, "utf-8", default_decoder_factory).unwrap().is_none());
+        assert_eq!(
+            resolve_declared(&d, "ISO-8859-1", default_decoder_factory).unwrap().unwrap().name(),
+            "ISO-8859-1"
+        );
+        assert!(resolve_declared(&d, "UTF-16", default_decoder_factory).is_err());
+        assert_eq!(
+            resolve_declared(&d, "KOI8-R", default_decoder_factory).unwrap_err(),
+            UnsupportedEncoding("KOI8-R".to_string())
+        );
+
+        let mut r = BufReader::new([0xFF, 0xFE, 0x3C, 0x00]);
+        let d = detect(&mut r).unwrap();
+        assert!(resolve_declared(&d, "UTF-16", default_decoder_factory).unwrap().is_none());
+        assert!(resolve_declared(&d, "UTF-16LE", default_decoder_factory).unwrap().is_none());
+        assert_eq!(
+            resolve_declared(&d, "UTF-8", default_decoder_factory).unwrap_err(),
+            EncodingConflict { declared: "UTF-8".to_string(), detected: "UTF-16LE".to_string() }
+        );
+    }
+}
@@ -6,6 +6,7 @@ use common;
 use common::{Name, HasPosition, Attribute, XmlVersion};
 use common::Error as CommonError;
 use namespace::Namespace;
+use reader::dtd::MarkupDeclaration;
 
 /// An element of an XML input stream.
 ///
@@ -26,8 +27,9 @@ pub enum XmlEvent {
         /// XML document encoding.
         ///
         /// If XML declaration is not present or does not contain `encoding` attribute,
-        /// defaults to `"UTF-8"`. This field is currently used for no other purpose than
-        /// informational.
+        /// contains the name of the encoding detected from the beginning of the stream,
+        /// which is `"UTF-8"` unless a byte order mark says otherwise. The declared
+        /// encoding is used to decode the rest of the document.
         pub encoding: String,
 
         /// XML standalone declaration.
@@ -38,6 +40,25 @@ pub enum XmlEvent {
         pub standalone: Option<bool>
     },
 
+    /// Denotes a document type declaration.
+    ///
+    /// This event is emitted after parsing `<!DOCTYPE>` declaration. It contains the root element
+    /// name, external identifiers and the declarations from the internal subset, if there is one.
+    /// No validation is performed against these declarations.
+    Doctype {
+        /// Name of the document root element.
+        pub name: String,
+
+        /// Public identifier of the external subset.
+        pub public_id: Option<String>,
+
+        /// System identifier of the external subset.
+        pub system_id: Option<String>,
+
+        /// Markup declarations from the internal subset, in document order.
+        pub declarations: Vec<MarkupDeclaration>
+    },
+
     /// Denotes to the end of the document stream.
     ///
     /// This event is always emitted after any other event (except `Error`). After it
@@ -66,7 +87,7 @@ pub enum XmlEvent {
 
         /// A list of attributes associated with the element.
         ///
-        /// Currently attributes are not checked for duplicates (TODO)
+        /// Attribute names are unique, even after namespace prefixes are resolved.
         pub attributes: Vec<Attribute>,
 
         /// Contents of the namespace mapping at this point of the document.
@@ -128,6 +149,8 @@ impl fmt::Show for XmlEvent {
                 write!(f, "StartDocument({}, {}, {})", version, *encoding, *standalone),
             EndDocument =>
                 write!(f, "EndDocument"),
+            Doctype { ref name, ref public_id, ref system_id, ref declarations } =>
+                write!(f, "Doctype({}, {}, {}, {})", name, public_id, system_id, declarations),
             ProcessingInstruction { ref name, ref data } =>
                 write!(f, "ProcessingInstruction({}{})", *name, match *data {
                     Some(ref data) => format!(", {}", data),
@@ -179,7 +202,7 @@ impl XmlEvent {
                     namespace: namespace
                 }),
             EndElement { ref name } =>
-                Some(::writer::events::EndElement { name: name }),
+                Some(::writer::events::EndElement { name: Some(name) }),
             Comment(ref data) => Some(::writer::events::Comment(data.as_slice())),
             CData(ref data) => Some(::writer::events::CData(data.as_slice())),
             Characters(ref data) => Some(::writer::events::Characters(data.as_slice())),
@@ -4,8 +4,17 @@
 
 use std::mem;
 use std::fmt;
-
-use common::{Error, HasPosition, is_whitespace_char, is_name_char};
+use std::io;
+use std::io::{IoError, IoResult};
+use std::str;
+
+use common;
+use common::{Error, HasPosition, XmlVersion, TextPosition, Span, is_whitespace_char, is_name_char,
+             is_valid_literal_char};
+use common::{UnexpectedToken, UnexpectedEof, InvalidCharacter, UnsupportedEncoding, EncodingConflict,
+             DecodingError, InputError};
+use reader::encoding;
+use reader::encoding::{Detection, DecoderFactory};
 
 /// `Token` represents a single lexeme of an XML document. These lexemes
 /// are used to perform actual parsing.
@@ -35,6 +44,12 @@ pub enum Token {
     Character(char),
     /// Whitespace character.
     Whitespace(char),
+    /// A run of characters scanned in bulk by `PullLexer::scan_text()`.
+    ///
+    /// The characters are appended directly to the buffer given to `scan_text()`, unless they
+    /// appear in the document verbatim and the caller does not need them; the token carries
+    /// the number of appended bytes and whether all of the characters are whitespace.
+    Text(uint, bool),
     /// `=`
     EqualsSign,
     /// `'`
@@ -55,26 +70,9 @@ impl fmt::Show for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Chunk(s)                       => write!(f, "{}", s),
+            Text(len, _)                   => write!(f, "<{} bytes of text>", len),
             Character(c) | Whitespace(c)   => write!(f, "{}", c),
-            other => write!(f, "{}", match other {
-                OpeningTagStart            => "<",
-                ProcessingInstructionStart => "<?",
-                DoctypeStart               => "<!DOCTYPE",
-                ClosingTagStart            => "</",
-                CommentStart               => "<!--",
-                CDataStart                 => "<![CDATA[",
-                TagEnd                     => ">",
-                EmptyTagEnd                => "/>",
-                ProcessingInstructionEnd   => "?>",
-                CommentEnd                 => "-->",
-                CDataEnd                   => "]]>",
-                ReferenceStart             => "&",
-                ReferenceEnd               => ";",
-                EqualsSign                 => "=",
-                SingleQuote                => "'",
-                DoubleQuote                => "\"",
-                _                          => unreachable!()
-            })
+            _                              => write!(f, "{}", self.as_static_str().unwrap())
         }
     }
 }
@@ -107,7 +105,7 @@ impl Token {
     #[inline]
     pub fn contains_char_data(&self) -> bool {
         match *self {
-            Whitespace(_) | Chunk(_) | Character(_) |
+            Whitespace(_) | Chunk(_) | Character(_) | Text(..) |
             TagEnd | EqualsSign | DoubleQuote | SingleQuote => true,
             _ => false
         }
@@ -123,6 +121,27 @@ impl Token {
     }
 }
 
+/// Kind of text which the lexer may scan in bulk, returning its characters as a single
+/// `Text` token instead of a token per character.
+///
+/// The mode is chosen by the parser according to its state. A run stops before any character
+/// which may start a token significant in that state, so the parser receives the same
+/// characters in the same order whether the text is scanned in bulk or not.
+#[deriving(Clone, PartialEq)]
+pub enum TextMode {
+    /// Characters are returned one by one.
+    NoBulkText,
+    /// Character data inside an element.
+    ContentText,
+    /// Attribute value delimited by the given quote character; `None` inside entity
+    /// replacement text, where quotes do not terminate the value.
+    AttributeValueText(Option<char>),
+    /// Comment body.
+    CommentText,
+    /// Contents of a CDATA section.
+    CDataText
+}
+
 enum State {
     /// Triggered on '<'
     TagOpened,
@@ -193,13 +212,58 @@ macro_rules! dispatch_on_enum_state(
 /// When it is not set, errors will be reported as `Err` objects with a string message.
 /// By default this flag is not set. Use `enable_errors` and `disable_errors` methods
 /// to toggle the behavior.
+///
+/// Characters are read from the buffer through a `Decoder`, which is selected upon
+/// the first call to `next_token` by inspecting the beginning of the stream. It can
+/// be replaced later with `set_declared_encoding` method.
+///
+/// Replacement text of general entities can be inserted into the input with `push_entity`
+/// method; it is read before any further characters from the buffer. Characters of
+/// replacement text do not affect the current position.
+///
+/// Line endings are normalized as required by XML specification, [section 2.11][1]: `\r\n`
+/// and `\r` are converted to `\n`. For XML 1.1 documents (see `set_version` method) `\r\x85`,
+/// `\x85` and `\u2028` are converted to `\n` too.
+///
+/// Every character read from the buffer is checked against `Char` production of the document
+/// XML version; invalid characters are always reported as errors.
+///
+/// Runs of plain characters can be scanned in bulk with `scan_text` method, which appends them
+/// to a buffer of the caller. UTF-8 data is scanned directly in the buffer of the reader;
+/// other encodings and entity replacement text are scanned character by character, but
+/// without producing a token for every character.
+///
+/// The lexer keeps track of whether the last token appears in the input verbatim, see
+/// `token_verbatim` method, so the text of such tokens can be taken from the input itself.
+///
+/// [1]: http://www.w3.org/TR/2006/REC-xml11-20060816/#sec-line-ends
 pub struct PullLexer {
     row: uint,
     col: uint,
     temp_char: Option<char>,
     st: State,
     skip_errors: bool,
-    eof_handled: bool
+    eof_handled: bool,
+    detection: Option<Detection>,
+    entities: Vec<EntityInput>,
+    input_size: uint,
+    offset: uint,
+    char_offset: uint,
+    char_start: TextPosition,
+    token_start: TextPosition,
+    version: XmlVersion,
+    after_cr: bool,
+    interrupted: bool,
+    peeked: Option<IoResult<(char, uint)>>,
+    char_verbatim: bool,
+    token_verbatim: bool
+}
+
+/// Replacement text of an entity which is being read.
+struct EntityInput {
+    name: String,
+    chars: Vec<char>,
+    pos: uint
 }
 
 /// Returns a new lexer with default state.
@@ -210,7 +274,20 @@ pub fn new() -> PullLexer {
         temp_char: None,
         st: Normal,
         skip_errors: false,
-        eof_handled: false
+        eof_handled: false,
+        detection: None,
+        entities: Vec::new(),
+        input_size: 0,
+        offset: 0,
+        char_offset: 0,
+        char_start: TextPosition::new(),
+        token_start: TextPosition::new(),
+        version: common::Version10,
+        after_cr: false,
+        interrupted: false,
+        peeked: None,
+        char_verbatim: false,
+        token_verbatim: false
     }
 }
 
@@ -235,36 +312,273 @@ impl PullLexer {
     #[inline]
     pub fn disable_errors(&mut self) { self.skip_errors = true; }
 
+    /// Sets XML version of the document, which affects end-of-line handling.
+    ///
+    /// By default XML 1.0 rules are used.
+    #[inline]
+    pub fn set_version(&mut self, version: XmlVersion) { self.version = version; }
+
+    /// Returns XML version of the document set by `set_version` method.
+    #[inline]
+    pub fn version(&self) -> XmlVersion { self.version }
+
+    /// Returns canonical name of the encoding which is used to decode the stream,
+    /// if it has already been detected.
+    #[inline]
+    pub fn encoding(&self) -> Option<&'static str> {
+        self.detection.as_ref().map(|d| d.decoder.name())
+    }
+
+    /// Applies the encoding declared in the XML declaration.
+    ///
+    /// The lexer switches to a decoder for the declared encoding if it differs from the
+    /// detected one. Returns an error if the declared encoding is unsupported or if it
+    /// conflicts with the encoding detected from a byte order mark.
+    ///
+    /// This method should be called only right after the XML declaration is read,
+    /// so there are no characters decoded in advance.
+    pub fn set_declared_encoding(&mut self, label: &str, factory: DecoderFactory) -> Result<(), Error> {
+        let result = match self.detection {
+            Some(ref mut detection) => match encoding::resolve_declared(detection, label, factory) {
+                Ok(Some(decoder)) => {
+                    detection.decoder = decoder;
+                    Ok(())
+                }
+                Ok(None) => Ok(()),
+                Err(kind) => Err(kind)
+            },
+            None => Ok(())
+        };
+        result.map_err(|kind| {
+            let msg = match kind {
+                UnsupportedEncoding(ref label) => format!("Unsupported encoding: {}", label),
+                EncodingConflict { ref declared, ref detected } =>
+                    format!("Declared encoding {} conflicts with detected encoding {}", declared, detected),
+                ref other => format!("Invalid encoding declaration: {}", other)
+            };
+            Error::new(self, kind, msg)
+        })
+    }
+
+    /// Inserts replacement text of an entity into the input.
+    ///
+    /// The text will be tokenized before any other characters from the buffer. The entity
+    /// is considered open until all of its text and all characters of the entities pushed
+    /// while reading it have been read.
+    ///
+    /// This method should be called only right after `ReferenceEnd` token is returned,
+    /// so there are no characters read in advance.
+    pub fn push_entity(&mut self, name: String, text: &str) {
+        self.entities.push(EntityInput {
+            name: name,
+            chars: text.chars().collect(),
+            pos: 0
+        });
+    }
+
+    /// Checks whether replacement text of the given entity is being read now.
+    pub fn is_entity_open(&self, name: &str) -> bool {
+        self.entities.iter().any(|e| e.name.as_slice() == name)
+    }
+
+    /// Returns the number of nested entities whose replacement text is being read.
+    #[inline]
+    pub fn entity_depth(&self) -> uint {
+        self.entities.len()
+    }
+
+    /// Returns the size of characters read from the buffer so far, in bytes of UTF-8 text.
+    ///
+    /// Characters of entity replacement text are not counted.
+    #[inline]
+    pub fn input_size(&self) -> uint {
+        self.input_size
+    }
+
+    /// Returns the number of bytes consumed from the buffer so far, including a byte order mark.
+    #[inline]
+    pub fn offset(&self) -> uint {
+        self.offset
+    }
+
+    /// Returns the fragment of the document occupied by the last returned token.
+    ///
+    /// Tokens read from entity replacement text are positioned at the end of the
+    /// entity reference.
+    pub fn token_span(&self) -> Span {
+        // a character read in advance does not belong to the token
+        let end = if self.temp_char.is_some() {
+            self.char_start.clone()
+        } else {
+            TextPosition { row: self.row, col: self.col, offset: self.offset }
+        };
+        Span { start: self.token_start.clone(), end: end }
+    }
+
+    /// Checks whether the last returned token appears in the input verbatim, so its text is
+    /// exactly the part of the input in its span.
+    ///
+    /// This is not the case for tokens read from entity replacement text, tokens containing
+    /// normalized line endings, runs of attribute value text with normalized whitespace, and
+    /// any tokens of documents in encodings other than UTF-8.
+    #[inline]
+    pub fn token_verbatim(&self) -> bool {
+        self.token_verbatim && self.encoding() == Some("UTF-8")
+    }
+
+    fn read_char<B: Buffer>(&mut self, b: &mut B) -> Result<Option<char>, Error> {
+        // Entities are popped only when the next character is requested, so the entity
+        // containing the last returned character is still considered open
+        loop {
+            match self.entities.last_mut() {
+                Some(e) if e.pos < e.chars.len() => {
+                    e.pos += 1;
+                    self.char_verbatim = false;
+                    return Ok(Some(e.chars[e.pos - 1]));
+                }
+                Some(_) => {}
+                None => break
+            }
+            self.entities.pop();
+        }
+
+        loop {
+            let start = self.offset;
+            let c = match try!(self.decode_char(b)) {
+                Some(c) => c,
+                None => return Ok(None)
+            };
+            self.char_offset = start;
+            self.char_verbatim = true;
+            let after_cr = mem::replace(&mut self.after_cr, false);
+            match c {
+                // the second character of \r\n or \r\x85 pair has already been returned as \n
+                '\n' if after_cr => {}
+                '\x85' if after_cr && self.version == common::Version11 => {}
+
+                '\r' => {
+                    self.after_cr = true;
+                    self.char_verbatim = false;
+                    return Ok(Some('\n'));
+                }
+                '\x85' | '\u2028' if self.version == common::Version11 => {
+                    self.char_verbatim = false;
+                    return Ok(Some('\n'));
+                }
+                _ => return Ok(Some(c))
+            }
+        }
+    }
+
+    fn decode_char<B: Buffer>(&mut self, b: &mut B) -> Result<Option<char>, Error> {
+        if self.detection.is_none() {
+            match encoding::detect(b) {
+                Ok(detection) => {
+                    self.offset = detection.bom_length;
+                    self.detection = Some(detection);
+                }
+                Err(e) => return Err(self.read_error(e))
+            }
+        }
+        let result = match mem::replace(&mut self.peeked, None) {
+            Some(result) => result,
+            None => self.decode_next(b)
+        };
+        match result {
+            Ok((c, len)) => {
+                self.interrupted = false;
+                self.input_size += c.len_utf8_bytes();
+                self.offset += len;
+                Ok(Some(c))
+            }
+            Err(ref e) if e.kind == io::EndOfFile => Ok(None),
+            Err(e) => {
+                // the decoder may keep a part of the character which was being read,
+                // so it has to be asked for the next character before anything else
+                self.interrupted = e.kind != io::InvalidInput;
+                Err(self.read_error(e))
+            }
+        }
+    }
+
+    /// Reads the next character with the current decoder, returning it together with
+    /// the number of bytes it occupied in the input.
+    fn decode_next<B: Buffer>(&mut self, b: &mut B) -> IoResult<(char, uint)> {
+        let decoder = &mut self.detection.as_mut().unwrap().decoder;
+        decoder.read_char(b).map(|c| (c, decoder.encoded_len(c)))
+    }
+
+    /// Returns the next character of the buffer without consuming it; the character is
+    /// consumed by the next `decode_char()` call.
+    ///
+    /// Returns `None` on errors and at the end of the stream; they are kept for
+    /// `decode_char()` too.
+    fn peek_char<B: Buffer>(&mut self, b: &mut B) -> Option<char> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.decode_next(b));
+        }
+        match self.peeked {
+            Some(Ok((c, _))) => Some(c),
+            _ => None
+        }
+    }
+
+    /// Converts an error returned by the decoder into a parser error.
+    ///
+    /// `InvalidInput` errors are produced by decoders on malformed input; all other errors
+    /// come from the buffer itself.
+    fn read_error(&self, e: IoError) -> Error {
+        match e.kind {
+            io::InvalidInput => {
+                let encoding = self.encoding().unwrap_or("UTF-8");
+                Error::new(self, DecodingError { encoding: encoding, offset: self.offset },
+                           format!("Invalid {} byte sequence at offset {}", encoding, self.offset))
+            }
+            _ => {
+                let msg = format!("I/O error: {}", e);
+                Error::new(self, InputError(e), msg)
+            }
+        }
+    }
+
     /// Tries to read next token from the buffer.
     ///
     /// It is possible to pass different instaces of `Buffer` each time
     /// this method is called, but the resulting behavior is undefined.
     ///
     /// Returns `None` when logical end of stream is encountered, that is,
-    /// after `b.read_char()` returns `None` and the current state is
-    /// is exhausted.
+    /// after the decoder reports the end of the buffer and the current state
+    /// is exhausted. Errors of the buffer and malformed byte sequences are
+    /// returned as `Some(Err(..))`; the position of the lexer is not changed
+    /// by them, so the call can be repeated if the buffer error was temporary.
     pub fn next_token<B: Buffer>(&mut self, b: &mut B) -> Option<LexResult> {
         // Already reached end of buffer
         if self.eof_handled {
             return None;
         }
 
-        // Check if we have saved a char for ourselves
+        // Check if we have saved a char for ourselves; it has already been
+        // taken into account in the current position
         if self.temp_char.is_some() {
             let c = mem::replace(&mut self.temp_char, None).unwrap();
-            match self.read_next_token(c) {
+            match self.dispatch_char(c) {
                 Some(t) => return Some(t),
                 None => {}  // continue
             }
         }
 
         // Read more data from the buffer
-        for_each!(c in b.read_char().ok() {
+        loop {
+            let c = match self.read_char(b) {
+                Ok(Some(c)) => c,
+                Ok(None) => break,
+                Err(e) => return Some(Err(e))
+            };
             match self.read_next_token(c) {
                 Some(t) => return Some(t),
                 None    => {}  // continue
             }
-        })
+        }
 
         // Handle end of stream
         self.eof_handled = true;
@@ -272,7 +586,7 @@ impl PullLexer {
             TagOpened | CommentOrCDataOrDoctypeStarted |
             CommentStarted | CDataStarted(_)| DoctypeStarted(_) |
             CommentClosing(Second)  =>
-                Some(Err(self.error("Unexpected end of stream"))),
+                Some(Err(self.eof_error())),
             ProcessingInstructionClosing =>
                 Some(Ok(Character('?'))),
             EmptyTagClosing =>
@@ -288,24 +602,259 @@ impl PullLexer {
         }
     }
 
+    /// Reads a run of plain characters in bulk and appends it to `target`.
+    ///
+    /// The run stops before any character which may start a token significant in the given
+    /// mode, so it consists of the same characters which `next_token` would return, normalized
+    /// in the same way: line endings are converted to `\n` and, in attribute values, whitespace
+    /// characters are replaced with spaces. Invalid characters, malformed input and errors of
+    /// the buffer end the run too; they are reported by the following `next_token` call.
+    ///
+    /// When `copy_verbatim` is not set and the run appears in the input verbatim (see
+    /// `token_verbatim`), it is not appended; its text can be found through the token span.
+    ///
+    /// Returns `Text` token if at least one character has been read and `None` otherwise,
+    /// in which case `next_token` should be called.
+    pub fn scan_text<B: Buffer>(&mut self, b: &mut B, mode: TextMode, target: &mut String,
+                                copy_verbatim: bool) -> Option<Token> {
+        match self.st {
+            Normal => {}
+            _ => return None
+        }
+        if mode == NoBulkText || self.eof_handled || self.temp_char.is_some() || self.detection.is_none() {
+            return None;
+        }
+
+        let start = target.len();
+        let offset = self.offset;
+        let mut whitespace = true;
+        let verbatim = if !self.entities.is_empty() {
+            // characters of replacement text do not change the position
+            self.token_start = self.char_start.clone();
+            self.scan_entity_text(mode, target, &mut whitespace);
+            false
+        } else {
+            self.token_start = TextPosition { row: self.row, col: self.col, offset: self.offset };
+            // the decoder may hold a part of a character after an error of the buffer
+            if self.encoding() == Some("UTF-8") && self.peeked.is_none() && !self.interrupted {
+                self.scan_utf8_text(b, mode, target, &mut whitespace, copy_verbatim)
+            } else {
+                self.scan_decoded_text(b, mode, target, &mut whitespace);
+                false
+            }
+        };
+
+        let len = target.len() - start;
+        let scanned = if verbatim { self.offset > offset } else { len > 0 };
+        if scanned {
+            self.token_verbatim = verbatim;
+            Some(Text(len, whitespace))
+        } else {
+            None
+        }
+    }
+
+    /// Scans replacement text of the innermost entity. The run ends with the text, because
+    /// the entity is closed only when the next character is requested.
+    fn scan_entity_text(&mut self, mode: TextMode, target: &mut String, whitespace: &mut bool) {
+        let e = self.entities.last_mut().unwrap();
+        while e.pos < e.chars.len() {
+            let c = e.chars[e.pos];
+            let next = if e.pos + 1 < e.chars.len() { Some(e.chars[e.pos + 1]) } else { None };
+            if is_text_end(c, next, mode) {
+                break;
+            }
+            push_text_char(target, c, mode, whitespace);
+            e.pos += 1;
+        }
+    }
+
+    /// Scans UTF-8 text in the buffer of the reader, copying runs of bytes to `target`.
+    ///
+    /// Returns `true` if no characters of the run have been normalized; such a run is copied
+    /// only when `copy_verbatim` is set.
+    fn scan_utf8_text<B: Buffer>(&mut self, b: &mut B, mode: TextMode, target: &mut String,
+                                 whitespace: &mut bool, copy_verbatim: bool) -> bool {
+        let version = self.version;
+        let in_attribute = match mode { AttributeValueText(_) => true, _ => false };
+        let (mut row, mut col, mut after_cr) = (self.row, self.col, self.after_cr);
+        let mut normalized = false;
+
+        let consumed = {
+            let buf = match b.fill_buf() {
+                Ok(buf) => buf,
+                Err(_) => return false  // will be handled when reading the next character
+            };
+
+            let mut i = 0;
+            let mut copied = 0;  // bytes before this index are already appended to the target
+            loop {
+                // skip plain characters, which are the most common ones
+                let run = i;
+                while i < buf.len() && is_plain_text_byte(buf[i]) {
+                    i += 1;
+                }
+                if i > run {
+                    col += i - run;
+                    after_cr = false;
+                    if *whitespace && buf.slice(run, i).iter().any(|&b| b != b' ') {
+                        *whitespace = false;
+                    }
+                }
+                if i == buf.len() {
+                    break;
+                }
+
+                // the length of the next character and the text replacing it, if it is not
+                // copied as is
+                let byte = buf[i];
+                let (width, replacement) = match byte {
+                    // the second character of \r\n pair has already been appended as \n
+                    b'\n' if after_cr => (1, Some("")),
+                    b'\r' => (1, Some(if in_attribute { " " } else { "\n" })),
+                    b'\t' | b'\n' => (1, if in_attribute { Some(" ") } else { None }),
+                    // invalid characters; 0x7F is restricted in XML 1.1
+                    0x00...0x1F | 0x7F => break,
+                    0x80...0xFF => {
+                        let width = str::utf8_char_width(byte);
+                        if width == 0 || i + width > buf.len() {
+                            break;
+                        }
+                        match str::from_utf8(buf.slice(i, i + width)).map(|s| s.char_at(0)) {
+                            // line endings of XML 1.1 are left to the usual path
+                            Some('\x85') | Some('\u2028') if version == common::Version11 => break,
+                            Some(c) if is_valid_literal_char(c, version) => (width, None),
+                            _ => break
+                        }
+                    }
+                    _ => {
+                        let next = if i + 1 < buf.len() { Some(buf[i + 1] as char) } else { None };
+                        if is_text_end(byte as char, next, mode) {
+                            break;
+                        }
+                        (1, None)
+                    }
+                };
+
+                match replacement {
+                    Some(s) => {
+                        target.push_str(str::from_utf8(buf.slice(copied, i)).unwrap());
+                        target.push_str(s);
+                        copied = i + width;
+                        normalized = true;
+                    }
+                    None => {}
+                }
+                match byte {
+                    b'\n' if after_cr => {}
+                    b'\r' | b'\n' => {
+                        row += 1;
+                        col = 0;
+                    }
+                    b'\t' | b' ' => col += 1,
+                    _ => {
+                        col += 1;
+                        *whitespace = false;
+                    }
+                }
+                after_cr = byte == b'\r';
+                i += width;
+            }
+            // all characters before `i` have been checked, so they are valid UTF-8
+            if normalized || copy_verbatim {
+                target.push_str(str::from_utf8(buf.slice(copied, i)).unwrap());
+            }
+            i
+        };
+        b.consume(consumed);
+
+        self.row = row;
+        self.col = col;
+        self.after_cr = after_cr;
+        self.offset += consumed;
+        self.input_size += consumed;
+        !normalized
+    }
+
+    /// Scans text with the current decoder, one character at a time.
+    ///
+    /// Characters are decoded in advance to see whether they end the run, so characters
+    /// which may be a part of a longer token are never included.
+    fn scan_decoded_text<B: Buffer>(&mut self, b: &mut B, mode: TextMode, target: &mut String,
+                                    whitespace: &mut bool) {
+        loop {
+            let c = match self.peek_char(b) {
+                Some(c) => c,
+                None => break  // errors are reported by the usual path
+            };
+            let skip = match c {
+                // the second character of \r\n or \r\x85 pair has already been appended as \n
+                '\n' => self.after_cr,
+                '\x85' => self.after_cr && self.version == common::Version11,
+                _ => false
+            };
+            if !skip && (!is_valid_literal_char(c, self.version) || is_text_end(c, None, mode)) {
+                break;
+            }
+            let _ = self.decode_char(b);  // consumes the character which has been peeked
+            self.after_cr = c == '\r';
+            if skip {
+                continue;
+            }
+
+            let c = match c {
+                '\r' => '\n',
+                '\x85' | '\u2028' if self.version == common::Version11 => '\n',
+                _ => c
+            };
+            push_text_char(target, c, mode, whitespace);
+            if c == '\n' {
+                self.row += 1;
+                self.col = 0;
+            } else {
+                self.col += 1;
+            }
+        }
+    }
+
     #[inline]
-    fn error(&self, msg: &str) -> Error {
-        Error::new(self, msg.to_string())
+    fn eof_error(&self) -> Error {
+        Error::new(self, UnexpectedEof, "Unexpected end of stream".to_string())
     }
 
     fn read_next_token(&mut self, c: char) -> LexStep {
-        if c == '\n' {
-            self.row += 1;
-            self.col = 0;
-        } else {
-            self.col += 1;
+        // Characters of replacement text do not change the position, and they
+        // were already checked when the entity declaration was read
+        if self.entities.is_empty() {
+            self.char_start = TextPosition { row: self.row, col: self.col, offset: self.char_offset };
+            if c == '\n' {
+                self.row += 1;
+                self.col = 0;
+            } else {
+                self.col += 1;
+            }
+
+            if !is_valid_literal_char(c, self.version) {
+                return Some(Err(Error::new_full(
+                    self.row, self.col - 1, InvalidCharacter(c),
+                    format!("Invalid character U+{:04X} for XML {}", c as u32, self.version)
+                )));
+            }
         }
 
         self.dispatch_char(c)
     }
 
-
     fn dispatch_char(&mut self, c: char) -> LexStep {
+        // a character read in the default state always starts a new token; a character read
+        // in advance may make a token non-verbatim, but it is then a part of the same text anyway
+        match self.st {
+            Normal => {
+                self.token_start = self.char_start.clone();
+                self.token_verbatim = self.char_verbatim;
+            }
+            _ => self.token_verbatim = self.token_verbatim && self.char_verbatim
+        }
         match self.st {
             Normal                         => self.normal(c),
             TagOpened                      => self.tag_opened(c),
@@ -346,6 +895,7 @@ impl PullLexer {
             Some(Err(
                 Error::new_full(
                     self.row, self.col-chunk.len()-1,
+                    UnexpectedToken { found: chunk.to_string(), expected: "markup", context: "markup" },
                     format!("Unexpected token {} before {}", chunk, c)
                 )
             ))
@@ -470,11 +1020,49 @@ impl PullLexer {
     }
 }
 
+/// Returns `true` for ASCII bytes which can be copied from the buffer in any text mode
+/// without further checks, that is, printable characters which never start a token
+/// significant inside text.
+#[inline]
+fn is_plain_text_byte(b: u8) -> bool {
+    match b {
+        b'<' | b'&' | b']' | b'-' | b'?' | b'/' | b'"' | b'\'' => false,
+        0x20...0x7E => true,
+        _ => false
+    }
+}
+
+/// Checks whether the character ends a run of text in the given mode.
+///
+/// Some tokens consist of several characters, so the next character is needed to decide;
+/// if it is not known yet, the run ends.
+fn is_text_end(c: char, next: Option<char>, mode: TextMode) -> bool {
+    match c {
+        '<' | '&' => match mode { ContentText | AttributeValueText(_) => true, _ => false },
+        ']' => match mode { ContentText | CDataText => next.map_or(true, |n| n == ']'), _ => false },
+        '-' => match mode { ContentText | CommentText => next.map_or(true, |n| n == '-'), _ => false },
+        '?' | '/' => match mode { ContentText => next.map_or(true, |n| n == '>'), _ => false },
+        _ => match mode { AttributeValueText(Some(q)) => c == q, _ => false }
+    }
+}
+
+/// Appends a character of a run of text; whitespace in attribute values is replaced with
+/// spaces, as it is done by the parser.
+#[inline]
+fn push_text_char(target: &mut String, c: char, mode: TextMode, whitespace: &mut bool) {
+    if is_whitespace_char(c) {
+        target.push(match mode { AttributeValueText(_) => ' ', _ => c });
+    } else {
+        *whitespace = false;
+        target.push(c);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::MemReader;
 
-    use common::{HasPosition};
+    use common::{HasPosition, Version11, TextPosition, Span};
 
     use super::{
         PullLexer,
@@ -490,13 +1078,17 @@ mod tests {
         Chunk,
         Character,
         Whitespace,
+        Text,
         CDataStart,
         CDataEnd,
         ReferenceStart,
         ReferenceEnd,
         SingleQuote,
         DoubleQuote,
-        EqualsSign
+        EqualsSign,
+        NoBulkText,
+        ContentText,
+        AttributeValueText
     };
 
     macro_rules! assert_oks(
@@ -670,6 +1262,143 @@ mod tests {
         assert_none!(for lex and buf)
     }
 
+    #[test]
+    fn token_spans() {
+        fn span(start: (uint, uint, uint), end: (uint, uint, uint)) -> Span {
+            let ((sr, sc, so), (er, ec, eo)) = (start, end);
+            Span {
+                start: TextPosition { row: sr, col: sc, offset: so },
+                end: TextPosition { row: er, col: ec, offset: eo }
+            }
+        }
+
+        let (mut lex, mut buf) = make_lex_and_buf("<a>\r\n\u00E9b/>");
+        assert_oks!(for lex and buf OpeningTagStart);
+        assert_eq!(lex.token_span(), span((0, 0, 0), (0, 1, 1)));
+        assert_oks!(for lex and buf Character('a'));
+        assert_eq!(lex.token_span(), span((0, 1, 1), (0, 2, 2)));
+        assert_oks!(for lex and buf TagEnd);
+        assert_eq!(lex.token_span(), span((0, 2, 2), (0, 3, 3)));
+        assert_oks!(for lex and buf Whitespace('\n'));
+        assert_eq!(lex.token_span(), span((0, 3, 3), (1, 0, 4)));
+        assert_oks!(for lex and buf Character('\u00E9'));
+        assert_eq!(lex.token_span(), span((1, 0, 5), (1, 1, 7)));
+        assert_oks!(for lex and buf Character('b'));
+        assert_eq!(lex.token_span(), span((1, 1, 7), (1, 2, 8)));
+        assert_oks!(for lex and buf EmptyTagEnd);
+        assert_eq!(lex.token_span(), span((1, 2, 8), (1, 4, 10)));
+    }
+
+    #[test]
+    fn bulk_text() {
+        let (mut lex, mut buf) = make_lex_and_buf("<a>some text\n with \u00E9]x-y--<b c='v\"-/>\t&amp;'/>");
+        let mut text = String::new();
+        assert_oks!(for lex and buf OpeningTagStart Character('a') TagEnd);
+
+        assert_eq!(lex.scan_text(&mut buf, ContentText, &mut text, true), Some(Text(22, false)));
+        assert_eq!(text.as_slice(), "some text\n with \u00E9]x-y");
+        assert_eq!(lex.token_span().start, TextPosition { row: 0, col: 3, offset: 3 });
+        assert_eq!(lex.token_span().end, TextPosition { row: 1, col: 11, offset: 25 });
+        assert_eq!((lex.row(), lex.col()), (1, 11));
+
+        // a possible start of `-->` is left to the usual path
+        assert_eq!(lex.scan_text(&mut buf, ContentText, &mut text, true), None);
+        assert_oks!(for lex and buf Chunk("--") OpeningTagStart);
+
+        assert_eq!(lex.scan_text(&mut buf, NoBulkText, &mut text, true), None);
+        assert_oks!(for lex and buf
+            Character('b') Whitespace(' ') Character('c') EqualsSign SingleQuote
+        );
+
+        let mut value = String::new();
+        assert_eq!(lex.scan_text(&mut buf, AttributeValueText(Some('\'')), &mut value, true), Some(Text(6, false)));
+        assert_eq!(value.as_slice(), "v\"-/> ");
+        assert_oks!(for lex and buf ReferenceStart);
+
+        // line endings are normalized inside runs
+        let (mut lex, mut buf) = make_lex_and_buf("<a>\r\nb\rc\r\n  </a>");
+        let mut text = String::new();
+        assert_oks!(for lex and buf OpeningTagStart Character('a') TagEnd);
+        assert_eq!(lex.scan_text(&mut buf, ContentText, &mut text, true), Some(Text(7, false)));
+        assert_eq!(text.as_slice(), "\nb\nc\n  ");
+        assert_eq!((lex.row(), lex.col()), (3, 2));
+        assert_oks!(for lex and buf ClosingTagStart);
+
+        let (mut lex, mut buf) = make_lex_and_buf("<a>\r\n \t</a>");
+        let mut text = String::new();
+        assert_oks!(for lex and buf OpeningTagStart Character('a') TagEnd);
+        assert_eq!(lex.scan_text(&mut buf, ContentText, &mut text, true), Some(Text(3, true)));
+
+        // verbatim runs are copied only on request, normalized ones always
+        let (mut lex, mut buf) = make_lex_and_buf("<a>text&amp;\r\n</a>");
+        let mut text = String::new();
+        assert_oks!(for lex and buf OpeningTagStart Character('a') TagEnd);
+        assert!(lex.token_verbatim());
+        assert_eq!(lex.scan_text(&mut buf, ContentText, &mut text, false), Some(Text(0, false)));
+        assert!(lex.token_verbatim());
+        assert_eq!((lex.token_span().start.offset, lex.token_span().end.offset), (3, 7));
+        assert_oks!(for lex and buf ReferenceStart Character('a') Character('m') Character('p') ReferenceEnd);
+        assert_eq!(lex.scan_text(&mut buf, ContentText, &mut text, false), Some(Text(1, true)));
+        assert!(!lex.token_verbatim());
+        assert_eq!(text.as_slice(), "\n");
+    }
+
+    #[test]
+    fn end_of_line_normalization() {
+        let (mut lex, mut buf) = make_lex_and_buf("a\r\nb\rc\n\r\rd\x85\u2028");
+
+        assert_oks!(for lex and buf
+            Character('a')
+            Whitespace('\n')
+            Character('b')
+            Whitespace('\n')
+            Character('c')
+            Whitespace('\n')
+            Whitespace('\n')
+            Whitespace('\n')
+            Character('d')
+            Character('\x85')
+            Character('\u2028')
+        )
+        assert_none!(for lex and buf);
+        assert_eq!(lex.row(), 5);
+        assert_eq!(lex.col(), 3);
+
+        let (mut lex, mut buf) = make_lex_and_buf("a\r\x85b\x85c\u2028");
+        lex.set_version(Version11);
+
+        assert_oks!(for lex and buf
+            Character('a')
+            Whitespace('\n')
+            Character('b')
+            Whitespace('\n')
+            Character('c')
+            Whitespace('\n')
+        )
+        assert_none!(for lex and buf);
+        assert_eq!(lex.row(), 3);
+        assert_eq!(lex.col(), 0);
+    }
+
+    #[test]
+    fn invalid_characters() {
+        let (mut lex, mut buf) = make_lex_and_buf("a\nbc\x01");
+        assert_oks!(for lex and buf
+            Character('a')
+            Whitespace('\n')
+            Character('b')
+            Character('c')
+        )
+        assert_err!(for lex and buf expect row 1 col 2, "Invalid character U+0001 for XML 1.0");
+
+        let (mut lex, mut buf) = make_lex_and_buf("\x7F");
+        assert_oks!(for lex and buf Character('\x7F'));
+
+        let (mut lex, mut buf) = make_lex_and_buf("\x7F");
+        lex.set_version(Version11);
+        assert_err!(for lex and buf expect row 0 col 0, "Invalid character U+007F for XML 1.1");
+    }
+
     #[test]
     fn end_of_stream_handling_ok() {
         macro_rules! eof_check(
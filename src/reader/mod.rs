@@ -6,15 +6,23 @@
 use std::io::Buffer;
 use std::io::{MemReader, BufReader};
 
+use common::Span;
+
 use self::parser::PullParser;
 use self::events::XmlEvent;
 
 pub use self::config::ParserConfig;
+pub use self::push::PushReader;
+pub use self::borrowed::BorrowedEventReader;
 
 mod lexer;
 mod parser;
 pub mod config;
 pub mod events;
+pub mod encoding;
+pub mod dtd;
+pub mod push;
+pub mod borrowed;
 
 /// Simple wrapper around an `std::io::Buffer` which provides pull-based XML parsing.
 pub struct EventReader<B> {
@@ -37,13 +45,35 @@ impl<B: Buffer> EventReader<B> {
 
     /// Pulls and returns next XML event from the stream.
     ///
-    /// If returned event is `xml::event::Error` or `xml::event::EndDocument`, then 
-    /// further calls to this method will return this event again.
+    /// If returned event is `xml::event::Error` or `xml::event::EndDocument`, then
+    /// further calls to this method will return this event again. The only exception
+    /// are errors of the underlying buffer (see `xml::common::InputError`): after them
+    /// the next call tries to read from the buffer again.
     #[inline]
     pub fn next(&mut self) -> XmlEvent { 
         self.parser.next(&mut self.source)
     }
 
+    /// Returns the fragment of the document occupied by the last event returned by `next()`.
+    ///
+    /// Events which are implied by the document structure, like `StartDocument` without
+    /// an XML declaration, have empty spans, and the `EndElement` event of an empty element
+    /// has the same span as its `StartElement` event.
+    #[inline]
+    pub fn last_event_span<'a>(&'a self) -> &'a Span {
+        self.parser.last_event_span()
+    }
+
+    /// Returns spans of attributes of the last event returned by `next()`, in the same
+    /// order as the attributes are listed in the event. Each span starts at the attribute
+    /// name and ends after the closing quote of its value.
+    ///
+    /// The slice is empty unless the last event was `StartElement`.
+    #[inline]
+    pub fn last_attribute_spans<'a>(&'a self) -> &'a [Span] {
+        self.parser.last_attribute_spans()
+    }
+
     /// Returns an iterator over XML events.
     ///
     /// When the next event is `xml::event::Error` or `xml::event::EndDocument`, then
@@ -77,9 +107,15 @@ impl<'a, B: Buffer> Iterator<XmlEvent> for Events<'a, B> {
 
 impl EventReader<MemReader> {
     /// Convenience method to create a reader from an owned string.
+    ///
+    /// The string is always decoded as UTF-8, regardless of the encoding declared
+    /// in the document.
     #[inline]
     pub fn new_from_string(source: String) -> EventReader<MemReader> {
-        EventReader::new_from_bytes(source.into_bytes())
+        EventReader::new_with_config(
+            MemReader::new(source.into_bytes()),
+            ParserConfig::new().ignore_encoding_declaration(true)
+        )
     }
 
     /// Convenience method to create a reader from an owned vector of bytes.
@@ -92,9 +128,15 @@ impl EventReader<MemReader> {
 
 impl<'r> EventReader<BufReader<'r>> {
     /// Convenience method to create a reader from a string slice.
+    ///
+    /// The string is always decoded as UTF-8, regardless of the encoding declared
+    /// in the document.
     #[inline]
     pub fn new_from_str_slice(source: &'r str) -> EventReader<BufReader<'r>> {
-        EventReader::new_from_bytes_slice(source.as_bytes())
+        EventReader::new_with_config(
+            BufReader::new(source.as_bytes()),
+            ParserConfig::new().ignore_encoding_declaration(true)
+        )
     }
 
     /// Convenience method to create a reader from a slice of bytes.
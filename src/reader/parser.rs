@@ -1,19 +1,36 @@
 //! Contains an implementation of pull-based XML parser.
 
+use std::cmp;
 use std::mem;
+use std::collections::{HashMap, HashSet};
+use std::collections::hashmap::{Occupied, Vacant};
 
 use common;
-use common::{Error, XmlVersion, Name, is_name_start_char, is_name_char, is_whitespace_char};
+use common::{Error, ErrorKind, HasPosition, XmlVersion, Name, TextPosition, Span, is_name_start_char, is_name_char, is_whitespace_char,
+             is_valid_referenced_char};
+use common::{UnexpectedToken, UnexpectedEof, InvalidName, InvalidDeclarationValue, InvalidProcessingInstruction,
+             MissingRootElement, UnclosedElement, MismatchedClosingTag, DuplicateAttribute, CharactersOutsideRoot,
+             UnboundPrefix, ReservedPrefix, InvalidNamespaceBinding, UndefinedEntity, RecursiveEntity,
+             UnparsedEntityReference, ExternalEntityReference, InvalidCharacterReference, InvalidEntityValue,
+             EntityExpansionLimit, DepthLimit, SizeLimit, RatioLimit, IoError};
 use namespace;
 use namespace::{NamespaceStack};
 
 use reader::events;
 use reader::events::XmlEvent;
 use reader::config::ParserConfig;
+use reader::dtd;
 use reader::lexer;
 use reader::lexer::{
     Token,
     PullLexer,
+    LexResult,
+    TextMode,
+    NoBulkText,
+    ContentText,
+    AttributeValueText,
+    CommentText,
+    CDataText,
     ProcessingInstructionStart,
     ProcessingInstructionEnd,
     DoctypeStart,
@@ -26,6 +43,7 @@ use reader::lexer::{
     Chunk,
     Character,
     Whitespace,
+    Text,
     CDataStart,
     CDataEnd,
     ReferenceStart,
@@ -39,8 +57,52 @@ static DEFAULT_VERSION: XmlVersion      = common::Version10;
 static DEFAULT_ENCODING: &'static str   = "UTF-8";
 static DEFAULT_STANDALONE: Option<bool> = None;
 
+/// Total size of entity replacement text after which expansion ratio limit is checked.
+static ENTITY_RATIO_CHECK_THRESHOLD: uint = 1024 * 1024;
+
 type ElementStack = Vec<Name>;
 
+/// A map from names of general entities declared in the DTD to their values.
+///
+/// Internal entities are stored with their replacement text.
+type EntityMap = HashMap<String, dtd::EntityValue>;
+
+/// A map from element names to declared types of their attributes.
+///
+/// Names are stored exactly as they are written in the DTD, that is, with prefixes.
+type AttributeTypeMap = HashMap<String, HashMap<String, dtd::AttributeType>>;
+
+/// A part of a string of an event, as reported by `PullParser::last_event_fragments()`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Fragment {
+    /// Bytes `[start, end)` of the input, which appear in the string verbatim.
+    SourceFragment(uint, uint),
+    /// Bytes `[start, end)` of the string in the event itself.
+    EventFragment(uint, uint)
+}
+
+impl Fragment {
+    /// Returns the length of the fragment in bytes.
+    #[inline]
+    pub fn len(&self) -> uint {
+        match *self {
+            SourceFragment(start, end) | EventFragment(start, end) => end - start
+        }
+    }
+
+    /// Checks whether the fragment is a part of the input.
+    #[inline]
+    pub fn is_source(&self) -> bool {
+        match *self {
+            SourceFragment(..) => true,
+            EventFragment(..) => false
+        }
+    }
+}
+
+/// Indices of fragments of a string in the list of fragments kept by the parser.
+type FragmentRange = (uint, uint);
+
 /// Pull-based XML parser.
 pub struct PullParser {
     config: ParserConfig,
@@ -53,8 +115,27 @@ pub struct PullParser {
     finish_event: Option<XmlEvent>,
     next_event: Option<XmlEvent>,
     est: ElementStack,
+    entities: EntityMap,
+    expanded_size: uint,
+    attribute_types: AttributeTypeMap,
+
+    token_span: Span,
+    text_start: TextPosition,
+    markup_start: TextPosition,
+    event_span: Span,
+    attribute_spans: Vec<Span>,
+
+    record_fragments: bool,
+    keep_fragments: bool,
+    buf_fragments: Vec<Fragment>,
+    fragments: Vec<Fragment>,
+    taken: FragmentRange,
+    pending_strings: Vec<FragmentRange>,
+    next_event_strings: Vec<FragmentRange>,
+    event_strings: Vec<FragmentRange>,
 
     encountered_element: bool,
+    encountered_doctype: bool,
     parsed_declaration: bool,
     inside_whitespace: bool,
     read_prefix_separator: bool,
@@ -73,20 +154,49 @@ impl PullParser {
 
             data: MarkupData {
                 name: String::new(),
+                name_fragments: (0, 0),
                 version: None,
                 encoding: None,
+                encoding_fragments: None,
                 standalone: None,
                 ref_data: String::new(),
+                ref_position: (0, 0),
                 element_name: None,
+                element_name_fragments: (0, 0),
                 quote: None,
+                quote_depth: 0,
                 attr_name: None,
-                attributes: vec!()
+                attr_name_fragments: (0, 0),
+                attr_start: TextPosition::new(),
+                attr_value_needed: false,
+                attributes: vec!(),
+                namespace_fragments: vec!(),
+                doctype_position: (0, 0)
             },
             finish_event: None,
             next_event: None,
             est: Vec::new(),
+            entities: HashMap::new(),
+            expanded_size: 0,
+            attribute_types: HashMap::new(),
+
+            token_span: Span::empty_at(TextPosition::new()),
+            text_start: TextPosition::new(),
+            markup_start: TextPosition::new(),
+            event_span: Span::empty_at(TextPosition::new()),
+            attribute_spans: Vec::new(),
+
+            record_fragments: false,
+            keep_fragments: false,
+            buf_fragments: Vec::new(),
+            fragments: Vec::new(),
+            taken: (0, 0),
+            pending_strings: Vec::new(),
+            next_event_strings: Vec::new(),
+            event_strings: Vec::new(),
 
             encountered_element: false,
+            encountered_doctype: false,
             parsed_declaration: false,
             inside_whitespace: true,
             read_prefix_separator: false,
@@ -104,7 +214,7 @@ enum State {
     InsideComment,
     InsideCData,
     InsideDeclaration(DeclarationSubstate),
-    InsideDoctype,
+    InsideDoctype(DoctypeSubstate),
     InsideReference(Box<State>)
 }
 
@@ -154,6 +264,15 @@ enum DeclarationSubstate {
     AfterStandaloneDeclValue
 }
 
+#[deriving(Clone, PartialEq)]
+enum DoctypeSubstate {
+    DTOutsideSubset,
+    DTInsideSubset,
+    DTInsideLiteral(QuoteToken, bool),  // the flag is set when the literal is inside the subset
+    DTInsideComment,
+    DTInsideProcessingInstruction
+}
+
 #[deriving(PartialEq)]
 enum QualifiedNameTarget {
     AttributeNameTarget,
@@ -161,7 +280,7 @@ enum QualifiedNameTarget {
     ClosingTagNameTarget
 }
 
-#[deriving(PartialEq, Eq)]
+#[deriving(Clone, PartialEq, Eq)]
 enum QuoteToken {
     SingleQuoteToken,
     DoubleQuoteToken
@@ -182,16 +301,26 @@ impl QuoteToken {
             DoubleQuoteToken => DoubleQuote
         }
     }
+
+    fn as_char(self) -> char {
+        match self {
+            SingleQuoteToken => '\'',
+            DoubleQuoteToken => '"'
+        }
+    }
 }
 
 struct AttributeData {
     name: Name,
-    value: String
+    value: String,
+    span: Span,  // the whole attribute, from the name to the closing quote
+    name_fragments: FragmentRange,
+    value_fragments: FragmentRange
 }
 
 impl AttributeData {
     fn into_attribute(self) -> common::Attribute {
-        let AttributeData { name, value } = self;
+        let AttributeData { name, value, .. } = self;
         common::Attribute {
             name: name,
             value: value
@@ -201,17 +330,28 @@ impl AttributeData {
 
 struct MarkupData {
     name: String,     // used for processing instruction name
+    name_fragments: FragmentRange,  // used for processing instruction name fragments
     ref_data: String,  // used for reference content
+    ref_position: (uint, uint),  // used for reference position
 
     version: Option<common::XmlVersion>,  // used for XML declaration version
     encoding: Option<String>,  // used for XML declaration encoding
+    encoding_fragments: Option<FragmentRange>,  // used for XML declaration encoding fragments
     standalone: Option<bool>,  // used for XML declaration standalone parameter
 
     element_name: Option<Name>,  // used for element name
+    element_name_fragments: FragmentRange,  // used for element name fragments
 
     quote: Option<QuoteToken>,  // used to hold opening quote for attribute value
+    quote_depth: uint,  // used to hold entity nesting depth of the opening quote
     attr_name: Option<Name>,  // used to hold attribute name
-    attributes: Vec<AttributeData>   // used to hold all accumulated attributes
+    attr_name_fragments: FragmentRange,  // used to hold attribute name fragments
+    attr_start: TextPosition,  // used to hold attribute name position
+    attr_value_needed: bool,  // used to hold whether the parser needs the attribute value
+    attributes: Vec<AttributeData>,   // used to hold all accumulated attributes
+    namespace_fragments: Vec<FragmentRange>,  // used to hold fragments of namespace declaration values
+
+    doctype_position: (uint, uint)  // used to hold DOCTYPE declaration content position
 }
 
 macro_rules! gen_takes(
@@ -235,6 +375,8 @@ gen_takes!(
     encoding     -> take_encoding, Option<String>, None;
     standalone   -> take_standalone, Option<bool>, None;
 
+    encoding_fragments -> take_encoding_fragments, Option<FragmentRange>, None;
+
     element_name -> take_element_name, Option<Name>, None;
 
     attr_name    -> take_attr_name, Option<Name>, None;
@@ -242,8 +384,8 @@ gen_takes!(
 )
 
 macro_rules! self_error(
-    ($this:ident; $msg:expr) => ($this.error($msg.to_string()));
-    ($this:ident; $fmt:expr, $($arg:expr),+) => ($this.error(format!($fmt, $($arg),+)))
+    ($this:ident; $kind:expr; $msg:expr) => ($this.error($kind, $msg.to_string()));
+    ($this:ident; $kind:expr; $fmt:expr, $($arg:expr),+) => ($this.error($kind, format!($fmt, $($arg),+)))
 )
 
 impl PullParser {
@@ -251,62 +393,228 @@ impl PullParser {
     ///
     /// This method should be always called with the same buffer. If you call it
     /// providing different buffers each time, the result will be undefined.
+    ///
+    /// Errors reported by the buffer are returned as `Error` events with `InputError`
+    /// kind. Unlike other errors, they are not final: the next call will try to
+    /// read from the buffer again, continuing the character which was interrupted
+    /// by the error, if any.
     pub fn next<B: Buffer>(&mut self, r: &mut B) -> XmlEvent {
         if self.finish_event.is_some() {
             return self.finish_event.as_ref().unwrap().clone();
         }
 
         if self.next_event.is_some() {
+            // the event is implied by the previous one, so it has the same span
+            self.attribute_spans.clear();
+            mem::swap(&mut self.event_strings, &mut self.next_event_strings);
+            self.next_event_strings.clear();
             return mem::replace(&mut self.next_event, None).unwrap();
         }
 
+        // fragments of the previous event are not needed anymore, unless it was an error
+        // of the buffer interrupting the current event
+        if !mem::replace(&mut self.keep_fragments, false) {
+            self.fragments.clear();
+        }
+        self.event_strings.clear();
+
         if self.pop_namespace {
             self.pop_namespace = false;
             self.nst.pop();
         }
 
-        for_each!(t in self.lexer.next_token(r) {
+        for_each!(t in self.next_token(r) {
+            self.token_span = self.lexer.token_span();
             match t {
-                Ok(t) => match self.dispatch_token(t) {
-                    Some(ev) => {
-                        match ev {
-                            events::EndDocument | events::Error(_) =>
-                                self.finish_event = Some(ev.clone()),
-                            _ => {}
+                Ok(t) => {
+                    // text can start only when the buffer is empty; a run of text has
+                    // already been appended to it by the lexer
+                    let buffered = match t {
+                        Text(len, _) => self.buf.len() - len,
+                        _ => self.buf.len()
+                    };
+                    if self.st == OutsideTag && buffered == 0 && self.buf_fragments.is_empty() {
+                        self.text_start = self.token_span.start.clone();
+                    }
+                    let declaration_end = t == ProcessingInstructionEnd;
+                    match self.dispatch_token(t) {
+                        Some(ev) => {
+                            self.update_event_span(&ev, declaration_end);
+                            mem::swap(&mut self.event_strings, &mut self.pending_strings);
+                            self.pending_strings.clear();
+                            match ev {
+                                events::Error(_) => {
+                                    self.event_strings.clear();
+                                    self.finish_event = Some(ev.clone());
+                                }
+                                events::EndDocument => self.finish_event = Some(ev.clone()),
+                                _ => {}
+                            }
+                            return ev;
                         }
-                        return ev;
+                        None => {}  // continue
                     }
-                    None => {}  // continue
-                },
+                }
 
-                // Pass through unexpected lexer errors
+                // Pass through unexpected lexer errors; errors of the buffer itself
+                // may be temporary, so reading can be retried after them
                 Err(e) => {
+                    self.event_span = self.token_span.clone();
+                    self.attribute_spans.clear();
+                    let retry = e.category() == IoError;
                     let ev = events::Error(e);
-                    self.finish_event = Some(ev.clone());
+                    if retry {
+                        self.keep_fragments = true;
+                    } else {
+                        self.finish_event = Some(ev.clone());
+                    }
                     return ev;
                 }
             }
         })
 
         // Handle end of stream
+        self.event_span = Span::empty_at(self.lexer.token_span().end);
+        self.attribute_spans.clear();
         let ev = if self.depth() == 0 {
             if self.encountered_element && self.st == OutsideTag {  // all is ok
                 events::EndDocument
             } else if !self.encountered_element {
-                self_error!(self; "Unexpected end of stream: no root element found")
+                self_error!(self; MissingRootElement; "Unexpected end of stream: no root element found")
             } else {  // self.st != OutsideTag
-                self_error!(self; "Unexpected end of stream")  // TODO: add expected hint?
+                self_error!(self; UnexpectedEof; "Unexpected end of stream")  // TODO: add expected hint?
             }
         } else {
-            self_error!(self; "Unexpected end of stream: still inside the root element")
+            let name = self.est.last().unwrap().clone();
+            self_error!(self; UnclosedElement(name); "Unexpected end of stream: still inside the root element")
         };
         self.finish_event = Some(ev.clone());
         ev
     }
 
+    /// Returns the fragment of the document occupied by the last returned event.
+    ///
+    /// Events which are implied, like `StartDocument` without an XML declaration,
+    /// have empty spans. `EndElement` event of an empty element has the same span as
+    /// its `StartElement` event.
+    #[inline]
+    pub fn last_event_span<'a>(&'a self) -> &'a Span {
+        &self.event_span
+    }
+
+    /// Returns spans of attributes of the last returned event, in the same order as
+    /// the attributes are listed in the event.
+    ///
+    /// The slice is empty unless the last event was `StartElement`.
     #[inline]
-    fn error(&self, msg: String) -> XmlEvent {
-        events::Error(Error::new(&self.lexer, msg))
+    pub fn last_attribute_spans<'a>(&'a self) -> &'a [Span] {
+        self.attribute_spans.as_slice()
+    }
+
+    /// Returns the number of bytes of the input which have been decoded into characters.
+    #[inline]
+    pub fn input_offset(&self) -> uint {
+        self.lexer.offset()
+    }
+
+    /// Makes the parser record where strings of events come from; they are then returned
+    /// by `last_event_fragments()`.
+    ///
+    /// Text which appears in the input verbatim is not copied to strings of events unless the
+    /// parser needs it itself, so this should be used only when the input is available to the
+    /// caller. This method should be called before the first event is read.
+    #[inline]
+    pub fn record_fragments(&mut self) {
+        self.record_fragments = true;
+    }
+
+    /// Returns fragments of the `i`-th string of the last returned event if the parser records
+    /// them, see `record_fragments()`.
+    ///
+    /// The string is the concatenation of its fragments: `SourceFragment`s are parts of the input
+    /// and `EventFragment`s are parts of the string in the event. Strings which do not come from
+    /// the input, like the encoding of an implied `StartDocument` event, have no fragments.
+    ///
+    /// Strings are numbered in the order of fields of the event: `encoding` of `StartDocument`;
+    /// `name`, `public_id` and `system_id` of `Doctype`, skipping absent identifiers; `name` and
+    /// `data` of `ProcessingInstruction`; the name of `StartElement`, names and values of its
+    /// attributes and then values of namespace declarations of the element; the name of
+    /// `EndElement`; the contents of other events. Qualified names are reported as a whole.
+    pub fn last_event_fragments<'a>(&'a self, i: uint) -> Option<&'a [Fragment]> {
+        if i < self.event_strings.len() {
+            let (start, end) = self.event_strings[i];
+            Some(self.fragments.slice(start, end))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of strings of the last returned event whose fragments are recorded.
+    #[inline]
+    pub fn last_event_string_count(&self) -> uint {
+        self.event_strings.len()
+    }
+
+    /// Reads the next token, letting the lexer scan text in bulk where it is safe
+    /// in the current state. Such text is appended to the buffer right away.
+    fn next_token<B: Buffer>(&mut self, r: &mut B) -> Option<LexResult> {
+        let mode = self.text_mode();
+        let copy_verbatim = !self.record_fragments || self.needs_text();
+        match self.lexer.scan_text(r, mode, &mut self.buf, copy_verbatim) {
+            Some(t) => Some(Ok(t)),
+            None => self.lexer.next_token(r)
+        }
+    }
+
+    /// Checks whether the parser needs the text of the buffer itself. When fragments are
+    /// recorded, text which appears in the input verbatim is put into the buffer only then.
+    fn needs_text(&self) -> bool {
+        match self.st {
+            OutsideTag | InsideCData => self.config.trim_whitespace,
+            InsideComment | InsideProcessingInstruction(PIInsideData) => false,
+            InsideOpeningTag(InsideAttributeValue) => self.data.attr_value_needed,
+            _ => true
+        }
+    }
+
+    fn text_mode(&self) -> TextMode {
+        if !self.config.bulk_text_scanning {
+            return NoBulkText;
+        }
+        match self.st {
+            OutsideTag if self.depth() > 0 => ContentText,
+            // quotes from entity replacement text do not terminate the value
+            InsideOpeningTag(InsideAttributeValue) => match self.data.quote {
+                Some(q) if self.data.quote_depth == self.lexer.entity_depth() =>
+                    AttributeValueText(Some(q.as_char())),
+                Some(_) => AttributeValueText(None),
+                None => NoBulkText
+            },
+            InsideComment => CommentText,
+            InsideCData => CDataText,
+            _ => NoBulkText
+        }
+    }
+
+    fn update_event_span(&mut self, ev: &XmlEvent, declaration_end: bool) {
+        self.event_span = match *ev {
+            // text is emitted when the next markup starts
+            events::Characters(_) | events::Whitespace(_) =>
+                Span { start: self.text_start.clone(), end: self.token_span.start.clone() },
+            events::StartDocument { .. } if !declaration_end =>
+                Span::empty_at(self.token_span.start.clone()),
+            events::Error(_) => self.token_span.clone(),
+            _ => Span { start: self.markup_start.clone(), end: self.token_span.end.clone() }
+        };
+        match *ev {
+            events::StartElement { .. } => {}
+            _ => self.attribute_spans.clear()
+        }
+    }
+
+    #[inline]
+    fn error(&self, kind: ErrorKind, msg: String) -> XmlEvent {
+        events::Error(Error::new(&self.lexer, kind, msg))
     }
 
     fn dispatch_token(&mut self, t: Token) -> Option<XmlEvent> {
@@ -314,7 +622,7 @@ impl PullParser {
             OutsideTag                     => self.outside_tag(t),
             InsideProcessingInstruction(s) => self.inside_processing_instruction(t, s),
             InsideDeclaration(s)           => self.inside_declaration(t, s),
-            InsideDoctype                  => self.inside_doctype(t),
+            InsideDoctype(s)               => self.inside_doctype(t, s),
             InsideOpeningTag(s)            => self.inside_opening_tag(t, s),
             InsideClosingTag(s)            => self.inside_closing_tag_name(t, s),
             InsideComment                  => self.inside_comment(t),
@@ -323,6 +631,14 @@ impl PullParser {
         }
     }
 
+    /// Returns the position of the character of the last token in the document.
+    #[inline]
+    fn current_char_position(&self) -> (uint, uint) {
+        // the lexer column points right after the current character
+        let col = self.lexer.col();
+        (self.lexer.row(), if col > 0 { col - 1 } else { 0 })
+    }
+
     #[inline]
     fn depth(&self) -> uint {
         self.est.len()
@@ -330,23 +646,174 @@ impl PullParser {
 
     #[inline]
     fn buf_has_data(&self) -> bool {
-        self.buf.len() > 0
+        self.buf.len() > 0 || !self.buf_fragments.is_empty()
     }
 
     #[inline]
     fn take_buf(&mut self) -> String {
+        self.take_fragments();
         mem::replace(&mut self.buf, String::new())
     }
 
+    /// Empties the buffer, keeping its memory for further use.
+    #[inline]
+    fn clear_buf(&mut self) {
+        self.take_fragments();
+        self.buf.truncate(0);
+    }
+
+    /// Moves fragments of the buffer contents to the fragments of taken strings; their
+    /// indices are stored in `self.taken`.
+    fn take_fragments(&mut self) {
+        if self.record_fragments {
+            let start = self.fragments.len();
+            self.fragments.push_all(self.buf_fragments.as_slice());
+            self.buf_fragments.clear();
+            self.taken = (start, self.fragments.len());
+        }
+    }
+
+    /// Reports fragments of the next string of the event which is being emitted.
+    #[inline]
+    fn report_string(&mut self, fragments: FragmentRange) {
+        if self.record_fragments {
+            self.pending_strings.push(fragments);
+        }
+    }
+
+    /// Stores fragments of the part `[start, end)` of a taken string and returns their indices.
+    ///
+    /// The string must contain all of its text, which is the case when the parser needs it.
+    fn slice_fragments(&mut self, taken: FragmentRange, start: uint, end: uint) -> FragmentRange {
+        let (first, last) = taken;
+        let result = self.fragments.len();
+        let mut pos = 0;  // position of the current fragment in the string
+        for i in range(first, last) {
+            let fragment = self.fragments[i];
+            let (from, to) = (cmp::max(pos, start), cmp::min(pos + fragment.len(), end));
+            if from < to {
+                self.fragments.push(match fragment {
+                    SourceFragment(s, _) => SourceFragment(s + from - pos, s + to - pos),
+                    EventFragment(..) => EventFragment(from - start, to - start)
+                });
+            }
+            pos += fragment.len();
+        }
+        (result, self.fragments.len())
+    }
+
+    /// Trims whitespace of the text taken from the buffer and reports its fragments.
+    fn trim_text(&mut self, text: &str) -> String {
+        let start = text.len() - text.trim_left_chars(is_whitespace_char).len();
+        let end = text.trim_right_chars(is_whitespace_char).len();
+        if !self.record_fragments {
+            return text.slice(start, end).to_string();
+        }
+        let taken = self.taken;
+        let fragments = self.slice_fragments(taken, start, cmp::max(start, end));
+        self.report_string(fragments);
+        let (first, last) = fragments;
+        if self.fragments.slice(first, last).iter().all(|f| f.is_source()) {
+            String::new()
+        } else {
+            text.slice(start, cmp::max(start, end)).to_string()
+        }
+    }
+
     #[inline]
     fn append_char_continue(&mut self, c: char) -> Option<XmlEvent> {
-        self.buf.push(c);
+        self.push_char(c, true);
         None
     }
 
     #[inline]
     fn append_str_continue(&mut self, s: &str) -> Option<XmlEvent> {
-        self.buf.push_str(s);
+        self.push_str(s, true);
+        None
+    }
+
+    /// Appends a character to the buffer; `own_text` tells whether it is the text of the current
+    /// token rather than its replacement.
+    fn push_char(&mut self, c: char, own_text: bool) {
+        let start = self.buf.len();
+        if own_text && self.record_source_fragment(c.len_utf8_bytes()) {
+            if self.needs_text() {
+                self.buf.push(c);
+            }
+        } else {
+            self.buf.push(c);
+            self.record_event_fragment(start);
+        }
+    }
+
+    /// Appends a string to the buffer; `own_text` tells whether it is the text of the current
+    /// token rather than its replacement.
+    fn push_str(&mut self, s: &str, own_text: bool) {
+        let start = self.buf.len();
+        if own_text && self.record_source_fragment(s.len()) {
+            if self.needs_text() {
+                self.buf.push_str(s);
+            }
+        } else {
+            self.buf.push_str(s);
+            self.record_event_fragment(start);
+        }
+    }
+
+    /// Appends characters of the token to the buffer.
+    fn push_token(&mut self, t: &Token) {
+        match *t {
+            Character(c) | Whitespace(c) => self.push_char(c, true),
+            Chunk(s) => self.push_str(s, true),
+            Text(len, _) => self.record_text(len),  // already appended by the lexer, if needed
+            _ => self.push_str(t.as_static_str().unwrap(), true)
+        }
+    }
+
+    #[inline]
+    fn append_token_continue(&mut self, t: &Token) -> Option<XmlEvent> {
+        self.push_token(t);
+        None
+    }
+
+    /// Records the text of the current token as a fragment of the buffer contents if fragments
+    /// are recorded and the token of `len` bytes appears in the input verbatim.
+    fn record_source_fragment(&mut self, len: uint) -> bool {
+        if !self.record_fragments || !self.lexer.token_verbatim() {
+            return false;
+        }
+        let (start, end) = (self.token_span.start.offset, self.token_span.end.offset);
+        if end - start != len {
+            return false;
+        }
+        push_fragment(&mut self.buf_fragments, SourceFragment(start, end));
+        true
+    }
+
+    /// Records the buffer contents after `start` as a fragment of them if fragments are recorded.
+    fn record_event_fragment(&mut self, start: uint) {
+        if self.record_fragments && self.buf.len() > start {
+            let end = self.buf.len();
+            push_fragment(&mut self.buf_fragments, EventFragment(start, end));
+        }
+    }
+
+    /// Records a run of text read by the lexer, whose last `len` bytes have been appended to
+    /// the buffer; a verbatim run is not appended unless the parser needs it.
+    fn record_text(&mut self, len: uint) {
+        let start = self.buf.len() - len;
+        let len = if len > 0 { len } else { self.token_span.end.offset - self.token_span.start.offset };
+        if !self.record_source_fragment(len) {
+            self.record_event_fragment(start);
+        }
+    }
+
+    /// Accounts for a run of text which has been read by the lexer.
+    fn text_continue(&mut self, len: uint, whitespace: bool) -> Option<XmlEvent> {
+        self.record_text(len);
+        if !whitespace {
+            self.inside_whitespace = false;
+        }
         None
     }
 
@@ -381,17 +848,23 @@ impl PullParser {
         }
 
         let invoke_callback = |this: &mut PullParser, t| {
-            let name = this.take_buf();
-            match common::parse_name(name.as_slice()) {
-                Some(name) => on_name(this, t, name),
-                None => Some(self_error!(this; "Qualified name is invalid: {}", name))
+            let parsed = common::parse_name(this.buf.as_slice());
+            match parsed {
+                Some(name) => {
+                    this.clear_buf();
+                    on_name(this, t, name)
+                }
+                None => {
+                    let name = this.take_buf();
+                    Some(self_error!(this; InvalidName(name.clone()); "Qualified name is invalid: {}", name))
+                }
             }
         };
 
         match t {
             // There can be only one colon, and not as the first character
             Character(':') if self.buf_has_data() && !self.read_prefix_separator => {
-                self.buf.push(':');
+                self.push_char(':', true);
                 self.read_prefix_separator = true;
                 None
             }
@@ -409,7 +882,8 @@ impl PullParser {
 
             Whitespace(_) => invoke_callback(self, t),
 
-            _ => Some(self_error!(self; "Unexpected token inside qualified name: {}", t))
+            _ => Some(self_error!(self; UnexpectedToken { found: t.to_string(), expected: "name", context: "qualified name" };
+                                  "Unexpected token inside qualified name: {}", t))
         }
     }
 
@@ -425,44 +899,65 @@ impl PullParser {
             DoubleQuote | SingleQuote => match self.data.quote {
                 None => {  // Entered attribute value
                     self.data.quote = Some(QuoteToken::from_token(&t));
+                    self.data.quote_depth = self.lexer.entity_depth();
                     None
                 }
-                Some(q) if q.as_token() == t => {
+                // quotes from entity replacement text do not terminate the value
+                Some(q) if q.as_token() == t && self.data.quote_depth == self.lexer.entity_depth() => {
                     self.data.quote = None;
                     let value = self.take_buf();
                     on_value(self, value)
                 }
-                _ => self.append_str_continue(t.to_string().as_slice()),
+                _ => self.append_token_continue(&t),
             },
 
             ReferenceStart => {
+                self.data.ref_position = self.current_char_position();
                 let st = box self.st.clone();
                 self.into_state_continue(InsideReference(st))
             }
 
             OpeningTagStart =>
-                Some(self_error!(self; "Unexpected token inside attribute value: <")),
+                Some(self_error!(self; UnexpectedToken { found: "<".to_string(), expected: "attribute value", context: "attribute value" };
+                                 "Unexpected token inside attribute value: <")),
+
+            // White space characters are normalized to spaces, see section 3.3.3 of XML spec
+            Whitespace(c) => {
+                self.push_char(' ', c == ' ');
+                None
+            }
+
+            // whitespace in the text has been normalized by the lexer
+            Text(len, _) => {
+                self.record_text(len);
+                None
+            }
 
             // Every character except " and ' and < is okay
-            _  => self.append_str_continue(t.to_string().as_slice()),
+            _  => self.append_token_continue(&t),
         }
     }
 
     fn outside_tag(&mut self, t: Token) -> Option<XmlEvent> {
         match t {
-            ReferenceStart =>
-                self.into_state_continue(InsideReference(box OutsideTag)),
+            ReferenceStart => {
+                self.data.ref_position = self.current_char_position();
+                self.into_state_continue(InsideReference(box OutsideTag))
+            }
 
             Whitespace(_) if self.depth() == 0 => None,  // skip whitespace outside of the root element
 
             _ if t.contains_char_data() && self.depth() == 0 =>
-                Some(self_error!(self; "Unexpected characters outside the root element: {}", t)),
+                Some(self_error!(self; CharactersOutsideRoot(t.to_string());
+                                 "Unexpected characters outside the root element: {}", t)),
 
             Whitespace(c) => self.append_char_continue(c),
 
+            Text(len, whitespace) => self.text_continue(len, whitespace),
+
             _ if t.contains_char_data() => {  // Non-whitespace char data
                 self.inside_whitespace = false;
-                self.append_str_continue(t.to_string().as_slice())
+                self.append_token_continue(&t)
             }
 
             ReferenceEnd => { // Semi-colon in a text outside an entity
@@ -483,17 +978,22 @@ impl PullParser {
             }
 
             _ => {
+                self.markup_start = self.token_span.start.clone();
+
                 // Encountered some markup event, flush the buffer as characters
                 // or a whitespace
                 let mut next_event = if self.buf_has_data() {
                     let buf = self.take_buf();
+                    let taken = self.taken;
                     if self.inside_whitespace && self.config.trim_whitespace {
                         None
                     } else if self.inside_whitespace && !self.config.whitespace_to_characters {
+                        self.report_string(taken);
                         Some(events::Whitespace(buf))
                     } else if self.config.trim_whitespace {
-                        Some(events::Characters(buf.as_slice().trim_chars(is_whitespace_char).to_string()))
+                        Some(events::Characters(self.trim_text(buf.as_slice())))
                     } else {
+                        self.report_string(taken);
                         Some(events::Characters(buf))
                     }
                 } else { None };
@@ -502,9 +1002,20 @@ impl PullParser {
                     ProcessingInstructionStart =>
                         self.into_state(InsideProcessingInstruction(PIInsideName), next_event),
 
-                    DoctypeStart if !self.encountered_element => {
+                    DoctypeStart if !self.encountered_element && !self.encountered_doctype => {
+                        // DOCTYPE must be preceded by the document start event
+                        if !self.parsed_declaration {
+                            self.parsed_declaration = true;
+                            next_event = Some(events::StartDocument {
+                                version: DEFAULT_VERSION,
+                                encoding: self.lexer.encoding().unwrap_or(DEFAULT_ENCODING).to_string(),
+                                standalone: DEFAULT_STANDALONE
+                            });
+                        }
+                        self.encountered_doctype = true;
+                        self.data.doctype_position = (self.lexer.row(), self.lexer.col());
                         self.lexer.disable_errors();
-                        self.into_state(InsideDoctype, next_event)
+                        self.into_state(InsideDoctype(DTOutsideSubset), next_event)
                     }
 
                     OpeningTagStart => {
@@ -514,7 +1025,7 @@ impl PullParser {
                             self.parsed_declaration = true;
                             let sd_event = events::StartDocument {
                                 version: DEFAULT_VERSION,
-                                encoding: DEFAULT_ENCODING.to_string(),
+                                encoding: self.lexer.encoding().unwrap_or(DEFAULT_ENCODING).to_string(),
                                 standalone: DEFAULT_STANDALONE
                             };
                             // next_event is always none here because we're outside of
@@ -541,20 +1052,147 @@ impl PullParser {
                         self.into_state(InsideCData, next_event)
                     }
 
-                    _ => Some(self_error!(self; "Unexpected token: {}", t))
+                    _ => Some(self_error!(self; UnexpectedToken {
+                                              found: t.to_string(),
+                                              expected: "element, comment, CDATA section or DOCTYPE declaration",
+                                              context: "markup"
+                                          };
+                                          "Unexpected token: {}", t))
                 }
             }
         }
     }
 
-    fn inside_doctype(&mut self, t: Token) -> Option<XmlEvent> {
-        match t {
-            TagEnd => {
-                self.lexer.enable_errors();
-                self.into_state_continue(OutsideTag)
+    /// Collects the text of a DOCTYPE declaration up to its closing `>`, skipping over
+    /// literals, comments and processing instructions which can contain `>` too. The text
+    /// is then parsed by `dtd::parse_doctype`.
+    fn inside_doctype(&mut self, t: Token, s: DoctypeSubstate) -> Option<XmlEvent> {
+        match s {
+            DTOutsideSubset => match t {
+                TagEnd => self.emit_doctype(),
+
+                // `?>` can only appear here in an invalid declaration, let the DTD parser report it
+                ProcessingInstructionEnd => {
+                    self.push_char('?', false);
+                    self.emit_doctype()
+                }
+
+                Character('[') => {
+                    self.push_char('[', true);
+                    self.into_state_continue(InsideDoctype(DTInsideSubset))
+                }
+
+                SingleQuote | DoubleQuote => {
+                    self.push_token(&t);
+                    self.into_state_continue(InsideDoctype(DTInsideLiteral(QuoteToken::from_token(&t), false)))
+                }
+
+                _ => self.append_token_continue(&t)
+            },
+
+            DTInsideSubset => {
+                self.push_token(&t);
+                match t {
+                    Character(']') => self.into_state_continue(InsideDoctype(DTOutsideSubset)),
+                    SingleQuote | DoubleQuote =>
+                        self.into_state_continue(InsideDoctype(DTInsideLiteral(QuoteToken::from_token(&t), true))),
+                    CommentStart => self.into_state_continue(InsideDoctype(DTInsideComment)),
+                    ProcessingInstructionStart =>
+                        self.into_state_continue(InsideDoctype(DTInsideProcessingInstruction)),
+                    Chunk(ref s) if s.as_slice() == "]]" =>
+                        Some(self_error!(self; UnexpectedToken { found: "]]".to_string(), expected: "markup declaration", context: "DOCTYPE declaration" };
+                                         "Unexpected token inside DOCTYPE internal subset: ]]")),
+                    CDataStart | CDataEnd =>
+                        Some(self_error!(self; UnexpectedToken { found: t.to_string(), expected: "markup declaration", context: "DOCTYPE declaration" };
+                                         "Unexpected token inside DOCTYPE internal subset: {}", t)),
+                    _ => None
+                }
+            },
+
+            DTInsideLiteral(q, inside_subset) => {
+                self.push_token(&t);
+                if t == q.as_token() {
+                    self.into_state_continue(InsideDoctype(
+                        if inside_subset { DTInsideSubset } else { DTOutsideSubset }
+                    ))
+                } else {
+                    None
+                }
+            },
+
+            DTInsideComment => {
+                self.push_token(&t);
+                match t {
+                    CommentEnd => self.into_state_continue(InsideDoctype(DTInsideSubset)),
+                    _ => None
+                }
+            },
+
+            DTInsideProcessingInstruction => {
+                self.push_token(&t);
+                match t {
+                    ProcessingInstructionEnd => self.into_state_continue(InsideDoctype(DTInsideSubset)),
+                    _ => None
+                }
             }
+        }
+    }
 
-            _ => None
+    fn emit_doctype(&mut self) -> Option<XmlEvent> {
+        self.lexer.enable_errors();
+        let text = self.take_buf();
+        let taken = self.taken;
+        let (row, col) = self.data.doctype_position;
+        match dtd::parse_doctype(text.as_slice(), row, col) {
+            Ok(doctype) => {
+                if self.record_fragments {
+                    let ranges = [Some(doctype.name_range), doctype.public_id_range, doctype.system_id_range];
+                    for range in ranges.iter().filter_map(|r| *r) {
+                        let (start, end) = range;
+                        let fragments = self.slice_fragments(taken, start, end);
+                        self.report_string(fragments);
+                    }
+                }
+                for decl in doctype.declarations.iter() {
+                    match *decl {
+                        dtd::EntityDecl { ref name, parameter: false, ref value } => {
+                            // the first declaration of an entity is binding
+                            if self.entities.contains_key(name) {
+                                continue;
+                            }
+                            let value = match *value {
+                                dtd::InternalEntity(ref literal) => match dtd::replacement_text(literal.as_slice(),
+                                                                                                self.lexer.version()) {
+                                    Ok(text) => dtd::InternalEntity(text),
+                                    Err(msg) => return Some(self.error(InvalidEntityValue(name.clone()), msg))
+                                },
+                                ref external => external.clone()
+                            };
+                            self.entities.insert(name.clone(), value);
+                        }
+                        dtd::AttlistDecl { ref element, ref attributes } => {
+                            let types = match self.attribute_types.entry(element.clone()) {
+                                Occupied(entry) => entry.into_mut(),
+                                Vacant(entry) => entry.set(HashMap::new())
+                            };
+                            for attr in attributes.iter() {
+                                // the first declaration of an attribute is binding
+                                if !types.contains_key(&attr.name) {
+                                    types.insert(attr.name.clone(), attr.attribute_type.clone());
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                self.into_state_emit(OutsideTag, events::Doctype {
+                    name: doctype.name,
+                    public_id: doctype.public_id,
+                    system_id: doctype.system_id,
+                    declarations: doctype.declarations
+                })
+            }
+            Err(e) => Some(events::Error(e))
         }
     }
 
@@ -572,15 +1210,19 @@ impl PullParser {
                     // but there is none
                     match name.as_slice() {
                         // Name is empty, it is an error
-                        "" => Some(self_error!(self; "Encountered processing instruction without name")),
+                        "" => Some(self_error!(self; InvalidProcessingInstruction(name.clone());
+                                               "Encountered processing instruction without name")),
 
                         // Found <?xml-like PI not at the beginning of a document,
                         // it is an error - see section 2.6 of XML 1.1 spec
                         "xml"|"xmL"|"xMl"|"xML"|"Xml"|"XmL"|"XMl"|"XML" =>
-                            Some(self_error!(self; "Invalid processing instruction: <?{}", name)),
+                            Some(self_error!(self; InvalidProcessingInstruction(name.clone());
+                                             "Invalid processing instruction: <?{}", name)),
 
                         // All is ok, emitting event
                         _ => {
+                            let taken = self.taken;
+                            self.report_string(taken);
                             self.into_state_emit(
                                 OutsideTag,
                                 events::ProcessingInstruction {
@@ -605,19 +1247,22 @@ impl PullParser {
                         // it is an error - see section 2.6 of XML 1.1 spec
                         "xml"|"xmL"|"xMl"|"xML"|"Xml"|"XmL"|"XMl"|"XML"
                             if self.encountered_element || self.parsed_declaration =>
-                            Some(self_error!(self; "Invalid processing instruction: <?{}", name)),
+                            Some(self_error!(self; InvalidProcessingInstruction(name.clone());
+                                             "Invalid processing instruction: <?{}", name)),
 
                         // All is ok, starting parsing PI data
                         _ => {
                             self.lexer.disable_errors();  // data is arbitrary, so disable errors
                             self.data.name = name;
+                            self.data.name_fragments = self.taken;
                             self.into_state_continue(InsideProcessingInstruction(PIInsideData))
                         }
 
                     }
                 }
 
-                _ => Some(self_error!(self; "Unexpected token: <?{}{}", self.buf, t))
+                _ => Some(self_error!(self; UnexpectedToken { found: t.to_string(), expected: "whitespace or ?>", context: "processing instruction" };
+                                      "Unexpected token: <?{}{}", self.buf, t))
             },
 
             PIInsideData => match t {
@@ -625,6 +1270,9 @@ impl PullParser {
                     self.lexer.enable_errors();
                     let name = self.data.take_name();
                     let data = self.take_buf();
+                    let (name_fragments, taken) = (self.data.name_fragments, self.taken);
+                    self.report_string(name_fragments);
+                    self.report_string(taken);
                     self.into_state_emit(
                         OutsideTag,
                         events::ProcessingInstruction {
@@ -636,7 +1284,7 @@ impl PullParser {
 
                 // Any other token should be treated as plain characters
                 _ => {
-                    self.buf.push_str(t.to_string().as_slice());
+                    self.push_token(&t);
                     None
                 }
             },
@@ -646,8 +1294,11 @@ impl PullParser {
     // TODO: remove redundancy via macros or extra methods
     fn inside_declaration(&mut self, t: Token, s: DeclarationSubstate) -> Option<XmlEvent> {
         macro_rules! unexpected_token(
-            ($this:expr; $t:expr) => (Some($this.error(format!("Unexpected token inside XML declaration: {}", $t))));
-            ($t:expr) => (unexpected_token!(self; $t));
+            ($this:expr; $t:expr, $expected:expr) => (Some($this.error(
+                UnexpectedToken { found: $t.to_string(), expected: $expected, context: "XML declaration" },
+                format!("Unexpected token inside XML declaration: {}", $t)
+            )));
+            ($t:expr, $expected:expr) => (unexpected_token!(self; $t, $expected));
         )
 
         #[inline]
@@ -656,9 +1307,31 @@ impl PullParser {
             let version = this.data.take_version();
             let encoding = this.data.take_encoding();
             let standalone = this.data.take_standalone();
+            match this.data.take_encoding_fragments() {
+                Some(fragments) => this.report_string(fragments),
+                None => {}
+            }
+            let version = version.unwrap_or(DEFAULT_VERSION);
+            this.lexer.set_version(version);
+
+            // switch the lexer to the declared encoding, if it is needed
+            if !this.config.ignore_encoding_declaration {
+                match encoding {
+                    Some(ref label) => match this.lexer.set_declared_encoding(label.as_slice(),
+                                                                              this.config.decoder_factory) {
+                        Ok(()) => {}
+                        Err(e) => return Some(events::Error(e))
+                    },
+                    None => {}
+                }
+            }
+
+            let encoding = encoding.unwrap_or_else(
+                || this.lexer.encoding().unwrap_or(DEFAULT_ENCODING).to_string()
+            );
             this.into_state_emit(OutsideTag, events::StartDocument {
-                version: version.unwrap_or(DEFAULT_VERSION),
-                encoding: encoding.unwrap_or(DEFAULT_ENCODING.to_string()),
+                version: version,
+                encoding: encoding,
                 standalone: standalone
             })
         }
@@ -667,7 +1340,7 @@ impl PullParser {
             BeforeVersion => match t {
                 Whitespace(_) => None,  // continue
                 Character('v') => self.into_state_continue(InsideDeclaration(InsideVersion)),
-                _ => unexpected_token!(t)
+                _ => unexpected_token!(t, "version")
             },
 
             InsideVersion => self.read_qualified_name(t, AttributeNameTarget, |this, token, name| {
@@ -676,14 +1349,14 @@ impl PullParser {
                         this.into_state_continue(InsideDeclaration(
                             if token == EqualsSign { InsideVersionValue } else { AfterVersion }
                         )),
-                    _ => unexpected_token!(this; name)
+                    _ => unexpected_token!(this; name, "version")
                 }
             }),
 
             AfterVersion => match t {
                 Whitespace(_) => None,
                 EqualsSign => self.into_state_continue(InsideDeclaration(InsideVersionValue)),
-                _ => unexpected_token!(t)
+                _ => unexpected_token!(t, "=")
             },
 
             InsideVersionValue => self.read_attribute_value(t, |this, value| {
@@ -695,7 +1368,8 @@ impl PullParser {
                 if this.data.version.is_some() {
                     this.into_state_continue(InsideDeclaration(AfterVersionValue))
                 } else {
-                    Some(self_error!(this; "Unexpected XML version value: {}", value))
+                    Some(self_error!(this; InvalidDeclarationValue { name: "version", value: value.clone() };
+                                     "Unexpected XML version value: {}", value))
                 }
             }),
 
@@ -704,7 +1378,7 @@ impl PullParser {
                 Character('e') => self.into_state_continue(InsideDeclaration(InsideEncoding)),
                 Character('s') => self.into_state_continue(InsideDeclaration(InsideStandaloneDecl)),
                 ProcessingInstructionEnd => emit_start_document(self),
-                _ => unexpected_token!(t)
+                _ => unexpected_token!(t, "encoding, standalone or ?>")
             },
 
             InsideEncoding => self.read_qualified_name(t, AttributeNameTarget, |this, token, name| {
@@ -713,18 +1387,19 @@ impl PullParser {
                         this.into_state_continue(InsideDeclaration(
                             if token == EqualsSign { InsideEncodingValue } else { AfterEncoding }
                         )),
-                    _ => unexpected_token!(this; name)
+                    _ => unexpected_token!(this; name, "encoding")
                 }
             }),
 
             AfterEncoding => match t {
                 Whitespace(_) => None,
                 EqualsSign => self.into_state_continue(InsideDeclaration(InsideEncodingValue)),
-                _ => unexpected_token!(t)
+                _ => unexpected_token!(t, "=")
             },
 
             InsideEncodingValue => self.read_attribute_value(t, |this, value| {
                 this.data.encoding = Some(value);
+                this.data.encoding_fragments = Some(this.taken);
                 this.into_state_continue(InsideDeclaration(BeforeStandaloneDecl))
             }),
 
@@ -732,7 +1407,7 @@ impl PullParser {
                 Whitespace(_) => None,  // skip whitespace
                 Character('s') => self.into_state_continue(InsideDeclaration(InsideStandaloneDecl)),
                 ProcessingInstructionEnd => emit_start_document(self),
-                _ => unexpected_token!(t)
+                _ => unexpected_token!(t, "standalone or ?>")
             },
 
             InsideStandaloneDecl => self.read_qualified_name(t, AttributeNameTarget, |this, token, name| {
@@ -741,14 +1416,14 @@ impl PullParser {
                         this.into_state_continue(InsideDeclaration(
                             if token == EqualsSign { InsideStandaloneDeclValue } else { AfterStandaloneDecl }
                         )),
-                    _ => unexpected_token!(this; name)
+                    _ => unexpected_token!(this; name, "standalone")
                 }
             }),
 
             AfterStandaloneDecl => match t {
                 Whitespace(_) => None,
                 EqualsSign => self.into_state_continue(InsideDeclaration(InsideStandaloneDeclValue)),
-                _ => unexpected_token!(t)
+                _ => unexpected_token!(t, "=")
             },
 
             InsideStandaloneDeclValue => self.read_attribute_value(t, |this, value| {
@@ -761,14 +1436,15 @@ impl PullParser {
                     this.data.standalone = standalone;
                     this.into_state_continue(InsideDeclaration(AfterStandaloneDeclValue))
                 } else {
-                    Some(self_error!(this; "Invalid standalone declaration value: {}", value))
+                    Some(self_error!(this; InvalidDeclarationValue { name: "standalone", value: value.clone() };
+                                     "Invalid standalone declaration value: {}", value))
                 }
             }),
 
             AfterStandaloneDeclValue => match t {
                 Whitespace(_) => None,  // skip whitespace
                 ProcessingInstructionEnd => emit_start_document(self),
-                _ => unexpected_token!(t)
+                _ => unexpected_token!(t, "?>")
             }
         }
     }
@@ -778,23 +1454,85 @@ impl PullParser {
         let mut name = self.data.take_element_name().unwrap();
         let mut attributes = self.data.take_attributes();
 
+        // values of attributes declared with types other than CDATA are normalized further
+        match self.attribute_types.find(&name.to_str_proper()) {
+            Some(types) => for attr in attributes.iter_mut() {
+                match types.find(&attr.name.to_str_proper()) {
+                    Some(&dtd::CDataType) | None => {}
+                    Some(_) => {
+                        let value = collapse_spaces(attr.value.as_slice());
+                        if value != attr.value {
+                            attr.value = value;
+                            if self.record_fragments {
+                                let start = self.fragments.len();
+                                self.fragments.push(EventFragment(0, attr.value.len()));
+                                attr.value_fragments = (start, start + 1);
+                            }
+                        }
+                    }
+                }
+            },
+            None => {}
+        }
+
         // check whether the name prefix is bound and fix its namespace
         match self.nst.get(&name.prefix) {
             Some("") => name.namespace = None,  // default namespace
             Some(ns) => name.namespace = Some(ns.to_string()),
-            None => return Some(self_error!(self; "Element {} prefix is unbound", name))
+            None => return Some(self_error!(self; UnboundPrefix(name.clone()); "Element {} prefix is unbound", name))
         }
 
-        // check and fix accumulated attributes prefixes
+        // check and fix accumulated attributes prefixes; the default namespace does not apply
+        // to attributes, see section 6.2 of Namespaces in XML spec
         for attr in attributes.iter_mut() {
+            if attr.name.prefix.is_none() {
+                attr.name.namespace = None;
+                continue;
+            }
             match self.nst.get(&attr.name.prefix) {
                 Some("") => attr.name.namespace = None,  // default namespace
                 Some(ns) => attr.name.namespace = Some(ns.to_string()),
-                None => return Some(self_error!(self; "Attribute {} prefix is unbound", attr.name))
+                None => return Some(self_error!(self; UnboundPrefix(attr.name.clone());
+                                                "Attribute {} prefix is unbound", attr.name))
             }
         }
 
+        // check for duplicate attributes, comparing names with namespaces resolved
+        let mut seen = HashSet::new();
+        for (i, attr) in attributes.iter().enumerate() {
+            if seen.insert((attr.name.namespace.clone(), attr.name.local_name.clone())) {
+                continue;
+            }
+            // the previous attribute is looked up only to report the error
+            let prev = attributes.slice_to(i).iter().find(|prev| {
+                prev.name.local_name == attr.name.local_name && prev.name.namespace == attr.name.namespace
+            }).unwrap();
+            let msg = if prev.name.prefix == attr.name.prefix {
+                format!("Duplicate attribute: {}", attr.name.to_str_proper())
+            } else {
+                format!("Attribute {} duplicates attribute {}: both prefixes are bound to {}",
+                        attr.name.to_str_proper(), prev.name.to_str_proper(),
+                        attr.name.namespace.as_ref().unwrap())
+            };
+            return Some(events::Error(Error::new_with_span(attr.span.clone(), DuplicateAttribute(attr.name.clone()), msg)));
+        }
+
+        let name_fragments = self.data.element_name_fragments;
+        self.report_string(name_fragments);
+        for attr in attributes.iter() {
+            self.report_string(attr.name_fragments);
+            self.report_string(attr.value_fragments);
+        }
+        if self.record_fragments {
+            self.pending_strings.push_all(self.data.namespace_fragments.as_slice());
+        }
+        self.data.namespace_fragments.clear();
+
+        self.attribute_spans = attributes.iter().map(|a| a.span.clone()).collect();
         if emit_end_element {
+            if self.record_fragments {
+                self.next_event_strings.push(name_fragments);
+            }
             self.pop_namespace = true;
             self.next_event = Some(events::EndElement {
                 name: name.clone()
@@ -811,15 +1549,20 @@ impl PullParser {
     }
 
     fn inside_opening_tag(&mut self, t: Token, s: OpeningTagSubstate) -> Option<XmlEvent> {
-        macro_rules! unexpected_token(($t:expr) => (Some(self_error!(self; "Unexpected token inside opening tag: {}", $t))))
+        macro_rules! unexpected_token(($t:expr, $expected:expr) => (Some(self_error!(self;
+            UnexpectedToken { found: $t.clone(), expected: $expected, context: "opening tag" };
+            "Unexpected token inside opening tag: {}", $t
+        ))))
         match s {
             InsideName => self.read_qualified_name(t, OpeningTagNameTarget, |this, token, name| {
                 match name.prefix_ref() {
                     Some(prefix) if prefix == namespace::NS_XML_PREFIX ||
                                     prefix == namespace::NS_XMLNS_PREFIX =>
-                        Some(self_error!(this; "'{}' cannot be an element name prefix", name.prefix)),
+                        Some(self_error!(this; ReservedPrefix(prefix.to_string());
+                                         "'{}' cannot be an element name prefix", name.prefix)),
                     _ => {
                         this.data.element_name = Some(name.clone());
+                        this.data.element_name_fragments = this.taken;
                         match token {
                             TagEnd => this.emit_start_element(false),
                             EmptyTagEnd => this.emit_start_element(true),
@@ -833,44 +1576,55 @@ impl PullParser {
             InsideTag => match t {
                 Whitespace(_) => None,  // skip whitespace
                 Character(c) if is_name_start_char(c) => {
-                    self.buf.push(c);
+                    self.data.attr_start = self.token_span.start.clone();
+                    self.push_char(c, true);
                     self.into_state_continue(InsideOpeningTag(InsideAttributeName))
                 }
                 TagEnd => self.emit_start_element(false),
                 EmptyTagEnd => self.emit_start_element(true),
-                _ => unexpected_token!(t.to_string())
+                _ => unexpected_token!(t.to_string(), "attribute name, > or />")
             },
 
             InsideAttributeName => self.read_qualified_name(t, AttributeNameTarget, |this, token, name| {
                 this.data.attr_name = Some(name);
+                this.data.attr_name_fragments = this.taken;
                 match token {
                     Whitespace(_) => this.into_state_continue(InsideOpeningTag(AfterAttributeName)),
-                    EqualsSign => this.into_state_continue(InsideOpeningTag(InsideAttributeValue)),
+                    EqualsSign => this.start_attribute_value(),
                     _ => unreachable!()
                 }
             }),
 
             AfterAttributeName => match t {
                 Whitespace(_) => None,
-                EqualsSign => self.into_state_continue(InsideOpeningTag(InsideAttributeValue)),
-                _ => unexpected_token!(t.to_string())
+                EqualsSign => self.start_attribute_value(),
+                _ => unexpected_token!(t.to_string(), "=")
             },
 
             InsideAttributeValue => self.read_attribute_value(t, |this, value| {
                 let name = this.data.take_attr_name().unwrap();  // unwrap() will always succeed here
+                let span = Span { start: this.data.attr_start.clone(), end: this.token_span.end.clone() };
+                let duplicate = |name: &Name| events::Error(Error::new_with_span(
+                    span.clone(), DuplicateAttribute(name.clone()), format!("Duplicate attribute: {}", name.to_str_proper())
+                ));
                 match name.prefix_ref() {
                     // declaring a new prefix; it is sufficient to check prefix only
                     // because "xmlns" prefix is reserved
                     Some(prefix) if prefix == namespace::NS_XMLNS_PREFIX => {
                         let ln = name.local_name.as_slice();
                         if ln == namespace::NS_XMLNS_PREFIX {
-                            Some(self_error!(this; "Cannot redefine '{}' prefix", namespace::NS_XMLNS_PREFIX))
+                            Some(self_error!(this; ReservedPrefix(ln.to_string());
+                                             "Cannot redefine '{}' prefix", namespace::NS_XMLNS_PREFIX))
                         } else if ln == namespace::NS_XML_PREFIX && value.as_slice() != namespace::NS_XML_URI {
-                            Some(self_error!(this; "'{}' prefix cannot be rebound to another value", namespace::NS_XML_PREFIX))
+                            Some(self_error!(this; ReservedPrefix(ln.to_string());
+                                             "'{}' prefix cannot be rebound to another value", namespace::NS_XML_PREFIX))
                         } else if value.is_empty() {
-                            Some(self_error!(this; "Cannot undefine a prefix: {}", ln))
+                            Some(self_error!(this; InvalidNamespaceBinding { prefix: Some(ln.to_string()), uri: value.clone() };
+                                             "Cannot undefine a prefix: {}", ln))
+                        } else if !this.nst.put(Some(name.local_name.clone()), value) {
+                            Some(duplicate(&name))
                         } else {
-                            this.nst.put(Some(name.local_name.clone()), value);
+                            this.data.namespace_fragments.push(this.taken);
                             this.into_state_continue(InsideOpeningTag(InsideTag))
                         }
                     }
@@ -880,10 +1634,13 @@ impl PullParser {
                         match value.as_slice() {
                             val if val == namespace::NS_XMLNS_PREFIX ||
                                    val == namespace::NS_XML_PREFIX =>
-                                Some(self_error!(this; "Namespace '{}' cannot be default", value)),
-                            _ => {
-                                this.nst.put(None, value.clone());
+                                Some(self_error!(this; InvalidNamespaceBinding { prefix: None, uri: value.clone() };
+                                                 "Namespace '{}' cannot be default", value)),
+                            _ => if this.nst.put(None, value.clone()) {
+                                this.data.namespace_fragments.push(this.taken);
                                 this.into_state_continue(InsideOpeningTag(InsideTag))
+                            } else {
+                                Some(duplicate(&name))
                             }
                         },
 
@@ -891,7 +1648,10 @@ impl PullParser {
                     _ => {
                         this.data.attributes.push(AttributeData {
                             name: name.clone(),
-                            value: value
+                            value: value,
+                            span: span.clone(),
+                            name_fragments: this.data.attr_name_fragments,
+                            value_fragments: this.taken
                         });
                         this.into_state_continue(InsideOpeningTag(InsideTag))
                     }
@@ -900,24 +1660,51 @@ impl PullParser {
         }
     }
 
+    /// Switches to reading an attribute value, checking whether the parser needs its text.
+    fn start_attribute_value(&mut self) -> Option<XmlEvent> {
+        self.data.attr_value_needed = self.record_fragments && self.attribute_value_needed();
+        self.into_state_continue(InsideOpeningTag(InsideAttributeValue))
+    }
+
+    /// Checks whether the value of the current attribute is used by the parser: it is so for
+    /// namespace declarations and for attributes whose values are normalized according to DTD.
+    fn attribute_value_needed(&self) -> bool {
+        let attr_name = self.data.attr_name.as_ref().unwrap();
+        if attr_name.prefix_ref() == Some(namespace::NS_XMLNS_PREFIX) ||
+           attr_name.prefix.is_none() && attr_name.local_name.as_slice() == namespace::NS_XMLNS_PREFIX {
+            return true;
+        }
+        let element_name = self.data.element_name.as_ref().unwrap();
+        match self.attribute_types.find(&element_name.to_str_proper()) {
+            Some(types) => match types.find(&attr_name.to_str_proper()) {
+                Some(&dtd::CDataType) | None => false,
+                Some(_) => true
+            },
+            None => false
+        }
+    }
+
     #[inline]
     fn emit_end_element(&mut self) -> Option<XmlEvent> {
         let mut name = self.data.take_element_name().unwrap();
+        let name_fragments = self.data.element_name_fragments;
 
         // check whether the name prefix is bound and fix its namespace
         match self.nst.get(&name.prefix) {
             Some("") => name.namespace = None,  // default namespace
             Some(ns) => name.namespace = Some(ns.to_string()),
-            None => return Some(self_error!(self; "Element {} prefix is unbound", name))
+            None => return Some(self_error!(self; UnboundPrefix(name.clone()); "Element {} prefix is unbound", name))
         }
 
         let op_name = self.est.pop().unwrap();
 
         if name == op_name {
             self.pop_namespace = true;
+            self.report_string(name_fragments);
             self.into_state_emit(OutsideTag, events::EndElement { name: name })
         } else {
-            Some(self_error!(self; "Unexpected closing tag: {}, expected {}", name, op_name))
+            Some(self_error!(self; MismatchedClosingTag { found: name.clone(), expected: op_name.clone() };
+                             "Unexpected closing tag: {}, expected {}", name, op_name))
         }
     }
 
@@ -927,13 +1714,16 @@ impl PullParser {
                 match name.prefix_ref() {
                     Some(prefix) if prefix == namespace::NS_XML_PREFIX ||
                                     prefix == namespace::NS_XMLNS_PREFIX =>
-                        Some(self_error!(this; "'{}' cannot be an element name prefix", name.prefix)),
+                        Some(self_error!(this; ReservedPrefix(prefix.to_string());
+                                         "'{}' cannot be an element name prefix", name.prefix)),
                     _ => {
                         this.data.element_name = Some(name.clone());
+                        this.data.element_name_fragments = this.taken;
                         match token {
                             Whitespace(_) => this.into_state_continue(InsideClosingTag(CTAfterName)),
                             TagEnd => this.emit_end_element(),
-                            _ => Some(self_error!(this; "Unexpected token inside closing tag: {}", token))
+                            _ => Some(self_error!(this; UnexpectedToken { found: token.to_string(), expected: ">", context: "closing tag" };
+                                                  "Unexpected token inside closing tag: {}", token))
                         }
                     }
                 }
@@ -941,7 +1731,8 @@ impl PullParser {
             CTAfterName => match t {
                 Whitespace(_) => None,  //  Skip whitespace
                 TagEnd => self.emit_end_element(),
-                _ => Some(self_error!(self; "Unexpected token inside closing tag: {}", t))
+                _ => Some(self_error!(self; UnexpectedToken { found: t.to_string(), expected: ">", context: "closing tag" };
+                                      "Unexpected token inside closing tag: {}", t))
             }
         }
     }
@@ -949,7 +1740,9 @@ impl PullParser {
     fn inside_comment(&mut self, t: Token) -> Option<XmlEvent> {
         match t {
             // Double dash is illegal inside a comment
-            Chunk(ref s) if s.as_slice() == "--" => Some(self_error!(self; "Unexpected token inside a comment: --")),
+            Chunk(ref s) if s.as_slice() == "--" =>
+                Some(self_error!(self; UnexpectedToken { found: "--".to_string(), expected: "-->", context: "comment" };
+                                 "Unexpected token inside a comment: --")),
 
             CommentEnd if self.config.ignore_comments => {
                 self.lexer.enable_errors();
@@ -959,12 +1752,21 @@ impl PullParser {
             CommentEnd => {
                 self.lexer.enable_errors();
                 let data = self.take_buf();
+                let taken = self.taken;
+                self.report_string(taken);
                 self.into_state_emit(OutsideTag, events::Comment(data))
             }
 
+            // the text has been appended by the lexer, but the buffer must not be modified
+            Text(len, _) if self.config.ignore_comments => {
+                let n = self.buf.len() - len;
+                self.buf.truncate(n);
+                None
+            }
+
             _ if self.config.ignore_comments => None,  // Do not modify buffer if ignoring the comment
 
-            _ => self.append_str_continue(t.to_string().as_slice()),
+            _ => self.append_token_continue(&t),
         }
     }
 
@@ -976,16 +1778,20 @@ impl PullParser {
                     None
                 } else {
                     let data = self.take_buf();
+                    let taken = self.taken;
+                    self.report_string(taken);
                     Some(events::CData(data))
                 };
                 self.into_state(OutsideTag, event)
             }
 
-            Whitespace(_) => self.append_str_continue(t.to_string().as_slice()),
+            Whitespace(_) => self.append_token_continue(&t),
+
+            Text(len, whitespace) => self.text_continue(len, whitespace),
 
             _ => {
                 self.inside_whitespace = false;
-                self.append_str_continue(t.to_string().as_slice())
+                self.append_token_continue(&t)
             }
         }
     }
@@ -1002,60 +1808,185 @@ impl PullParser {
             }
 
             ReferenceEnd => {
-                // TODO: check for unicode correctness
                 let name = self.data.take_ref_data();
                 let name_len = name.len();  // compute once
+                let version = self.lexer.version();
                 let c = match name.as_slice() {
                     "lt"   => Ok('<'),
                     "gt"   => Ok('>'),
                     "amp"  => Ok('&'),
                     "apos" => Ok('\''),
                     "quot" => Ok('"'),
-                    ""     => Err(self_error!(self; "Encountered empty entity")),
+                    ""     => Err(self_error!(self; UnexpectedToken { found: ";".to_string(), expected: "entity name", context: "reference" };
+                                                  "Encountered empty entity")),
                     _ if name_len > 2 && name.as_slice().slice(0, 2) == "#x" => {
                         let num_str = name.as_slice().slice(2, name_len);
                         if num_str == "0" {
-                            Err(self_error!(self; "Null character entity is not allowed"))
+                            Err(self.reference_error(InvalidCharacterReference(name.clone()),
+                                                    "Null character entity is not allowed".to_string()))
                         } else {
                             match from_str_radix(num_str, 16).and_then(char::from_u32) {
-                                Some(c) => Ok(c),
-                                None    => Err(self_error!(self; "Invalid hexadecimal character number in an entity: {}", name))
+                                Some(c) if is_valid_referenced_char(c, version) => Ok(c),
+                                Some(c) => Err(self.reference_error(InvalidCharacterReference(name.clone()), format!(
+                                    "Character reference to U+{:04X} is not allowed in XML {}", c as u32, version
+                                ))),
+                                None    => Err(self.reference_error(InvalidCharacterReference(name.clone()), format!(
+                                    "Invalid hexadecimal character number in an entity: {}", name
+                                )))
                             }
                         }
                     }
                     _ if name_len > 1 && name.as_slice().char_at(0) == '#' => {
                         let num_str = name.as_slice().slice(1, name_len);
                         if num_str == "0" {
-                            Err(self_error!(self; "Null character entity is not allowed"))
+                            Err(self.reference_error(InvalidCharacterReference(name.clone()),
+                                                    "Null character entity is not allowed".to_string()))
                         } else {
                             match from_str_radix(num_str, 10).and_then(char::from_u32) {
-                                Some(c) => Ok(c),
-                                None    => Err(self_error!(self; "Invalid decimal character number in an entity: {}", name))
+                                Some(c) if is_valid_referenced_char(c, version) => Ok(c),
+                                Some(c) => Err(self.reference_error(InvalidCharacterReference(name.clone()), format!(
+                                    "Character reference to U+{:04X} is not allowed in XML {}", c as u32, version
+                                ))),
+                                None    => Err(self.reference_error(InvalidCharacterReference(name.clone()), format!(
+                                    "Invalid decimal character number in an entity: {}", name
+                                )))
                             }
                         }
                     },
-                    _ => Err(self_error!(self; "Unexpected entity: {}", name))
+                    _ => return self.expand_entity(name.clone(), prev_st)
                 };
                 match c {
                     Ok(c) => {
-                        self.buf.push(c);
+                        if prev_st == OutsideTag && !is_whitespace_char(c) {
+                            self.inside_whitespace = false;
+                        }
+                        self.push_char(c, false);
                         self.into_state_continue(prev_st)
                     }
                     Err(e) => Some(e)
                 }
             }
 
-            _ => Some(self_error!(self; "Unexpected token inside an entity: {}", t))
+            _ => Some(self_error!(self; UnexpectedToken { found: t.to_string(), expected: "name character or ;", context: "reference" };
+                                  "Unexpected token inside an entity: {}", t))
         }
     }
+
+    /// Returns an error event positioned at the beginning of the current reference.
+    fn reference_error(&self, kind: ErrorKind, msg: String) -> XmlEvent {
+        let (row, col) = self.data.ref_position;
+        events::Error(Error::new_full(row, col, kind, msg))
+    }
+
+    /// Expands a reference to a general entity which is not predefined.
+    ///
+    /// Replacement text of entities declared in the DTD is inserted into the lexer input, so
+    /// markup and references inside it are parsed as usual. Values of extra entities from
+    /// the parser configuration are appended to the buffer verbatim.
+    fn expand_entity(&mut self, name: String, prev_st: State) -> Option<XmlEvent> {
+        let declared = self.entities.find(&name).map(|v| v.clone());
+        match declared {
+            Some(dtd::InternalEntity(text)) => {
+                if self.lexer.is_entity_open(name.as_slice()) {
+                    return Some(self_error!(self; RecursiveEntity(name.clone()); "Recursive entity reference: {}", name));
+                }
+                if self.lexer.entity_depth() >= self.config.max_entity_expansion_depth {
+                    let limit = DepthLimit(self.config.max_entity_expansion_depth);
+                    return Some(self_error!(self; EntityExpansionLimit { entity: name.clone(), limit: limit };
+                        "Entity expansion limit exceeded: {} is nested deeper than {} levels",
+                        name, self.config.max_entity_expansion_depth
+                    ));
+                }
+                match self.account_expansion(name.as_slice(), text.len()) {
+                    Some(e) => return Some(e),
+                    None => {}
+                }
+                self.lexer.push_entity(name, text.as_slice());
+                self.into_state_continue(prev_st)
+            }
+
+            Some(dtd::ExternalEntity { notation: Some(_), .. }) =>
+                Some(self_error!(self; UnparsedEntityReference(name.clone());
+                                 "Unparsed entity cannot be referenced: {}", name)),
+
+            Some(dtd::ExternalEntity { .. }) =>
+                Some(self_error!(self; ExternalEntityReference(name.clone());
+                                 "External entities are not supported: {}", name)),
+
+            None => match self.config.extra_entities.find(&name).map(|v| v.clone()) {
+                Some(value) => {
+                    match self.account_expansion(name.as_slice(), value.len()) {
+                        Some(e) => return Some(e),
+                        None => {}
+                    }
+                    if prev_st == OutsideTag && value.as_slice().chars().any(|c| !is_whitespace_char(c)) {
+                        self.inside_whitespace = false;
+                    }
+                    self.push_str(value.as_slice(), false);
+                    self.into_state_continue(prev_st)
+                }
+                None => Some(self_error!(self; UndefinedEntity(name.clone()); "Unexpected entity: {}", name))
+            }
+        }
+    }
+
+    /// Adds the size of replacement text of an entity to the total and checks that
+    /// expansion limits from the configuration are not exceeded.
+    fn account_expansion(&mut self, name: &str, size: uint) -> Option<XmlEvent> {
+        self.expanded_size += size;
+        if self.expanded_size > self.config.max_entity_expansion_size {
+            let limit = SizeLimit(self.config.max_entity_expansion_size);
+            Some(self_error!(self; EntityExpansionLimit { entity: name.to_string(), limit: limit };
+                "Entity expansion limit exceeded: expanding {} makes replacement text longer than {} bytes",
+                name, self.config.max_entity_expansion_size
+            ))
+        } else if self.config.max_entity_expansion_ratio > 0 &&
+                  self.expanded_size > ENTITY_RATIO_CHECK_THRESHOLD &&
+                  self.expanded_size / self.config.max_entity_expansion_ratio > self.lexer.input_size() {
+            let limit = RatioLimit(self.config.max_entity_expansion_ratio);
+            Some(self_error!(self; EntityExpansionLimit { entity: name.to_string(), limit: limit };
+                "Entity expansion limit exceeded: expanding {} makes replacement text more than {} times longer than the document",
+                name, self.config.max_entity_expansion_ratio
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Removes leading and trailing spaces and replaces sequences of spaces with a single space.
+///
+/// Only space characters (`#x20`) are affected; other white space can only get into attribute
+/// values through character references, and it must be preserved.
+fn collapse_spaces(value: &str) -> String {
+    value.split(' ').filter(|s| !s.is_empty()).collect::<Vec<&str>>().connect(" ")
+}
+
+/// Appends a fragment to the list, merging it with the last one if they are adjacent.
+fn push_fragment(fragments: &mut Vec<Fragment>, fragment: Fragment) {
+    let merged = match (fragments.last(), fragment) {
+        (Some(&SourceFragment(start, end)), SourceFragment(s, e)) if end == s => Some(SourceFragment(start, e)),
+        (Some(&EventFragment(start, end)), EventFragment(s, e)) if end == s => Some(EventFragment(start, e)),
+        _ => None
+    };
+    match merged {
+        Some(f) => { fragments.pop(); fragments.push(f); }
+        None => fragments.push(fragment)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::BufReader;
-
-    use common::{Name, Attribute};
-    use reader::parser::PullParser;
+    use std::cmp;
+    use std::io;
+    use std::io::{BufReader, MemReader, IoResult, IoError};
+    use std::slice::bytes;
+
+    use common::{Name, Attribute, HasPosition, Span};
+    use common::{UnexpectedToken, UnclosedElement, MismatchedClosingTag, UnboundPrefix, UndefinedEntity};
+    use common::{SyntaxError, WellFormednessError, NamespaceError, DecodingError, InputError};
+    use common::{EntityExpansionLimit, RatioLimit};
+    use reader::parser::{PullParser, SourceFragment, EventFragment};
     use reader::ParserConfig;
     use reader::events;
 
@@ -1104,6 +2035,290 @@ mod tests {
         expect_event!(r, p, events::EndDocument);
     }
 
+    #[test]
+    fn utf16_document_with_bom() {
+        let data: Vec<u8> = "\uFEFF<?xml version=\"1.0\" encoding=\"UTF-16\"?><a>\xE9</a>"
+            .utf16_units()
+            .flat_map(|u| vec![(u & 0xFF) as u8, (u >> 8) as u8].into_iter())
+            .collect();
+        let mut r = BufReader::new(data.as_slice());
+        let mut p = new_parser();
+
+        expect_event!(r, p, events::StartDocument { ref encoding, .. } if encoding.as_slice() == "UTF-16");
+        expect_event!(r, p, events::StartElement { ref name, .. } if *name == Name::new_local("a"));
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "\xE9");
+        expect_event!(r, p, events::EndElement { ref name } if *name == Name::new_local("a"));
+        expect_event!(r, p, events::EndDocument);
+    }
+
+    #[test]
+    fn latin1_document() {
+        let data = b"<?xml version='1.0' encoding='ISO-8859-1'?><a>caf\xE9</a>";
+        let mut r = BufReader::new(data);
+        let mut p = new_parser();
+
+        expect_event!(r, p, events::StartDocument { ref encoding, .. } if encoding.as_slice() == "ISO-8859-1");
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "caf\xE9");
+    }
+
+    #[test]
+    fn conflicting_encoding_declaration() {
+        let (mut r, mut p) = test_data!("\uFEFF<?xml version='1.0' encoding='ISO-8859-1'?><a/>");
+
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Declared encoding ISO-8859-1 conflicts with detected encoding UTF-8"
+        );
+    }
+
+    #[test]
+    fn doctype_with_internal_subset() {
+        let (mut r, mut p) = test_data!(r#"
+            <!DOCTYPE a SYSTEM "a.dtd" [
+                <!ELEMENT a (#PCDATA)>
+                <!ATTLIST a x CDATA "1>2">
+                <!-- > -->
+            ]>
+            <a x="y"/>
+        "#);
+
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Doctype { ref name, ref public_id, ref system_id, ref declarations }
+            if name.as_slice() == "a" && public_id.is_none() &&
+               *system_id == Some("a.dtd".to_string()) &&
+               declarations.len() == 3
+        );
+        expect_event!(r, p, events::StartElement { ref name, .. } if *name == Name::new_local("a"));
+        expect_event!(r, p, events::EndElement { ref name } if *name == Name::new_local("a"));
+        expect_event!(r, p, events::EndDocument);
+    }
+
+    #[test]
+    fn doctype_after_root_element() {
+        let (mut r, mut p) = test_data!(r#"<a/><!DOCTYPE a>"#);
+
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::EndElement { .. });
+        expect_event!(r, p, events::Error(ref e) if e.msg() == "Unexpected token: <!DOCTYPE");
+    }
+
+    #[test]
+    fn declared_entities_expansion() {
+        let (mut r, mut p) = test_data!(r#"
+            <!DOCTYPE a [
+                <!ENTITY who "world">
+                <!ENTITY greeting "hello, &who;">
+                <!ENTITY q '"'>
+                <!ENTITY b "<b>&#98;old</b>">
+            ]>
+            <a attr="&q;&greeting;&q;">&greeting; &b;</a>
+        "#);
+
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Doctype { .. });
+        expect_event!(r, p, events::StartElement { ref attributes, .. }
+            if attributes.len() == 1 &&
+               attributes[0] == Attribute::new_local("attr", "\"hello, world\"")
+        );
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "hello, world ");
+        expect_event!(r, p, events::StartElement { ref name, .. } if *name == Name::new_local("b"));
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "bold");
+        expect_event!(r, p, events::EndElement { ref name } if *name == Name::new_local("b"));
+        expect_event!(r, p, events::EndElement { ref name } if *name == Name::new_local("a"));
+        expect_event!(r, p, events::EndDocument);
+    }
+
+    #[test]
+    fn recursive_entity() {
+        let (mut r, mut p) = test_data!(r#"
+            <!DOCTYPE a [
+                <!ENTITY x "x &y;">
+                <!ENTITY y "y &x;">
+            ]>
+            <a>&x;</a>
+        "#);
+
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Doctype { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e) if e.msg() == "Recursive entity reference: x");
+    }
+
+    #[test]
+    fn entity_expansion_limits() {
+        static DATA: &'static str = r#"
+            <!DOCTYPE lolz [
+                <!ENTITY lol "lol">
+                <!ENTITY lol1 "&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;">
+                <!ENTITY lol2 "&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;">
+                <!ENTITY lol3 "&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;">
+            ]>
+            <lolz>&lol3;</lolz>
+        "#;
+
+        let mut r = BufReader::new(DATA.as_bytes());
+        let mut p = PullParser::new(ParserConfig::new().max_entity_expansion_size(1000));
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Doctype { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Entity expansion limit exceeded: expanding lol makes replacement text longer than 1000 bytes"
+        );
+
+        let mut r = BufReader::new(DATA.as_bytes());
+        let mut p = PullParser::new(ParserConfig::new().max_entity_expansion_depth(2));
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Doctype { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Entity expansion limit exceeded: lol1 is nested deeper than 2 levels"
+        );
+    }
+
+    #[test]
+    fn entity_expansion_ratio_limit() {
+        static DATA: &'static str = r#"
+            <!DOCTYPE lolz [
+                <!ENTITY lol "lol">
+                <!ENTITY lol1 "&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;">
+                <!ENTITY lol2 "&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;">
+                <!ENTITY lol3 "&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;">
+                <!ENTITY lol4 "&lol3;&lol3;&lol3;&lol3;&lol3;&lol3;&lol3;&lol3;&lol3;&lol3;">
+                <!ENTITY lol5 "&lol4;&lol4;&lol4;&lol4;&lol4;&lol4;&lol4;&lol4;&lol4;&lol4;">
+            ]>
+            <lolz>&lol5;&lol5;&lol5;&lol5;</lolz>
+        "#;
+
+        let mut r = BufReader::new(DATA.as_bytes());
+        let mut p = PullParser::new(ParserConfig::new());
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Doctype { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if match *e.kind() {
+                EntityExpansionLimit { limit: RatioLimit(10), .. } => true,
+                _ => false
+            }
+        );
+
+        // zero ratio disables the limit
+        let mut r = BufReader::new(DATA.as_bytes());
+        let mut p = PullParser::new(ParserConfig::new().max_entity_expansion_ratio(0));
+        loop {
+            match p.next(&mut r) {
+                events::Error(e) => panic!("Unexpected error: {}", e),
+                events::EndDocument => break,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn extra_entities_from_config() {
+        static DATA: &'static str = r#"<a title="&copy;">&nbsp;&lt;&unknown;</a>"#;
+        let mut r = BufReader::new(DATA.as_bytes());
+        let mut p = PullParser::new(ParserConfig::new().add_entity("nbsp", "\u00A0").add_entity("copy", "\u00A9"));
+
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { ref attributes, .. }
+            if attributes[0] == Attribute::new_local("title", "\u00A9")
+        );
+        expect_event!(r, p, events::Error(ref e) if e.msg() == "Unexpected entity: unknown");
+    }
+
+    #[test]
+    fn attribute_value_normalization() {
+        let (mut r, mut p) = test_data!("
+            <!DOCTYPE a [
+                <!ATTLIST a tokens NMTOKENS #IMPLIED>
+                <!ENTITY ws \"&#9; \">
+            ]>
+            <a plain=\"x\ty\n z&#9;&#10;\" tokens=\"  x\t\ty &ws; z  \"/>
+        ");
+
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Doctype { .. });
+        expect_event!(r, p, events::StartElement { ref attributes, .. }
+            if attributes.len() == 2 &&
+               attributes[0] == Attribute::new_local("plain", "x y  z\t\n") &&
+               attributes[1] == Attribute::new_local("tokens", "x y z")
+        );
+    }
+
+    #[test]
+    fn end_of_line_normalization() {
+        let (mut r, mut p) = test_data!("<a x='1\r\n2'>line 1\r\nline 2\rline 3</a>");
+
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { ref attributes, .. }
+            if attributes[0] == Attribute::new_local("x", "1 2")
+        );
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "line 1\nline 2\nline 3");
+    }
+
+    #[test]
+    fn duplicate_attributes() {
+        let (mut r, mut p) = test_data!(r#"<a x="1" y="2" x="3"/>"#);
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Duplicate attribute: x" && e.row() == 0 && e.col() == 15 &&
+               e.span().map(|s| (s.start.offset, s.end.offset)) == Some((15, 20))
+        );
+
+        let (mut r, mut p) = test_data!(r#"
+<a xmlns:p="urn:x" xmlns:q="urn:x"
+   p:attr="1" q:attr="2"/>"#);
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Attribute q:attr duplicates attribute p:attr: both prefixes are bound to urn:x" &&
+               e.row() == 2 && e.col() == 14
+        );
+
+        let (mut r, mut p) = test_data!(r#"<a xmlns:p="urn:x" xmlns:p="urn:y"/>"#);
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Duplicate attribute: xmlns:p" && e.row() == 0 && e.col() == 19 &&
+               e.span().map(|s| (s.start.offset, s.end.offset)) == Some((19, 34))
+        );
+
+        let (mut r, mut p) = test_data!(r#"<a xmlns="urn:x" xmlns='urn:y' b="1"/>"#);
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Duplicate attribute: xmlns" && e.row() == 0 && e.col() == 17 &&
+               e.span().map(|s| (s.start.offset, s.end.offset)) == Some((17, 30))
+        );
+    }
+
+    #[test]
+    fn unprefixed_attributes_are_not_in_default_namespace() {
+        // the legal example from section 6.3 of Namespaces in XML spec
+        let (mut r, mut p) = test_data!(r#"<x xmlns="urn:a" xmlns:n1="urn:a"><good a="1" n1:a="2"/></x>"#);
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::StartElement { ref name, ref attributes, .. }
+            if name.namespace == Some("urn:a".to_string()) && attributes.len() == 2 &&
+               attributes[0].name.namespace == None &&
+               attributes[1].name.namespace == Some("urn:a".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_character_references() {
+        let (mut r, mut p) = test_data!("<a>\n  text &#x1;</a>");
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if e.msg() == "Character reference to U+0001 is not allowed in XML 1.0" &&
+               e.row() == 1 && e.col() == 7
+        );
+
+        let (mut r, mut p) = test_data!("<?xml version='1.1'?><a>&#1;&#x7F;</a>");
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "\x01\x7F");
+    }
+
     #[test]
     fn opening_tag_in_attribute_value() {
         let (mut r, mut p) = test_data!(r#"
@@ -1115,4 +2330,223 @@ mod tests {
             if e.msg() == "Unexpected token inside attribute value: <"
         );
     }
+
+    #[test]
+    fn error_kinds() {
+        let (mut r, mut p) = test_data!("<a><b></a>");
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if *e.kind() == MismatchedClosingTag { found: Name::new_local("a"), expected: Name::new_local("b") } &&
+               e.category() == WellFormednessError
+        );
+
+        let (mut r, mut p) = test_data!("<p:a/>");
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if *e.kind() == UnboundPrefix(Name { prefix: Some("p".to_string()), namespace: None,
+                                                 local_name: "a".to_string() }) &&
+               e.category() == NamespaceError
+        );
+
+        let (mut r, mut p) = test_data!("<a>&unknown;</a>");
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e) if *e.kind() == UndefinedEntity("unknown".to_string()));
+
+        let (mut r, mut p) = test_data!("<a>text");
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e) if *e.kind() == UnclosedElement(Name::new_local("a")));
+
+        let (mut r, mut p) = test_data!("<a></a x>");
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if match *e.kind() {
+                UnexpectedToken { ref found, expected: ">", context: "closing tag" } => found.as_slice() == "x",
+                _ => false
+            } && e.category() == SyntaxError
+        );
+    }
+
+    #[test]
+    fn event_spans() {
+        fn bounds(span: &Span) -> ((uint, uint, uint), (uint, uint, uint)) {
+            ((span.start.row, span.start.col, span.start.offset), (span.end.row, span.end.col, span.end.offset))
+        }
+
+        let (mut r, mut p) = test_data!("<?xml version=\"1.0\"?>\n<a x=\"1\" y='2'>text<b/></a>");
+        expect_event!(r, p, events::StartDocument { .. });
+        assert_eq!(bounds(p.last_event_span()), ((0, 0, 0), (0, 21, 21)));
+        expect_event!(r, p, events::StartElement { .. });
+        assert_eq!(bounds(p.last_event_span()), ((1, 0, 22), (1, 15, 37)));
+        let attribute_bounds: Vec<((uint, uint, uint), (uint, uint, uint))> =
+            p.last_attribute_spans().iter().map(|s| bounds(s)).collect();
+        assert_eq!(attribute_bounds,
+                   vec!(((1, 3, 25), (1, 8, 30)), ((1, 9, 31), (1, 14, 36))));
+        expect_event!(r, p, events::Characters(_));
+        assert_eq!(bounds(p.last_event_span()), ((1, 15, 37), (1, 19, 41)));
+        assert!(p.last_attribute_spans().is_empty());
+        expect_event!(r, p, events::StartElement { .. });
+        assert_eq!(bounds(p.last_event_span()), ((1, 19, 41), (1, 23, 45)));
+        expect_event!(r, p, events::EndElement { .. });
+        assert_eq!(bounds(p.last_event_span()), ((1, 19, 41), (1, 23, 45)));
+        expect_event!(r, p, events::EndElement { .. });
+        assert_eq!(bounds(p.last_event_span()), ((1, 23, 45), (1, 27, 49)));
+        expect_event!(r, p, events::EndDocument);
+        assert_eq!(bounds(p.last_event_span()), ((1, 27, 49), (1, 27, 49)));
+
+        // the document start is implied
+        let (mut r, mut p) = test_data!("  <a/>");
+        expect_event!(r, p, events::StartDocument { .. });
+        assert_eq!(bounds(p.last_event_span()), ((0, 2, 2), (0, 2, 2)));
+    }
+
+    #[test]
+    fn event_string_fragments() {
+        let (mut r, mut p) = test_data!("<a x=\"1&amp;2\">t&lt;u</a>");
+        p.record_fragments();
+        expect_event!(r, p, events::StartDocument { .. });
+        assert_eq!(p.last_event_string_count(), 0);
+
+        // the value is not needed by the parser, so only the replacement text is copied
+        expect_event!(r, p, events::StartElement { ref attributes, .. } if attributes[0].value.as_slice() == "&");
+        assert_eq!(p.last_event_string_count(), 3);
+        assert_eq!(p.last_event_fragments(0), Some([SourceFragment(1, 2)].as_slice()));
+        assert_eq!(p.last_event_fragments(1), Some([SourceFragment(3, 4)].as_slice()));
+        assert_eq!(p.last_event_fragments(2),
+                   Some([SourceFragment(6, 7), EventFragment(0, 1), SourceFragment(12, 13)].as_slice()));
+
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "<");
+        assert_eq!(p.last_event_fragments(0),
+                   Some([SourceFragment(15, 16), EventFragment(0, 1), SourceFragment(20, 21)].as_slice()));
+
+        expect_event!(r, p, events::EndElement { .. });
+        assert_eq!(p.last_event_fragments(0), Some([SourceFragment(23, 24)].as_slice()));
+        assert_eq!(p.last_event_fragments(1), None);
+    }
+
+    #[test]
+    fn bulk_text_scanning() {
+        fn parse(data: &str, config: ParserConfig) -> Vec<events::XmlEvent> {
+            let mut r = BufReader::new(data.as_bytes());
+            let mut p = PullParser::new(config);
+            let mut result = Vec::new();
+            loop {
+                let ev = p.next(&mut r);
+                result.push(ev.clone());
+                match ev {
+                    events::EndDocument | events::Error(_) => return result,
+                    _ => {}
+                }
+            }
+        }
+
+        let documents = [
+            "<a>text with > and = and \"quotes' and ;</a>",
+            "<a>\r\n  <b x='a\tb\nc \"q\" &amp; /> -- ]'>caf\u00E9 \U0001F600 ]] - ? /</b>\n</a>",
+            "<a><!-- comment - with -- dashes --></a>",
+            "<a><!-- a comment -><!-- --> <![CDATA[ <x> ] ]] ]]> ]]></a>",
+            "<a>text ?> text</a>",
+            "<a>text /> text</a>",
+            "<a>text --> text</a>",
+            "<a>text \x01 text</a>",
+            "<!DOCTYPE a [<!ENTITY e 'entity &#x26; text'>]><a x='&e;'>&e;more</a>",
+            "<!DOCTYPE a [<!ENTITY q \"it's &#x22;q&#x22;\tand -- ]]\">]><a x='&q; y' y=\"&q;\">&q;&q;</a>",
+            "<?xml version='1.0' encoding='ISO-8859-1'?><a x='caf\u00E9 \"q\"\r\n'>caf\u00E9 - ]] /\r\ntext</a>",
+            "<?xml version='1.1'?><a x='a\r\u0085b\tc'>\r\u0085line\u2028 end\r</a>",
+            "<?xml version='1.1'?><a>text \u007F text</a>"
+        ];
+        fn config(i: uint) -> ParserConfig {
+            match i {
+                0 => ParserConfig::new(),
+                1 => ParserConfig::new().ignore_comments(false).coalesce_characters(false),
+                _ => ParserConfig::new().cdata_to_characters(true).trim_whitespace(true)
+            }
+        }
+
+        for data in documents.iter() {
+            for i in range(0u, 3) {
+                let expected = parse(*data, config(i).bulk_text_scanning(false));
+                assert_eq!(parse(*data, config(i)), expected);
+            }
+        }
+    }
+
+    /// A buffer which fails once when the given number of bytes has been consumed.
+    struct FlakyBuffer {
+        data: Vec<u8>,
+        pos: uint,
+        fail_at: Option<uint>
+    }
+
+    impl Reader for FlakyBuffer {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            let n = {
+                let available = try!(self.fill_buf());
+                let n = cmp::min(available.len(), buf.len());
+                bytes::copy_memory(buf, available.slice_to(n));
+                n
+            };
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl Buffer for FlakyBuffer {
+        fn fill_buf<'a>(&'a mut self) -> IoResult<&'a [u8]> {
+            let fail_at = self.fail_at;
+            match fail_at {
+                Some(n) if n == self.pos => {
+                    self.fail_at = None;
+                    Err(IoError { kind: io::TimedOut, desc: "timed out", detail: None })
+                }
+                _ if self.pos == self.data.len() => Err(io::standard_error(io::EndOfFile)),
+                fail_at => Ok(self.data.slice(self.pos, fail_at.unwrap_or(self.data.len())))
+            }
+        }
+
+        fn consume(&mut self, amt: uint) {
+            self.pos += amt;
+        }
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut r = MemReader::new(b"<a>caf\xE9</a>".to_vec());
+        let mut p = new_parser();
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if *e.kind() == DecodingError { encoding: "UTF-8", offset: 6 } &&
+               e.msg() == "Invalid UTF-8 byte sequence at offset 6"
+        );
+        expect_event!(r, p, events::Error(ref e) if *e.kind() == DecodingError { encoding: "UTF-8", offset: 6 });
+
+        // reading continues after an error of the buffer
+        let mut r = FlakyBuffer { data: b"<a>text</a>".to_vec(), pos: 0, fail_at: Some(5) };
+        let mut p = new_parser();
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if match *e.kind() { InputError(ref e) => e.kind == io::TimedOut, _ => false }
+        );
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "text");
+        expect_event!(r, p, events::EndElement { .. });
+        expect_event!(r, p, events::EndDocument);
+
+        // the error may happen in the middle of a multi-byte character
+        let mut r = FlakyBuffer { data: b"<a>caf\xC3\xA9</a>".to_vec(), pos: 0, fail_at: Some(7) };
+        let mut p = new_parser();
+        expect_event!(r, p, events::StartDocument { .. });
+        expect_event!(r, p, events::StartElement { .. });
+        expect_event!(r, p, events::Error(ref e)
+            if match *e.kind() { InputError(ref e) => e.kind == io::TimedOut, _ => false }
+        );
+        expect_event!(r, p, events::Characters(ref data) if data.as_slice() == "caf\xE9");
+        expect_event!(r, p, events::EndElement { .. });
+        expect_event!(r, p, events::EndDocument);
+    }
 }
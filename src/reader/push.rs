@@ -0,0 +1,269 @@
+//! Contains push-based interface for the XML parser.
+//!
+//! `PushReader` is useful when the document arrives in pieces, for example, from a non-blocking
+//! socket, and no blocking `Buffer` can be given to `EventReader`.
+
+use std::io;
+use std::io::{IoResult, IoError};
+use std::cmp;
+use std::slice::bytes;
+
+use common::{Span, ErrorKind, InputError};
+
+use reader::config::ParserConfig;
+use reader::events;
+use reader::events::XmlEvent;
+use reader::parser::PullParser;
+
+/// Push-based XML parser.
+///
+/// The input is passed to the parser with `feed()` method in chunks of arbitrary size. A chunk
+/// can end anywhere: in the middle of a multi-byte character, of a token like `<![CDATA[` or
+/// of an entity reference. Events are pulled with `next()` method, which returns `None` when
+/// more input is needed to produce the next event. After the last chunk `finish()` method must
+/// be called, so the parser can handle the end of the document.
+///
+/// Apart from that, the parser behaves exactly like `EventReader` with the same configuration.
+pub struct PushReader {
+    parser: PullParser,
+    input: FeedBuffer
+}
+
+impl PushReader {
+    /// Creates a new push parser with the default configuration.
+    #[inline]
+    pub fn new() -> PushReader {
+        PushReader::new_with_config(ParserConfig::new())
+    }
+
+    /// Creates a new push parser with the provided configuration.
+    #[inline]
+    pub fn new_with_config(config: ParserConfig) -> PushReader {
+        PushReader {
+            parser: PullParser::new(config),
+            input: FeedBuffer { data: Vec::new(), pos: 0, discarded: 0, finished: false }
+        }
+    }
+
+    /// Appends a chunk of the document to the parser input.
+    ///
+    /// # Failure
+    ///
+    /// Fails if called after `finish()`.
+    pub fn feed(&mut self, chunk: &[u8]) {
+        if self.input.finished {
+            panic!("Input has already been finished");
+        }
+        self.input.feed(chunk);
+    }
+
+    /// Signals that the whole document has been fed to the parser.
+    #[inline]
+    pub fn finish(&mut self) {
+        self.input.finished = true;
+    }
+
+    /// Pulls the next XML event.
+    ///
+    /// Returns `None` if the input fed so far is not enough to produce the next event; the call
+    /// should be repeated after the next chunk is fed. After `finish()` has been called this
+    /// method never returns `None`.
+    ///
+    /// If returned event is `xml::event::Error` or `xml::event::EndDocument`, then
+    /// further calls to this method will return this event again.
+    pub fn next(&mut self) -> Option<XmlEvent> {
+        match self.parser.next(&mut self.input) {
+            events::Error(ref e) if is_need_more_input(e.kind()) => {
+                // the decoder may have consumed the beginning of an incomplete character,
+                // so the input is moved back to the end of the last decoded one
+                self.input.rewind_to(self.parser.input_offset());
+                None
+            }
+            ev => Some(ev)
+        }
+    }
+
+    /// Returns the fragment of the document occupied by the last event returned by `next()`.
+    ///
+    /// See `EventReader::last_event_span()` for details.
+    #[inline]
+    pub fn last_event_span<'a>(&'a self) -> &'a Span {
+        self.parser.last_event_span()
+    }
+
+    /// Returns spans of attributes of the last event returned by `next()`.
+    ///
+    /// See `EventReader::last_attribute_spans()` for details.
+    #[inline]
+    pub fn last_attribute_spans<'a>(&'a self) -> &'a [Span] {
+        self.parser.last_attribute_spans()
+    }
+}
+
+/// Description of the error which `FeedBuffer` returns when it runs out of data.
+static NEED_MORE_INPUT: &'static str = "more input is needed";
+
+fn is_need_more_input(kind: &ErrorKind) -> bool {
+    match *kind {
+        InputError(ref e) => e.kind == io::ResourceUnavailable && e.desc == NEED_MORE_INPUT,
+        _ => false
+    }
+}
+
+/// A buffer holding the input fed to `PushReader` which has not been decoded yet.
+///
+/// When its data is exhausted, the buffer reports `ResourceUnavailable` error instead of
+/// the end of file, unless the input is finished.
+struct FeedBuffer {
+    data: Vec<u8>,
+    pos: uint,
+    discarded: uint,  // number of bytes removed from the beginning of data
+    finished: bool
+}
+
+impl FeedBuffer {
+    fn feed(&mut self, chunk: &[u8]) {
+        if self.pos > 0 {
+            let rest = self.data.slice_from(self.pos).to_vec();
+            self.discarded += self.pos;
+            self.pos = 0;
+            self.data = rest;
+        }
+        self.data.push_all(chunk);
+    }
+
+    /// Moves the current position to the given offset from the beginning of the input.
+    #[inline]
+    fn rewind_to(&mut self, offset: uint) {
+        self.pos = offset - self.discarded;
+    }
+}
+
+impl Reader for FeedBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let n = {
+            let available = try!(self.fill_buf());
+            let n = cmp::min(available.len(), buf.len());
+            bytes::copy_memory(buf, available.slice_to(n));
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl Buffer for FeedBuffer {
+    fn fill_buf<'a>(&'a mut self) -> IoResult<&'a [u8]> {
+        let available = self.data.len() - self.pos;
+        // encoding detection looks at up to four first bytes of the input
+        let at_start = self.discarded + self.pos == 0;
+        if !self.finished && (available == 0 || at_start && available < 4) {
+            Err(IoError { kind: io::ResourceUnavailable, desc: NEED_MORE_INPUT, detail: None })
+        } else if available == 0 {
+            Err(io::standard_error(io::EndOfFile))
+        } else {
+            Ok(self.data.slice_from(self.pos))
+        }
+    }
+
+    #[inline]
+    fn consume(&mut self, amt: uint) {
+        self.pos += amt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reader::EventReader;
+    use reader::events;
+    use reader::events::XmlEvent;
+
+    use super::PushReader;
+
+    fn pull_all(data: &[u8]) -> Vec<XmlEvent> {
+        let mut r = EventReader::new_from_bytes_slice(data);
+        r.events().collect()
+    }
+
+    fn push_in_chunks(data: &[u8], chunk_size: uint) -> Vec<XmlEvent> {
+        let mut r = PushReader::new();
+        let mut result = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            r.feed(chunk);
+            loop {
+                match r.next() {
+                    Some(events::Error(e)) => {
+                        result.push(events::Error(e));
+                        return result;
+                    }
+                    Some(ev) => result.push(ev),
+                    None => break
+                }
+            }
+        }
+        r.finish();
+        loop {
+            let ev = r.next().unwrap();
+            result.push(ev.clone());
+            match ev {
+                events::EndDocument | events::Error(_) => break,
+                _ => {}
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn arbitrary_chunk_boundaries() {
+        static DATA: &'static str =
+            "<?xml version=\"1.0\"?>\r\n<a x=\"&amp;é\">café \U0001F600<![CDATA[<x>]]>&lt;&#x41;</a>";
+        let expected = pull_all(DATA.as_bytes());
+        assert_eq!(expected.last(), Some(&events::EndDocument));
+
+        for chunk_size in range(1u, 8) {
+            assert_eq!(push_in_chunks(DATA.as_bytes(), chunk_size), expected);
+        }
+    }
+
+    #[test]
+    fn utf16_input_in_chunks() {
+        let data = [0xFF, 0xFE, 0x3C, 0x00, 0x61, 0x00, 0x2F, 0x00, 0x3E, 0x00];
+        let expected = pull_all(data.as_slice());
+        assert_eq!(expected.last(), Some(&events::EndDocument));
+        assert_eq!(push_in_chunks(data.as_slice(), 1), expected);
+    }
+
+    #[test]
+    fn need_more_input() {
+        let mut r = PushReader::new();
+        assert!(r.next().is_none());
+
+        r.feed(b"<a>text");
+        match r.next() {
+            Some(events::StartDocument { .. }) => {}
+            e => panic!("Unexpected event: {}", e)
+        }
+        match r.next() {
+            Some(events::StartElement { .. }) => {}
+            e => panic!("Unexpected event: {}", e)
+        }
+        assert!(r.next().is_none());
+
+        r.feed(b"</a>");
+        match r.next() {
+            Some(events::Characters(ref data)) if data.as_slice() == "text" => {}
+            e => panic!("Unexpected event: {}", e)
+        }
+        match r.next() {
+            Some(events::EndElement { .. }) => {}
+            e => panic!("Unexpected event: {}", e)
+        }
+        assert!(r.next().is_none());
+
+        r.finish();
+        match r.next() {
+            Some(events::EndDocument) => {}
+            e => panic!("Unexpected event: {}", e)
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Contains SAX-like callback-based interface for the XML parser.
+//!
+//! An application implements `ContentHandler` trait and passes the handler to `parse` function
+//! together with an `EventReader`. The function pulls events from the reader and calls the
+//! corresponding methods of the handler until the end of the document, an error, or until
+//! the handler asks to stop.
+
+use std::io::Buffer;
+
+use common::{Error, Name, Attribute, XmlVersion};
+use namespace::{Namespace, NamespaceStack};
+use reader::EventReader;
+use reader::dtd::MarkupDeclaration;
+use reader::events;
+
+/// Tells `parse` whether it should continue after a handler method returns.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Flow {
+    /// Continue parsing.
+    Continue,
+    /// Stop parsing; no more handler methods will be called.
+    Stop
+}
+
+/// The way `parse` has finished.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Outcome {
+    /// The whole document has been parsed.
+    Completed,
+    /// The handler has asked to stop.
+    Stopped,
+    /// The document is malformed or could not be read; the error has also been passed
+    /// to `ContentHandler::error()`.
+    Failed(Error)
+}
+
+/// Receives notifications about the logical content of a document.
+///
+/// Methods are called in document order. All of them have default implementations which
+/// ignore the notification, so only the interesting ones have to be implemented. Methods which
+/// return `Flow` can stop parsing by returning `Stop`.
+pub trait ContentHandler {
+    /// Called once at the beginning of the document, with the data from XML declaration or
+    /// the default values if there is no declaration.
+    fn start_document(&mut self, _version: XmlVersion, _encoding: &str, _standalone: Option<bool>) -> Flow {
+        Continue
+    }
+
+    /// Called once at the end of the document.
+    fn end_document(&mut self) {}
+
+    /// Called on the document type declaration.
+    fn doctype(&mut self, _name: &str, _public_id: Option<&str>, _system_id: Option<&str>,
+               _declarations: &[MarkupDeclaration]) -> Flow {
+        Continue
+    }
+
+    /// Called before `start_element()` for every namespace prefix mapping introduced by
+    /// the element. `None` prefix means the default namespace.
+    fn start_prefix_mapping(&mut self, _prefix: Option<&str>, _uri: &str) -> Flow {
+        Continue
+    }
+
+    /// Called after `end_element()` for every mapping reported by `start_prefix_mapping()`
+    /// for the element.
+    fn end_prefix_mapping(&mut self, _prefix: Option<&str>) -> Flow {
+        Continue
+    }
+
+    /// Called at the beginning of an element, with its attributes and all namespace mappings
+    /// in scope.
+    fn start_element(&mut self, _name: &Name, _attributes: &[Attribute], _namespace: &Namespace) -> Flow {
+        Continue
+    }
+
+    /// Called at the end of an element.
+    fn end_element(&mut self, _name: &Name) -> Flow {
+        Continue
+    }
+
+    /// Called on character data.
+    ///
+    /// Adjacent character data may be reported in several calls, depending on the parser
+    /// configuration.
+    fn characters(&mut self, _data: &str) -> Flow {
+        Continue
+    }
+
+    /// Called on whitespace-only character data inside elements. By default passes the data
+    /// to `characters()`, because the parser does not tell element content from mixed content.
+    ///
+    /// It is not called if `whitespace_to_characters` parser option is set; `characters()`
+    /// is called instead.
+    fn ignorable_whitespace(&mut self, data: &str) -> Flow {
+        self.characters(data)
+    }
+
+    /// Called on the contents of a CDATA section. By default passes the data to `characters()`.
+    fn cdata(&mut self, data: &str) -> Flow {
+        self.characters(data)
+    }
+
+    /// Called on a processing instruction other than XML declaration.
+    fn processing_instruction(&mut self, _name: &str, _data: Option<&str>) -> Flow {
+        Continue
+    }
+
+    /// Called on a comment. Comments are reported only if `ignore_comments` parser
+    /// option is unset.
+    fn comment(&mut self, _data: &str) -> Flow {
+        Continue
+    }
+
+    /// Called when the document turns out to be malformed or cannot be read. No other
+    /// methods are called after it.
+    fn error(&mut self, _error: &Error) {}
+}
+
+/// Parses the document from the given reader, passing its content to the handler.
+///
+/// Parsing ends after the end of the document, after the first error, or when a handler
+/// method returns `Stop`.
+pub fn parse<B: Buffer, H: ContentHandler>(reader: &mut EventReader<B>, handler: &mut H) -> Outcome {
+    // namespaces of the open elements, used to find prefix mappings introduced by each of them
+    let mut scopes: Vec<Namespace> = vec!(NamespaceStack::default().squash());
+    let mut introduced: Vec<Vec<Option<String>>> = Vec::new();
+
+    macro_rules! try_flow(
+        ($e:expr) => (
+            match $e {
+                Continue => {}
+                Stop => return Stopped
+            }
+        )
+    )
+
+    loop {
+        match reader.next() {
+            events::StartDocument { version, encoding, standalone } =>
+                try_flow!(handler.start_document(version, encoding.as_slice(), standalone)),
+
+            events::Doctype { name, public_id, system_id, declarations } =>
+                try_flow!(handler.doctype(
+                    name.as_slice(),
+                    public_id.as_ref().map(|id| id.as_slice()),
+                    system_id.as_ref().map(|id| id.as_slice()),
+                    declarations.as_slice()
+                )),
+
+            events::StartElement { name, attributes, namespace } => {
+                let mut prefixes: Vec<Option<String>> = {
+                    let parent = scopes.last().unwrap();
+                    namespace.0.iter()
+                        .filter(|&(prefix, uri)| parent.get(prefix) != Some(uri.as_slice()))
+                        .map(|(prefix, _)| prefix.clone())
+                        .collect()
+                };
+                // mappings have no order in `Namespace`, so report them in a stable one
+                prefixes.sort();
+                for prefix in prefixes.iter() {
+                    let uri = namespace.get(prefix).unwrap();
+                    try_flow!(handler.start_prefix_mapping(prefix.as_ref().map(|p| p.as_slice()), uri));
+                }
+                try_flow!(handler.start_element(&name, attributes.as_slice(), &namespace));
+                scopes.push(namespace);
+                introduced.push(prefixes);
+            }
+
+            events::EndElement { name } => {
+                try_flow!(handler.end_element(&name));
+                scopes.pop();
+                for prefix in introduced.pop().unwrap().iter() {
+                    try_flow!(handler.end_prefix_mapping(prefix.as_ref().map(|p| p.as_slice())));
+                }
+            }
+
+            events::ProcessingInstruction { name, data } =>
+                try_flow!(handler.processing_instruction(name.as_slice(), data.as_ref().map(|d| d.as_slice()))),
+
+            events::CData(data) => try_flow!(handler.cdata(data.as_slice())),
+            events::Comment(data) => try_flow!(handler.comment(data.as_slice())),
+            events::Characters(data) => try_flow!(handler.characters(data.as_slice())),
+            events::Whitespace(data) => try_flow!(handler.ignorable_whitespace(data.as_slice())),
+
+            events::EndDocument => {
+                handler.end_document();
+                return Completed;
+            }
+
+            events::Error(e) => {
+                handler.error(&e);
+                return Failed(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::uint;
+    use std::io::BufReader;
+
+    use common::{Error, Name, Attribute, XmlVersion, MismatchedClosingTag};
+    use namespace::Namespace;
+    use reader::{EventReader, ParserConfig};
+
+    use super::{ContentHandler, Flow, Continue, Stop, Completed, Stopped, Failed, parse};
+
+    /// Records calls as strings and stops after the given number of them.
+    struct Recorder {
+        calls: Vec<String>,
+        limit: uint
+    }
+
+    impl Recorder {
+        fn new(limit: uint) -> Recorder {
+            Recorder { calls: Vec::new(), limit: limit }
+        }
+
+        fn record(&mut self, call: String) -> Flow {
+            self.calls.push(call);
+            if self.calls.len() < self.limit { Continue } else { Stop }
+        }
+    }
+
+    impl ContentHandler for Recorder {
+        fn start_document(&mut self, version: XmlVersion, encoding: &str, _: Option<bool>) -> Flow {
+            self.record(format!("start_document({}, {})", version, encoding))
+        }
+
+        fn end_document(&mut self) {
+            self.calls.push("end_document".to_string());
+        }
+
+        fn start_prefix_mapping(&mut self, prefix: Option<&str>, uri: &str) -> Flow {
+            self.record(format!("start_prefix_mapping({}, {})", prefix, uri))
+        }
+
+        fn end_prefix_mapping(&mut self, prefix: Option<&str>) -> Flow {
+            self.record(format!("end_prefix_mapping({})", prefix))
+        }
+
+        fn start_element(&mut self, name: &Name, attributes: &[Attribute], _: &Namespace) -> Flow {
+            self.record(format!("start_element({}, {})", name, attributes))
+        }
+
+        fn end_element(&mut self, name: &Name) -> Flow {
+            self.record(format!("end_element({})", name))
+        }
+
+        fn characters(&mut self, data: &str) -> Flow {
+            self.record(format!("characters({})", data))
+        }
+
+        fn processing_instruction(&mut self, name: &str, data: Option<&str>) -> Flow {
+            self.record(format!("processing_instruction({}, {})", name, data))
+        }
+
+        fn comment(&mut self, data: &str) -> Flow {
+            self.record(format!("comment({})", data))
+        }
+
+        fn error(&mut self, error: &Error) {
+            self.calls.push(format!("error({})", error));
+        }
+    }
+
+    static DATA: &'static str =
+        "<?pi data?><a xmlns:p='urn:p' x='1'><!--c--><p:b>text<![CDATA[cdata]]></p:b></a>";
+
+    #[test]
+    fn callbacks() {
+        let mut reader = EventReader::new_with_config(
+            BufReader::new(DATA.as_bytes()),
+            ParserConfig::new().ignore_comments(false)
+        );
+        let mut handler = Recorder::new(uint::MAX);
+        assert_eq!(parse(&mut reader, &mut handler), Completed);
+        assert_eq!(handler.calls, vec!(
+            "start_document(1.0, UTF-8)".to_string(),
+            "processing_instruction(pi, Some(data))".to_string(),
+            "start_prefix_mapping(Some(p), urn:p)".to_string(),
+            "start_element(a, [x=\"1\"])".to_string(),
+            "comment(c)".to_string(),
+            "start_element({urn:p}p:b, [])".to_string(),
+            "characters(text)".to_string(),
+            "characters(cdata)".to_string(),
+            "end_element({urn:p}p:b)".to_string(),
+            "end_element(a)".to_string(),
+            "end_prefix_mapping(Some(p))".to_string(),
+            "end_document".to_string()
+        ));
+    }
+
+    #[test]
+    fn whitespace_between_inline_elements() {
+        let mut reader = EventReader::new_from_str_slice("<p><b>x</b> <i>y</i></p>");
+        let mut handler = Recorder::new(uint::MAX);
+        assert_eq!(parse(&mut reader, &mut handler), Completed);
+        assert_eq!(handler.calls.slice(4, 7), [
+            "end_element(b)".to_string(),
+            "characters( )".to_string(),
+            "start_element(i, [])".to_string()
+        ].as_slice());
+    }
+
+    #[test]
+    fn early_stop() {
+        let mut reader = EventReader::new_from_str_slice(DATA);
+        let mut handler = Recorder::new(4);
+        assert_eq!(parse(&mut reader, &mut handler), Stopped);
+        assert_eq!(handler.calls.len(), 4);
+        assert_eq!(handler.calls.last().unwrap().as_slice(), "start_element(a, [x=\"1\"])");
+    }
+
+    #[test]
+    fn error() {
+        let mut reader = EventReader::new_from_str_slice("<a></b>");
+        let mut handler = Recorder::new(uint::MAX);
+        match parse(&mut reader, &mut handler) {
+            Failed(ref e) => match *e.kind() {
+                MismatchedClosingTag { .. } => {}
+                ref k => panic!("Unexpected error kind: {}", k)
+            },
+            o => panic!("Unexpected outcome: {}", o)
+        }
+        assert!(handler.calls.last().unwrap().as_slice().starts_with("error("));
+    }
+}
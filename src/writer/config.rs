@@ -5,7 +5,11 @@ pub struct EmitterConfig {
     pub perform_indent: bool,
     pub write_document_declaration: bool,
     pub normalize_empty_elements: bool,
-    pub cdata_to_characters: bool
+    pub cdata_to_characters: bool,
+    pub sanitize_comments: bool,
+    pub check_well_formedness: bool,
+    pub repair_namespaces: bool,
+    pub pad_self_closing: bool
 }
 
 impl EmitterConfig {
@@ -16,7 +20,11 @@ impl EmitterConfig {
             write_document_declaration: true,
             perform_indent: false,
             normalize_empty_elements: true,
-            cdata_to_characters: false
+            cdata_to_characters: false,
+            sanitize_comments: false,
+            check_well_formedness: false,
+            repair_namespaces: false,
+            pad_self_closing: false
         }
     }
 }
@@ -27,5 +35,9 @@ gen_setters!(EmitterConfig,
     perform_indent: bool,
     write_document_declaration: bool,
     normalize_empty_elements: bool,
-    cdata_to_characters: bool
+    cdata_to_characters: bool,
+    sanitize_comments: bool,
+    check_well_formedness: bool,
+    repair_namespaces: bool,
+    pad_self_closing: bool
 )
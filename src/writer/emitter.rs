@@ -3,16 +3,20 @@ use std::iter;
 use std::fmt;
 
 use common;
-use common::{XmlVersion, Attribute, Name, escape_str};
+use common::{XmlVersion, Attribute, Name, escape_str, is_whitespace_char, is_name_start_char, is_name_char};
 use namespace::{NamespaceStack, NamespaceIterable};
 
 use writer::config::EmitterConfig;
 
+#[deriving(PartialEq, Show)]
 pub enum EmitterErrorKind {
     IoError,
     DocumentStartAlreadyEmitted,
     UnexpectedEvent,
-    InvalidWhitespaceEvent
+    InvalidWhitespaceEvent,
+    InvalidCommentContent,
+    InvalidDoctype,
+    InvalidName
 }
 
 pub struct EmitterError {
@@ -60,20 +64,36 @@ pub struct Emitter {
     indent_level: uint,
     indent_stack: Vec<IndentFlags>,
 
-    start_document_emitted: bool
+    element_stack: Vec<Name>,
+    start_element_pending: bool,
+
+    start_document_emitted: bool,
+    doctype_emitted: bool,
+    root_element_started: bool
 }
 
 impl Emitter {
     pub fn new(config: EmitterConfig) -> Emitter {
+        // predefined prefixes are only consulted when the namespaces are repaired
+        let nst = if config.repair_namespaces {
+            NamespaceStack::default()
+        } else {
+            NamespaceStack::empty()
+        };
         Emitter {
             config: config,
 
-            nst: NamespaceStack::empty(),
+            nst: nst,
 
             indent_level: 0,
             indent_stack: vec!(IndentFlags::empty()),
 
-            start_document_emitted: false
+            element_stack: Vec::new(),
+            start_element_pending: false,
+
+            start_document_emitted: false,
+            doctype_emitted: false,
+            root_element_started: false
         }
     }
 }
@@ -157,7 +177,7 @@ impl Emitter {
     }
 
     fn before_markup<W: Writer>(&mut self, target: &mut W) -> EmitterResult<()> {
-        if !self.wrote_text() && (self.indent_level > 0 || self.wrote_markup()) {
+        if self.config.perform_indent && !self.wrote_text() && (self.indent_level > 0 || self.wrote_markup()) {
             let indent_level = self.indent_level;
             try!(self.write_newline(target, indent_level));
             if self.indent_level > 0 && self.config.indent_string.len() > 0 {
@@ -173,17 +193,17 @@ impl Emitter {
 
     fn before_start_element<W: Writer>(&mut self, target: &mut W) -> EmitterResult<()> {
         try!(self.before_markup(target));
+        self.after_markup();
         self.indent_stack.push(WROTE_NOTHING);
         Ok(())
     }
 
     fn after_start_element(&mut self) {
-        self.after_markup();
         self.indent_level += 1;
     }
 
     fn before_end_element<W: Writer>(&mut self, target: &mut W) -> EmitterResult<()> {
-        if self.indent_level > 0 && self.wrote_markup() && !self.wrote_text() {
+        if self.config.perform_indent && self.indent_level > 0 && self.wrote_markup() && !self.wrote_text() {
             let indent_level = self.indent_level;
             self.write_newline(target, indent_level - 1)
         } else {
@@ -230,6 +250,7 @@ impl Emitter {
 
     pub fn emit_processing_instruction<W: Writer>(&mut self, target: &mut W, name: &str, data: Option<&str>) -> EmitterResult<()> {
         try!(self.check_document_started(target));
+        try!(self.close_pending_start_element(target));
 
         wrapped_with!(self; before_markup(target) and after_markup,
             io_chain!(
@@ -242,30 +263,208 @@ impl Emitter {
         )
     }
 
+    pub fn emit_doctype<W: Writer>(&mut self, target: &mut W, name: &str, public_id: Option<&str>,
+                                   system_id: Option<&str>, internal_subset: Option<&str>) -> EmitterResult<()> {
+        if self.root_element_started {
+            return Err(error(UnexpectedEvent, "Doctype declaration is only allowed before the root element"));
+        }
+        if self.doctype_emitted {
+            return Err(error(UnexpectedEvent, "Doctype declaration is already emitted"));
+        }
+        if public_id.is_some() && system_id.is_none() {
+            return Err(error(InvalidDoctype, "Public identifier requires a system identifier"));
+        }
+        let public_id = match public_id {
+            Some(id) => Some(try!(quote_literal(id))),
+            None => None
+        };
+        let system_id = match system_id {
+            Some(id) => Some(try!(quote_literal(id))),
+            None => None
+        };
+
+        try!(self.check_document_started(target));
+        self.doctype_emitted = true;
+
+        wrapped_with!(self; before_markup(target) and after_markup,
+            io_chain!(
+                write!(target, "<!DOCTYPE {}", name),
+
+                match (public_id, system_id) {
+                    (Some(public_id), Some(system_id)) => write!(target, " PUBLIC {} {}", public_id, system_id),
+                    (_, Some(system_id)) => write!(target, " SYSTEM {}", system_id),
+                    _ => Ok(())
+                },
+
+                if_present!(internal_subset, write!(target, " [{}]", internal_subset)),
+
+                write!(target, ">")
+            )
+        )
+    }
+
     fn emit_start_element_initial<'a, W: Writer,
                                   N: NamespaceIterable<'a, I>,
                                   I: Iterator<(Option<&'a str>, &'a str)>
                                  >(&mut self, target: &mut W, name: &Name, attributes: &[Attribute],
-                                   namespace: &'a N) -> EmitterResult<()> {
+                                   namespace: &'a N) -> EmitterResult<Name> {
+        if self.config.check_well_formedness {
+            if self.element_stack.is_empty() && self.root_element_started {
+                return Err(error(UnexpectedEvent, "Document already has a root element"));
+            }
+            if !is_valid_name(name) || attributes.iter().any(|a| !is_valid_name(&a.name)) {
+                return Err(error(InvalidName, "Element or attribute name is not a valid XML name"));
+            }
+        }
+
         try!(self.check_document_started(target));
+        try!(self.close_pending_start_element(target));
+        self.root_element_started = true;
 
         try!(self.before_start_element(target));
 
+        if self.config.repair_namespaces {
+            let (name, attributes) = self.repair_namespaces(name, attributes, namespace);
+
+            let result = self.emit_repaired_start_tag(target, &name, attributes.as_slice());
+            if result.is_err() {
+                // the element is not recorded, so its scope would never be popped
+                self.nst.pop();
+            }
+            result.map(|_| name)
+        } else {
+            io_try!(write!(target, "<{}", name.to_str_proper()));
+
+            try!(self.emit_namespace_attributes(target, namespace));
+
+            try!(self.emit_attributes(target, attributes));
+            Ok(name.clone())
+        }
+    }
+
+    /// Opens a new namespace scope and declares in it the mappings from `namespace` and the
+    /// prefixes required by the name and the attributes which are not bound yet.
+    ///
+    /// Returns the name and the attributes with prefixes which should be written.
+    fn repair_namespaces<'a, N: NamespaceIterable<'a, I>,
+                         I: Iterator<(Option<&'a str>, &'a str)>
+                        >(&mut self, name: &Name, attributes: &[Attribute],
+                          namespace: &'a N) -> (Name, Vec<Attribute>) {
+        self.nst.push_empty();
+        for (prefix, uri) in namespace.uri_mappings() {
+            match prefix {
+                Some("xmlns") | Some("xml") => continue,
+                _ => {}
+            }
+            let prefix = prefix.map(|p| p.to_string());
+            if self.nst.get(&prefix) != Some(uri) {
+                self.nst.put(prefix, uri.to_string());
+            }
+        }
+
+        let name = self.repair_name(name, false);
+        let mut repaired = Vec::with_capacity(attributes.len());
+        for attr in attributes.iter() {
+            repaired.push(Attribute {
+                name: self.repair_name(&attr.name, true),
+                value: attr.value.clone()
+            });
+        }
+        (name, repaired)
+    }
+
+    /// Writes the beginning of a start tag with the mappings declared in the current scope.
+    fn emit_repaired_start_tag<W: Writer>(&mut self, target: &mut W, name: &Name,
+                                          attributes: &[Attribute]) -> EmitterResult<()> {
         io_try!(write!(target, "<{}", name.to_str_proper()));
 
-        try!(self.emit_namespace_attributes(target, namespace));
+        try!(self.emit_declared_namespaces(target));
 
         self.emit_attributes(target, attributes)
     }
 
-    pub fn emit_empty_element<'a, W: Writer,
-                              N: NamespaceIterable<'a, I>,
-                              I: Iterator<(Option<&'a str>, &'a str)>
-                             >(&mut self, target: &mut W, name: &Name, attributes: &[Attribute],
-                               namespace: &'a N) -> EmitterResult<()> {
-        try!(self.emit_start_element_initial(target, name, attributes, namespace));
+    /// Returns the name with a prefix bound to its namespace URI, declaring the prefix in
+    /// the current scope if necessary.
+    fn repair_name(&mut self, name: &Name, is_attribute: bool) -> Name {
+        let uri = match name.namespace {
+            Some(ref uri) if !uri.is_empty() => uri.as_slice(),
+            _ => {
+                // unprefixed element names without namespace require empty default namespace
+                if name.prefix.is_none() && !is_attribute &&
+                   self.nst.get(&None).map_or(false, |uri| !uri.is_empty()) {
+                    self.nst.put(None, String::new());
+                }
+                return name.clone();
+            }
+        };
+
+        match name.prefix {
+            Some(ref prefix) => {
+                let prefix = Some(prefix.clone());
+                let bound = self.nst.get(&prefix).map(|bound| bound == uri);
+                let declared_here = self.nst.0.last().unwrap().get(&prefix).is_some();
+                match bound {
+                    Some(true) => return name.clone(),
+                    // the prefix may be rebound unless it is already used on this element
+                    Some(false) if declared_here => {}
+                    _ => {
+                        self.nst.put(prefix, uri.to_string());
+                        return name.clone();
+                    }
+                }
+            }
+            // default namespace does not apply to attributes, so they always need a prefix
+            None if !is_attribute && self.nst.get(&None) == Some(uri) => return name.clone(),
+            None => {}
+        }
+
+        let prefix = match self.find_prefix(uri) {
+            Some(prefix) => prefix,
+            None => {
+                let prefix = self.generate_prefix();
+                self.nst.put(Some(prefix.clone()), uri.to_string());
+                prefix
+            }
+        };
+        Name {
+            local_name: name.local_name.clone(),
+            prefix: Some(prefix),
+            namespace: Some(uri.to_string())
+        }
+    }
 
-        io_wrap(write!(target, "/>"))
+    /// Returns a prefix which is bound to the given URI in the current scope.
+    fn find_prefix(&self, uri: &str) -> Option<String> {
+        for (prefix, bound) in self.nst.uri_mappings() {
+            match prefix {
+                Some(prefix) if bound == uri && prefix != "xmlns" => return Some(prefix.to_string()),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Returns a prefix like `ns0` which is not bound in the current scope.
+    fn generate_prefix(&self) -> String {
+        let mut i = 0u;
+        loop {
+            let prefix = format!("ns{}", i);
+            if self.nst.get(&Some(prefix.clone())).is_none() {
+                return prefix;
+            }
+            i += 1;
+        }
+    }
+
+    /// Writes the namespace mappings declared in the current scope.
+    fn emit_declared_namespaces<W: Writer>(&self, target: &mut W) -> EmitterResult<()> {
+        for (prefix, uri) in self.nst.0.last().unwrap().uri_mappings() {
+            io_try!(match prefix {
+                Some(prefix) => write!(target, " xmlns:{}=\"{}\"", prefix, uri),
+                None => write!(target, " xmlns=\"{}\"", uri)
+            });
+        }
+        Ok(())
     }
 
     pub fn emit_start_element<'a, W: Writer,
@@ -273,9 +472,23 @@ impl Emitter {
                               I: Iterator<(Option<&'a str>, &'a str)>
                              >(&mut self, target: &mut W, name: &Name, attributes: &[Attribute],
                                namespace: &'a N) -> EmitterResult<()> {
-        try!(self.emit_start_element_initial(target, name, attributes, namespace));
+        let name = try!(self.emit_start_element_initial(target, name, attributes, namespace));
+
+        // the start tag is finished by the next event, so that it can become an empty element tag
+        self.start_element_pending = true;
+        self.element_stack.push(name);
+        self.after_start_element();
+        Ok(())
+    }
 
-        io_wrap(write!(target, ">"))
+    /// Finishes the start tag which has been left open by `emit_start_element()`, if any.
+    fn close_pending_start_element<W: Writer>(&mut self, target: &mut W) -> EmitterResult<()> {
+        if self.start_element_pending {
+            self.start_element_pending = false;
+            io_wrap(target.write_str(">"))
+        } else {
+            Ok(())
+        }
     }
 
     pub fn emit_namespace_attributes<'a, W: Writer,
@@ -301,16 +514,71 @@ impl Emitter {
         Ok(())
     }
 
-    pub fn emit_end_element<W: Writer>(&mut self, target: &mut W, name: &Name) -> EmitterResult<()> {
+    pub fn emit_end_element<W: Writer>(&mut self, target: &mut W, name: Option<&Name>) -> EmitterResult<()> {
+        match self.element_stack.last() {
+            Some(open) => match name {
+                Some(name) if self.config.check_well_formedness && !self.is_open_element(open, name) =>
+                    return Err(error(UnexpectedEvent, "End element does not match the open element")),
+                _ => {}
+            },
+            None if self.config.check_well_formedness || name.is_none() =>
+                return Err(error(UnexpectedEvent, "End element without an open element")),
+            None => {}
+        }
+        let open = self.element_stack.pop();
+        if self.config.repair_namespaces && open.is_some() {
+            self.nst.pop();
+        }
+        let matches_open = match (name, open.as_ref()) {
+            (Some(name), Some(open)) => self.is_open_element(open, name),
+            _ => true
+        };
+        if self.start_element_pending && matches_open && self.config.normalize_empty_elements {
+            self.start_element_pending = false;
+            io_try!(target.write_str(if self.config.pad_self_closing { " />" } else { "/>" }));
+            self.after_end_element();
+            return Ok(());
+        }
+        try!(self.close_pending_start_element(target));
+
+        // prefixes of repaired names may differ from the given ones
+        let name = match (name, open) {
+            (Some(name), _) if !self.config.repair_namespaces => name.to_str_proper(),
+            (_, Some(open)) => open.to_str_proper(),
+            (Some(name), None) => name.to_str_proper(),
+            (None, None) => unreachable!()
+        };
+
         wrapped_with!(self; before_end_element(target) and after_end_element,
-            io_wrap(write!(target, "</{}>", name.to_str_proper()))
+            io_wrap(write!(target, "</{}>", name))
         )
     }
 
+    /// Checks whether `name` denotes the open element with name `open`, comparing namespace
+    /// URIs instead of prefixes when namespaces are repaired.
+    fn is_open_element(&self, open: &Name, name: &Name) -> bool {
+        open.local_name == name.local_name && if self.config.repair_namespaces {
+            open.namespace == name.namespace
+        } else {
+            open.prefix == name.prefix
+        }
+    }
+
+    pub fn emit_end_document<W: Writer>(&mut self, target: &mut W) -> EmitterResult<()> {
+        while !self.element_stack.is_empty() {
+            try!(self.emit_end_element(target, None));
+        }
+        io_wrap(target.flush())
+    }
+
     pub fn emit_cdata<W: Writer>(&mut self, target: &mut W, content: &str) -> EmitterResult<()> {
+        if self.config.check_well_formedness && self.element_stack.is_empty() {
+            return Err(error(UnexpectedEvent, "CDATA is not allowed outside of the root element"));
+        }
         if self.config.cdata_to_characters {
             self.emit_characters(target, content)
         } else {
+            try!(self.close_pending_start_element(target));
             io_try!(target.write_str("<![CDATA["));
             io_try!(target.write_str(content));
             io_try!(target.write_str("]]>"));
@@ -320,12 +588,323 @@ impl Emitter {
     }
 
     pub fn emit_characters<W: Writer>(&mut self, target: &mut W, content: &str) -> EmitterResult<()> {
+        if self.config.check_well_formedness && self.element_stack.is_empty() &&
+           !content.chars().all(is_whitespace_char) {
+            return Err(error(UnexpectedEvent, "Text is not allowed outside of the root element"));
+        }
+        try!(self.close_pending_start_element(target));
         io_try!(target.write_str(escape_str(content).as_slice()));
         self.after_text();
         Ok(())
     }
 
     pub fn emit_comment<W: Writer>(&mut self, target: &mut W, content: &str) -> EmitterResult<()> {
-        Ok(())  // TODO: proper write
+        if content.contains("--") || content.ends_with("-") {
+            if !self.config.sanitize_comments {
+                return Err(error(InvalidCommentContent, "Comment content contains \"--\" or ends with \"-\""));
+            }
+            return self.emit_comment(target, sanitize_comment(content).as_slice());
+        }
+
+        try!(self.check_document_started(target));
+        try!(self.close_pending_start_element(target));
+
+        wrapped_with!(self; before_markup(target) and after_markup,
+            io_wrap(write!(target, "<!--{}-->", content))
+        )
+    }
+}
+
+/// Checks that the prefix and the local part of the name are valid XML names without colons.
+fn is_valid_name(name: &Name) -> bool {
+    fn is_valid_part(part: &str) -> bool {
+        let mut chars = part.chars();
+        match chars.next() {
+            Some(c) if c != ':' && is_name_start_char(c) => chars.all(|c| c != ':' && is_name_char(c)),
+            _ => false
+        }
+    }
+
+    name.prefix.as_ref().map_or(true, |p| is_valid_part(p.as_slice())) &&
+        is_valid_part(name.local_name.as_slice())
+}
+
+/// Encloses an external identifier in quotes which do not occur in it.
+fn quote_literal(literal: &str) -> EmitterResult<String> {
+    if !literal.contains("\"") {
+        Ok(format!("\"{}\"", literal))
+    } else if !literal.contains("'") {
+        Ok(format!("'{}'", literal))
+    } else {
+        Err(error(InvalidDoctype, "External identifier contains both kinds of quotes"))
+    }
+}
+
+/// Separates adjacent hyphens with spaces and adds a space after a trailing hyphen, so
+/// the content can be written inside a comment.
+fn sanitize_comment(content: &str) -> String {
+    let mut result = String::with_capacity(content.len() + 1);
+    let mut prev = ' ';
+    for c in content.chars() {
+        if c == '-' && prev == '-' {
+            result.push(' ');
+        }
+        result.push(c);
+        prev = c;
+    }
+    if prev == '-' {
+        result.push(' ');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::MemWriter;
+
+    use common::{Name, Attribute};
+    use namespace::Namespace;
+    use writer::config::EmitterConfig;
+
+    use super::{Emitter, EmitterErrorKind, EmitterResult, InvalidCommentContent, UnexpectedEvent,
+                InvalidDoctype, InvalidName};
+
+    fn emitter(config: EmitterConfig) -> Emitter {
+        Emitter::new(config.write_document_declaration(false))
+    }
+
+    fn output(w: MemWriter) -> String {
+        String::from_utf8(w.unwrap()).unwrap()
+    }
+
+    fn assert_error(result: EmitterResult<()>, expected: EmitterErrorKind) {
+        match result {
+            Err(ref err) if err.kind == expected => {}
+            Err(ref err) => panic!("Unexpected error: {}", err),
+            Ok(()) => panic!("Event should be rejected")
+        }
+    }
+
+    #[test]
+    fn comments() {
+        let mut e = emitter(EmitterConfig::new().perform_indent(true));
+        let mut w = MemWriter::new();
+        let a = Name::new_local("a");
+        let ns = Namespace::empty();
+        e.emit_comment(&mut w, " top ").unwrap();
+        e.emit_start_element(&mut w, &a, [].as_slice(), &ns).unwrap();
+        e.emit_comment(&mut w, "inner").unwrap();
+        e.emit_start_element(&mut w, &a, [].as_slice(), &ns).unwrap();
+        e.emit_characters(&mut w, "text").unwrap();
+        e.emit_end_element(&mut w, Some(&a)).unwrap();
+        e.emit_end_element(&mut w, Some(&a)).unwrap();
+        assert_eq!(output(w).as_slice(), "<!-- top -->\n<a>\n  <!--inner-->\n  <a>text</a>\n</a>");
+    }
+
+    #[test]
+    fn indentation() {
+        let (a, b, c, d) = (Name::new_local("a"), Name::new_local("b"), Name::new_local("c"), Name::new_local("d"));
+        let ns = Namespace::empty();
+        let write = |e: &mut Emitter| -> String {
+            let mut w = MemWriter::new();
+            e.emit_start_element(&mut w, &a, [].as_slice(), &ns).unwrap();
+            e.emit_start_element(&mut w, &b, [].as_slice(), &ns).unwrap();
+            e.emit_start_element(&mut w, &c, [].as_slice(), &ns).unwrap();
+            e.emit_end_element(&mut w, Some(&c)).unwrap();
+            e.emit_processing_instruction(&mut w, "pi", None).unwrap();
+            e.emit_end_element(&mut w, Some(&b)).unwrap();
+            e.emit_start_element(&mut w, &d, [].as_slice(), &ns).unwrap();
+            e.emit_characters(&mut w, "text").unwrap();
+            e.emit_end_element(&mut w, Some(&d)).unwrap();
+            e.emit_end_element(&mut w, Some(&a)).unwrap();
+            output(w)
+        };
+
+        // nested markup is indented by its depth, elements with text are kept on one line
+        assert_eq!(write(&mut emitter(EmitterConfig::new().perform_indent(true))).as_slice(),
+                   "<a>\n  <b>\n    <c/>\n    <?pi?>\n  </b>\n  <d>text</d>\n</a>");
+        // no line breaks are added without indentation
+        assert_eq!(write(&mut emitter(EmitterConfig::new())).as_slice(),
+                   "<a><b><c/><?pi?></b><d>text</d></a>");
+    }
+
+    #[test]
+    fn invalid_comments() {
+        for content in ["a--b", "a-", "-"].iter() {
+            let mut e = emitter(EmitterConfig::new());
+            let mut w = MemWriter::new();
+            match e.emit_comment(&mut w, *content) {
+                Err(ref err) => match err.kind {
+                    InvalidCommentContent => {}
+                    _ => panic!("Unexpected error: {}", err)
+                },
+                Ok(()) => panic!("Comment {} should be rejected", content)
+            }
+        }
+
+        let mut e = emitter(EmitterConfig::new().sanitize_comments(true));
+        let mut w = MemWriter::new();
+        e.emit_comment(&mut w, "a--b---").unwrap();
+        assert_eq!(output(w).as_slice(), "<!--a- -b- - - -->");
+    }
+
+    #[test]
+    fn doctypes() {
+        let mut e = emitter(EmitterConfig::new().perform_indent(true));
+        let mut w = MemWriter::new();
+        let html = Name::new_local("html");
+        e.emit_doctype(&mut w, "html", Some("-//W3C//DTD XHTML 1.0 Strict//EN"),
+                       Some("http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd"), None).unwrap();
+        e.emit_start_element(&mut w, &html, [].as_slice(), &Namespace::empty()).unwrap();
+        match e.emit_doctype(&mut w, "html", None, None, None) {
+            Err(ref err) => match err.kind {
+                UnexpectedEvent => {}
+                _ => panic!("Unexpected error: {}", err)
+            },
+            Ok(()) => panic!("Doctype after the root element should be rejected")
+        }
+        e.emit_end_element(&mut w, Some(&html)).unwrap();
+        assert_eq!(
+            output(w).as_slice(),
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \
+             \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">\n<html/>"
+        );
+
+        let mut e = emitter(EmitterConfig::new());
+        let mut w = MemWriter::new();
+        e.emit_doctype(&mut w, "a", None, Some("a\".dtd"), Some("<!ELEMENT a EMPTY>")).unwrap();
+        assert_eq!(output(w).as_slice(), "<!DOCTYPE a SYSTEM 'a\".dtd' [<!ELEMENT a EMPTY>]>");
+
+        let mut e = emitter(EmitterConfig::new());
+        let mut w = MemWriter::new();
+        match e.emit_doctype(&mut w, "a", Some("-//A//EN"), None, None) {
+            Err(ref err) => match err.kind {
+                InvalidDoctype => {}
+                _ => panic!("Unexpected error: {}", err)
+            },
+            Ok(()) => panic!("Public identifier without system identifier should be rejected")
+        }
+    }
+
+    #[test]
+    fn well_formedness() {
+        let config = || EmitterConfig::new().check_well_formedness(true);
+        let a = Name::new_local("a");
+        let b = Name::new_local("b");
+        let ns = Namespace::empty();
+
+        let mut e = emitter(config());
+        let mut w = MemWriter::new();
+        e.emit_characters(&mut w, "\n").unwrap();
+        assert_error(e.emit_characters(&mut w, "text"), UnexpectedEvent);
+        assert_error(e.emit_cdata(&mut w, "text"), UnexpectedEvent);
+        assert_error(e.emit_end_element(&mut w, Some(&a)), UnexpectedEvent);
+        e.emit_start_element(&mut w, &a, [].as_slice(), &ns).unwrap();
+        e.emit_start_element(&mut w, &b, [].as_slice(), &ns).unwrap();
+        assert_error(e.emit_end_element(&mut w, Some(&a)), UnexpectedEvent);
+        e.emit_end_element(&mut w, Some(&b)).unwrap();
+        e.emit_end_element(&mut w, Some(&a)).unwrap();
+        assert_error(e.emit_start_element(&mut w, &b, [].as_slice(), &ns), UnexpectedEvent);
+        assert_eq!(output(w).as_slice(), "\n<a><b/></a>");
+
+        let mut e = emitter(config());
+        let mut w = MemWriter::new();
+        assert_error(e.emit_start_element(&mut w, &Name::new_local("1a"), [].as_slice(), &ns), InvalidName);
+        assert_error(e.emit_start_element(&mut w, &Name::new_local("a:b"), [].as_slice(), &ns), InvalidName);
+        let attributes = [Attribute::new_local("a b", "")];
+        assert_error(e.emit_start_element(&mut w, &a, attributes.as_slice(), &ns), InvalidName);
+        e.emit_start_element(&mut w, &Name::new("b", "p", "urn:p"), [].as_slice(), &ns).unwrap();
+
+        // without checking everything is written as is
+        let mut e = emitter(EmitterConfig::new());
+        let mut w = MemWriter::new();
+        e.emit_characters(&mut w, "text").unwrap();
+        e.emit_start_element(&mut w, &a, [].as_slice(), &ns).unwrap();
+        e.emit_end_element(&mut w, Some(&b)).unwrap();
+        assert_eq!(output(w).as_slice(), "text<a></b>");
+    }
+
+    #[test]
+    fn namespace_repairing() {
+        let config = || EmitterConfig::new().repair_namespaces(true);
+        let name = |local: &str, uri: &str| Name {
+            local_name: local.to_string(),
+            prefix: None,
+            namespace: Some(uri.to_string())
+        };
+
+        let mut e = emitter(config().check_well_formedness(true));
+        let mut w = MemWriter::new();
+        let (a, b, c) = (name("a", "urn:a"), Name::new("b", "p", "urn:p"), name("c", "urn:a"));
+        let attributes = [Attribute { name: name("x", "urn:x"), value: "1".to_string() }];
+        e.emit_start_element(&mut w, &a, [].as_slice(), &Namespace::empty()).unwrap();
+        e.emit_start_element(&mut w, &b, [].as_slice(), &Namespace::empty()).unwrap();
+        e.emit_start_element(&mut w, &c, attributes.as_slice(), &Namespace::empty()).unwrap();
+        e.emit_end_element(&mut w, None).unwrap();
+        e.emit_end_element(&mut w, Some(&b)).unwrap();
+        e.emit_end_element(&mut w, Some(&a)).unwrap();
+        assert_eq!(
+            output(w).as_slice(),
+            "<ns0:a xmlns:ns0=\"urn:a\"><p:b xmlns:p=\"urn:p\">\
+             <ns0:c xmlns:ns1=\"urn:x\" ns1:x=\"1\"/></p:b></ns0:a>"
+        );
+
+        // mappings which are already in scope are not declared again
+        let mut e = emitter(config());
+        let mut w = MemWriter::new();
+        let mut ns = Namespace::empty();
+        ns.put(Some("p".to_string()), "urn:p".to_string());
+        e.emit_start_element(&mut w, &Name::new("a", "p", "urn:p"), [].as_slice(), &ns).unwrap();
+        e.emit_start_element(&mut w, &b, [].as_slice(), &ns).unwrap();
+        e.emit_end_document(&mut w).unwrap();
+        assert_eq!(output(w).as_slice(), "<p:a xmlns:p=\"urn:p\"><p:b/></p:a>");
+
+        // default namespace is undeclared for names without namespace
+        let mut e = emitter(config());
+        let mut w = MemWriter::new();
+        let mut ns = Namespace::empty();
+        ns.put(None, "urn:d".to_string());
+        e.emit_start_element(&mut w, &name("d", "urn:d"), [].as_slice(), &ns).unwrap();
+        e.emit_start_element(&mut w, &Name::new_local("f"), [].as_slice(), &Namespace::empty()).unwrap();
+        e.emit_end_document(&mut w).unwrap();
+        assert_eq!(output(w).as_slice(), "<d xmlns=\"urn:d\"><f xmlns=\"\"/></d>");
+
+        // attributes in the default namespace get a prefix
+        let mut e = emitter(config());
+        let mut w = MemWriter::new();
+        let attributes = [Attribute { name: name("x", "urn:d"), value: "1".to_string() }];
+        e.emit_start_element(&mut w, &name("d", "urn:d"), [].as_slice(), &ns).unwrap();
+        e.emit_start_element(&mut w, &name("e", "urn:d"), attributes.as_slice(), &Namespace::empty()).unwrap();
+        e.emit_end_document(&mut w).unwrap();
+        assert_eq!(output(w).as_slice(), "<d xmlns=\"urn:d\"><e xmlns:ns0=\"urn:d\" ns0:x=\"1\"/></d>");
+    }
+
+    #[test]
+    fn empty_elements() {
+        let a = Name::new_local("a");
+        let b = Name::new_local("b");
+        let ns = Namespace::empty();
+        let attributes = [Attribute::new_local("x", "1")];
+        let write = |e: &mut Emitter| -> String {
+            let mut w = MemWriter::new();
+            e.emit_start_element(&mut w, &a, [].as_slice(), &ns).unwrap();
+            e.emit_start_element(&mut w, &b, attributes.as_slice(), &ns).unwrap();
+            e.emit_end_element(&mut w, None).unwrap();
+            e.emit_start_element(&mut w, &b, [].as_slice(), &ns).unwrap();
+            e.emit_characters(&mut w, "").unwrap();
+            e.emit_end_element(&mut w, None).unwrap();
+            e.emit_start_element(&mut w, &b, [].as_slice(), &ns).unwrap();
+            e.emit_end_document(&mut w).unwrap();
+            output(w)
+        };
+
+        assert_eq!(write(&mut emitter(EmitterConfig::new())).as_slice(),
+                   "<a><b x=\"1\"/><b></b><b/></a>");
+        assert_eq!(write(&mut emitter(EmitterConfig::new().pad_self_closing(true))).as_slice(),
+                   "<a><b x=\"1\" /><b></b><b /></a>");
+        assert_eq!(write(&mut emitter(EmitterConfig::new().normalize_empty_elements(false))).as_slice(),
+                   "<a><b x=\"1\"></b><b></b><b></b></a>");
+        assert_eq!(write(&mut emitter(EmitterConfig::new().perform_indent(true))).as_slice(),
+                   "<a>\n  <b x=\"1\"/>\n  <b></b>\n  <b/>\n</a>");
     }
 }
@@ -23,6 +23,27 @@ pub enum XmlEvent<'a> {
         pub standalone: Option<bool>
     },
 
+    /// Denotes a document type declaration.
+    ///
+    /// This event must be written before the root element. The internal subset, if present,
+    /// is written as is between square brackets, so it should contain well-formed markup
+    /// declarations.
+    Doctype {
+        /// Name of the document root element.
+        pub name: &'a str,
+
+        /// Public identifier of the external subset.
+        ///
+        /// If it is present, `system_id` must be present too.
+        pub public_id: Option<&'a str>,
+
+        /// System identifier of the external subset.
+        pub system_id: Option<&'a str>,
+
+        /// Raw contents of the internal subset.
+        pub internal_subset: Option<&'a str>
+    },
+
     /// Denotes an XML processing instruction.
     ///
     /// This event contains a processing instruction target (`name`) and opaque `data`. It
@@ -61,7 +82,9 @@ pub enum XmlEvent<'a> {
     /// latter case it is emitted immediately after corresponding `StartElement` event.
     EndElement {
         /// Qualified name of the element.
-        pub name: &'a Name
+        ///
+        /// If it is `None`, the name of the last open element is used.
+        pub name: Option<&'a Name>
     },
 
     /// Denotes CDATA content.
@@ -33,6 +33,8 @@ impl<W: Writer> EventWriter<W> {
         match event {
             events::StartDocument { version, encoding, standalone } => 
                 self.emitter.emit_start_document(&mut self.sink, version, encoding.unwrap_or("UTF-8"), standalone),
+            events::Doctype { name, public_id, system_id, internal_subset } =>
+                self.emitter.emit_doctype(&mut self.sink, name, public_id, system_id, internal_subset),
             events::ProcessingInstruction { name, data } =>
                 self.emitter.emit_processing_instruction(&mut self.sink, name, data),
             events::StartElement { name, attributes, namespace } =>
@@ -47,6 +49,14 @@ impl<W: Writer> EventWriter<W> {
                 self.emitter.emit_characters(&mut self.sink, content)
         }
     }
+
+    /// Closes all open elements and flushes the underlying writer.
+    ///
+    /// This method can be used to finish the document when its producer has stopped in
+    /// the middle of an element.
+    pub fn finish(&mut self) -> EventWriterResult<()> {
+        self.emitter.emit_end_document(&mut self.sink)
+    }
 }
 
 impl EventWriter<MemWriter> {
@@ -66,12 +76,39 @@ mod tests {
     use std::io;
     use std::io::{File, BufferedReader, MemWriter};
 
+    use common::Name;
+    use namespace::Namespace;
     use reader::EventReader;
-    use writer::EventWriter;
+    use writer::{EventWriter, EmitterConfig};
+    use writer::events;
 
     #[inline]
     fn reader_by_ref<R: Reader>(r: &mut R) -> io::RefReader<R> { r.by_ref() }
 
+    #[test]
+    fn finish_closes_open_elements() {
+        let mut b = MemWriter::new();
+        {
+            let config = EmitterConfig::new().write_document_declaration(false);
+            let mut w = EventWriter::new_with_config(b.by_ref(), config);
+            let (a, c) = (Name::new_local("a"), Name::new("c", "p", "urn:p"));
+            let mut namespace = Namespace::empty();
+            w.write(events::StartElement { name: &a, attributes: [].as_slice(), namespace: &namespace }).unwrap();
+            namespace.put(Some("p".to_string()), "urn:p".to_string());
+            w.write(events::StartElement { name: &c, attributes: [].as_slice(), namespace: &namespace }).unwrap();
+            w.write(events::EndElement { name: None }).unwrap();
+            w.write(events::StartElement { name: &c, attributes: [].as_slice(), namespace: &namespace }).unwrap();
+            w.write(events::Characters("text")).unwrap();
+            w.finish().unwrap();
+            assert!(w.write(events::EndElement { name: None }).is_err());
+        }
+
+        assert_eq!(
+            String::from_utf8(b.unwrap()).unwrap().as_slice(),
+            "<a><p:c xmlns:p=\"urn:p\"/><p:c xmlns:p=\"urn:p\">text</p:c></a>"
+        );
+    }
+
     #[ignore]
     fn writer_test() {
         let mut f = File::open(&Path::new("data/sample_1.xml")).unwrap();
@@ -0,0 +1,587 @@
+//! Contains the evaluator of XPath expressions and the core function library.
+
+use std::collections::{HashMap, HashSet};
+use std::f64;
+use std::num::Float;
+use std::uint;
+
+use common::is_whitespace_char;
+use dom::Document;
+use namespace::{Namespace, NS_XML_PREFIX, NS_XML_URI};
+use reader::dtd;
+
+use xpath::{XPathResult, Value, NodeSetValue, BooleanValue, NumberValue, StringValue};
+use xpath::{UnknownPrefix, UnknownVariable, UnknownFunction, InvalidArguments, NotANodeSet};
+use xpath::node::{NodeRef, ElementType, AttributeType, NamespaceType, TextType, CommentType,
+                  ProcessingInstructionType};
+use xpath::parser::{Expr, BinaryExpr, NegateExpr, LiteralExpr, NumberExpr, VariableExpr,
+                    FunctionCallExpr, FilterExpr, PathExpr, RootStart, ContextStart, ExprStart};
+use xpath::parser::{BinaryOp, OrOp, AndOp, EqOp, NotEqOp, LtOp, LtEqOp, GtOp, GtEqOp, AddOp, SubOp,
+                    MulOp, DivOp, ModOp, UnionOp};
+use xpath::parser::{Step, Axis, AncestorAxis, AncestorOrSelfAxis, AttributeAxis, ChildAxis,
+                    DescendantAxis, DescendantOrSelfAxis, FollowingAxis, FollowingSiblingAxis,
+                    NamespaceAxis, ParentAxis, PrecedingAxis, PrecedingSiblingAxis, SelfAxis};
+use xpath::parser::{NodeTest, AnyNameTest, PrefixTest, NameTest, CommentTest, TextTest,
+                    ProcessingInstructionTest, AnyNodeTest};
+
+/// The context of evaluation of an expression.
+#[deriving(Clone)]
+pub struct Context<'d> {
+    pub node: NodeRef<'d>,
+    pub position: uint,
+    pub size: uint
+}
+
+pub struct Evaluator<'a, 'd: 'a> {
+    document: &'d Document,
+    namespace: &'a Namespace,
+    variables: &'a HashMap<String, Value<'d>>
+}
+
+impl<'a, 'd> Evaluator<'a, 'd> {
+    pub fn new(document: &'d Document, namespace: &'a Namespace,
+               variables: &'a HashMap<String, Value<'d>>) -> Evaluator<'a, 'd> {
+        Evaluator { document: document, namespace: namespace, variables: variables }
+    }
+
+    pub fn evaluate(&self, expr: &Expr, context: &Context<'d>) -> XPathResult<Value<'d>> {
+        match *expr {
+            BinaryExpr(OrOp, ref left, ref right) =>
+                Ok(BooleanValue(try!(self.evaluate(&**left, context)).boolean() ||
+                                try!(self.evaluate(&**right, context)).boolean())),
+            BinaryExpr(AndOp, ref left, ref right) =>
+                Ok(BooleanValue(try!(self.evaluate(&**left, context)).boolean() &&
+                                try!(self.evaluate(&**right, context)).boolean())),
+            BinaryExpr(UnionOp, ref left, ref right) => {
+                let mut nodes = try!(self.evaluate_nodes(&**left, context));
+                nodes.extend(try!(self.evaluate_nodes(&**right, context)).into_iter());
+                Ok(NodeSetValue(document_order(nodes)))
+            }
+            BinaryExpr(op, ref left, ref right) => {
+                let left = try!(self.evaluate(&**left, context));
+                let right = try!(self.evaluate(&**right, context));
+                Ok(match op {
+                    AddOp => NumberValue(left.number() + right.number()),
+                    SubOp => NumberValue(left.number() - right.number()),
+                    MulOp => NumberValue(left.number() * right.number()),
+                    DivOp => NumberValue(left.number() / right.number()),
+                    ModOp => NumberValue(left.number() % right.number()),
+                    op => BooleanValue(compare(op, &left, &right))
+                })
+            }
+            NegateExpr(ref e) => Ok(NumberValue(-try!(self.evaluate(&**e, context)).number())),
+            LiteralExpr(ref s) => Ok(StringValue(s.clone())),
+            NumberExpr(n) => Ok(NumberValue(n)),
+            VariableExpr(ref name) => match self.variables.find(name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(UnknownVariable(name.clone()))
+            },
+            FunctionCallExpr(ref prefix, ref name, ref args) => match *prefix {
+                Some(ref prefix) => Err(UnknownFunction(format!("{}:{}", prefix, name))),
+                None => self.call(name.as_slice(), args.as_slice(), context)
+            },
+            FilterExpr(ref primary, ref predicates) => {
+                let mut nodes = try!(self.evaluate_nodes(&**primary, context));
+                for predicate in predicates.iter() {
+                    nodes = try!(self.filter(nodes, predicate));
+                }
+                Ok(NodeSetValue(nodes))
+            }
+            PathExpr(ref start, ref steps) => {
+                let mut nodes = match *start {
+                    RootStart => vec!(NodeRef::root(self.document)),
+                    ContextStart => vec!(context.node.clone()),
+                    ExprStart(ref e) => try!(self.evaluate_nodes(&**e, context))
+                };
+                for step in steps.iter() {
+                    let mut result = Vec::new();
+                    for node in nodes.iter() {
+                        result.extend(try!(self.apply_step(node, step)).into_iter());
+                    }
+                    nodes = document_order(result);
+                }
+                Ok(NodeSetValue(nodes))
+            }
+        }
+    }
+
+    fn evaluate_nodes(&self, expr: &Expr, context: &Context<'d>) -> XPathResult<Vec<NodeRef<'d>>> {
+        match try!(self.evaluate(expr, context)) {
+            NodeSetValue(nodes) => Ok(nodes),
+            _ => Err(NotANodeSet)
+        }
+    }
+
+    /// Evaluates the predicate for every node and keeps those for which it is true. Nodes
+    /// are given in the order of the axis they were selected with.
+    fn filter(&self, nodes: Vec<NodeRef<'d>>, predicate: &Expr) -> XPathResult<Vec<NodeRef<'d>>> {
+        let size = nodes.len();
+        let mut result = Vec::new();
+        for (i, node) in nodes.into_iter().enumerate() {
+            let context = Context { node: node.clone(), position: i + 1, size: size };
+            let keep = match try!(self.evaluate(predicate, &context)) {
+                NumberValue(n) => n == (i + 1) as f64,
+                v => v.boolean()
+            };
+            if keep {
+                result.push(node);
+            }
+        }
+        Ok(result)
+    }
+
+    fn apply_step(&self, node: &NodeRef<'d>, step: &Step) -> XPathResult<Vec<NodeRef<'d>>> {
+        let test = try!(self.resolve_test(&step.test));
+        let principal_type = match step.axis {
+            AttributeAxis => AttributeType,
+            NamespaceAxis => NamespaceType,
+            _ => ElementType
+        };
+
+        let mut nodes: Vec<NodeRef<'d>> = axis_nodes(node, step.axis).into_iter().filter(|n| {
+            match test {
+                AnyNodeMatch => true,
+                TextMatch => n.node_type() == TextType,
+                CommentMatch => n.node_type() == CommentType,
+                ProcessingInstructionMatch(ref target) =>
+                    n.node_type() == ProcessingInstructionType &&
+                    target.as_ref().map_or(true, |t| *t == n.local_name()),
+                NameMatch(ref uri, ref local) =>
+                    n.node_type() == principal_type &&
+                    local.as_ref().map_or(true, |l| *l == n.local_name()) &&
+                    uri.as_ref().map_or(true, |u| *u == n.namespace_uri())
+            }
+        }).collect();
+
+        for predicate in step.predicates.iter() {
+            nodes = try!(self.filter(nodes, predicate));
+        }
+        Ok(nodes)
+    }
+
+    fn resolve_test(&self, test: &NodeTest) -> XPathResult<NodeMatch> {
+        Ok(match *test {
+            AnyNameTest => NameMatch(None, None),
+            PrefixTest(ref prefix) => NameMatch(Some(try!(self.resolve_prefix(prefix))), None),
+            NameTest(Some(ref prefix), ref local) =>
+                NameMatch(Some(try!(self.resolve_prefix(prefix))), Some(local.clone())),
+            NameTest(None, ref local) => NameMatch(Some(String::new()), Some(local.clone())),
+            CommentTest => CommentMatch,
+            TextTest => TextMatch,
+            ProcessingInstructionTest(ref target) => ProcessingInstructionMatch(target.clone()),
+            AnyNodeTest => AnyNodeMatch
+        })
+    }
+
+    fn resolve_prefix(&self, prefix: &String) -> XPathResult<String> {
+        match self.namespace.get(&Some(prefix.clone())) {
+            Some(uri) => Ok(uri.to_string()),
+            None if prefix.as_slice() == NS_XML_PREFIX => Ok(NS_XML_URI.to_string()),
+            None => Err(UnknownPrefix(prefix.clone()))
+        }
+    }
+
+    fn call(&self, name: &str, args: &[Expr], context: &Context<'d>) -> XPathResult<Value<'d>> {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args.iter() {
+            values.push(try!(self.evaluate(arg, context)));
+        }
+        let values = values.as_slice();
+
+        macro_rules! check_args(
+            ($min:expr, $max:expr) => (
+                if values.len() < $min || values.len() > $max {
+                    return Err(InvalidArguments(name.to_string()));
+                }
+            )
+        )
+
+        // the argument as a string, or the string-value of the context node if it is omitted
+        let string_arg = |i: uint| -> String {
+            match values.get(i) {
+                Some(v) => v.string(),
+                None => context.node.string_value()
+            }
+        };
+
+        // the first node of the argument node-set, or the context node if it is omitted
+        let node_arg = || -> XPathResult<Option<NodeRef<'d>>> {
+            match values.get(0) {
+                Some(&NodeSetValue(ref nodes)) => Ok(nodes.as_slice().get(0).map(|n| n.clone())),
+                Some(_) => Err(InvalidArguments(name.to_string())),
+                None => Ok(Some(context.node.clone()))
+            }
+        };
+
+        Ok(match name {
+            // node-set functions
+            "last" => { check_args!(0, 0); NumberValue(context.size as f64) }
+            "position" => { check_args!(0, 0); NumberValue(context.position as f64) }
+            "count" => {
+                check_args!(1, 1);
+                match values[0] {
+                    NodeSetValue(ref nodes) => NumberValue(nodes.len() as f64),
+                    _ => return Err(InvalidArguments(name.to_string()))
+                }
+            }
+            "id" => {
+                check_args!(1, 1);
+                let ids: Vec<String> = match values[0] {
+                    NodeSetValue(ref nodes) => nodes.iter().map(|n| n.string_value()).collect(),
+                    ref v => vec!(v.string())
+                };
+                NodeSetValue(self.elements_by_id(ids))
+            }
+            "local-name" => {
+                check_args!(0, 1);
+                StringValue(try!(node_arg()).map(|n| n.local_name()).unwrap_or(String::new()))
+            }
+            "namespace-uri" => {
+                check_args!(0, 1);
+                StringValue(try!(node_arg()).map(|n| n.namespace_uri()).unwrap_or(String::new()))
+            }
+            "name" => {
+                check_args!(0, 1);
+                StringValue(try!(node_arg()).map(|n| n.qualified_name()).unwrap_or(String::new()))
+            }
+
+            // string functions
+            "string" => { check_args!(0, 1); StringValue(string_arg(0)) }
+            "concat" => {
+                check_args!(2, uint::MAX);
+                let mut result = String::new();
+                for v in values.iter() {
+                    result.push_str(v.string().as_slice());
+                }
+                StringValue(result)
+            }
+            "starts-with" => {
+                check_args!(2, 2);
+                BooleanValue(string_arg(0).as_slice().starts_with(string_arg(1).as_slice()))
+            }
+            "contains" => {
+                check_args!(2, 2);
+                BooleanValue(string_arg(0).as_slice().contains(string_arg(1).as_slice()))
+            }
+            "substring-before" => {
+                check_args!(2, 2);
+                let s = string_arg(0);
+                StringValue(match s.as_slice().find_str(string_arg(1).as_slice()) {
+                    Some(i) => s.as_slice().slice_to(i).to_string(),
+                    None => String::new()
+                })
+            }
+            "substring-after" => {
+                check_args!(2, 2);
+                let (s, pattern) = (string_arg(0), string_arg(1));
+                StringValue(match s.as_slice().find_str(pattern.as_slice()) {
+                    Some(i) => s.as_slice().slice_from(i + pattern.len()).to_string(),
+                    None => String::new()
+                })
+            }
+            "substring" => {
+                check_args!(2, 3);
+                let start = round(values[1].number());
+                let end = match values.get(2) {
+                    Some(len) => start + round(len.number()),
+                    None => f64::INFINITY
+                };
+                // characters are numbered from 1; NaN comparisons select nothing
+                StringValue(string_arg(0).as_slice().chars().enumerate()
+                    .filter(|&(i, _)| { let p = (i + 1) as f64; p >= start && p < end })
+                    .map(|(_, c)| c)
+                    .collect())
+            }
+            "string-length" => {
+                check_args!(0, 1);
+                NumberValue(string_arg(0).as_slice().char_len() as f64)
+            }
+            "normalize-space" => {
+                check_args!(0, 1);
+                let s = string_arg(0);
+                let words: Vec<&str> = s.as_slice().split(is_whitespace_char).filter(|w| !w.is_empty()).collect();
+                StringValue(words.as_slice().connect(" "))
+            }
+            "translate" => {
+                check_args!(3, 3);
+                let from: Vec<char> = string_arg(1).as_slice().chars().collect();
+                let to: Vec<char> = string_arg(2).as_slice().chars().collect();
+                StringValue(string_arg(0).as_slice().chars().filter_map(|c| {
+                    match from.iter().position(|&f| f == c) {
+                        Some(i) => to.as_slice().get(i).map(|&t| t),
+                        None => Some(c)
+                    }
+                }).collect())
+            }
+
+            // boolean functions
+            "boolean" => { check_args!(1, 1); BooleanValue(values[0].boolean()) }
+            "not" => { check_args!(1, 1); BooleanValue(!values[0].boolean()) }
+            "true" => { check_args!(0, 0); BooleanValue(true) }
+            "false" => { check_args!(0, 0); BooleanValue(false) }
+            "lang" => {
+                check_args!(1, 1);
+                BooleanValue(lang_matches(&context.node, values[0].string().as_slice()))
+            }
+
+            // number functions
+            "number" => {
+                check_args!(0, 1);
+                NumberValue(match values.get(0) {
+                    Some(v) => v.number(),
+                    None => StringValue(context.node.string_value()).number()
+                })
+            }
+            "sum" => {
+                check_args!(1, 1);
+                match values[0] {
+                    NodeSetValue(ref nodes) =>
+                        NumberValue(nodes.iter().fold(0.0, |sum, n| sum + StringValue(n.string_value()).number())),
+                    _ => return Err(InvalidArguments(name.to_string()))
+                }
+            }
+            "floor" => { check_args!(1, 1); NumberValue(values[0].number().floor()) }
+            "ceiling" => { check_args!(1, 1); NumberValue(values[0].number().ceil()) }
+            "round" => { check_args!(1, 1); NumberValue(round(values[0].number())) }
+
+            _ => return Err(UnknownFunction(name.to_string()))
+        })
+    }
+
+    /// Returns elements which have attributes of `ID` type with any of the given values.
+    /// Attribute types are taken from the internal subset of the document type declaration.
+    fn elements_by_id(&self, values: Vec<String>) -> Vec<NodeRef<'d>> {
+        let mut id_attributes = HashSet::new();
+        match self.document.doctype {
+            Some(ref doctype) => for decl in doctype.declarations.iter() {
+                match *decl {
+                    dtd::AttlistDecl { ref element, ref attributes } => for attr in attributes.iter() {
+                        if attr.attribute_type == dtd::IdType {
+                            id_attributes.insert((element.clone(), attr.name.clone()));
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            None => {}
+        }
+
+        let mut ids = HashSet::new();
+        for value in values.iter() {
+            for id in value.as_slice().split(is_whitespace_char) {
+                if !id.is_empty() {
+                    ids.insert(id.to_string());
+                }
+            }
+        }
+
+        let mut result = Vec::new();
+        if id_attributes.is_empty() || ids.is_empty() {
+            return result;
+        }
+        let root = NodeRef::root(self.document);
+        let mut all = Vec::new();
+        push_descendants(&root, &mut all);
+        for node in all.into_iter() {
+            let found = match node.element() {
+                Some(e) => {
+                    let element_name = e.name.to_str_proper();
+                    e.attributes.iter().any(|a| {
+                        id_attributes.contains(&(element_name.clone(), a.name.to_str_proper())) &&
+                        ids.contains(&a.value)
+                    })
+                }
+                None => false
+            };
+            if found {
+                result.push(node);
+            }
+        }
+        result
+    }
+}
+
+/// A node test with its prefixes resolved to namespace URIs. `None` in `NameMatch` matches
+/// any namespace URI or local name.
+enum NodeMatch {
+    AnyNodeMatch,
+    TextMatch,
+    CommentMatch,
+    ProcessingInstructionMatch(Option<String>),
+    NameMatch(Option<String>, Option<String>)
+}
+
+/// Sorts nodes in document order and removes duplicates.
+fn document_order<'d>(mut nodes: Vec<NodeRef<'d>>) -> Vec<NodeRef<'d>> {
+    nodes.sort();
+    nodes.dedup();
+    nodes
+}
+
+/// Returns the nodes of the axis in proximity order, that is, reverse axes return nodes
+/// in reverse document order.
+fn axis_nodes<'d>(node: &NodeRef<'d>, axis: Axis) -> Vec<NodeRef<'d>> {
+    let mut result = Vec::new();
+    match axis {
+        ChildAxis => result = node.children(),
+        DescendantAxis => push_descendants(node, &mut result),
+        DescendantOrSelfAxis => {
+            result.push(node.clone());
+            push_descendants(node, &mut result);
+        }
+        ParentAxis => result.extend(node.parent().into_iter()),
+        AncestorAxis => push_ancestors(node, &mut result),
+        AncestorOrSelfAxis => {
+            result.push(node.clone());
+            push_ancestors(node, &mut result);
+        }
+        FollowingSiblingAxis => result = node.following_siblings(),
+        PrecedingSiblingAxis => {
+            result = node.preceding_siblings();
+            result.reverse();
+        }
+        FollowingAxis => push_following(node, &mut result),
+        PrecedingAxis => {
+            push_preceding(node, &mut result);
+            result.reverse();
+        }
+        AttributeAxis => result = node.attributes(),
+        NamespaceAxis => result = node.namespaces(),
+        SelfAxis => result.push(node.clone())
+    }
+    result
+}
+
+fn push_descendants<'d>(node: &NodeRef<'d>, result: &mut Vec<NodeRef<'d>>) {
+    for child in node.children().into_iter() {
+        result.push(child.clone());
+        push_descendants(&child, result);
+    }
+}
+
+fn push_ancestors<'d>(node: &NodeRef<'d>, result: &mut Vec<NodeRef<'d>>) {
+    let mut current = node.parent();
+    loop {
+        match current {
+            Some(n) => {
+                current = n.parent();
+                result.push(n);
+            }
+            None => break
+        }
+    }
+}
+
+/// Pushes the nodes following the given one in document order, excluding its descendants.
+fn push_following<'d>(node: &NodeRef<'d>, result: &mut Vec<NodeRef<'d>>) {
+    if !node.is_tree_node() {
+        // nodes following an attribute include the children of its element
+        let element = node.parent().unwrap();
+        push_descendants(&element, result);
+        push_following(&element, result);
+        return;
+    }
+    let mut current = node.clone();
+    loop {
+        for sibling in current.following_siblings().into_iter() {
+            result.push(sibling.clone());
+            push_descendants(&sibling, result);
+        }
+        current = match current.parent() {
+            Some(parent) => parent,
+            None => break
+        };
+    }
+}
+
+/// Pushes the nodes preceding the given one in document order, excluding its ancestors.
+fn push_preceding<'d>(node: &NodeRef<'d>, result: &mut Vec<NodeRef<'d>>) {
+    if !node.is_tree_node() {
+        push_preceding(&node.parent().unwrap(), result);
+        return;
+    }
+    let mut chain = vec!(node.clone());
+    push_ancestors(node, &mut chain);
+    for ancestor in chain.iter().rev() {
+        for sibling in ancestor.preceding_siblings().into_iter() {
+            result.push(sibling.clone());
+            push_descendants(&sibling, result);
+        }
+    }
+}
+
+/// Compares two values with the given comparison operator according to XPath rules.
+fn compare<'d>(op: BinaryOp, left: &Value<'d>, right: &Value<'d>) -> bool {
+    match (left, right) {
+        (&NodeSetValue(ref l), &NodeSetValue(ref r)) => l.iter().any(|a| {
+            let a = StringValue(a.string_value());
+            r.iter().any(|b| compare_atoms(op, &a, &StringValue(b.string_value())))
+        }),
+        (&NodeSetValue(_), &BooleanValue(_)) | (&BooleanValue(_), &NodeSetValue(_)) =>
+            compare_atoms(op, &BooleanValue(left.boolean()), &BooleanValue(right.boolean())),
+        (&NodeSetValue(ref l), r) => l.iter().any(|a| compare_atoms(op, &StringValue(a.string_value()), r)),
+        (l, &NodeSetValue(ref r)) => r.iter().any(|b| compare_atoms(op, l, &StringValue(b.string_value()))),
+        (l, r) => compare_atoms(op, l, r)
+    }
+}
+
+/// Compares two values which are not node-sets.
+fn compare_atoms<'d>(op: BinaryOp, left: &Value<'d>, right: &Value<'d>) -> bool {
+    match op {
+        EqOp | NotEqOp => {
+            let equal = match (left, right) {
+                (&BooleanValue(_), _) | (_, &BooleanValue(_)) => left.boolean() == right.boolean(),
+                (&NumberValue(_), _) | (_, &NumberValue(_)) => left.number() == right.number(),
+                _ => left.string() == right.string()
+            };
+            if op == EqOp { equal } else { !equal }
+        }
+        LtOp => left.number() < right.number(),
+        LtEqOp => left.number() <= right.number(),
+        GtOp => left.number() > right.number(),
+        GtEqOp => left.number() >= right.number(),
+        _ => unreachable!()
+    }
+}
+
+/// Rounds the number to the closest integer, rounding halves towards positive infinity.
+fn round(n: f64) -> f64 {
+    if n.is_nan() || n.is_infinite() {
+        n
+    } else if n < 0.0 && n >= -0.5 {
+        -0.0
+    } else {
+        (n + 0.5).floor()
+    }
+}
+
+/// Checks whether `xml:lang` attribute of the node or its closest ancestor which has it
+/// denotes the given language or its sublanguage.
+fn lang_matches<'d>(node: &NodeRef<'d>, lang: &str) -> bool {
+    let mut current = Some(node.clone());
+    loop {
+        let n = match current {
+            Some(n) => n,
+            None => return false
+        };
+        match n.element() {
+            Some(e) => for attr in e.attributes.iter() {
+                if attr.name.local_name.as_slice() == "lang" &&
+                   attr.name.namespace_ref() == Some(NS_XML_URI) {
+                    let value = to_lower(attr.value.as_slice());
+                    let lang = to_lower(lang);
+                    return value == lang ||
+                           value.as_slice().starts_with(lang.as_slice()) &&
+                           value.as_slice().slice_from(lang.len()).starts_with("-");
+                }
+            },
+            None => {}
+        }
+        current = n.parent();
+    }
+}
+
+#[inline]
+fn to_lower(s: &str) -> String {
+    s.chars().map(|c| c.to_lowercase()).collect()
+}
@@ -0,0 +1,261 @@
+//! Contains the lexer for XPath expressions.
+
+use common::{is_whitespace_char, is_name_start_char, is_name_char};
+
+use xpath::{XPathResult, SyntaxError, str_to_number};
+
+/// A token of an XPath expression.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Token {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Dot,
+    DotDot,
+    At,
+    Comma,
+    ColonColon,
+    Slash,
+    SlashSlash,
+    Pipe,
+    Plus,
+    Minus,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Multiply,
+    And,
+    Or,
+    Mod,
+    Div,
+    /// `*` name test.
+    Star,
+    /// `prefix:*` name test.
+    PrefixStar(String),
+    /// A name test with optional prefix.
+    QName(Option<String>, String),
+    /// `comment`, `text`, `processing-instruction` or `node` followed by `(`.
+    NodeType(String),
+    /// A function name with optional prefix.
+    FunctionName(Option<String>, String),
+    /// A name followed by `::`.
+    AxisName(String),
+    Literal(String),
+    Number(f64),
+    /// A variable reference without the leading `$`.
+    Variable(String)
+}
+
+impl Token {
+    fn is_operator(&self) -> bool {
+        match *self {
+            And | Or | Mod | Div | Multiply | Slash | SlashSlash | Pipe | Plus | Minus |
+            Eq | NotEq | Lt | LtEq | Gt | GtEq => true,
+            _ => false
+        }
+    }
+}
+
+/// Splits the expression into tokens, pairing each of them with its byte offset.
+///
+/// `*` and names like `and` or `div` are disambiguated according to section 3.7 of XPath 1.0
+/// specification: they are operators if there is a preceding token and it is not one of `@`,
+/// `::`, `(`, `[`, `,` or another operator.
+pub fn tokenize(expression: &str) -> XPathResult<Vec<(Token, uint)>> {
+    let mut lexer = Lexer {
+        chars: expression.char_indices().collect(),
+        pos: 0,
+        len: expression.len()
+    };
+    let mut tokens: Vec<(Token, uint)> = Vec::new();
+    loop {
+        lexer.skip_whitespace();
+        let offset = lexer.offset();
+        let c = match lexer.peek(0) {
+            Some(c) => c,
+            None => return Ok(tokens)
+        };
+        let operator_expected = match tokens.last() {
+            Some(&(ref t, _)) => match *t {
+                At | ColonColon | LParen | LBracket | Comma => false,
+                ref t => !t.is_operator()
+            },
+            None => false
+        };
+
+        let token = match c {
+            '(' => { lexer.pos += 1; LParen }
+            ')' => { lexer.pos += 1; RParen }
+            '[' => { lexer.pos += 1; LBracket }
+            ']' => { lexer.pos += 1; RBracket }
+            '@' => { lexer.pos += 1; At }
+            ',' => { lexer.pos += 1; Comma }
+            '|' => { lexer.pos += 1; Pipe }
+            '+' => { lexer.pos += 1; Plus }
+            '-' => { lexer.pos += 1; Minus }
+            '=' => { lexer.pos += 1; Eq }
+            '*' => { lexer.pos += 1; if operator_expected { Multiply } else { Star } }
+            '/' => if lexer.peek(1) == Some('/') { lexer.pos += 2; SlashSlash } else { lexer.pos += 1; Slash },
+            '<' => if lexer.peek(1) == Some('=') { lexer.pos += 2; LtEq } else { lexer.pos += 1; Lt },
+            '>' => if lexer.peek(1) == Some('=') { lexer.pos += 2; GtEq } else { lexer.pos += 1; Gt },
+            '!' if lexer.peek(1) == Some('=') => { lexer.pos += 2; NotEq }
+            ':' if lexer.peek(1) == Some(':') => { lexer.pos += 2; ColonColon }
+            '.' => match lexer.peek(1) {
+                Some('.') => { lexer.pos += 2; DotDot }
+                Some('0'...'9') => Number(lexer.read_number()),
+                _ => { lexer.pos += 1; Dot }
+            },
+            '0'...'9' => Number(lexer.read_number()),
+            '"' | '\'' => Literal(try!(lexer.read_literal(c))),
+            '$' => {
+                lexer.pos += 1;
+                match try!(lexer.read_qname()) {
+                    (Some(prefix), local) => Variable(format!("{}:{}", prefix, local)),
+                    (None, local) => Variable(local)
+                }
+            }
+            c if is_ncname_start_char(c) => {
+                if operator_expected {
+                    match lexer.read_ncname().as_slice() {
+                        "and" => And,
+                        "or" => Or,
+                        "mod" => Mod,
+                        "div" => Div,
+                        name => return Err(SyntaxError(offset, format!("Expected an operator, found '{}'", name)))
+                    }
+                } else {
+                    try!(lexer.read_name_token())
+                }
+            }
+            c => return Err(SyntaxError(offset, format!("Unexpected character '{}'", c)))
+        };
+        tokens.push((token, offset));
+    }
+}
+
+#[inline]
+fn is_ncname_start_char(c: char) -> bool {
+    c != ':' && is_name_start_char(c)
+}
+
+#[inline]
+fn is_ncname_char(c: char) -> bool {
+    c != ':' && is_name_char(c)
+}
+
+struct Lexer {
+    chars: Vec<(uint, char)>,
+    pos: uint,
+    len: uint
+}
+
+impl Lexer {
+    #[inline]
+    fn peek(&self, n: uint) -> Option<char> {
+        self.chars.as_slice().get(self.pos + n).map(|&(_, c)| c)
+    }
+
+    #[inline]
+    fn offset(&self) -> uint {
+        match self.chars.as_slice().get(self.pos) {
+            Some(&(offset, _)) => offset,
+            None => self.len
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek(0).map_or(false, is_whitespace_char) {
+            self.pos += 1;
+        }
+    }
+
+    fn read_while(&mut self, pred: fn(char) -> bool) -> String {
+        let mut result = String::new();
+        loop {
+            match self.peek(0) {
+                Some(c) if pred(c) => { result.push(c); self.pos += 1; }
+                _ => return result
+            }
+        }
+    }
+
+    fn read_ncname(&mut self) -> String {
+        let mut result = String::new();
+        result.push(self.peek(0).unwrap());
+        self.pos += 1;
+        result.push_str(self.read_while(is_ncname_char).as_slice());
+        result
+    }
+
+    fn read_qname(&mut self) -> XPathResult<(Option<String>, String)> {
+        if !self.peek(0).map_or(false, is_ncname_start_char) {
+            return Err(SyntaxError(self.offset(), "Expected a name".to_string()));
+        }
+        let name = self.read_ncname();
+        if self.peek(0) == Some(':') && self.peek(1).map_or(false, is_ncname_start_char) {
+            self.pos += 1;
+            Ok((Some(name), self.read_ncname()))
+        } else {
+            Ok((None, name))
+        }
+    }
+
+    /// Reads a name test, a node type, a function name or an axis name.
+    fn read_name_token(&mut self) -> XPathResult<Token> {
+        // `prefix:*` is checked before a qualified name
+        let start = self.pos;
+        let prefix = self.read_ncname();
+        if self.peek(0) == Some(':') && self.peek(1) == Some('*') {
+            self.pos += 2;
+            return Ok(PrefixStar(prefix));
+        }
+        self.pos = start;
+
+        let (prefix, local) = try!(self.read_qname());
+        let end = self.pos;
+        self.skip_whitespace();
+        let token = match (self.peek(0), self.peek(1)) {
+            (Some('('), _) => match (prefix, local.as_slice()) {
+                (None, "comment") | (None, "text") | (None, "processing-instruction") | (None, "node") =>
+                    NodeType(local.clone()),
+                (prefix, _) => FunctionName(prefix, local.clone())
+            },
+            (Some(':'), Some(':')) if prefix.is_none() => AxisName(local),
+            _ => QName(prefix, local)
+        };
+        self.pos = end;
+        Ok(token)
+    }
+
+    fn read_number(&mut self) -> f64 {
+        let mut digits = self.read_while(is_digit);
+        if self.peek(0) == Some('.') {
+            self.pos += 1;
+            digits.push('.');
+            digits.push_str(self.read_while(is_digit).as_slice());
+        }
+        str_to_number(digits.as_slice())
+    }
+
+    fn read_literal(&mut self, quote: char) -> XPathResult<String> {
+        let start = self.offset();
+        self.pos += 1;
+        let mut result = String::new();
+        loop {
+            match self.peek(0) {
+                Some(c) if c == quote => { self.pos += 1; return Ok(result); }
+                Some(c) => { result.push(c); self.pos += 1; }
+                None => return Err(SyntaxError(start, "Unterminated string literal".to_string()))
+            }
+        }
+    }
+}
+
+#[inline]
+fn is_digit(c: char) -> bool {
+    c >= '0' && c <= '9'
+}
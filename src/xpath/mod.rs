@@ -0,0 +1,356 @@
+//! Contains an implementation of XPath 1.0 over `dom` document trees.
+//!
+//! An expression is compiled once with `XPath::compile()` and then can be evaluated against
+//! any number of documents. The result of evaluation is a `Value`, which is one of the four
+//! XPath types: a node-set, a boolean, a number or a string. Nodes of a node-set are `NodeRef`
+//! values which point into the document.
+//!
+//! Namespace prefixes used in the expression are resolved through a `Namespace` provided by
+//! the caller, and names are compared by namespace URI and local name, so prefixes in the
+//! expression do not have to match the prefixes used in the document. Unprefixed names in
+//! the expression always denote names without a namespace, as XPath 1.0 requires.
+//!
+//! The whole XPath 1.0 core function library is supported. `id()` function finds elements by
+//! attributes declared with `ID` type in the internal subset of the document type declaration.
+//!
+//! Documents which are too large to be loaded into a tree can be searched with a subset of
+//! location paths using `stream` module.
+
+use std::collections::HashMap;
+use std::f64;
+use std::fmt;
+use std::from_str::from_str;
+use std::num::Float;
+
+use common::is_whitespace_char;
+use dom::Document;
+use namespace::Namespace;
+
+pub use self::node::NodeRef;
+
+use self::eval::{Evaluator, Context};
+use self::parser::Expr;
+
+pub mod node;
+pub mod stream;
+mod lexer;
+mod parser;
+mod eval;
+
+/// Result of an XPath expression compilation or evaluation.
+pub type XPathResult<T> = Result<T, XPathError>;
+
+/// An error of XPath expression compilation or evaluation.
+#[deriving(Clone, PartialEq)]
+pub enum XPathError {
+    /// The expression is malformed; contains the byte offset of the error in the expression
+    /// and its description.
+    SyntaxError(uint, String),
+
+    /// A namespace prefix used in the expression is not mapped to a URI.
+    UnknownPrefix(String),
+
+    /// A variable used in the expression is not provided.
+    UnknownVariable(String),
+
+    /// A function used in the expression does not exist.
+    UnknownFunction(String),
+
+    /// A function is called with wrong number or wrong types of arguments.
+    InvalidArguments(String),
+
+    /// A node-set is required but the expression produces a value of another type.
+    NotANodeSet,
+
+    /// The expression is valid but is not supported in this context, for example, by
+    /// the streaming matcher.
+    UnsupportedExpression(String)
+}
+
+impl fmt::Show for XPathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyntaxError(offset, ref msg) => write!(f, "Syntax error at {}: {}", offset, msg),
+            UnknownPrefix(ref prefix) => write!(f, "Unknown namespace prefix: {}", prefix),
+            UnknownVariable(ref name) => write!(f, "Unknown variable: ${}", name),
+            UnknownFunction(ref name) => write!(f, "Unknown function: {}()", name),
+            InvalidArguments(ref name) => write!(f, "Invalid arguments of function {}()", name),
+            NotANodeSet => write!(f, "Expression does not produce a node-set"),
+            UnsupportedExpression(ref msg) => write!(f, "Unsupported expression: {}", msg)
+        }
+    }
+}
+
+/// A value of an XPath expression.
+///
+/// Nodes of a node-set are always in document order and contain no duplicates.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Value<'d> {
+    /// A set of nodes of the document.
+    NodeSetValue(Vec<NodeRef<'d>>),
+    /// A boolean value.
+    BooleanValue(bool),
+    /// A floating-point number.
+    NumberValue(f64),
+    /// A string.
+    StringValue(String)
+}
+
+impl<'d> Value<'d> {
+    /// Converts the value to a boolean as `boolean()` XPath function does.
+    pub fn boolean(&self) -> bool {
+        match *self {
+            NodeSetValue(ref nodes) => !nodes.is_empty(),
+            BooleanValue(b) => b,
+            NumberValue(n) => n != 0.0 && !n.is_nan(),
+            StringValue(ref s) => !s.is_empty()
+        }
+    }
+
+    /// Converts the value to a number as `number()` XPath function does.
+    pub fn number(&self) -> f64 {
+        match *self {
+            BooleanValue(b) => if b { 1.0 } else { 0.0 },
+            NumberValue(n) => n,
+            _ => str_to_number(self.string().as_slice())
+        }
+    }
+
+    /// Converts the value to a string as `string()` XPath function does.
+    pub fn string(&self) -> String {
+        match *self {
+            NodeSetValue(ref nodes) => nodes.as_slice().get(0).map(|n| n.string_value()).unwrap_or(String::new()),
+            BooleanValue(b) => (if b { "true" } else { "false" }).to_string(),
+            NumberValue(n) => number_to_string(n),
+            StringValue(ref s) => s.clone()
+        }
+    }
+
+    /// Returns the nodes of a node-set value, or `None` if the value is not a node-set.
+    pub fn into_nodes(self) -> Option<Vec<NodeRef<'d>>> {
+        match self {
+            NodeSetValue(nodes) => Some(nodes),
+            _ => None
+        }
+    }
+}
+
+/// Converts a string to a number according to XPath rules: the string must consist of
+/// an optional minus sign and a decimal number, possibly surrounded by whitespace; anything
+/// else is NaN.
+fn str_to_number(s: &str) -> f64 {
+    let s = s.trim_chars(is_whitespace_char);
+    let digits = if s.starts_with("-") { s.slice_from(1) } else { s };
+    let mut seen_digit = false;
+    let mut seen_dot = false;
+    for c in digits.chars() {
+        match c {
+            '0'...'9' => seen_digit = true,
+            '.' if !seen_dot => seen_dot = true,
+            _ => return f64::NAN
+        }
+    }
+    if !seen_digit {
+        return f64::NAN;
+    }
+    let mut normalized = String::with_capacity(s.len() + 2);
+    if s.starts_with("-") { normalized.push('-'); }
+    if digits.starts_with(".") { normalized.push('0'); }
+    normalized.push_str(digits);
+    if digits.ends_with(".") { normalized.push('0'); }
+    from_str::<f64>(normalized.as_slice()).unwrap_or(f64::NAN)
+}
+
+/// Converts a number to a string according to XPath rules: integers are written without
+/// a decimal point, and special values are written as `NaN`, `Infinity` and `-Infinity`.
+fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n == f64::INFINITY {
+        "Infinity".to_string()
+    } else if n == f64::NEG_INFINITY {
+        "-Infinity".to_string()
+    } else if n == n.trunc() && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// A compiled XPath expression.
+pub struct XPath {
+    expr: Expr
+}
+
+impl XPath {
+    /// Compiles the given expression.
+    pub fn compile(expression: &str) -> XPathResult<XPath> {
+        let tokens = try!(lexer::tokenize(expression));
+        let expr = try!(parser::parse(tokens, expression.len()));
+        Ok(XPath { expr: expr })
+    }
+
+    /// Evaluates the expression with the root of the document as the context node.
+    ///
+    /// `namespace` maps the prefixes used in the expression to namespace URIs. `xml` prefix
+    /// is always mapped to the XML namespace unless `namespace` maps it to something else.
+    pub fn evaluate<'d>(&self, document: &'d Document, namespace: &Namespace) -> XPathResult<Value<'d>> {
+        self.evaluate_at(&NodeRef::root(document), namespace, &HashMap::new())
+    }
+
+    /// Evaluates the expression with the given context node and variable bindings.
+    ///
+    /// Variables are looked up by their names exactly as written in the expression, without
+    /// the leading `$`.
+    pub fn evaluate_at<'d>(&self, context: &NodeRef<'d>, namespace: &Namespace,
+                           variables: &HashMap<String, Value<'d>>) -> XPathResult<Value<'d>> {
+        let evaluator = Evaluator::new(context.document(), namespace, variables);
+        evaluator.evaluate(&self.expr, &Context { node: context.clone(), position: 1, size: 1 })
+    }
+
+    /// Evaluates the expression with the root of the document as the context node and returns
+    /// the resulting nodes.
+    ///
+    /// Returns `NotANodeSet` error if the expression produces a value of another type.
+    pub fn select<'d>(&self, document: &'d Document, namespace: &Namespace) -> XPathResult<Vec<NodeRef<'d>>> {
+        self.evaluate(document, namespace).and_then(|v| v.into_nodes().ok_or(NotANodeSet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::f64;
+    use std::io::BufReader;
+    use std::num::Float;
+
+    use dom::Document;
+    use namespace::Namespace;
+    use reader::{EventReader, ParserConfig};
+
+    use super::{XPath, Value, NodeSetValue, BooleanValue, NumberValue, StringValue};
+    use super::{SyntaxError, UnknownPrefix, UnknownFunction, NotANodeSet};
+    use super::node::{ElementType, TextType, AttributeType, NamespaceType, CommentType};
+
+    static DATA: &'static str = "\
+        <?xml version='1.0'?>\
+        <!DOCTYPE catalog [<!ATTLIST p:item code ID #IMPLIED>]>\
+        <catalog xmlns:p='urn:products' xml:lang='en-GB'>\
+          <!--first-->\
+          <p:item id='x' code='c1' price='10'>Apple<![CDATA[ pie]]></p:item>\
+          <p:item id='y' code='c2' price='2.5'><name>Pear</name></p:item>\
+          <other p:id='z'>  spaced   out  </other>\
+          <?pi data?>\
+        </catalog>";
+
+    fn document() -> Document {
+        let mut reader = EventReader::new_with_config(
+            BufReader::new(DATA.as_bytes()),
+            ParserConfig::new().ignore_comments(false)
+        );
+        Document::from_reader(&mut reader).unwrap()
+    }
+
+    fn namespace() -> Namespace {
+        let mut ns = Namespace::empty();
+        ns.put(Some("q".to_string()), "urn:products".to_string());
+        ns
+    }
+
+    fn eval<'d>(doc: &'d Document, expr: &str) -> Value<'d> {
+        XPath::compile(expr).unwrap().evaluate(doc, &namespace()).unwrap()
+    }
+
+    fn strings(doc: &Document, expr: &str) -> Vec<String> {
+        XPath::compile(expr).unwrap().select(doc, &namespace()).unwrap()
+            .iter().map(|n| n.string_value()).collect()
+    }
+
+    #[test]
+    fn location_paths() {
+        let doc = document();
+        assert_eq!(strings(&doc, "//q:item[@id='x']/text()"), vec!("Apple pie".to_string()));
+        assert_eq!(strings(&doc, "/catalog/q:item/@price"), vec!("10".to_string(), "2.5".to_string()));
+        assert_eq!(strings(&doc, "//name/ancestor::*/@id"), vec!("y".to_string()));
+        assert_eq!(strings(&doc, "//q:item[2]/preceding-sibling::node()[1]"), vec!("Apple pie".to_string()));
+        assert_eq!(strings(&doc, "//q:item[last()]/following::node()").len(), 3);
+        assert_eq!(strings(&doc, "//name/preceding::comment()"), vec!("first".to_string()));
+        assert_eq!(strings(&doc, "//*[@q:id]"), vec!("  spaced   out  ".to_string()));
+        assert_eq!(strings(&doc, "/descendant::q:*[position() > 1]/.."), strings(&doc, "/catalog"));
+        assert_eq!(strings(&doc, "(//q:item | //other)[@id != 'x']/@id"), vec!("y".to_string()));
+        assert_eq!(strings(&doc, "//processing-instruction('pi')"), vec!("data".to_string()));
+
+        let nodes = XPath::compile("/catalog/node()").unwrap().select(&doc, &namespace()).unwrap();
+        let types: Vec<_> = nodes.iter().map(|n| n.node_type()).collect();
+        assert_eq!(types.len(), 5);
+        assert_eq!(types[0], CommentType);
+        assert_eq!(types[1], ElementType);
+
+        let item = XPath::compile("//q:item[1]").unwrap().select(&doc, &namespace()).unwrap();
+        let ns = Namespace::empty();
+        let vars = HashMap::new();
+        let attrs = XPath::compile("@*").unwrap().evaluate_at(&item[0], &ns, &vars).unwrap().into_nodes().unwrap();
+        assert!(attrs.iter().all(|n| n.node_type() == AttributeType));
+        assert_eq!(attrs.len(), 3);
+        let nss = XPath::compile("namespace::*").unwrap().evaluate_at(&item[0], &ns, &vars).unwrap().into_nodes().unwrap();
+        assert!(nss.iter().all(|n| n.node_type() == NamespaceType));
+        assert_eq!(nss.iter().map(|n| n.local_name()).collect::<Vec<String>>(),
+                   vec!("p".to_string(), "xml".to_string()));
+        let text = XPath::compile("text()").unwrap().evaluate_at(&item[0], &ns, &vars).unwrap().into_nodes().unwrap();
+        assert_eq!(text[0].node_type(), TextType);
+    }
+
+    #[test]
+    fn functions_and_operators() {
+        let doc = document();
+        assert_eq!(eval(&doc, "count(//q:item)"), NumberValue(2.0));
+        assert_eq!(eval(&doc, "sum(//@price) * 2"), NumberValue(25.0));
+        assert_eq!(eval(&doc, "7 mod 3 - 1 div 2"), NumberValue(0.5));
+        assert_eq!(eval(&doc, "-(1 + 2)"), NumberValue(-3.0));
+        assert_eq!(eval(&doc, "round(2.5) + floor(-1.5) + ceiling(1.2)"), NumberValue(3.0));
+        assert!(eval(&doc, "number('abc')").number().is_nan());
+        assert_eq!(eval(&doc, "1 div 0"), NumberValue(f64::INFINITY));
+
+        assert_eq!(eval(&doc, "string(1 div 0)"), StringValue("Infinity".to_string()));
+        assert_eq!(eval(&doc, "string(2.50)"), StringValue("2.5".to_string()));
+        assert_eq!(eval(&doc, "concat(name(//q:item), ':', local-name(//q:item), ':', namespace-uri(//q:item))"),
+                   StringValue("p:item:item:urn:products".to_string()));
+        assert_eq!(eval(&doc, "normalize-space(//other)"), StringValue("spaced out".to_string()));
+        assert_eq!(eval(&doc, "substring('12345', 1.5, 2.6)"), StringValue("234".to_string()));
+        assert_eq!(eval(&doc, "substring('12345', 0, 3)"), StringValue("12".to_string()));
+        assert_eq!(eval(&doc, "substring-before('a/b/c', '/')"), StringValue("a".to_string()));
+        assert_eq!(eval(&doc, "substring-after('a/b/c', '/')"), StringValue("b/c".to_string()));
+        assert_eq!(eval(&doc, "translate('bar', 'abc', 'AB')"), StringValue("BAr".to_string()));
+        assert_eq!(eval(&doc, "string-length(//name)"), NumberValue(4.0));
+
+        assert_eq!(eval(&doc, "//@price > 5 and //@price < 5"), BooleanValue(true));
+        assert_eq!(eval(&doc, "//@price = 2.5"), BooleanValue(true));
+        assert_eq!(eval(&doc, "//@price = '2.50'"), BooleanValue(false));
+        assert_eq!(eval(&doc, "not(//missing) = true()"), BooleanValue(true));
+        assert_eq!(eval(&doc, "starts-with(//name, 'Pe') or contains(//name, 'x')"), BooleanValue(true));
+        assert_eq!(eval(&doc, "boolean(//name[lang('en')])"), BooleanValue(true));
+        assert_eq!(eval(&doc, "boolean(//name[lang('GB')])"), BooleanValue(false));
+
+        match eval(&doc, "id('c2 c3')") {
+            NodeSetValue(ref nodes) => {
+                assert_eq!(nodes.len(), 1);
+                assert_eq!(nodes[0].string_value().as_slice(), "Pear");
+            }
+            ref v => panic!("Unexpected value: {}", v)
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let doc = document();
+        match XPath::compile("//a[") {
+            Err(SyntaxError(4, _)) => {}
+            r => panic!("Unexpected result: {}", r.map(|_| ()))
+        }
+        assert!(XPath::compile("a b").is_err());
+        assert!(XPath::compile("'unterminated").is_err());
+        assert_eq!(XPath::compile("//x:a").unwrap().evaluate(&doc, &namespace()), Err(UnknownPrefix("x".to_string())));
+        assert_eq!(XPath::compile("foo()").unwrap().evaluate(&doc, &namespace()), Err(UnknownFunction("foo".to_string())));
+        assert_eq!(XPath::compile("1 + 2").unwrap().select(&doc, &namespace()), Err(NotANodeSet));
+    }
+}
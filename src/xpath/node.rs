@@ -0,0 +1,368 @@
+//! Contains `NodeRef` type which represents nodes of the XPath data model.
+
+use std::cmp::{Ordering, Equal};
+use std::collections::HashMap;
+use std::fmt;
+
+use common::Attribute;
+use dom;
+use dom::{Document, Element, ElementNode, TextNode, CDataNode, CommentNode, ProcessingInstructionNode,
+          Text, CData, Comment, ProcessingInstruction};
+use namespace::{NS_XML_PREFIX, NS_XML_URI};
+
+/// Type of an XPath node.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum NodeType {
+    /// The root of the document.
+    RootType,
+    /// An element.
+    ElementType,
+    /// An attribute of an element.
+    AttributeType,
+    /// A namespace mapping in scope of an element.
+    NamespaceType,
+    /// Character data, including CDATA sections.
+    TextType,
+    /// A comment.
+    CommentType,
+    /// A processing instruction.
+    ProcessingInstructionType
+}
+
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show)]
+enum Position {
+    TreePosition,
+    NamespacePosition(uint),
+    AttributePosition(uint)
+}
+
+/// A reference to a node of a document in the XPath data model.
+///
+/// Apart from the nodes of the document tree, there are the root node, which is the parent of
+/// the top-level nodes, and attribute and namespace nodes, whose parents are elements. Adjacent
+/// text and CDATA nodes of the tree form a single text node, which is referenced by the first
+/// of them.
+///
+/// References are ordered in document order. References to nodes of different documents
+/// must not be compared.
+#[deriving(Clone)]
+pub struct NodeRef<'d> {
+    document: &'d Document,
+    path: Vec<uint>,
+    position: Position
+}
+
+impl<'d> NodeRef<'d> {
+    /// Returns a reference to the root node of the document.
+    #[inline]
+    pub fn root(document: &'d Document) -> NodeRef<'d> {
+        NodeRef { document: document, path: Vec::new(), position: TreePosition }
+    }
+
+    /// Returns a reference to the node of the document tree at the given path, as accepted by
+    /// `Document::node()`, or `None` if there is no such node.
+    pub fn at(document: &'d Document, path: &[uint]) -> Option<NodeRef<'d>> {
+        document.node(path).map(|_| NodeRef { document: document, path: path.to_vec(), position: TreePosition })
+    }
+
+    /// Returns the document this node belongs to.
+    #[inline]
+    pub fn document(&self) -> &'d Document {
+        self.document
+    }
+
+    /// Returns the path of the node in the document tree; for attribute and namespace nodes
+    /// this is the path of their element.
+    #[inline]
+    pub fn path<'a>(&'a self) -> &'a [uint] {
+        self.path.as_slice()
+    }
+
+    /// Returns the node of the document tree this reference points to, if it is not the root,
+    /// an attribute or a namespace node.
+    pub fn node(&self) -> Option<&'d dom::Node> {
+        match self.position {
+            TreePosition if !self.path.is_empty() => self.document.node(self.path.as_slice()),
+            _ => None
+        }
+    }
+
+    /// Returns the element this reference points to, if it is an element node.
+    pub fn element(&self) -> Option<&'d Element> {
+        self.node().and_then(|n| n.as_element())
+    }
+
+    /// Returns the attribute this reference points to, if it is an attribute node.
+    pub fn attribute(&self) -> Option<&'d Attribute> {
+        match self.position {
+            AttributePosition(i) => self.owner_element().map(|e| &e.attributes[i]),
+            _ => None
+        }
+    }
+
+    /// Returns the type of the node.
+    pub fn node_type(&self) -> NodeType {
+        match self.position {
+            NamespacePosition(_) => NamespaceType,
+            AttributePosition(_) => AttributeType,
+            TreePosition => match self.node() {
+                None => RootType,
+                Some(&ElementNode(_)) => ElementType,
+                Some(&TextNode(_)) | Some(&CDataNode(_)) => TextType,
+                Some(&CommentNode(_)) => CommentType,
+                Some(&ProcessingInstructionNode(_)) => ProcessingInstructionType
+            }
+        }
+    }
+
+    /// Returns the local part of the expanded name of the node. For namespace nodes this is
+    /// the prefix, and for processing instructions this is the target. Nodes without names
+    /// have an empty local name.
+    pub fn local_name(&self) -> String {
+        match self.position {
+            NamespacePosition(i) => self.namespace_mapping(i).0.unwrap_or(String::new()),
+            AttributePosition(_) => self.attribute().unwrap().name.local_name.clone(),
+            TreePosition => match self.node() {
+                Some(&ElementNode(ref e)) => e.name.local_name.clone(),
+                Some(&ProcessingInstructionNode(ProcessingInstruction { ref name, .. })) => name.clone(),
+                _ => String::new()
+            }
+        }
+    }
+
+    /// Returns the namespace URI of the expanded name of the node, or an empty string if
+    /// the name has no namespace.
+    pub fn namespace_uri(&self) -> String {
+        let name = match self.position {
+            AttributePosition(_) => &self.attribute().unwrap().name,
+            TreePosition => match self.element() {
+                Some(e) => &e.name,
+                None => return String::new()
+            },
+            NamespacePosition(_) => return String::new()
+        };
+        name.namespace.clone().unwrap_or(String::new())
+    }
+
+    /// Returns the qualified name of the node as written in the document.
+    pub fn qualified_name(&self) -> String {
+        match self.position {
+            AttributePosition(_) => self.attribute().unwrap().name.to_str_proper(),
+            TreePosition => match self.element() {
+                Some(e) => e.name.to_str_proper(),
+                None => self.local_name()
+            },
+            NamespacePosition(_) => self.local_name()
+        }
+    }
+
+    /// Returns the string-value of the node as defined by XPath.
+    pub fn string_value(&self) -> String {
+        match self.position {
+            NamespacePosition(i) => self.namespace_mapping(i).1,
+            AttributePosition(_) => self.attribute().unwrap().value.clone(),
+            TreePosition => match self.node() {
+                None => {
+                    let mut result = String::new();
+                    for node in self.document.children.iter() {
+                        result.push_str(node.text().as_slice());
+                    }
+                    result
+                }
+                Some(&TextNode(_)) | Some(&CDataNode(_)) => {
+                    let index = *self.path.last().unwrap();
+                    text_run(self.siblings().slice_from(index))
+                }
+                Some(&CommentNode(Comment(ref data))) => data.clone(),
+                Some(&ProcessingInstructionNode(ProcessingInstruction { ref data, .. })) =>
+                    data.clone().unwrap_or(String::new()),
+                Some(&ElementNode(ref e)) => e.text()
+            }
+        }
+    }
+
+    /// Returns the parent of the node; attribute and namespace nodes have their elements as
+    /// parents. Only the root node has no parent.
+    pub fn parent(&self) -> Option<NodeRef<'d>> {
+        match self.position {
+            TreePosition if self.path.is_empty() => None,
+            TreePosition => Some(NodeRef {
+                document: self.document,
+                path: self.path.init().to_vec(),
+                position: TreePosition
+            }),
+            _ => Some(NodeRef { document: self.document, path: self.path.clone(), position: TreePosition })
+        }
+    }
+
+    /// Returns the children of the root or of an element node in document order.
+    pub fn children(&self) -> Vec<NodeRef<'d>> {
+        let nodes = match self.position {
+            TreePosition => match self.node() {
+                None => self.document.children.as_slice(),
+                Some(&ElementNode(ref e)) => e.children.as_slice(),
+                Some(_) => return Vec::new()
+            },
+            _ => return Vec::new()
+        };
+
+        let mut result = Vec::new();
+        let mut in_text = false;
+        for (i, node) in nodes.iter().enumerate() {
+            let is_text = is_text(node);
+            if is_text && in_text {
+                continue;  // a part of the previous text node
+            }
+            in_text = is_text;
+            if is_text && text_run(nodes.slice_from(i)).is_empty() {
+                continue;  // text nodes are never empty
+            }
+            result.push(self.child(i));
+        }
+        result
+    }
+
+    /// Returns the siblings of the node which follow it, in document order.
+    pub fn following_siblings(&self) -> Vec<NodeRef<'d>> {
+        match (self.position.clone(), self.path.last()) {
+            (TreePosition, Some(&index)) =>
+                self.parent().unwrap().children().into_iter().filter(|n| *n.path.last().unwrap() > index).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    /// Returns the siblings of the node which precede it, in document order.
+    pub fn preceding_siblings(&self) -> Vec<NodeRef<'d>> {
+        match (self.position.clone(), self.path.last()) {
+            (TreePosition, Some(&index)) =>
+                self.parent().unwrap().children().into_iter().filter(|n| *n.path.last().unwrap() < index).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    /// Returns the attribute nodes of an element node in document order.
+    pub fn attributes(&self) -> Vec<NodeRef<'d>> {
+        match self.element() {
+            Some(e) => range(0, e.attributes.len()).map(|i| NodeRef {
+                document: self.document,
+                path: self.path.clone(),
+                position: AttributePosition(i)
+            }).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Returns the namespace nodes of an element node, one for every namespace mapping in
+    /// scope of the element, ordered by prefix.
+    pub fn namespaces(&self) -> Vec<NodeRef<'d>> {
+        match self.element() {
+            Some(_) => range(0, self.namespace_mappings().len()).map(|i| NodeRef {
+                document: self.document,
+                path: self.path.clone(),
+                position: NamespacePosition(i)
+            }).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Checks whether the node is an attribute or a namespace node.
+    #[inline]
+    pub fn is_tree_node(&self) -> bool {
+        self.position == TreePosition
+    }
+
+    fn child(&self, index: uint) -> NodeRef<'d> {
+        let mut path = self.path.clone();
+        path.push(index);
+        NodeRef { document: self.document, path: path, position: TreePosition }
+    }
+
+    /// Returns the children list containing the node.
+    fn siblings(&self) -> &'d [dom::Node] {
+        let path = self.path.init();
+        match self.document.node(path) {
+            Some(&ElementNode(ref e)) => e.children.as_slice(),
+            _ => self.document.children.as_slice()
+        }
+    }
+
+    fn owner_element(&self) -> Option<&'d Element> {
+        self.document.node(self.path.as_slice()).and_then(|n| n.as_element())
+    }
+
+    /// Returns namespace mappings in scope of the element at the node path, sorted by prefix.
+    fn namespace_mappings(&self) -> Vec<(Option<String>, String)> {
+        let mut mappings = HashMap::new();
+        mappings.insert(Some(NS_XML_PREFIX.to_string()), NS_XML_URI.to_string());
+        let mut nodes = self.document.children.as_slice();
+        for &i in self.path.iter() {
+            match nodes.get(i) {
+                Some(&ElementNode(ref e)) => {
+                    for (prefix, uri) in e.namespace.0.iter() {
+                        mappings.insert(prefix.clone(), uri.clone());
+                    }
+                    nodes = e.children.as_slice();
+                }
+                _ => break
+            }
+        }
+        // an empty URI undeclares the default namespace
+        let mut result: Vec<(Option<String>, String)> = mappings.into_iter().filter(|&(_, ref uri)| !uri.is_empty()).collect();
+        result.sort();
+        result
+    }
+
+    fn namespace_mapping(&self, index: uint) -> (Option<String>, String) {
+        self.namespace_mappings().swap_remove(index).unwrap()
+    }
+}
+
+fn is_text(node: &dom::Node) -> bool {
+    match *node {
+        TextNode(_) | CDataNode(_) => true,
+        _ => false
+    }
+}
+
+/// Returns the concatenated data of text and CDATA nodes at the beginning of the slice.
+fn text_run(nodes: &[dom::Node]) -> String {
+    let mut result = String::new();
+    for node in nodes.iter() {
+        match *node {
+            TextNode(Text(ref data)) | CDataNode(CData(ref data)) => result.push_str(data.as_slice()),
+            _ => break
+        }
+    }
+    result
+}
+
+impl<'d> PartialEq for NodeRef<'d> {
+    fn eq(&self, other: &NodeRef<'d>) -> bool {
+        self.path == other.path && self.position == other.position
+    }
+}
+
+impl<'d> Eq for NodeRef<'d> {}
+
+impl<'d> PartialOrd for NodeRef<'d> {
+    fn partial_cmp(&self, other: &NodeRef<'d>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'d> Ord for NodeRef<'d> {
+    // a path is a prefix of the paths of all descendants, and attributes and namespace
+    // nodes go after their element and before its children
+    fn cmp(&self, other: &NodeRef<'d>) -> Ordering {
+        match self.path.cmp(&other.path) {
+            Equal => self.position.cmp(&other.position),
+            ord => ord
+        }
+    }
+}
+
+impl<'d> fmt::Show for NodeRef<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({}, {})", self.node_type(), self.path, self.position)
+    }
+}
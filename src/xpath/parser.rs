@@ -0,0 +1,468 @@
+//! Contains the syntax tree of XPath expressions and the parser which builds it.
+
+use xpath::{XPathResult, SyntaxError};
+use xpath::lexer::{Token, LParen, RParen, LBracket, RBracket, Dot, DotDot, At, Comma, ColonColon,
+                   Slash, SlashSlash, Pipe, Plus, Minus, Eq, NotEq, Lt, LtEq, Gt, GtEq, Multiply,
+                   And, Or, Mod, Div, Star, PrefixStar, QName, NodeType, FunctionName, AxisName,
+                   Literal, Number, Variable};
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum BinaryOp {
+    OrOp,
+    AndOp,
+    EqOp,
+    NotEqOp,
+    LtOp,
+    LtEqOp,
+    GtOp,
+    GtEqOp,
+    AddOp,
+    SubOp,
+    MulOp,
+    DivOp,
+    ModOp,
+    UnionOp
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Expr {
+    BinaryExpr(BinaryOp, Box<Expr>, Box<Expr>),
+    NegateExpr(Box<Expr>),
+    LiteralExpr(String),
+    NumberExpr(f64),
+    VariableExpr(String),
+    /// Function prefix, local name and arguments.
+    FunctionCallExpr(Option<String>, String, Vec<Expr>),
+    /// A primary expression with predicates.
+    FilterExpr(Box<Expr>, Vec<Expr>),
+    PathExpr(PathStart, Vec<Step>)
+}
+
+/// The node-set a location path starts from.
+#[deriving(Clone, PartialEq, Show)]
+pub enum PathStart {
+    RootStart,
+    ContextStart,
+    ExprStart(Box<Expr>)
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Step {
+    pub axis: Axis,
+    pub test: NodeTest,
+    pub predicates: Vec<Expr>
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Axis {
+    AncestorAxis,
+    AncestorOrSelfAxis,
+    AttributeAxis,
+    ChildAxis,
+    DescendantAxis,
+    DescendantOrSelfAxis,
+    FollowingAxis,
+    FollowingSiblingAxis,
+    NamespaceAxis,
+    ParentAxis,
+    PrecedingAxis,
+    PrecedingSiblingAxis,
+    SelfAxis
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum NodeTest {
+    /// `*`
+    AnyNameTest,
+    /// `prefix:*`
+    PrefixTest(String),
+    /// `prefix:local` or `local`
+    NameTest(Option<String>, String),
+    /// `comment()`
+    CommentTest,
+    /// `text()`
+    TextTest,
+    /// `processing-instruction()` with optional target literal
+    ProcessingInstructionTest(Option<String>),
+    /// `node()`
+    AnyNodeTest
+}
+
+fn axis_by_name(name: &str) -> Option<Axis> {
+    Some(match name {
+        "ancestor" => AncestorAxis,
+        "ancestor-or-self" => AncestorOrSelfAxis,
+        "attribute" => AttributeAxis,
+        "child" => ChildAxis,
+        "descendant" => DescendantAxis,
+        "descendant-or-self" => DescendantOrSelfAxis,
+        "following" => FollowingAxis,
+        "following-sibling" => FollowingSiblingAxis,
+        "namespace" => NamespaceAxis,
+        "parent" => ParentAxis,
+        "preceding" => PrecedingAxis,
+        "preceding-sibling" => PrecedingSiblingAxis,
+        "self" => SelfAxis,
+        _ => return None
+    })
+}
+
+/// Parses the tokens of an expression of the given length in bytes.
+pub fn parse(tokens: Vec<(Token, uint)>, len: uint) -> XPathResult<Expr> {
+    let mut parser = Parser { tokens: tokens, pos: 0, len: len };
+    let expr = try!(parser.parse_expr());
+    match parser.peek() {
+        None => Ok(expr),
+        Some(t) => parser.error(format!("Unexpected {}", t))
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, uint)>,
+    pos: uint,
+    len: uint
+}
+
+impl Parser {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.as_slice().get(self.pos).map(|&(ref t, _)| t.clone())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let result = self.peek();
+        if result.is_some() {
+            self.pos += 1;
+        }
+        result
+    }
+
+    fn offset(&self) -> uint {
+        match self.tokens.as_slice().get(self.pos) {
+            Some(&(_, offset)) => offset,
+            None => self.len
+        }
+    }
+
+    fn error<T>(&self, msg: String) -> XPathResult<T> {
+        Err(SyntaxError(self.offset(), msg))
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek().as_ref() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> XPathResult<()> {
+        if self.eat(&token) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(t) => self.error(format!("Expected {}, found {}", token, t)),
+                None => self.error(format!("Expected {}, found end of expression", token))
+            }
+        }
+    }
+
+    fn parse_expr(&mut self) -> XPathResult<Expr> {
+        let mut left = try!(self.parse_and());
+        while self.eat(&Or) {
+            let right = try!(self.parse_and());
+            left = BinaryExpr(OrOp, box left, box right);
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> XPathResult<Expr> {
+        let mut left = try!(self.parse_equality());
+        while self.eat(&And) {
+            let right = try!(self.parse_equality());
+            left = BinaryExpr(AndOp, box left, box right);
+        }
+        Ok(left)
+    }
+
+    fn parse_equality(&mut self) -> XPathResult<Expr> {
+        let mut left = try!(self.parse_relational());
+        loop {
+            let op = match self.peek() {
+                Some(Eq) => EqOp,
+                Some(NotEq) => NotEqOp,
+                _ => return Ok(left)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_relational());
+            left = BinaryExpr(op, box left, box right);
+        }
+    }
+
+    fn parse_relational(&mut self) -> XPathResult<Expr> {
+        let mut left = try!(self.parse_additive());
+        loop {
+            let op = match self.peek() {
+                Some(Lt) => LtOp,
+                Some(LtEq) => LtEqOp,
+                Some(Gt) => GtOp,
+                Some(GtEq) => GtEqOp,
+                _ => return Ok(left)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_additive());
+            left = BinaryExpr(op, box left, box right);
+        }
+    }
+
+    fn parse_additive(&mut self) -> XPathResult<Expr> {
+        let mut left = try!(self.parse_multiplicative());
+        loop {
+            let op = match self.peek() {
+                Some(Plus) => AddOp,
+                Some(Minus) => SubOp,
+                _ => return Ok(left)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_multiplicative());
+            left = BinaryExpr(op, box left, box right);
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> XPathResult<Expr> {
+        let mut left = try!(self.parse_unary());
+        loop {
+            let op = match self.peek() {
+                Some(Multiply) => MulOp,
+                Some(Div) => DivOp,
+                Some(Mod) => ModOp,
+                _ => return Ok(left)
+            };
+            self.pos += 1;
+            let right = try!(self.parse_unary());
+            left = BinaryExpr(op, box left, box right);
+        }
+    }
+
+    fn parse_unary(&mut self) -> XPathResult<Expr> {
+        if self.eat(&Minus) {
+            Ok(NegateExpr(box try!(self.parse_unary())))
+        } else {
+            self.parse_union()
+        }
+    }
+
+    fn parse_union(&mut self) -> XPathResult<Expr> {
+        let mut left = try!(self.parse_path());
+        while self.eat(&Pipe) {
+            let right = try!(self.parse_path());
+            left = BinaryExpr(UnionOp, box left, box right);
+        }
+        Ok(left)
+    }
+
+    fn parse_path(&mut self) -> XPathResult<Expr> {
+        let mut steps = Vec::new();
+        match self.peek() {
+            Some(Variable(_)) | Some(LParen) | Some(Literal(_)) | Some(Number(_)) | Some(FunctionName(..)) => {
+                let primary = try!(self.parse_primary());
+                let predicates = try!(self.parse_predicates());
+                let filter = if predicates.is_empty() { primary } else { FilterExpr(box primary, predicates) };
+                if !try!(self.parse_separator(&mut steps)) {
+                    return Ok(filter);
+                }
+                try!(self.parse_steps(&mut steps));
+                Ok(PathExpr(ExprStart(box filter), steps))
+            }
+            Some(Slash) => {
+                self.pos += 1;
+                if self.starts_step() {
+                    try!(self.parse_steps(&mut steps));
+                }
+                Ok(PathExpr(RootStart, steps))
+            }
+            Some(SlashSlash) => {
+                try!(self.parse_separator(&mut steps));
+                try!(self.parse_steps(&mut steps));
+                Ok(PathExpr(RootStart, steps))
+            }
+            _ => {
+                try!(self.parse_steps(&mut steps));
+                Ok(PathExpr(ContextStart, steps))
+            }
+        }
+    }
+
+    /// Consumes `/` or `//`, adding `descendant-or-self::node()` step for the latter.
+    fn parse_separator(&mut self, steps: &mut Vec<Step>) -> XPathResult<bool> {
+        if self.eat(&Slash) {
+            Ok(true)
+        } else if self.eat(&SlashSlash) {
+            steps.push(Step { axis: DescendantOrSelfAxis, test: AnyNodeTest, predicates: Vec::new() });
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn starts_step(&self) -> bool {
+        match self.peek() {
+            Some(Dot) | Some(DotDot) | Some(At) | Some(AxisName(_)) | Some(Star) | Some(PrefixStar(_)) |
+            Some(QName(..)) | Some(NodeType(_)) => true,
+            _ => false
+        }
+    }
+
+    fn parse_steps(&mut self, steps: &mut Vec<Step>) -> XPathResult<()> {
+        steps.push(try!(self.parse_step()));
+        while try!(self.parse_separator(steps)) {
+            steps.push(try!(self.parse_step()));
+        }
+        Ok(())
+    }
+
+    fn parse_step(&mut self) -> XPathResult<Step> {
+        let axis = match self.peek() {
+            Some(Dot) => {
+                self.pos += 1;
+                return Ok(Step { axis: SelfAxis, test: AnyNodeTest, predicates: Vec::new() });
+            }
+            Some(DotDot) => {
+                self.pos += 1;
+                return Ok(Step { axis: ParentAxis, test: AnyNodeTest, predicates: Vec::new() });
+            }
+            Some(At) => {
+                self.pos += 1;
+                AttributeAxis
+            }
+            Some(AxisName(name)) => match axis_by_name(name.as_slice()) {
+                Some(axis) => {
+                    self.pos += 1;
+                    try!(self.expect(ColonColon));
+                    axis
+                }
+                None => return self.error(format!("Unknown axis: {}", name))
+            },
+            _ => ChildAxis
+        };
+        let test = try!(self.parse_node_test());
+        let predicates = try!(self.parse_predicates());
+        Ok(Step { axis: axis, test: test, predicates: predicates })
+    }
+
+    fn parse_node_test(&mut self) -> XPathResult<NodeTest> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Star) => Ok(AnyNameTest),
+            Some(PrefixStar(prefix)) => Ok(PrefixTest(prefix)),
+            Some(QName(prefix, local)) => Ok(NameTest(prefix, local)),
+            Some(NodeType(node_type)) => {
+                try!(self.expect(LParen));
+                let test = match node_type.as_slice() {
+                    "comment" => CommentTest,
+                    "text" => TextTest,
+                    "node" => AnyNodeTest,
+                    _ => match self.peek() {
+                        Some(Literal(target)) => {
+                            self.pos += 1;
+                            ProcessingInstructionTest(Some(target))
+                        }
+                        _ => ProcessingInstructionTest(None)
+                    }
+                };
+                try!(self.expect(RParen));
+                Ok(test)
+            }
+            Some(t) => Err(SyntaxError(offset, format!("Expected a node test, found {}", t))),
+            None => Err(SyntaxError(offset, "Expected a node test, found end of expression".to_string()))
+        }
+    }
+
+    fn parse_predicates(&mut self) -> XPathResult<Vec<Expr>> {
+        let mut predicates = Vec::new();
+        while self.eat(&LBracket) {
+            predicates.push(try!(self.parse_expr()));
+            try!(self.expect(RBracket));
+        }
+        Ok(predicates)
+    }
+
+    fn parse_primary(&mut self) -> XPathResult<Expr> {
+        match self.next() {
+            Some(Variable(name)) => Ok(VariableExpr(name)),
+            Some(Literal(value)) => Ok(LiteralExpr(value)),
+            Some(Number(value)) => Ok(NumberExpr(value)),
+            Some(LParen) => {
+                let expr = try!(self.parse_expr());
+                try!(self.expect(RParen));
+                Ok(expr)
+            }
+            Some(FunctionName(prefix, name)) => {
+                try!(self.expect(LParen));
+                let mut args = Vec::new();
+                if !self.eat(&RParen) {
+                    loop {
+                        args.push(try!(self.parse_expr()));
+                        if !self.eat(&Comma) {
+                            break;
+                        }
+                    }
+                    try!(self.expect(RParen));
+                }
+                Ok(FunctionCallExpr(prefix, name, args))
+            }
+            _ => unreachable!()  // checked by parse_path()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use xpath::lexer::tokenize;
+
+    use super::{parse, Expr, BinaryExpr, PathExpr, FilterExpr, FunctionCallExpr, NumberExpr, LiteralExpr,
+                RootStart, ContextStart, Step, AddOp, MulOp, EqOp, ChildAxis, AttributeAxis,
+                DescendantOrSelfAxis, ParentAxis, NameTest, AnyNameTest, AnyNodeTest, TextTest};
+
+    fn parse_str(s: &str) -> Expr {
+        parse(tokenize(s).unwrap(), s.len()).unwrap()
+    }
+
+    fn step(axis: super::Axis, test: super::NodeTest) -> Step {
+        Step { axis: axis, test: test, predicates: Vec::new() }
+    }
+
+    #[test]
+    fn operators_and_names() {
+        // `*` and `div` are operators only after an operand
+        assert_eq!(parse_str("* * div"), BinaryExpr(MulOp,
+            box PathExpr(ContextStart, vec!(step(ChildAxis, AnyNameTest))),
+            box PathExpr(ContextStart, vec!(step(ChildAxis, NameTest(None, "div".to_string()))))
+        ));
+        assert_eq!(parse_str("1 + 2 * 3"), BinaryExpr(AddOp,
+            box NumberExpr(1.0),
+            box BinaryExpr(MulOp, box NumberExpr(2.0), box NumberExpr(3.0))
+        ));
+    }
+
+    #[test]
+    fn abbreviated_paths() {
+        assert_eq!(parse_str("//p:a/../@b"), PathExpr(RootStart, vec!(
+            step(DescendantOrSelfAxis, AnyNodeTest),
+            step(ChildAxis, NameTest(Some("p".to_string()), "a".to_string())),
+            step(ParentAxis, AnyNodeTest),
+            step(AttributeAxis, NameTest(None, "b".to_string()))
+        )));
+        assert_eq!(parse_str("f('x')[1]/text()"), PathExpr(
+            super::ExprStart(box FilterExpr(
+                box FunctionCallExpr(None, "f".to_string(), vec!(LiteralExpr("x".to_string()))),
+                vec!(NumberExpr(1.0))
+            )),
+            vec!(step(ChildAxis, TextTest))
+        ));
+        assert_eq!(parse_str("/ = a"), BinaryExpr(EqOp,
+            box PathExpr(RootStart, Vec::new()),
+            box PathExpr(ContextStart, vec!(step(ChildAxis, NameTest(None, "a".to_string()))))
+        ));
+    }
+}
@@ -0,0 +1,448 @@
+//! Contains a streaming matcher of simple location paths.
+//!
+//! `Selector` reads events from an `EventReader` and finds elements matching a `StreamPath`
+//! without building a tree of the whole document. Each matching element is returned either
+//! as a stream of its events or as an owned `dom::Element`, so only one matching element at
+//! a time has to be kept in memory, if any.
+//!
+//! Stream paths are a subset of XPath location paths: steps along `child` and `descendant`
+//! axes (including `//` abbreviation) with element name tests and predicates on attributes,
+//! for example, `/catalog//p:product[@type='book' and @id]`. Matching elements nested into
+//! another matching element are not reported separately; they are a part of the outer match.
+
+use std::io::Buffer;
+
+use common::{Error, Name, Attribute};
+use dom::{DocumentBuilder, Element, ElementNode};
+use namespace::{Namespace, NS_XML_PREFIX, NS_XML_URI};
+use reader::EventReader;
+use reader::events;
+use reader::events::XmlEvent;
+
+use xpath::{XPathResult, XPathError, UnknownPrefix, UnsupportedExpression};
+use xpath::lexer;
+use xpath::parser;
+use xpath::parser::{Expr, BinaryExpr, LiteralExpr, PathExpr, RootStart, ContextStart, AndOp, EqOp,
+                    AttributeAxis, ChildAxis, DescendantAxis, DescendantOrSelfAxis,
+                    AnyNameTest, PrefixTest, NameTest, AnyNodeTest};
+
+/// A compiled path for `Selector`.
+#[deriving(Clone)]
+pub struct StreamPath {
+    steps: Vec<PathStep>
+}
+
+#[deriving(Clone)]
+struct PathStep {
+    /// Whether the element may be any descendant of the element matched by the previous step
+    /// instead of a child.
+    descendant: bool,
+    /// Namespace URI, which is empty for names without namespace, or `None` for any namespace.
+    namespace: Option<String>,
+    /// Local name or `None` for any name.
+    local_name: Option<String>,
+    attributes: Vec<AttributeTest>
+}
+
+#[deriving(Clone)]
+struct AttributeTest {
+    namespace: String,
+    local_name: String,
+    /// Required value or `None` if the attribute only has to be present.
+    value: Option<String>
+}
+
+impl StreamPath {
+    /// Compiles the given path.
+    ///
+    /// Namespace prefixes used in the path are resolved through `namespace`. Relative paths
+    /// are matched from the root of the document, just like absolute ones.
+    ///
+    /// Returns `UnsupportedExpression` error if the expression is valid XPath but is not
+    /// a path supported by the streaming matcher.
+    pub fn compile(path: &str, namespace: &Namespace) -> XPathResult<StreamPath> {
+        let expr = try!(parser::parse(try!(lexer::tokenize(path)), path.len()));
+        let steps = match expr {
+            PathExpr(RootStart, steps) | PathExpr(ContextStart, steps) => steps,
+            _ => return Err(unsupported("only location paths are supported"))
+        };
+
+        let mut result = Vec::new();
+        let mut descendant = false;
+        for step in steps.into_iter() {
+            match step.axis {
+                DescendantOrSelfAxis if step.test == AnyNodeTest && step.predicates.is_empty() => {
+                    descendant = true;  // `//`
+                    continue;
+                }
+                DescendantAxis => descendant = true,
+                ChildAxis => {}
+                _ => return Err(unsupported("only child and descendant axes are supported"))
+            }
+            let (ns, local_name) = match step.test {
+                AnyNameTest => (None, None),
+                PrefixTest(ref prefix) => (Some(try!(resolve_prefix(prefix, namespace))), None),
+                NameTest(Some(ref prefix), ref local) =>
+                    (Some(try!(resolve_prefix(prefix, namespace))), Some(local.clone())),
+                NameTest(None, ref local) => (Some(String::new()), Some(local.clone())),
+                _ => return Err(unsupported("only element name tests are supported"))
+            };
+            let mut attributes = Vec::new();
+            for predicate in step.predicates.iter() {
+                try!(convert_predicate(predicate, namespace, &mut attributes));
+            }
+            result.push(PathStep {
+                descendant: descendant,
+                namespace: ns,
+                local_name: local_name,
+                attributes: attributes
+            });
+            descendant = false;
+        }
+
+        if descendant || result.is_empty() {
+            return Err(unsupported("the path must end with an element name test"));
+        }
+        Ok(StreamPath { steps: result })
+    }
+
+    /// Computes the steps reached by an element given the steps reached by its parent.
+    fn advance(&self, states: &[uint], name: &Name, attributes: &[Attribute]) -> Vec<uint> {
+        let mut result = Vec::new();
+        for &i in states.iter() {
+            let step = &self.steps[i];
+            if step.descendant && !result.contains(&i) {
+                result.push(i);
+            }
+            if step.matches(name, attributes) && !result.contains(&(i + 1)) {
+                result.push(i + 1);
+            }
+        }
+        result
+    }
+}
+
+impl PathStep {
+    fn matches(&self, name: &Name, attributes: &[Attribute]) -> bool {
+        self.local_name.as_ref().map_or(true, |l| *l == name.local_name) &&
+        self.namespace.as_ref().map_or(true, |ns| ns.as_slice() == name.namespace_ref().unwrap_or("")) &&
+        self.attributes.iter().all(|test| attributes.iter().any(|attr| {
+            attr.name.local_name == test.local_name &&
+            attr.name.namespace_ref().unwrap_or("") == test.namespace.as_slice() &&
+            test.value.as_ref().map_or(true, |v| *v == attr.value)
+        }))
+    }
+}
+
+#[inline]
+fn unsupported(msg: &str) -> XPathError {
+    UnsupportedExpression(msg.to_string())
+}
+
+fn resolve_prefix(prefix: &String, namespace: &Namespace) -> XPathResult<String> {
+    match namespace.get(&Some(prefix.clone())) {
+        Some(uri) => Ok(uri.to_string()),
+        None if prefix.as_slice() == NS_XML_PREFIX => Ok(NS_XML_URI.to_string()),
+        None => Err(UnknownPrefix(prefix.clone()))
+    }
+}
+
+/// Converts `@name`, `@name = 'value'` and conjunctions of them.
+fn convert_predicate(expr: &Expr, namespace: &Namespace, result: &mut Vec<AttributeTest>) -> XPathResult<()> {
+    match *expr {
+        BinaryExpr(AndOp, ref left, ref right) => {
+            try!(convert_predicate(&**left, namespace, result));
+            convert_predicate(&**right, namespace, result)
+        }
+        BinaryExpr(EqOp, ref left, ref right) => {
+            let (attr, value) = match (&**left, &**right) {
+                (attr, &LiteralExpr(ref value)) | (&LiteralExpr(ref value), attr) => (attr, value),
+                _ => return Err(unsupported("attributes can only be compared with literals"))
+            };
+            let (ns, local_name) = try!(attribute_name(attr, namespace));
+            result.push(AttributeTest { namespace: ns, local_name: local_name, value: Some(value.clone()) });
+            Ok(())
+        }
+        ref attr => {
+            let (ns, local_name) = try!(attribute_name(attr, namespace));
+            result.push(AttributeTest { namespace: ns, local_name: local_name, value: None });
+            Ok(())
+        }
+    }
+}
+
+fn attribute_name(expr: &Expr, namespace: &Namespace) -> XPathResult<(String, String)> {
+    match *expr {
+        PathExpr(ContextStart, ref steps) if steps.len() == 1 &&
+                                              steps[0].axis == AttributeAxis &&
+                                              steps[0].predicates.is_empty() => match steps[0].test {
+            NameTest(Some(ref prefix), ref local) => Ok((try!(resolve_prefix(prefix, namespace)), local.clone())),
+            NameTest(None, ref local) => Ok((String::new(), local.clone())),
+            _ => Err(unsupported("attribute predicates must use attribute names"))
+        },
+        _ => Err(unsupported("only attribute predicates are supported"))
+    }
+}
+
+/// Finds elements matching a `StreamPath` in the events of an `EventReader`.
+pub struct Selector<'r, B: 'r> {
+    reader: &'r mut EventReader<B>,
+    path: StreamPath,
+    /// Steps reached by the open elements which are not inside a match, with the root
+    /// of the document at the bottom.
+    states: Vec<Vec<uint>>,
+    /// The start event of the current match if it has not been returned yet.
+    pending: Option<XmlEvent>,
+    /// The number of open elements of the current match.
+    depth: uint,
+    finished: bool
+}
+
+impl<'r, B: Buffer> Selector<'r, B> {
+    /// Creates a selector which reads events from the given reader.
+    ///
+    /// The reader should be positioned at the beginning of the document.
+    pub fn new(reader: &'r mut EventReader<B>, path: &StreamPath) -> Selector<'r, B> {
+        Selector {
+            reader: reader,
+            path: path.clone(),
+            states: vec!(vec!(0)),
+            pending: None,
+            depth: 0,
+            finished: false
+        }
+    }
+
+    /// Finds the next matching element and returns an iterator over its events, from its
+    /// `StartElement` to its `EndElement` inclusive.
+    ///
+    /// If the iterator of the previous match has not been exhausted, the rest of its events
+    /// is skipped. Returns `None` after the end of the document, and an error if the document
+    /// is malformed; errors inside a match are returned by the iterator as `Error` events.
+    pub fn next_match<'s>(&'s mut self) -> Option<Result<MatchEvents<'s, 'r, B>, Error>> {
+        match self.skip_match() {
+            Some(e) => return Some(Err(e)),
+            None => {}
+        }
+        while !self.finished {
+            match self.reader.next() {
+                events::StartElement { name, attributes, namespace } => {
+                    let states = self.path.advance(self.states.last().unwrap().as_slice(),
+                                                   &name, attributes.as_slice());
+                    let matched = states.contains(&self.path.steps.len());
+                    self.states.push(states);
+                    if matched {
+                        self.pending = Some(events::StartElement {
+                            name: name,
+                            attributes: attributes,
+                            namespace: namespace
+                        });
+                        return Some(Ok(MatchEvents { selector: self }));
+                    }
+                }
+                events::EndElement { .. } => { self.states.pop(); }
+                events::EndDocument => self.finished = true,
+                events::Error(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Finds the next matching element and reads it into a tree.
+    ///
+    /// All namespace mappings in scope of the element which differ from the default ones are
+    /// declared on the returned element, so it can be used independently of the document.
+    pub fn next_element(&mut self) -> Option<Result<Element, Error>> {
+        let mut builder = DocumentBuilder::new();
+        {
+            let events = match self.next_match() {
+                Some(Ok(events)) => events,
+                Some(Err(e)) => return Some(Err(e)),
+                None => return None
+            };
+            for event in events {
+                match builder.push_event(event) {
+                    Ok(()) => {}
+                    Err(e) => return Some(Err(e))
+                }
+            }
+        }
+        builder.push_event(events::EndDocument).unwrap();
+        match builder.into_document().unwrap().children.pop() {
+            Some(ElementNode(e)) => Some(Ok(e)),
+            _ => unreachable!()
+        }
+    }
+
+    /// Returns an iterator over matching elements read into trees.
+    #[inline]
+    pub fn elements<'s>(&'s mut self) -> Elements<'s, 'r, B> {
+        Elements { selector: self }
+    }
+
+    fn next_match_event(&mut self) -> Option<XmlEvent> {
+        match self.pending.take() {
+            Some(start) => {
+                self.depth = 1;
+                return Some(start);
+            }
+            None => {}
+        }
+        if self.depth == 0 {
+            return None;
+        }
+        let event = self.reader.next();
+        match event {
+            events::StartElement { .. } => self.depth += 1,
+            events::EndElement { .. } => {
+                self.depth -= 1;
+                if self.depth == 0 {
+                    self.states.pop();
+                }
+            }
+            events::Error(_) => {
+                self.depth = 0;
+                self.finished = true;
+            }
+            _ => {}
+        }
+        Some(event)
+    }
+
+    /// Skips the rest of the current match, returning an error if it occurs there.
+    fn skip_match(&mut self) -> Option<Error> {
+        loop {
+            match self.next_match_event() {
+                Some(events::Error(e)) => return Some(e),
+                Some(_) => {}
+                None => return None
+            }
+        }
+    }
+}
+
+/// An iterator over events of a matching element, returned by `Selector::next_match()`.
+pub struct MatchEvents<'s, 'r: 's, B: 'r> {
+    selector: &'s mut Selector<'r, B>
+}
+
+impl<'s, 'r, B: Buffer> Iterator<XmlEvent> for MatchEvents<'s, 'r, B> {
+    #[inline]
+    fn next(&mut self) -> Option<XmlEvent> {
+        self.selector.next_match_event()
+    }
+}
+
+/// An iterator over matching elements, returned by `Selector::elements()`.
+pub struct Elements<'s, 'r: 's, B: 'r> {
+    selector: &'s mut Selector<'r, B>
+}
+
+impl<'s, 'r, B: Buffer> Iterator<Result<Element, Error>> for Elements<'s, 'r, B> {
+    #[inline]
+    fn next(&mut self) -> Option<Result<Element, Error>> {
+        self.selector.next_element()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use namespace::Namespace;
+    use reader::EventReader;
+    use reader::events;
+    use xpath::{UnknownPrefix, UnsupportedExpression};
+
+    use super::{StreamPath, Selector};
+
+    static DATA: &'static str = "\
+        <catalog xmlns='urn:c' xmlns:p='urn:p'>\
+          <p:product type='book' id='1'><p:name>A</p:name></p:product>\
+          <group>\
+            <p:product type='food' id='2'><p:name>B</p:name></p:product>\
+            <p:product type='book'><p:name>C</p:name></p:product>\
+          </group>\
+          <p:product type='book' id='3'><p:product id='4'/></p:product>\
+        </catalog>";
+
+    fn namespace() -> Namespace {
+        let mut ns = Namespace::empty();
+        ns.put(Some("c".to_string()), "urn:c".to_string());
+        ns.put(Some("q".to_string()), "urn:p".to_string());
+        ns
+    }
+
+    fn select_ids(path: &str) -> Vec<String> {
+        let path = StreamPath::compile(path, &namespace()).unwrap();
+        let mut reader = EventReader::new_from_str_slice(DATA);
+        let mut selector = Selector::new(&mut reader, &path);
+        selector.elements().map(|e| {
+            let e = e.unwrap();
+            e.attributes.iter().find(|a| a.name.local_name.as_slice() == "id" && a.name.namespace.is_none())
+                .map(|a| a.value.clone()).unwrap_or("-".to_string())
+        }).collect()
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(select_ids("/c:catalog/q:product"), vec!("1".to_string(), "3".to_string()));
+        assert_eq!(select_ids("//q:product"), vec!("1".to_string(), "2".to_string(), "-".to_string(), "3".to_string()));
+        assert_eq!(select_ids("//c:group/*[@type='book']"), vec!("-".to_string()));
+        assert_eq!(select_ids("c:catalog//q:product[@id and @type = 'book']"), vec!("1".to_string(), "3".to_string()));
+        assert_eq!(select_ids("/c:catalog/descendant::q:product/q:product"), vec!("4".to_string()));
+        assert!(select_ids("/catalog").is_empty());  // unprefixed names have no namespace
+    }
+
+    #[test]
+    fn match_events() {
+        let path = StreamPath::compile("//q:product", &namespace()).unwrap();
+        let mut reader = EventReader::new_from_str_slice(DATA);
+        let mut selector = Selector::new(&mut reader, &path);
+
+        // the first match is read partially, the rest of it is skipped
+        match selector.next_match().unwrap().unwrap().next() {
+            Some(events::StartElement { ref name, .. }) => assert_eq!(name.local_name.as_slice(), "product"),
+            e => panic!("Unexpected event: {}", e)
+        }
+        let events: Vec<_> = selector.next_match().unwrap().unwrap().collect();
+        assert_eq!(events.len(), 5);
+        match events[2] {
+            events::Characters(ref data) => assert_eq!(data.as_slice(), "B"),
+            ref e => panic!("Unexpected event: {}", e)
+        }
+
+        let element = selector.next_element().unwrap().unwrap();
+        assert_eq!(element.child("name", Some("urn:p")).unwrap().text().as_slice(), "C");
+        // mappings in scope are declared on the element
+        assert_eq!(element.namespace.get(&Some("p".to_string())), Some("urn:p"));
+        assert_eq!(element.namespace.get(&None), Some("urn:c"));
+
+        assert!(selector.next_element().is_some());
+        assert!(selector.next_match().is_none());
+    }
+
+    #[test]
+    fn errors() {
+        let ns = namespace();
+        match StreamPath::compile("//x:a", &ns) {
+            Err(UnknownPrefix(ref prefix)) if prefix.as_slice() == "x" => {}
+            _ => panic!("Prefix x should be unknown")
+        }
+        for path in ["count(a)", "a/..", "a[1]", "a[@b > 1]", "a/text()"].iter() {
+            match StreamPath::compile(*path, &ns) {
+                Err(UnsupportedExpression(_)) => {}
+                Err(e) => panic!("Unexpected error for {}: {}", path, e),
+                Ok(_) => panic!("Path {} should not be supported", path)
+            }
+        }
+
+        let path = StreamPath::compile("//c:group", &ns).unwrap();
+        let mut reader = EventReader::new_from_str_slice("<catalog xmlns='urn:c'><group></catalog>");
+        let mut selector = Selector::new(&mut reader, &path);
+        assert!(selector.next_element().unwrap().is_err());
+        assert!(selector.next_element().is_none());
+    }
+}